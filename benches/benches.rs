@@ -2,10 +2,14 @@
 use cabac::perf::fpaq_parallel_simd_get_pattern;
 
 use cabac::perf::{
-    fpaq_get_pattern, fpaq_parallel_get_pattern, fpaq_parallel_put_pattern, fpaq_put_pattern,
-    h265_get_pattern, h265_get_pattern_bypass, h265_put_pattern, h265_put_pattern_bypass,
-    rans32_get_pattern, rans32_get_pattern_bypass, rans32_put_pattern, rans32_put_pattern_bypass,
-    vp8_get_pattern, vp8_get_pattern_bypass, vp8_put_pattern, vp8_put_pattern_bypass,
+    av1_get_pattern, av1_get_symbols, av1_put_pattern, av1_put_symbols, fenwick_get_symbols,
+    fenwick_put_symbols, fpaq_get_pattern, fpaq_parallel_get_pattern, fpaq_parallel_put_pattern,
+    fpaq_put_pattern, fse_get_symbols, fse_put_symbols, h265_get_pattern, h265_get_pattern_bypass,
+    h265_put_pattern, h265_put_pattern_bypass, hybrid_uint_get_values, hybrid_uint_put_values,
+    rans32_get_pattern, rans32_get_pattern_bypass, rans32_get_symbols, rans32_put_pattern,
+    rans32_put_pattern_bypass, rans32_put_symbols, rans32x_get_symbols, rans32x_put_symbols,
+    rans64_get_pattern, rans64_put_pattern, vp8_get_pattern, vp8_get_pattern_bypass,
+    vp8_get_symbols, vp8_put_pattern, vp8_put_pattern_bypass, vp8_put_symbols,
 };
 
 use criterion::{criterion_group, criterion_main, Criterion};
@@ -17,11 +21,39 @@ fn criterion_benchmark(c: &mut Criterion) {
         .for_each(|x| pattern.push(x));
 
     let rans_pattern = rans32_put_pattern(&pattern);
+    let rans64_pattern = rans64_put_pattern(&pattern);
     let vp8_pattern = vp8_put_pattern(&pattern);
     let h265_pattern = h265_put_pattern(&pattern);
     let fpaq_pattern = fpaq_put_pattern(&pattern);
     let fpaq_parallel_pattern = fpaq_parallel_put_pattern(&pattern);
 
+    let av1_pattern = av1_put_pattern(&pattern);
+
+    // skewed 16 symbol alphabet so that the adaptation matters
+    let mut symbols = Vec::<u8>::new();
+    for _ in 0..65535 / 4 {
+        let a: u8 = rand::Rng::gen_range(&mut rand::thread_rng(), 0..16);
+        let b: u8 = rand::Rng::gen_range(&mut rand::thread_rng(), 0..16);
+        symbols.push(a.min(b));
+    }
+
+    let av1_symbols = av1_put_symbols(&symbols);
+    let vp8_symbols = vp8_put_symbols(&symbols);
+    let fse_symbols = fse_put_symbols(&symbols);
+    let fenwick_symbols = fenwick_put_symbols(&symbols);
+    let rans32_symbols = rans32_put_symbols(&symbols);
+    let rans32x8_symbols = rans32x_put_symbols::<8>(&symbols);
+    let rans32x32_symbols = rans32x_put_symbols::<32>(&symbols);
+
+    // integers of all sizes, with small values more likely
+    let mut values = Vec::<u32>::new();
+    for _ in 0..65535 / 4 {
+        let v: u32 = rand::Rng::gen(&mut rand::thread_rng());
+        let shift = rand::Rng::gen_range(&mut rand::thread_rng(), 0..32);
+        values.push(v >> shift);
+    }
+    let hybrid_uint_values = hybrid_uint_put_values(&values);
+
     let rans_pattern_bypass = rans32_put_pattern_bypass(&pattern);
     let vp8_pattern_bypass = vp8_put_pattern_bypass(&pattern);
     let h265_pattern_bypass = h265_put_pattern_bypass(&pattern);
@@ -62,6 +94,18 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    c.bench_function("Rans64 read", |b| {
+        b.iter(|| {
+            rans64_get_pattern(&pattern, &rans64_pattern);
+        });
+    });
+
+    c.bench_function("Rans64 write", |b| {
+        b.iter(|| {
+            rans64_put_pattern(&pattern);
+        })
+    });
+
     c.bench_function("Fpaq write", |b| {
         b.iter(|| {
             fpaq_put_pattern(&pattern);
@@ -104,6 +148,102 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    c.bench_function("AV1 read", |b| {
+        b.iter(|| {
+            av1_get_pattern(&pattern, &av1_pattern);
+        })
+    });
+
+    c.bench_function("AV1 write", |b| {
+        b.iter(|| {
+            av1_put_pattern(&pattern);
+        })
+    });
+
+    c.bench_function("AV1 read 16 symbols", |b| {
+        b.iter(|| {
+            av1_get_symbols(&symbols, &av1_symbols);
+        })
+    });
+
+    c.bench_function("AV1 write 16 symbols", |b| {
+        b.iter(|| {
+            av1_put_symbols(&symbols);
+        })
+    });
+
+    c.bench_function("VP8 read 16 symbols binarized", |b| {
+        b.iter(|| {
+            vp8_get_symbols(&symbols, &vp8_symbols);
+        })
+    });
+
+    c.bench_function("VP8 write 16 symbols binarized", |b| {
+        b.iter(|| {
+            vp8_put_symbols(&symbols);
+        })
+    });
+
+    c.bench_function("FSE read 16 symbols", |b| {
+        b.iter(|| {
+            fse_get_symbols(&symbols, &fse_symbols);
+        })
+    });
+
+    c.bench_function("FSE write 16 symbols", |b| {
+        b.iter(|| {
+            fse_put_symbols(&symbols);
+        })
+    });
+
+    c.bench_function("Fenwick read 16 symbols", |b| {
+        b.iter(|| {
+            fenwick_get_symbols(&symbols, &fenwick_symbols);
+        })
+    });
+
+    c.bench_function("Fenwick write 16 symbols", |b| {
+        b.iter(|| {
+            fenwick_put_symbols(&symbols);
+        })
+    });
+
+    c.bench_function("Rans32 read 16 symbols", |b| {
+        b.iter(|| {
+            rans32_get_symbols(&symbols, &rans32_symbols);
+        })
+    });
+
+    c.bench_function("Rans32x8 read 16 symbols", |b| {
+        b.iter(|| {
+            rans32x_get_symbols::<8>(&symbols, &rans32x8_symbols);
+        })
+    });
+
+    c.bench_function("Rans32x32 read 16 symbols", |b| {
+        b.iter(|| {
+            rans32x_get_symbols::<32>(&symbols, &rans32x32_symbols);
+        })
+    });
+
+    c.bench_function("Rans32x8 write 16 symbols", |b| {
+        b.iter(|| {
+            rans32x_put_symbols::<8>(&symbols);
+        })
+    });
+
+    c.bench_function("Hybrid uint read", |b| {
+        b.iter(|| {
+            hybrid_uint_get_values(&values, &hybrid_uint_values);
+        })
+    });
+
+    c.bench_function("Hybrid uint write", |b| {
+        b.iter(|| {
+            hybrid_uint_put_values(&values);
+        })
+    });
+
     #[cfg(feature = "simd")]
     c.bench_function("Fpaq0 parallel simd read", |b| {
         b.iter(|| {
@@ -0,0 +1,646 @@
+//! AV1/Daala multi-symbol adaptive range coder (od_ec).
+//!
+//! Unlike the other coders in this crate this one codes symbols from an alphabet of up to 16
+//! values directly, using a 15 bit inverse cumulative distribution (icdf) per context, so a
+//! 16 value symbol costs one coding step instead of 4 binary ones. The CDFs adapt after each
+//! symbol with a rate that starts fast and slows down as the context sees more symbols.
+//!
+//! The output is compatible with `od_ec_encode_cdf_q15`/`od_ec_decode_cdf_q15` in libaom.
+/*
+ * Copyright (c) 2001-2016, Alliance for Open Media. All rights reserved
+ *
+ * This source code is subject to the terms of the BSD 2 Clause License and
+ * the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+ * was not distributed with this source code in the LICENSE file, you can
+ * obtain it at www.aomedia.org/license/software. If the Alliance for Open
+ * Media Patent License 1.0 was not distributed with this source code in the
+ * PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+ */
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::traits::{CabacReader, CabacWriter, SymbolReader, SymbolWriter};
+
+/// precision of the CDFs, 32768 is a probability of one
+const CDF_PROB_TOP: u32 = 1 << 15;
+
+/// the probabilities are reduced to 9 bits before being multiplied with the range
+const EC_PROB_SHIFT: u32 = 6;
+
+/// minimum size of the interval of each symbol so that no symbol ever has a zero probability
+const EC_MIN_PROB: u32 = 4;
+
+/// size of the window of the decoder, in bits
+const WINDOW_SIZE: i32 = 64;
+
+/// once the input is exhausted we pretend that there are lots of zero bits left
+const LOTS_OF_BITS: i32 = 0x4000;
+
+/// once we have this many bytes buffered, write out the ones that can't be changed by a carry
+const WRITE_THRESHOLD: usize = 4096;
+
+/// adaptive context for a symbol with an alphabet of N values (2-16). Stores the inverse CDF
+/// in 15 bits precision (icdf\[i\] is 32768 times the probability of a symbol > i), along with
+/// the count of symbols seen that determines the adaptation rate.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AV1Context<const N: usize> {
+    icdf: [u16; N],
+    count: u16,
+}
+
+impl<const N: usize> Default for AV1Context<N> {
+    /// default value is a uniform distribution of the symbols
+    fn default() -> Self {
+        let mut cdf = [0; N];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = (CDF_PROB_TOP * (i as u32 + 1) / N as u32) as u16;
+        }
+        Self::new(cdf).expect("alphabet must have 2 to 16 symbols")
+    }
+}
+
+impl<const N: usize> AV1Context<N> {
+    /// creates a context from a CDF in the form it is given in the AV1 specification, where
+    /// cdf\[i\] is 32768 times the probability of a symbol <= i. The last entry must be 32768.
+    pub fn new(cdf: [u16; N]) -> Result<Self> {
+        if !(2..=16).contains(&N) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "alphabet must have 2 to 16 symbols",
+            ));
+        }
+        if u32::from(cdf[N - 1]) != CDF_PROB_TOP {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "last CDF entry must be 32768",
+            ));
+        }
+        if cdf.windows(2).any(|w| w[0] > w[1]) {
+            return Err(Error::new(ErrorKind::InvalidInput, "CDF must not decrease"));
+        }
+
+        Ok(AV1Context {
+            icdf: cdf.map(|c| (CDF_PROB_TOP - u32::from(c)) as u16),
+            count: 0,
+        })
+    }
+
+    /// returns the inverse CDF used for the next symbol
+    pub fn get_icdf(&self) -> &[u16; N] {
+        &self.icdf
+    }
+
+    /// moves the CDF towards the symbol that was coded. The rate is faster for the first
+    /// symbols and for smaller alphabets (`update_cdf` in libaom).
+    #[inline(always)]
+    pub fn update(&mut self, symbol: usize) {
+        let rate = 4 + u32::from(self.count > 15) + u32::from(self.count > 31) + u32::from(N > 3);
+
+        // branchless so that the loop is vectorized
+        for (i, icdf) in self.icdf[..N - 1].iter_mut().enumerate() {
+            let c = u32::from(*icdf);
+            let below = 0u32.wrapping_sub(u32::from(i < symbol));
+            *icdf = (c + (((CDF_PROB_TOP - c) >> rate) & below) - ((c >> rate) & !below)) as u16;
+        }
+
+        self.count += u16::from(self.count < 32);
+    }
+}
+
+/// converts a probability of a one to an interval size of a range for a bool
+#[inline(always)]
+fn bool_split(rng: u32, f: u16) -> u32 {
+    (((rng >> 8) * (u32::from(f) >> EC_PROB_SHIFT)) >> (7 - EC_PROB_SHIFT)) + EC_MIN_PROB
+}
+
+/// returns the part of the range above the symbol s with the given icdf value, out of the
+/// n symbols of the alphabet
+#[inline(always)]
+fn symbol_split(rng: u32, icdf: u16, s: usize, n: usize) -> u32 {
+    (((rng >> 8) * (u32::from(icdf) >> EC_PROB_SHIFT)) >> (7 - EC_PROB_SHIFT))
+        + EC_MIN_PROB * (n - 1 - s) as u32
+}
+
+pub struct AV1Writer<W> {
+    writer: W,
+    low: u64,
+    rng: u32,
+    cnt: i32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> AV1Writer<W> {
+    pub fn new(writer: W) -> Self {
+        AV1Writer {
+            writer,
+            low: 0,
+            rng: 0x8000,
+            cnt: -9,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// writes the symbol and adapts the context towards it
+    #[inline(always)]
+    pub fn put_symbol<const N: usize>(
+        &mut self,
+        symbol: usize,
+        context: &mut AV1Context<N>,
+    ) -> Result<()> {
+        self.put_symbol_with_icdf(symbol, context.get_icdf())?;
+        context.update(symbol);
+        Ok(())
+    }
+
+    /// writes the symbol with a fixed inverse CDF that isn't adapted
+    /// (`od_ec_encode_cdf_q15` in libaom)
+    #[inline(always)]
+    pub fn put_symbol_with_icdf<const N: usize>(
+        &mut self,
+        symbol: usize,
+        icdf: &[u16; N],
+    ) -> Result<()> {
+        let n = N;
+        if symbol >= n {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "symbol is outside the alphabet",
+            ));
+        }
+        debug_assert_eq!(icdf[n - 1], 0);
+
+        let mut low = self.low;
+        let mut rng = self.rng;
+
+        let v = symbol_split(rng, icdf[symbol], symbol, n);
+        if symbol > 0 {
+            let u = symbol_split(rng, icdf[symbol - 1], symbol - 1, n);
+            low += u64::from(rng - u);
+            rng = u - v;
+        } else {
+            rng -= v;
+        }
+
+        self.normalize(low, rng)
+    }
+
+    /// writes a bit where f is 32768 times the probability of it being a one
+    /// (`od_ec_encode_bool_q15` in libaom)
+    #[inline(always)]
+    pub fn put_bool(&mut self, bit: bool, f: u16) -> Result<()> {
+        debug_assert!(f > 0 && u32::from(f) < CDF_PROB_TOP);
+
+        let mut low = self.low;
+        let mut rng = self.rng;
+
+        let v = bool_split(rng, f);
+        if bit {
+            low += u64::from(rng - v);
+            rng = v;
+        } else {
+            rng -= v;
+        }
+
+        self.normalize(low, rng)
+    }
+
+    /// writes a bit with a probability of 1/2
+    pub fn put_bit(&mut self, bit: bool) -> Result<()> {
+        self.put_bool(bit, 16384)
+    }
+
+    /// writes the lower `bits` bits of value, most significant bit first
+    pub fn put_literal(&mut self, value: u32, bits: u32) -> Result<()> {
+        for i in (0..bits).rev() {
+            self.put_bit((value >> i) & 1 != 0)?;
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn normalize(&mut self, mut low: u64, rng: u32) -> Result<()> {
+        let d = rng.leading_zeros() as i32 - 16;
+        let mut c = self.cnt;
+        let mut s = c + d;
+
+        if s >= 0 {
+            c += 16;
+            let mut m = (1u64 << c) - 1;
+            if s >= 8 {
+                self.push_precarry((low >> c) as u32)?;
+                low &= m;
+                c -= 8;
+                m >>= 8;
+            }
+            self.push_precarry((low >> c) as u32)?;
+            s = c + d - 24;
+            low &= m;
+        }
+
+        self.low = low << d;
+        self.rng = rng << d;
+        self.cnt = s;
+        Ok(())
+    }
+
+    /// adds the next byte of output, where bit 8 is a carry into the bytes already buffered
+    fn push_precarry(&mut self, value: u32) -> Result<()> {
+        if value > 0xff {
+            for b in self.buffer.iter_mut().rev() {
+                *b = b.wrapping_add(1);
+                if *b != 0 {
+                    break;
+                }
+            }
+        }
+        self.buffer.push(value as u8);
+
+        if self.buffer.len() >= WRITE_THRESHOLD {
+            self.write_completed()?;
+        }
+        Ok(())
+    }
+
+    /// writes out all the bytes before the last one that isn't 0xff, since a carry can't
+    /// get past it
+    #[cold]
+    fn write_completed(&mut self) -> Result<()> {
+        if let Some(last) = self.buffer.iter().rposition(|&b| b != 0xff) {
+            self.writer.write_all(&self.buffer[..last])?;
+            self.buffer.copy_within(last.., 0);
+            self.buffer.truncate(self.buffer.len() - last);
+        }
+        Ok(())
+    }
+
+    /// writes the remaining bits with enough precision to decode everything that was written
+    /// (`od_ec_enc_done` in libaom)
+    pub fn finish(&mut self) -> Result<()> {
+        let mut c = self.cnt;
+        let mut s = c + 10;
+        let m = 0x3fffu64;
+        let mut e = ((self.low + m) & !m) | (m + 1);
+
+        if s > 0 {
+            let mut n = (1u64 << (c + 16)) - 1;
+            loop {
+                self.push_precarry((e >> (c + 16)) as u32)?;
+                e &= n;
+                s -= 8;
+                c -= 8;
+                n >>= 8;
+                if s <= 0 {
+                    break;
+                }
+            }
+        }
+
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.low = 0;
+        self.rng = 0x8000;
+        self.cnt = -9;
+        Ok(())
+    }
+}
+
+/// the bool special case of the trait uses an adaptive two symbol CDF, and the bypass uses a
+/// fixed probability of 1/2
+impl<W: Write> CabacWriter<AV1Context<2>> for AV1Writer<W> {
+    #[inline(always)]
+    fn put(&mut self, bit: bool, context: &mut AV1Context<2>) -> Result<()> {
+        self.put_symbol(usize::from(bit), context)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bit(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, const N: usize> SymbolWriter<AV1Context<N>> for AV1Writer<W> {
+    #[inline(always)]
+    fn put_symbol(&mut self, symbol: usize, context: &mut AV1Context<N>) -> Result<()> {
+        self.put_symbol(symbol, context)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+pub struct AV1Reader<R> {
+    reader: R,
+    /// the difference between the top of the range and the coded value, complemented
+    dif: u64,
+    rng: u32,
+    cnt: i32,
+}
+
+impl<R: Read> AV1Reader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut r = AV1Reader {
+            reader,
+            dif: (1 << (WINDOW_SIZE - 1)) - 1,
+            rng: 0x8000,
+            cnt: -15,
+        };
+        r.refill()?;
+        Ok(r)
+    }
+
+    /// reads a symbol and adapts the context towards it
+    #[inline(always)]
+    pub fn get_symbol<const N: usize>(&mut self, context: &mut AV1Context<N>) -> Result<usize> {
+        let symbol = self.get_symbol_with_icdf(context.get_icdf())?;
+        context.update(symbol);
+        Ok(symbol)
+    }
+
+    /// reads a symbol that was written with a fixed inverse CDF
+    /// (`od_ec_decode_cdf_q15` in libaom)
+    #[inline(always)]
+    pub fn get_symbol_with_icdf<const N: usize>(&mut self, icdf: &[u16; N]) -> Result<usize> {
+        let r = self.rng;
+        let c = (self.dif >> (WINDOW_SIZE - 16)) as u32;
+
+        // the splits strictly decrease, so the symbol is the number of splits above the value.
+        // Calculating all of them avoids a mispredicted branch for each step of the search.
+        let symbol = icdf
+            .iter()
+            .enumerate()
+            .map(|(s, &f)| usize::from(c < symbol_split(r, f, s, N)))
+            .sum::<usize>();
+
+        let u = if symbol > 0 {
+            symbol_split(r, icdf[symbol - 1], symbol - 1, N)
+        } else {
+            r
+        };
+        let v = symbol_split(r, icdf[symbol], symbol, N);
+
+        let dif = self.dif - (u64::from(v) << (WINDOW_SIZE - 16));
+        self.normalize(dif, u - v)?;
+        Ok(symbol)
+    }
+
+    /// reads a bit where f is 32768 times the probability of it being a one
+    /// (`od_ec_decode_bool_q15` in libaom)
+    #[inline(always)]
+    pub fn get_bool(&mut self, f: u16) -> Result<bool> {
+        let r = self.rng;
+        let v = bool_split(r, f);
+        let vw = u64::from(v) << (WINDOW_SIZE - 16);
+
+        let (bit, dif, rng) = if self.dif >= vw {
+            (false, self.dif - vw, r - v)
+        } else {
+            (true, self.dif, v)
+        };
+
+        self.normalize(dif, rng)?;
+        Ok(bit)
+    }
+
+    /// reads a bit with a probability of 1/2
+    pub fn get_bit(&mut self) -> Result<bool> {
+        self.get_bool(16384)
+    }
+
+    /// reads an unsigned literal of `bits` bits, most significant bit first
+    pub fn get_literal(&mut self, bits: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            value = (value << 1) | u32::from(self.get_bit()?);
+        }
+        Ok(value)
+    }
+
+    #[inline(always)]
+    fn normalize(&mut self, dif: u64, rng: u32) -> Result<()> {
+        let d = rng.leading_zeros() as i32 - 16;
+        self.cnt -= d;
+
+        // shifts in ones instead of zeros since the value is complemented
+        self.dif = ((dif + 1) << d) - 1;
+        self.rng = rng << d;
+
+        if self.cnt < 0 {
+            self.refill()?;
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn refill(&mut self) -> Result<()> {
+        let mut s = WINDOW_SIZE - 9 - (self.cnt + 15);
+        while s >= 0 {
+            let mut v = [0u8; 1];
+            if self.reader.read(&mut v)? == 0 {
+                self.cnt = LOTS_OF_BITS;
+                break;
+            }
+
+            self.dif ^= u64::from(v[0]) << s;
+            self.cnt += 8;
+            s -= 8;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> CabacReader<AV1Context<2>> for AV1Reader<R> {
+    #[inline(always)]
+    fn get(&mut self, context: &mut AV1Context<2>) -> Result<bool> {
+        Ok(self.get_symbol(context)? != 0)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bit()
+    }
+}
+
+impl<R: Read, const N: usize> SymbolReader<AV1Context<N>> for AV1Reader<R> {
+    #[inline(always)]
+    fn get_symbol(&mut self, context: &mut AV1Context<N>) -> Result<usize> {
+        self.get_symbol(context)
+    }
+}
+
+/// Literal implementation of the symbol decoder from section 8.2 of the AV1 specification,
+/// used to verify that the output of the writer is decoded the same way by the standard.
+#[cfg(test)]
+struct SpecSymbolDecoder<'a> {
+    data: &'a [u8],
+    bit_pos: usize,
+    symbol_value: u32,
+    symbol_range: u32,
+    symbol_max_bits: i32,
+}
+
+#[cfg(test)]
+impl<'a> SpecSymbolDecoder<'a> {
+    fn f(&mut self, n: i32) -> u32 {
+        let mut x = 0;
+        for _ in 0..n {
+            let bit = (self.data[self.bit_pos >> 3] >> (7 - (self.bit_pos & 7))) & 1;
+            x = (x << 1) | u32::from(bit);
+            self.bit_pos += 1;
+        }
+        x
+    }
+
+    fn init_symbol(data: &'a [u8]) -> Self {
+        let sz = data.len() as i32;
+        let mut d = SpecSymbolDecoder {
+            data,
+            bit_pos: 0,
+            symbol_value: 0,
+            symbol_range: 1 << 15,
+            symbol_max_bits: 8 * sz - 15,
+        };
+        let num_bits = (sz * 8).min(15);
+        let buf = d.f(num_bits);
+        let padded_buf = buf << (15 - num_bits);
+        d.symbol_value = ((1 << 15) - 1) ^ padded_buf;
+        d
+    }
+
+    fn read_symbol(&mut self, icdf: &[u16]) -> usize {
+        let n = icdf.len() as u32;
+        let mut cur = self.symbol_range;
+        let mut symbol = -1i32;
+        let mut prev;
+        loop {
+            symbol += 1;
+            prev = cur;
+            let f = u32::from(icdf[symbol as usize]);
+            cur = (((self.symbol_range >> 8) * (f >> EC_PROB_SHIFT)) >> (7 - EC_PROB_SHIFT))
+                + EC_MIN_PROB * (n - symbol as u32 - 1);
+            if self.symbol_value >= cur {
+                break;
+            }
+        }
+        self.symbol_range = prev - cur;
+        self.symbol_value -= cur;
+
+        let bits = 15 - (31 - self.symbol_range.leading_zeros() as i32);
+        self.symbol_range <<= bits;
+        let num_bits = bits.min(self.symbol_max_bits.max(0));
+        let new_data = self.f(num_bits);
+        let padded_data = new_data << (bits - num_bits);
+        self.symbol_value = padded_data ^ (((self.symbol_value + 1) << bits) - 1);
+        self.symbol_max_bits -= bits;
+
+        symbol as usize
+    }
+}
+
+#[test]
+fn matches_spec_decoder() {
+    use rand::Rng;
+    use std::io::Cursor;
+
+    let mut rng = rand::thread_rng();
+
+    for len in [0, 1, 2, 5, 20, 100, 10000] {
+        // skewed distribution so that the contexts adapt away from uniform
+        let symbols: Vec<usize> = (0..len)
+            .map(|_| rng.gen_range(0..16).min(rng.gen_range(0..16)))
+            .collect();
+
+        let mut buffer = Vec::new();
+        let mut writer = AV1Writer::new(&mut buffer);
+        let mut contexts = [AV1Context::<16>::default(); 3];
+        let mut bool_context = AV1Context::<2>::default();
+        for (i, &s) in symbols.iter().enumerate() {
+            writer.put_symbol(s, &mut contexts[i % 3]).unwrap();
+            writer.put(s < 3, &mut bool_context).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut spec = SpecSymbolDecoder::init_symbol(&buffer);
+        let mut reader = AV1Reader::new(Cursor::new(&buffer)).unwrap();
+        let mut spec_contexts = [AV1Context::<16>::default(); 3];
+        let mut spec_bool_context = AV1Context::<2>::default();
+        let mut contexts = [AV1Context::<16>::default(); 3];
+        let mut bool_context = AV1Context::<2>::default();
+        for (i, &s) in symbols.iter().enumerate() {
+            let c = &mut spec_contexts[i % 3];
+            let spec_symbol = spec.read_symbol(c.get_icdf());
+            c.update(spec_symbol);
+            assert_eq!(s, spec_symbol, "spec offset {i}");
+
+            let spec_bool = spec.read_symbol(spec_bool_context.get_icdf());
+            spec_bool_context.update(spec_bool);
+            assert_eq!(s < 3, spec_bool == 1, "spec offset {i}");
+
+            assert_eq!(
+                s,
+                reader.get_symbol(&mut contexts[i % 3]).unwrap(),
+                "offset {i}"
+            );
+            assert_eq!(s < 3, reader.get(&mut bool_context).unwrap(), "offset {i}");
+        }
+    }
+}
+
+#[test]
+fn bools_and_literals() {
+    use rand::Rng;
+    use std::io::Cursor;
+
+    let mut rng = rand::thread_rng();
+    let bools: Vec<(bool, u16, u32)> = (0..10000)
+        .map(|_| {
+            let f = rng.gen_range(1..32768);
+            (rng.gen_range(0..32768) < f, f, rng.gen_range(0..1 << 12))
+        })
+        .collect();
+
+    let mut buffer = Vec::new();
+    let mut writer = AV1Writer::new(&mut buffer);
+    for &(bit, f, literal) in &bools {
+        writer.put_bool(bit, f).unwrap();
+        writer.put_literal(literal, 12).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = AV1Reader::new(Cursor::new(&buffer)).unwrap();
+    for &(bit, f, literal) in &bools {
+        assert_eq!(bit, reader.get_bool(f).unwrap());
+        assert_eq!(literal, reader.get_literal(12).unwrap());
+    }
+}
+
+#[test]
+fn context_adaptation() {
+    // matches the rate of update_cdf in libaom
+    let mut c = AV1Context::<4>::new([8192, 16384, 24576, 32768]).unwrap();
+    c.update(0);
+    assert_eq!(c.get_icdf(), &[24576 - 768, 16384 - 512, 8192 - 256, 0]);
+
+    for _ in 0..100 {
+        c.update(3);
+    }
+    assert_eq!(c.count, 32);
+    assert_eq!(c.get_icdf(), &[30218, 27985, 25759, 0]);
+}
+
+#[test]
+fn invalid_input() {
+    let error = AV1Context::<1>::new([32768]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    for cdf in [[8192, 16384, 24576, 32767], [8192, 24576, 16384, 32768]] {
+        let error = AV1Context::<4>::new(cdf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    let mut writer = AV1Writer::new(Vec::new());
+    let error = writer
+        .put_symbol(4, &mut AV1Context::<4>::default())
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
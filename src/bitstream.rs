@@ -0,0 +1,319 @@
+//! Raw bit reader and writer for the headers that are written without arithmetic coding.
+//!
+//! H.264/H.265 parameter sets and slice headers, as well as the VP8/VP9 frame headers, consist of
+//! fixed length fields (u(n)) and Exp-Golomb codes (ue(v)/se(v)) that are followed by the arithmetic
+//! coded payload. Once the header is byte aligned, the underlying writer or reader can be handed over
+//! to one of the arithmetic coders, which then continues at the next byte.
+//!
+//! The reader never reads further ahead than the byte that contains the next bit, so the handoff
+//! also works with streaming readers (the only exception being `more_rbsp_data`, which has to look
+//! at the rest of the RBSP).
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Writes bits MSB first into the underlying writer.
+pub struct BitWriter<W> {
+    writer: W,
+    /// bits that don't fill up a byte yet, stored in the lower `num_bits` bits
+    bits: u64,
+    num_bits: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(writer: W) -> Self {
+        BitWriter {
+            writer,
+            bits: 0,
+            num_bits: 0,
+        }
+    }
+
+    /// writes the lower `num_bits` bits of value (u(n) in the spec)
+    pub fn put_bits(&mut self, value: u32, num_bits: u32) -> Result<()> {
+        assert!(num_bits <= 32);
+        assert!(num_bits == 32 || value >> num_bits == 0, "value too large");
+
+        self.bits = (self.bits << num_bits) | u64::from(value);
+        self.num_bits += num_bits;
+
+        while self.num_bits >= 8 {
+            self.num_bits -= 8;
+            self.writer
+                .write_all(&[(self.bits >> self.num_bits) as u8])?;
+        }
+        self.bits &= (1 << self.num_bits) - 1;
+
+        Ok(())
+    }
+
+    /// writes a single bit (u(1) or f(1))
+    pub fn put_flag(&mut self, value: bool) -> Result<()> {
+        self.put_bits(u32::from(value), 1)
+    }
+
+    /// writes an unsigned Exp-Golomb code (ue(v)). Values up to u32::MAX - 1 can be coded.
+    pub fn put_ue(&mut self, value: u32) -> Result<()> {
+        assert!(value < u32::MAX, "value too large for ue(v)");
+
+        let code = value + 1;
+        let len = 32 - code.leading_zeros();
+
+        self.put_bits(0, len - 1)?;
+        self.put_bits(code, len)
+    }
+
+    /// writes a signed Exp-Golomb code (se(v)), positive values are mapped to odd codes.
+    /// i32::MIN can't be coded.
+    pub fn put_se(&mut self, value: i32) -> Result<()> {
+        assert!(value != i32::MIN, "value too large for se(v)");
+
+        let code = if value > 0 {
+            (value as u32) * 2 - 1
+        } else {
+            value.unsigned_abs() * 2
+        };
+
+        self.put_ue(code)
+    }
+
+    /// true if the next bit is written at the start of a byte
+    pub fn is_byte_aligned(&self) -> bool {
+        self.num_bits == 0
+    }
+
+    /// writes zero bits until the output is byte aligned
+    pub fn byte_align(&mut self) -> Result<()> {
+        self.put_bits(0, (8 - self.num_bits) % 8)
+    }
+
+    /// writes rbsp_trailing_bits, which is the rbsp_stop_one_bit followed by zero bits until
+    /// the output is byte aligned. The same pattern is used by the byte_alignment() syntax.
+    pub fn put_rbsp_trailing_bits(&mut self) -> Result<()> {
+        self.put_flag(true)?;
+        self.byte_align()
+    }
+
+    /// returns the underlying writer so that the arithmetic coded data can be written after the
+    /// header. The output has to be byte aligned.
+    pub fn into_inner(self) -> W {
+        assert!(self.is_byte_aligned(), "output is not byte aligned");
+        self.writer
+    }
+}
+
+/// Reads bits MSB first from the underlying reader.
+pub struct BitReader<R> {
+    reader: R,
+    /// bits of the current byte that haven't been read yet, stored in the lower `num_bits` bits
+    bits: u32,
+    num_bits: u32,
+    /// rest of the RBSP, which is only read when calling `more_rbsp_data`
+    lookahead: Vec<u8>,
+    lookahead_pos: usize,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(reader: R) -> Self {
+        BitReader {
+            reader,
+            bits: 0,
+            num_bits: 0,
+            lookahead: Vec::new(),
+            lookahead_pos: 0,
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<u8> {
+        if self.lookahead_pos < self.lookahead.len() {
+            self.lookahead_pos += 1;
+            return Ok(self.lookahead[self.lookahead_pos - 1]);
+        }
+
+        let mut b = [0u8];
+        self.reader.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    /// reads `num_bits` bits (u(n) in the spec)
+    pub fn get_bits(&mut self, num_bits: u32) -> Result<u32> {
+        assert!(num_bits <= 32);
+
+        let mut value = 0u64;
+        let mut remaining = num_bits;
+
+        while remaining > 0 {
+            if self.num_bits == 0 {
+                self.bits = u32::from(self.next_byte()?);
+                self.num_bits = 8;
+            }
+
+            let n = remaining.min(self.num_bits);
+            self.num_bits -= n;
+            remaining -= n;
+
+            value = (value << n) | u64::from((self.bits >> self.num_bits) & ((1 << n) - 1));
+        }
+
+        Ok(value as u32)
+    }
+
+    /// reads a single bit (u(1) or f(1))
+    pub fn get_flag(&mut self) -> Result<bool> {
+        Ok(self.get_bits(1)? != 0)
+    }
+
+    /// reads an unsigned Exp-Golomb code (ue(v))
+    pub fn get_ue(&mut self) -> Result<u32> {
+        let mut leading_zeros = 0;
+        while !self.get_flag()? {
+            leading_zeros += 1;
+
+            if leading_zeros > 31 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Exp-Golomb code longer than 32 bits",
+                ));
+            }
+        }
+
+        let suffix = self.get_bits(leading_zeros)?;
+        Ok(((1u64 << leading_zeros) - 1 + u64::from(suffix)) as u32)
+    }
+
+    /// reads a signed Exp-Golomb code (se(v))
+    pub fn get_se(&mut self) -> Result<i32> {
+        let code = self.get_ue()?;
+
+        if code & 1 != 0 {
+            Ok(code.div_ceil(2) as i32)
+        } else {
+            Ok(-((code / 2) as i32))
+        }
+    }
+
+    /// true if the next bit is at the start of a byte
+    pub fn is_byte_aligned(&self) -> bool {
+        self.num_bits == 0
+    }
+
+    /// skips the bits until the next byte boundary
+    pub fn byte_align(&mut self) {
+        self.num_bits = 0;
+    }
+
+    /// returns true if there is more data in the RBSP before the rbsp_trailing_bits. Since
+    /// this needs to find the last 1 bit of the RBSP, the rest of the underlying reader is read
+    /// into memory.
+    pub fn more_rbsp_data(&mut self) -> Result<bool> {
+        let _ = self.reader.read_to_end(&mut self.lookahead)?;
+
+        let rest = &self.lookahead[self.lookahead_pos..];
+
+        // position of the last 1 bit, counted from the next bit to be read
+        let last_one = if let Some(i) = rest.iter().rposition(|&b| b != 0) {
+            self.num_bits as usize + i * 8 + 7 - rest[i].trailing_zeros() as usize
+        } else if self.bits & ((1 << self.num_bits) - 1) != 0 {
+            let current = self.bits & ((1 << self.num_bits) - 1);
+            self.num_bits as usize - 1 - current.trailing_zeros() as usize
+        } else {
+            return Ok(false);
+        };
+
+        Ok(last_one > 0)
+    }
+
+    /// returns the underlying reader positioned at the byte following the header, so that
+    /// the arithmetic decoder can continue from there. The input has to be byte aligned, and
+    /// `more_rbsp_data` must not have been called.
+    pub fn into_inner(self) -> R {
+        assert!(self.is_byte_aligned(), "input is not byte aligned");
+        assert!(
+            self.lookahead.is_empty(),
+            "rest of the input was already read"
+        );
+        self.reader
+    }
+}
+
+#[test]
+fn exp_golomb_codes() {
+    // (value, bits, length) from the table in the H.264/H.265 spec
+    let ue = [
+        (0, 0b1, 1),
+        (1, 0b010, 3),
+        (2, 0b011, 3),
+        (3, 0b00100, 5),
+        (6, 0b00111, 5),
+        (7, 0b0001000, 7),
+    ];
+    let se = [
+        (0, 0b1, 1),
+        (1, 0b010, 3),
+        (-1, 0b011, 3),
+        (2, 0b00100, 5),
+        (-3, 0b00111, 5),
+    ];
+
+    for (value, code, len) in ue {
+        let mut expected = BitWriter::new(Vec::new());
+        expected.put_bits(code, len).unwrap();
+        expected.byte_align().unwrap();
+
+        let mut writer = BitWriter::new(Vec::new());
+        writer.put_ue(value).unwrap();
+        writer.byte_align().unwrap();
+
+        let output = writer.into_inner();
+        assert_eq!(output, expected.into_inner());
+        assert_eq!(BitReader::new(&output[..]).get_ue().unwrap(), value);
+    }
+
+    for (value, code, len) in se {
+        let mut expected = BitWriter::new(Vec::new());
+        expected.put_bits(code, len).unwrap();
+        expected.byte_align().unwrap();
+
+        let mut writer = BitWriter::new(Vec::new());
+        writer.put_se(value).unwrap();
+        writer.byte_align().unwrap();
+
+        let output = writer.into_inner();
+        assert_eq!(output, expected.into_inner());
+        assert_eq!(BitReader::new(&output[..]).get_se().unwrap(), value);
+    }
+
+    // extremes
+    let mut writer = BitWriter::new(Vec::new());
+    writer.put_ue(u32::MAX - 1).unwrap();
+    writer.put_se(i32::MIN + 1).unwrap();
+    writer.put_se(i32::MAX).unwrap();
+    writer.put_bits(0xdeadbeef, 32).unwrap();
+    writer.put_rbsp_trailing_bits().unwrap();
+
+    let output = writer.into_inner();
+    let mut reader = BitReader::new(&output[..]);
+    assert_eq!(reader.get_ue().unwrap(), u32::MAX - 1);
+    assert_eq!(reader.get_se().unwrap(), i32::MIN + 1);
+    assert_eq!(reader.get_se().unwrap(), i32::MAX);
+    assert_eq!(reader.get_bits(32).unwrap(), 0xdeadbeef);
+    assert!(!reader.more_rbsp_data().unwrap());
+}
+
+#[test]
+fn more_rbsp_data() {
+    for num_flags in 0..30 {
+        let mut writer = BitWriter::new(Vec::new());
+        for i in 0..num_flags {
+            writer.put_flag(i % 3 == 0).unwrap();
+        }
+        writer.put_rbsp_trailing_bits().unwrap();
+
+        let output = writer.into_inner();
+        let mut reader = BitReader::new(&output[..]);
+        for i in 0..num_flags {
+            assert!(reader.more_rbsp_data().unwrap());
+            assert_eq!(reader.get_flag().unwrap(), i % 3 == 0);
+        }
+        assert!(!reader.more_rbsp_data().unwrap());
+        assert!(reader.get_flag().unwrap());
+    }
+}
@@ -103,6 +103,8 @@ impl<W: Write> CabacWriter<DebugContext> for DebugWriter<W> {
 
 #[test]
 fn roundtrip_value() {
+    use crate::vp8::KF_YMODE_TREE;
+
     let mut output = Vec::with_capacity(1000);
     let mut writer = DebugWriter::new(&mut output).unwrap();
     let mut context = [DebugContext::default(); 8];
@@ -114,6 +116,9 @@ fn roundtrip_value() {
         writer.put_n_bits(0x456, 24, &mut context).unwrap();
         writer.put_unary_encoded(i, &mut context).unwrap();
         writer.put_branched(i as u8, &mut context_branch).unwrap();
+        writer
+            .put_tree((i % 5) as u8, &KF_YMODE_TREE, &mut context)
+            .unwrap();
     }
 
     writer.finish().unwrap();
@@ -128,5 +133,9 @@ fn roundtrip_value() {
         assert_eq!(reader.get_n_bits(24, &mut context).unwrap(), 0x456);
         assert_eq!(reader.get_unary_encoded(&mut context).unwrap(), i);
         assert_eq!(reader.get_branched(&mut context_branch).unwrap(), i as u8);
+        assert_eq!(
+            reader.get_tree(&KF_YMODE_TREE, &mut context).unwrap(),
+            (i % 5) as u8
+        );
     }
 }
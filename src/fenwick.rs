@@ -0,0 +1,419 @@
+//! Adaptive multi-symbol range coder for large alphabets, with the symbol frequencies kept in a
+//! Fenwick tree (binary indexed tree).
+//!
+//! The tree gives the cumulative frequency of a symbol and finds the symbol for a cumulative
+//! frequency in O(log n), so alphabets like the 256 byte values or 1024 tokens can be coded
+//! directly instead of being binarized. Symbols can be excluded from a coding step, as in PPM
+//! after an escape, in which case their frequencies are taken out of the interval.
+//!
+//! The symbols are coded with the carry-propagating range coder of LZMA, using the interface
+//! that PPMd uses in 7-Zip.
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{
+    lzma::{LzmaReader, LzmaWriter},
+    traits::{SymbolReader, SymbolWriter},
+};
+
+/// limit of the total frequency, above which the frequencies are halved
+const MAX_TOTAL: u32 = 1 << 16;
+
+/// default amount added to the frequency of a symbol after it is coded
+const DEFAULT_INCREMENT: u32 = 24;
+
+/// adaptive frequencies of the symbols 0..n, starting out uniform
+#[derive(Clone)]
+pub struct FenwickModel {
+    /// tree[i] holds the sum of the frequencies of the symbols (i - lowbit(i), i] (1 based)
+    tree: Vec<u32>,
+    freq: Vec<u32>,
+    total: u32,
+    increment: u32,
+}
+
+impl FenwickModel {
+    pub fn new(symbols: usize) -> Result<Self> {
+        Self::with_increment(symbols, DEFAULT_INCREMENT)
+    }
+
+    /// creates a model whose frequencies grow by `increment` each time a symbol is coded. A
+    /// larger increment adapts faster but halves the frequencies more often.
+    pub fn with_increment(symbols: usize, increment: u32) -> Result<Self> {
+        if symbols == 0 || symbols > (MAX_TOTAL / 2) as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "number of symbols must be between 1 and 32768",
+            ));
+        }
+        if increment == 0 || increment > MAX_TOTAL / 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "increment must be between 1 and 32768",
+            ));
+        }
+
+        let mut model = FenwickModel {
+            tree: vec![0; symbols + 1],
+            freq: vec![1; symbols],
+            total: symbols as u32,
+            increment,
+        };
+        model.rebuild();
+        Ok(model)
+    }
+
+    pub fn len(&self) -> usize {
+        self.freq.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.freq.is_empty()
+    }
+
+    pub fn get_frequency(&self, symbol: usize) -> u32 {
+        self.freq[symbol]
+    }
+
+    pub fn get_total(&self) -> u32 {
+        self.total
+    }
+
+    /// returns the sum of the frequencies of the symbols below `symbol`
+    pub fn get_cumulative(&self, symbol: usize) -> u32 {
+        let mut i = symbol;
+        let mut sum = 0;
+        while i > 0 {
+            sum += self.tree[i];
+            i &= i - 1;
+        }
+        sum
+    }
+
+    /// returns the symbol whose interval contains the cumulative frequency `target`, and the
+    /// start of that interval
+    fn find(&self, target: u32) -> (usize, u32) {
+        let n = self.freq.len();
+        let mut pos = 0;
+        let mut start = 0;
+        let mut step = 1 << (usize::BITS - 1 - n.leading_zeros());
+        while step > 0 {
+            if pos + step <= n && start + self.tree[pos + step] <= target {
+                pos += step;
+                start += self.tree[pos];
+            }
+            step >>= 1;
+        }
+        (pos, start)
+    }
+
+    /// adds the increment to the symbol and halves all frequencies if the total gets too large
+    pub fn update(&mut self, symbol: usize) {
+        self.freq[symbol] += self.increment;
+        self.total += self.increment;
+
+        if self.total > MAX_TOTAL {
+            self.freq.iter_mut().for_each(|f| *f = (*f + 1) >> 1);
+            self.rebuild();
+            return;
+        }
+
+        let mut i = symbol + 1;
+        while i < self.tree.len() {
+            self.tree[i] += self.increment;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// builds the tree from the frequencies in O(n)
+    fn rebuild(&mut self) {
+        self.tree[0] = 0;
+        self.tree[1..].copy_from_slice(&self.freq);
+        for i in 1..self.tree.len() {
+            let parent = i + (i & i.wrapping_neg());
+            if parent < self.tree.len() {
+                self.tree[parent] += self.tree[i];
+            }
+        }
+        self.total = self.freq.iter().sum();
+    }
+
+    /// returns the total with the excluded symbols taken out, which have to be sorted without
+    /// duplicates, in range and leave at least one symbol
+    fn excluded_total(&self, excluded: &[usize]) -> Result<u32> {
+        if !excluded.windows(2).all(|w| w[0] < w[1])
+            || excluded.last().is_some_and(|&x| x >= self.len())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "excluded symbols must be sorted, unique and in range",
+            ));
+        }
+
+        let total = self.total - excluded.iter().map(|&x| self.freq[x]).sum::<u32>();
+        if total == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "excluded symbols can't contain every symbol",
+            ));
+        }
+        Ok(total)
+    }
+
+    /// returns the start and total of the interval of the symbol with the excluded symbols
+    /// taken out, which can't contain the symbol
+    fn excluded_interval(&self, symbol: usize, excluded: &[usize]) -> Result<(u32, u32)> {
+        let total = self.excluded_total(excluded)?;
+        if excluded.binary_search(&symbol).is_ok() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "excluded symbols can't contain the symbol",
+            ));
+        }
+
+        let start = self.get_cumulative(symbol)
+            - excluded
+                .iter()
+                .take_while(|&&x| x < symbol)
+                .map(|&x| self.freq[x])
+                .sum::<u32>();
+        Ok((start, total))
+    }
+}
+
+pub struct FenwickWriter<W> {
+    coder: LzmaWriter<W>,
+}
+
+impl<W: Write> FenwickWriter<W> {
+    pub fn new(writer: W) -> Self {
+        FenwickWriter {
+            coder: LzmaWriter::new(writer),
+        }
+    }
+
+    /// writes the symbol and updates the model
+    pub fn put_symbol(&mut self, symbol: usize, model: &mut FenwickModel) -> Result<()> {
+        let start = model.get_cumulative(symbol);
+        self.coder
+            .put_frequency(start, model.freq[symbol], model.total)?;
+        model.update(symbol);
+        Ok(())
+    }
+
+    /// writes the symbol as if the excluded symbols had a frequency of zero, for example
+    /// because they were already ruled out by an escape from a higher order model. `excluded`
+    /// has to be sorted without duplicates.
+    pub fn put_symbol_excluding(
+        &mut self,
+        symbol: usize,
+        model: &mut FenwickModel,
+        excluded: &[usize],
+    ) -> Result<()> {
+        let (start, total) = model.excluded_interval(symbol, excluded)?;
+        self.coder.put_frequency(start, model.freq[symbol], total)?;
+        model.update(symbol);
+        Ok(())
+    }
+
+    /// writes out the rest of the range coder
+    pub fn finish(&mut self) -> Result<()> {
+        self.coder.finish()
+    }
+}
+
+impl<W: Write> SymbolWriter<FenwickModel> for FenwickWriter<W> {
+    fn put_symbol(&mut self, symbol: usize, model: &mut FenwickModel) -> Result<()> {
+        self.put_symbol(symbol, model)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+pub struct FenwickReader<R> {
+    coder: LzmaReader<R>,
+}
+
+impl<R: Read> FenwickReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        Ok(FenwickReader {
+            coder: LzmaReader::new(reader)?,
+        })
+    }
+
+    /// reads a symbol and updates the model
+    pub fn get_symbol(&mut self, model: &mut FenwickModel) -> Result<usize> {
+        let target = self.coder.get_threshold(model.total)?;
+        let (symbol, start) = model.find(target);
+
+        self.coder.remove_frequency(start, model.freq[symbol])?;
+        model.update(symbol);
+        Ok(symbol)
+    }
+
+    /// reads a symbol written by `FenwickWriter::put_symbol_excluding` with the same excluded
+    /// symbols, which are checked in the same way as by the writer
+    pub fn get_symbol_excluding(
+        &mut self,
+        model: &mut FenwickModel,
+        excluded: &[usize],
+    ) -> Result<usize> {
+        let total = model.excluded_total(excluded)?;
+        let mut target = self.coder.get_threshold(total)?;
+
+        // move the target past the intervals of the excluded symbols below it
+        for &x in excluded {
+            if model.get_cumulative(x) <= target {
+                target += model.freq[x];
+            } else {
+                break;
+            }
+        }
+
+        let (symbol, _) = model.find(target);
+        let (start, _) = model.excluded_interval(symbol, excluded)?;
+        self.coder.remove_frequency(start, model.freq[symbol])?;
+        model.update(symbol);
+        Ok(symbol)
+    }
+}
+
+impl<R: Read> SymbolReader<FenwickModel> for FenwickReader<R> {
+    fn get_symbol(&mut self, model: &mut FenwickModel) -> Result<usize> {
+        self.get_symbol(model)
+    }
+}
+
+#[test]
+fn fenwick_tree_queries() {
+    let mut model = FenwickModel::with_increment(37, 5).unwrap();
+    for s in [3, 3, 36, 0, 20, 3] {
+        model.update(s);
+    }
+
+    let mut cumulative = 0;
+    for s in 0..37 {
+        assert_eq!(model.get_cumulative(s), cumulative);
+        for t in cumulative..cumulative + model.get_frequency(s) {
+            assert_eq!(model.find(t), (s, cumulative));
+        }
+        cumulative += model.get_frequency(s);
+    }
+    assert_eq!(cumulative, model.get_total());
+
+    // rescaling keeps every symbol codable
+    for _ in 0..20000 {
+        model.update(7);
+    }
+    assert!(model.get_total() <= MAX_TOTAL);
+    assert!((0..37).all(|s| model.get_frequency(s) > 0));
+    assert_eq!(model.get_cumulative(37), model.get_total());
+}
+
+#[test]
+fn invalid_model() {
+    for (symbols, increment) in [(0, 24), (32769, 24), (256, 0), (256, 32769)] {
+        let result = FenwickModel::with_increment(symbols, increment);
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+    assert!(FenwickModel::with_increment(32768, 32768).is_ok());
+}
+
+#[test]
+fn roundtrip_bytes() {
+    use crate::test_util::random_symbols;
+
+    let symbols = random_symbols(50000, 256);
+
+    let mut output = Vec::new();
+    let mut writer = FenwickWriter::new(&mut output);
+    let mut model = FenwickModel::new(256).unwrap();
+    for &s in &symbols {
+        writer.put_symbol(s, &mut model).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = FenwickReader::new(&output[..]).unwrap();
+    let mut model = FenwickModel::new(256).unwrap();
+    for &s in &symbols {
+        assert_eq!(reader.get_symbol(&mut model).unwrap(), s);
+    }
+}
+
+#[test]
+fn roundtrip_with_exclusion() {
+    use crate::test_util::random_symbols;
+
+    let exclusions: [&[usize]; 4] = [&[], &[0, 1, 2], &[5, 700, 1023], &[1]];
+
+    // skip symbols that are excluded in the step
+    let steps: Vec<(usize, &[usize])> = random_symbols(20000, 1024)
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| (s, exclusions[i % 4]))
+        .filter(|(s, excluded)| excluded.binary_search(s).is_err())
+        .collect();
+
+    let mut output = Vec::new();
+    let mut writer = FenwickWriter::new(&mut output);
+    let mut model = FenwickModel::new(1024).unwrap();
+    for &(s, excluded) in &steps {
+        writer
+            .put_symbol_excluding(s, &mut model, excluded)
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = FenwickReader::new(&output[..]).unwrap();
+    let mut model = FenwickModel::new(1024).unwrap();
+    for &(s, excluded) in &steps {
+        assert_eq!(
+            reader.get_symbol_excluding(&mut model, excluded).unwrap(),
+            s
+        );
+    }
+
+    let mut writer = FenwickWriter::new(Vec::new());
+    let mut model = FenwickModel::new(16).unwrap();
+    assert!(writer.put_symbol_excluding(3, &mut model, &[3]).is_err());
+    assert!(writer.put_symbol_excluding(3, &mut model, &[5, 4]).is_err());
+
+    // the reader rejects the same exclusions instead of panicking
+    let all: Vec<usize> = (0..16).collect();
+    let invalid: [&[usize]; 4] = [&[5, 4], &[2, 2], &[16], &all];
+    for excluded in invalid {
+        let mut reader = FenwickReader::new(&output[..]).unwrap();
+        let mut model = FenwickModel::new(16).unwrap();
+        assert!(reader.get_symbol_excluding(&mut model, excluded).is_err());
+    }
+}
+
+#[test]
+fn known_answer() {
+    let text = b"abracadabra arbadacarba abracadabra";
+
+    let mut output = Vec::new();
+    let mut writer = FenwickWriter::new(&mut output);
+    let mut model = FenwickModel::new(256).unwrap();
+    for &s in text {
+        writer.put_symbol(usize::from(s), &mut model).unwrap();
+    }
+    writer.finish().unwrap();
+    assert_eq!(
+        output,
+        [
+            0x00, 0x61, 0x70, 0x07, 0x93, 0x2b, 0xb3, 0xab, 0x75, 0xcd, 0x44, 0xf5, 0x19, 0xb5,
+            0xe7, 0x37, 0x33, 0x05, 0x76, 0x7c, 0xf2, 0x4f
+        ]
+    );
+
+    let mut reader = FenwickReader::new(&output[..]).unwrap();
+    let mut model = FenwickModel::new(256).unwrap();
+    for &s in text {
+        assert_eq!(reader.get_symbol(&mut model).unwrap(), usize::from(s));
+    }
+}
@@ -12,10 +12,13 @@
 //!
 //! This was then rediscovered by Ilia Muraviev and Matt Mahoney in <https://mattmahoney.net/dc/fpaq0.cpp>
 use crate::{
-    traits::{CabacReader, CabacWriter},
+    traits::{CabacReader, CabacWriter, ProbabilityReader, ProbabilityWriter},
     vp8::VP8Context,
 };
-use std::io::{Read, Result, Write};
+use std::{
+    io::{Read, Result, Write},
+    num::NonZeroU8,
+};
 
 /// Implementation of a binary arithmetic decoder based on the FPAQ0 algorithm.
 pub struct Fpaq0Decoder<R> {
@@ -43,6 +46,32 @@ impl<R: Read> Fpaq0Decoder<R> {
         })
     }
 
+    /// reads a bit that was coded with a fixed probability of it being zero of
+    /// probability / 256, rather than with an adaptive context
+    pub fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool> {
+        let mut xl = self.xl;
+        let mut xr = self.xr;
+        let mut x = self.x;
+
+        let xm = xl + ((xr - xl) >> 8) * u32::from(probability.get());
+
+        let mut bit = true;
+        if x <= xm {
+            xr = xm;
+            bit = false;
+        } else {
+            xl = xm + 1;
+        }
+
+        Self::fill_bits(&mut xl, &mut xr, &mut x, &mut self.inner_reader)?;
+
+        self.xl = xl;
+        self.xr = xr;
+        self.x = x;
+
+        Ok(bit)
+    }
+
     fn fill_bits(
         xl: &mut u32,
         xr: &mut u32,
@@ -60,10 +89,9 @@ impl<R: Read> Fpaq0Decoder<R> {
         }
         Ok(())
     }
-}
 
-impl<R: Read> CabacReader<VP8Context> for Fpaq0Decoder<R> {
-    fn get_bypass(&mut self) -> Result<bool> {
+    /// reads a bit with a probability of 1/2 without a context
+    pub fn get_bypass(&mut self) -> Result<bool> {
         let mut xl = self.xl;
         let mut xr = self.xr;
 
@@ -83,32 +111,28 @@ impl<R: Read> CabacReader<VP8Context> for Fpaq0Decoder<R> {
 
         Ok(bit)
     }
+}
 
-    fn get(&mut self, cur_ctx: &mut VP8Context) -> Result<bool> {
-        let mut xl = self.xl;
-        let mut xr = self.xr;
-        let mut x = self.x;
-
-        let xm = xl + ((xr - xl) >> 8) * u32::from(cur_ctx.get_probability().get());
-
-        let mut bit = true;
-        if x <= xm {
-            xr = xm;
-            bit = false;
-        } else {
-            xl = xm + 1;
-        }
+impl<R: Read> CabacReader<VP8Context> for Fpaq0Decoder<R> {
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
 
-        let b = cur_ctx.record_and_update_bit(bit);
+    fn get(&mut self, cur_ctx: &mut VP8Context) -> Result<bool> {
+        let bit = self.get_with_probability(cur_ctx.get_probability())?;
 
-        Self::fill_bits(&mut xl, &mut xr, &mut x, &mut self.inner_reader)?;
+        *cur_ctx = cur_ctx.record_and_update_bit(bit);
+        Ok(bit)
+    }
+}
 
-        *cur_ctx = b;
-        self.xl = xl;
-        self.xr = xr;
-        self.x = x;
+impl<R: Read> ProbabilityReader for Fpaq0Decoder<R> {
+    fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool> {
+        self.get_with_probability(probability)
+    }
 
-        Ok(bit)
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
     }
 }
 
@@ -128,23 +152,13 @@ impl<W: Write> Fpaq0Encoder<W> {
         }
     }
 
-    fn flush_bits(xl: &mut u32, xr: &mut u32, inner_writer: &mut impl Write) -> Result<()> {
-        while 0 == ((*xl ^ *xr) & 0xFF00_0000) {
-            let byte = (*xr >> 24) as u8;
-            inner_writer.write_all(&[byte])?;
-            *xl <<= 8;
-            *xr = (*xr << 8) | 0x0000_00FF;
-        }
-        Ok(())
-    }
-}
-
-impl<W: Write> CabacWriter<VP8Context> for Fpaq0Encoder<W> {
-    fn put(&mut self, bit: bool, branch: &mut VP8Context) -> Result<()> {
+    /// writes a bit with a fixed probability of it being zero of probability / 256, rather
+    /// than with an adaptive context
+    pub fn put_with_probability(&mut self, bit: bool, probability: NonZeroU8) -> Result<()> {
         let mut xl = self.xl;
         let mut xr = self.xr;
 
-        let xm = xl + ((xr - xl) >> 8) * u32::from(branch.get_probability().get());
+        let xm = xl + ((xr - xl) >> 8) * u32::from(probability.get());
 
         // left/lower part of the interval corresponds to zero
         if !bit {
@@ -153,18 +167,26 @@ impl<W: Write> CabacWriter<VP8Context> for Fpaq0Encoder<W> {
             xl = xm + 1;
         }
 
-        let b = branch.record_and_update_bit(bit);
-
         Self::flush_bits(&mut xl, &mut xr, &mut self.inner_writer)?;
 
         self.xl = xl;
         self.xr = xr;
-        *branch = b;
 
         Ok(())
     }
 
-    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+    fn flush_bits(xl: &mut u32, xr: &mut u32, inner_writer: &mut impl Write) -> Result<()> {
+        while 0 == ((*xl ^ *xr) & 0xFF00_0000) {
+            let byte = (*xr >> 24) as u8;
+            inner_writer.write_all(&[byte])?;
+            *xl <<= 8;
+            *xr = (*xr << 8) | 0x0000_00FF;
+        }
+        Ok(())
+    }
+
+    /// writes a bit with a probability of 1/2 without a context
+    pub fn put_bypass(&mut self, bit: bool) -> Result<()> {
         let mut xl = self.xl;
         let mut xr = self.xr;
 
@@ -186,9 +208,43 @@ impl<W: Write> CabacWriter<VP8Context> for Fpaq0Encoder<W> {
         Ok(())
     }
 
-    fn finish(&mut self) -> Result<()> {
+    /// flushes the remaining bits
+    pub fn finish(&mut self) -> Result<()> {
         let byte = (self.xr >> 24) as u8;
         self.inner_writer.write_all(&[byte])?;
         self.inner_writer.write_all(&[0, 0, 0])
     }
 }
+
+impl<W: Write> CabacWriter<VP8Context> for Fpaq0Encoder<W> {
+    fn put(&mut self, bit: bool, branch: &mut VP8Context) -> Result<()> {
+        let b = branch.record_and_update_bit(bit);
+
+        self.put_with_probability(bit, branch.get_probability())?;
+
+        *branch = b;
+        Ok(())
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ProbabilityWriter for Fpaq0Encoder<W> {
+    fn put_with_probability(&mut self, bit: bool, probability: NonZeroU8) -> Result<()> {
+        self.put_with_probability(bit, probability)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
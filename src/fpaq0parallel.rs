@@ -33,6 +33,7 @@ use crate::vp8::VP8Context;
 use std::{
     collections::VecDeque,
     io::{Read, Result, Write},
+    num::NonZeroU8,
 };
 
 /// This holds the output and stitches together the future output in the right
@@ -280,11 +281,26 @@ impl Fpaq0EncoderParallel {
         bit: bool,
         branch: &mut VP8Context,
         writer: &mut ParallelEncoderOutput<W>,
+    ) -> Result<()> {
+        let b = branch.record_and_update_bit(bit);
+
+        self.put_with_probability(bit, branch.get_probability(), writer)?;
+
+        *branch = b;
+        Ok(())
+    }
+
+    /// puts bit into the stream with a fixed probability of it being zero of probability / 256
+    pub fn put_with_probability<W: Write>(
+        &mut self,
+        bit: bool,
+        probability: NonZeroU8,
+        writer: &mut ParallelEncoderOutput<W>,
     ) -> Result<()> {
         let mut xl = self.xl;
         let mut xr = self.xr;
 
-        let xm = xl + ((xr - xl) >> 8) * u32::from(branch.get_probability().get());
+        let xm = xl + ((xr - xl) >> 8) * u32::from(probability.get());
 
         // left/lower part of the interval corresponds to zero
 
@@ -294,11 +310,8 @@ impl Fpaq0EncoderParallel {
             xl = xm + 1;
         }
 
-        let b = branch.record_and_update_bit(bit);
-
         self.flush_bits(writer, &mut xl, &mut xr)?;
 
-        *branch = b;
         self.xl = xl;
         self.xr = xr;
         Ok(())
@@ -366,10 +379,23 @@ impl Fpaq0DecoderParallel {
 
     /// reads a bit from the stream given a certain probability context
     pub fn get(&mut self, cur_ctx: &mut VP8Context, reader: &mut impl Read) -> Result<bool> {
+        let bit = self.get_with_probability(cur_ctx.get_probability(), reader)?;
+
+        *cur_ctx = cur_ctx.record_and_update_bit(bit);
+        Ok(bit)
+    }
+
+    /// reads a bit from the stream that was coded with a fixed probability of it being zero of
+    /// probability / 256
+    pub fn get_with_probability(
+        &mut self,
+        probability: NonZeroU8,
+        reader: &mut impl Read,
+    ) -> Result<bool> {
         let mut xl = self.xl;
         let mut xr = self.xr;
 
-        let xm = xl + ((xr - xl) >> 8) * u32::from(cur_ctx.get_probability().get());
+        let xm = xl + ((xr - xl) >> 8) * u32::from(probability.get());
         let mut bit = true;
         if self.x <= xm {
             bit = false;
@@ -378,11 +404,8 @@ impl Fpaq0DecoderParallel {
             xl = xm + 1;
         }
 
-        let c = cur_ctx.record_and_update_bit(bit);
-
         self.fill_bits(&mut xl, &mut xr, reader)?;
 
-        *cur_ctx = c;
         self.xl = xl;
         self.xr = xr;
 
@@ -492,11 +515,33 @@ impl Fpaq0DecoderParallelSimd {
 
         let cmp = xm.cmp_lt(self.x);
 
+        VP8Context::record_and_update_bit_wide(cur_ctx, cmp);
+
+        self.advance(xm, cmp, reader)
+    }
+
+    /// reads a bit from each of the streams that were coded with fixed probabilities of them
+    /// being zero of probability / 256
+    #[inline(always)]
+    pub fn get_with_probability(
+        &mut self,
+        probability: [NonZeroU8; 4],
+        reader: &mut impl Read,
+    ) -> Result<u32> {
+        let xm: u32x4 = self.xl
+            + ((self.xr - self.xl) >> 8) * u32x4::from(probability.map(|p| u32::from(p.get())));
+
+        let cmp = xm.cmp_lt(self.x);
+
+        self.advance(xm, cmp, reader)
+    }
+
+    /// narrows the intervals to the side selected by cmp and returns the decoded bits
+    #[inline(always)]
+    fn advance(&mut self, xm: u32x4, cmp: u32x4, reader: &mut impl Read) -> Result<u32> {
         let mut xrw = cmp.blend(self.xr, xm);
         let mut xlw = cmp.blend(xm + 1, self.xl);
 
-        VP8Context::record_and_update_bit_wide(cur_ctx, cmp);
-
         let bitmask = bitmask(cmp);
 
         self.fill_bits(&mut xlw, &mut xrw, reader)?;
@@ -0,0 +1,624 @@
+//! tANS (table based asymmetric numeral systems) for static multi-symbol alphabets, using the
+//! table construction of FSE as used in zstd.
+//!
+//! The symbol histogram is normalized so that the counts add up to the table size, and the
+//! symbols are spread over the table. Each state of the decoding table holds the symbol,
+//! the number of bits to read and the base of the next state, so that decoding a symbol is a
+//! table lookup and a read of a few bits without any multiplications or divisions. Like zstd,
+//! symbols can have a count of -1, which means that their probability is below 1/table size
+//! and they occupy a single state at the end of the table.
+//!
+//! Like rANS, the encoder has to process the symbols in reverse, so they are buffered and
+//! written out in blocks.
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::bitstream::{BitReader, BitWriter};
+
+pub const MIN_TABLE_LOG: u32 = 5;
+pub const MAX_TABLE_LOG: u32 = 15;
+
+/// number of symbols coded with one state before the encoder starts over
+const BLOCK_SIZE: usize = 1 << 14;
+
+#[derive(Clone, Copy, Default)]
+struct DecodeEntry {
+    new_state: u16,
+    symbol: u8,
+    num_bits: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SymbolTransform {
+    delta_num_bits: u32,
+    delta_find_state: i32,
+}
+
+/// coding tables for a normalized symbol distribution, shared by the encoder and decoder
+pub struct FseTable {
+    table_log: u32,
+    normalized: Vec<i16>,
+    decode: Vec<DecodeEntry>,
+    /// encoder states indexed by symbol start and the state shifted by the output bits
+    state_table: Vec<u16>,
+    symbol_transform: Vec<SymbolTransform>,
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn high_bit(v: u32) -> u32 {
+    31 - v.leading_zeros()
+}
+
+impl FseTable {
+    /// builds the tables from normalized counts, which have to add up to 1 << table_log with
+    /// -1 counting as 1. There can be up to 256 symbols.
+    pub fn new(normalized: &[i16], table_log: u32) -> Result<Self> {
+        if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) {
+            return Err(invalid_data("table log out of range"));
+        }
+        if normalized.is_empty() || normalized.len() > 256 {
+            return Err(invalid_data("invalid number of symbols"));
+        }
+        if normalized.iter().any(|&n| n < -1) {
+            return Err(invalid_data("invalid normalized count"));
+        }
+
+        let table_size = 1usize << table_log;
+        let total: usize = normalized.iter().map(|&n| n.unsigned_abs() as usize).sum();
+        if total != table_size {
+            return Err(invalid_data(
+                "normalized counts don't add up to the table size",
+            ));
+        }
+
+        // symbols with a count of -1 take the states at the end of the table
+        let mut symbols = vec![0u8; table_size];
+        let mut high_threshold = table_size - 1;
+        for (s, &n) in normalized.iter().enumerate() {
+            if n == -1 {
+                symbols[high_threshold] = s as u8;
+                high_threshold = high_threshold.wrapping_sub(1);
+            }
+        }
+
+        // spread the other symbols over the table with a step that is coprime to the size
+        let step = (table_size >> 1) + (table_size >> 3) + 3;
+        let mask = table_size - 1;
+        let mut position = 0;
+        for (s, &n) in normalized.iter().enumerate() {
+            for _ in 0..n.max(0) {
+                symbols[position] = s as u8;
+                loop {
+                    position = (position + step) & mask;
+                    if position <= high_threshold {
+                        break;
+                    }
+                }
+            }
+        }
+        debug_assert_eq!(position, 0);
+
+        // the decoding table maps each state to the symbol and the range of the next states
+        let mut symbol_next: Vec<u32> = normalized
+            .iter()
+            .map(|&n| u32::from(n.unsigned_abs()))
+            .collect();
+        let mut decode = vec![DecodeEntry::default(); table_size];
+        for (entry, &s) in decode.iter_mut().zip(symbols.iter()) {
+            let next_state = symbol_next[usize::from(s)];
+            symbol_next[usize::from(s)] += 1;
+
+            let num_bits = table_log - high_bit(next_state);
+            *entry = DecodeEntry {
+                new_state: ((next_state << num_bits) - table_size as u32) as u16,
+                symbol: s,
+                num_bits: num_bits as u8,
+            };
+        }
+
+        // the encoder state table lists the states of each symbol in order
+        let mut cumulative = Vec::with_capacity(normalized.len() + 1);
+        cumulative.push(0usize);
+        for &n in normalized {
+            cumulative.push(cumulative.last().unwrap() + n.unsigned_abs() as usize);
+        }
+        let mut state_table = vec![0u16; table_size];
+        for (u, &s) in symbols.iter().enumerate() {
+            let c = &mut cumulative[usize::from(s)];
+            state_table[*c] = (table_size + u) as u16;
+            *c += 1;
+        }
+
+        let mut total = 0i32;
+        let symbol_transform = normalized
+            .iter()
+            .map(|&n| match n {
+                0 => SymbolTransform {
+                    delta_num_bits: ((table_log + 1) << 16) - table_size as u32,
+                    delta_find_state: 0,
+                },
+                -1 | 1 => {
+                    let t = SymbolTransform {
+                        delta_num_bits: (table_log << 16) - table_size as u32,
+                        delta_find_state: total - 1,
+                    };
+                    total += 1;
+                    t
+                }
+                _ => {
+                    let max_bits_out = table_log - high_bit(n as u32 - 1);
+                    let min_state_plus = (n as u32) << max_bits_out;
+                    let t = SymbolTransform {
+                        delta_num_bits: (max_bits_out << 16) - min_state_plus,
+                        delta_find_state: total - i32::from(n),
+                    };
+                    total += i32::from(n);
+                    t
+                }
+            })
+            .collect();
+
+        Ok(FseTable {
+            table_log,
+            normalized: normalized.to_vec(),
+            decode,
+            state_table,
+            symbol_transform,
+        })
+    }
+
+    /// normalizes the histogram of the symbols and builds the tables. Symbols with a share
+    /// below one state get a count of -1.
+    pub fn from_counts(counts: &[u32], table_log: u32) -> Result<Self> {
+        let normalized = normalize_counts(counts, table_log)?;
+        Self::new(&normalized, table_log)
+    }
+
+    pub fn table_log(&self) -> u32 {
+        self.table_log
+    }
+
+    pub fn normalized_counts(&self) -> &[i16] {
+        &self.normalized
+    }
+
+    /// writes the normalized counts. Each count is written as count + 1 with a truncated binary
+    /// code whose range is limited by the part of the table that is still unassigned, and runs
+    /// of zero counts are written in 2 bit steps after a zero, similar to the header of FSE.
+    pub fn write_header<W: Write>(&self, writer: &mut BitWriter<W>) -> Result<()> {
+        writer.put_bits(self.table_log - MIN_TABLE_LOG, 4)?;
+
+        let mut remaining = 1u32 << self.table_log;
+        let mut symbol = 0;
+        while remaining > 0 {
+            let n = self.normalized[symbol];
+            put_truncated(writer, (n + 1) as u32, remaining + 2)?;
+            remaining -= u32::from(n.unsigned_abs());
+            symbol += 1;
+
+            if n == 0 {
+                let start = symbol;
+                while self.normalized[symbol] == 0 {
+                    symbol += 1;
+                }
+
+                let mut run = symbol - start;
+                while run >= 3 {
+                    writer.put_bits(3, 2)?;
+                    run -= 3;
+                }
+                writer.put_bits(run as u32, 2)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// reads the normalized counts written by `write_header` and builds the tables
+    pub fn read_header<R: Read>(reader: &mut BitReader<R>) -> Result<Self> {
+        let table_log = reader.get_bits(4)? + MIN_TABLE_LOG;
+        if table_log > MAX_TABLE_LOG {
+            return Err(invalid_data("table log out of range"));
+        }
+
+        let mut normalized = Vec::new();
+        let mut remaining = 1u32 << table_log;
+        while remaining > 0 {
+            let n = get_truncated(reader, remaining + 2)? as i16 - 1;
+            remaining -= u32::from(n.unsigned_abs());
+            normalized.push(n);
+
+            if n == 0 {
+                loop {
+                    let run = reader.get_bits(2)?;
+                    normalized.resize(normalized.len() + run as usize, 0);
+                    if run < 3 {
+                        break;
+                    }
+                }
+            }
+
+            if normalized.len() > 256 {
+                return Err(invalid_data("too many symbols"));
+            }
+        }
+
+        Self::new(&normalized, table_log)
+    }
+}
+
+/// writes value < n with a truncated binary code
+fn put_truncated<W: Write>(writer: &mut BitWriter<W>, value: u32, n: u32) -> Result<()> {
+    let k = high_bit(n);
+    let u = (2 << k) - n;
+    if value < u {
+        writer.put_bits(value, k)
+    } else {
+        writer.put_bits(value + u, k + 1)
+    }
+}
+
+fn get_truncated<R: Read>(reader: &mut BitReader<R>, n: u32) -> Result<u32> {
+    let k = high_bit(n);
+    let u = (2 << k) - n;
+    let value = reader.get_bits(k)?;
+    if value < u {
+        Ok(value)
+    } else {
+        let value = ((value << 1) | reader.get_bits(1)?) - u;
+        if value >= n {
+            return Err(invalid_data("invalid normalized count"));
+        }
+        Ok(value)
+    }
+}
+
+/// scales the counts so that they add up to 1 << table_log. Every symbol that occurs keeps a
+/// nonzero count, either at least 1 or -1 if its share is below one state.
+pub fn normalize_counts(counts: &[u32], table_log: u32) -> Result<Vec<i16>> {
+    if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) {
+        return Err(invalid_data("table log out of range"));
+    }
+
+    let table_size = 1u64 << table_log;
+    let total: u64 = counts.iter().map(|&c| u64::from(c)).sum();
+    if total == 0 {
+        return Err(invalid_data("no symbols to normalize"));
+    }
+    if counts.iter().filter(|&&c| c != 0).count() as u64 > table_size {
+        return Err(invalid_data("too many symbols for the table size"));
+    }
+
+    let mut normalized: Vec<i16> = counts
+        .iter()
+        .map(|&c| {
+            let share = u64::from(c) * table_size;
+            if c == 0 {
+                0
+            } else if share < total {
+                -1
+            } else {
+                ((share + total / 2) / total) as i16
+            }
+        })
+        .collect();
+
+    // give the rounding error to (or take it from) the most probable symbols
+    let mut distributed: i64 = normalized.iter().map(|&n| i64::from(n.abs())).sum();
+    while distributed != table_size as i64 {
+        let (largest, _) = normalized
+            .iter()
+            .enumerate()
+            .max_by_key(|&(i, &n)| (n, std::cmp::Reverse(i)))
+            .unwrap();
+
+        if distributed < table_size as i64 {
+            normalized[largest] += (table_size as i64 - distributed) as i16;
+            distributed = table_size as i64;
+        } else {
+            if normalized[largest] <= 1 {
+                // every symbol is down to a single state
+                return Err(invalid_data("too many symbols for the table size"));
+            }
+            normalized[largest] -= 1;
+            distributed -= 1;
+        }
+    }
+
+    Ok(normalized)
+}
+
+pub struct FseWriter<'a, W: Write> {
+    writer: BitWriter<W>,
+    table: &'a FseTable,
+    symbols: Vec<u8>,
+}
+
+impl<'a, W: Write> FseWriter<'a, W> {
+    pub fn new(writer: W, table: &'a FseTable) -> Self {
+        FseWriter {
+            writer: BitWriter::new(writer),
+            table,
+            symbols: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// writes a symbol, which must have a nonzero count in the table
+    pub fn put_symbol(&mut self, symbol: u8) -> Result<()> {
+        if self
+            .table
+            .normalized
+            .get(usize::from(symbol))
+            .copied()
+            .unwrap_or(0)
+            == 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "symbol has a count of zero",
+            ));
+        }
+
+        self.symbols.push(symbol);
+        if self.symbols.len() == BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// encodes the buffered symbols in reverse and writes the final state followed by the
+    /// bits in the order the decoder needs them
+    fn flush_block(&mut self) -> Result<()> {
+        let table = self.table;
+        let table_size = 1u32 << table.table_log;
+
+        let mut bits = Vec::with_capacity(self.symbols.len());
+        let mut state = table_size;
+        for &s in self.symbols.iter().rev() {
+            let t = table.symbol_transform[usize::from(s)];
+            let num_bits = (state + t.delta_num_bits) >> 16;
+            bits.push((state & ((1 << num_bits) - 1), num_bits));
+            state = u32::from(
+                table.state_table[((state >> num_bits) as i32 + t.delta_find_state) as usize],
+            );
+        }
+
+        self.writer.put_bits(state - table_size, table.table_log)?;
+        for &(value, num_bits) in bits.iter().rev() {
+            self.writer.put_bits(value, num_bits)?;
+        }
+
+        self.symbols.clear();
+        Ok(())
+    }
+
+    /// writes out the buffered symbols and pads the output to a whole byte
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.symbols.is_empty() {
+            self.flush_block()?;
+        }
+        self.writer.byte_align()
+    }
+}
+
+pub struct FseReader<'a, R: Read> {
+    reader: BitReader<R>,
+    table: &'a FseTable,
+    state: usize,
+    /// symbols left in the current block
+    remaining: usize,
+}
+
+impl<'a, R: Read> FseReader<'a, R> {
+    pub fn new(reader: R, table: &'a FseTable) -> Self {
+        FseReader {
+            reader: BitReader::new(reader),
+            table,
+            state: 0,
+            remaining: 0,
+        }
+    }
+
+    pub fn get_symbol(&mut self) -> Result<u8> {
+        if self.remaining == 0 {
+            self.state = self.reader.get_bits(self.table.table_log)? as usize;
+            self.remaining = BLOCK_SIZE;
+        }
+        self.remaining -= 1;
+
+        let e = self.table.decode[self.state];
+        self.state =
+            usize::from(e.new_state) + self.reader.get_bits(u32::from(e.num_bits))? as usize;
+        Ok(e.symbol)
+    }
+}
+
+/// predefined distribution of the literals length codes from RFC 8878
+#[cfg(test)]
+const LITERALS_LENGTH_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+
+#[test]
+fn matches_rfc8878_decoding_table() {
+    // (symbol, number of bits, base) of the states of the literals length table in appendix A
+    let expected: [(u8, u8, u16); 64] = [
+        (0, 4, 0),
+        (0, 4, 16),
+        (1, 5, 32),
+        (3, 5, 0),
+        (4, 5, 0),
+        (6, 5, 0),
+        (7, 5, 0),
+        (9, 5, 0),
+        (10, 5, 0),
+        (12, 5, 0),
+        (14, 6, 0),
+        (16, 5, 0),
+        (18, 5, 0),
+        (19, 5, 0),
+        (21, 5, 0),
+        (22, 5, 0),
+        (24, 5, 0),
+        (25, 5, 32),
+        (26, 5, 0),
+        (27, 6, 0),
+        (29, 6, 0),
+        (31, 6, 0),
+        (0, 4, 32),
+        (1, 4, 0),
+        (2, 5, 0),
+        (4, 5, 32),
+        (5, 5, 0),
+        (7, 5, 32),
+        (8, 5, 0),
+        (10, 5, 32),
+        (11, 5, 0),
+        (13, 6, 0),
+        (16, 5, 32),
+        (17, 5, 0),
+        (19, 5, 32),
+        (20, 5, 0),
+        (22, 5, 32),
+        (23, 5, 0),
+        (25, 4, 0),
+        (25, 4, 16),
+        (26, 5, 32),
+        (28, 6, 0),
+        (30, 6, 0),
+        (0, 4, 48),
+        (1, 4, 16),
+        (2, 5, 32),
+        (3, 5, 32),
+        (5, 5, 32),
+        (6, 5, 32),
+        (8, 5, 32),
+        (9, 5, 32),
+        (11, 5, 32),
+        (12, 5, 32),
+        (15, 6, 0),
+        (17, 5, 32),
+        (18, 5, 32),
+        (20, 5, 32),
+        (21, 5, 32),
+        (23, 5, 32),
+        (24, 5, 32),
+        (35, 6, 0),
+        (34, 6, 0),
+        (33, 6, 0),
+        (32, 6, 0),
+    ];
+
+    let table = FseTable::new(&LITERALS_LENGTH_DEFAULT, 6).unwrap();
+    for (state, (e, &(symbol, num_bits, base))) in
+        table.decode.iter().zip(expected.iter()).enumerate()
+    {
+        assert_eq!(
+            (e.symbol, e.num_bits, e.new_state),
+            (symbol, num_bits, base),
+            "state {state}"
+        );
+    }
+}
+
+#[test]
+fn header_roundtrip() {
+    let mut counts = [0u32; 200];
+    counts[0] = 1000;
+    counts[1] = 10;
+    counts[7] = 1;
+    counts[50] = 300;
+    counts[51] = 2;
+    counts[199] = 77;
+
+    let table = FseTable::from_counts(&counts, 9).unwrap();
+    let normalized = table.normalized_counts();
+    assert_eq!(
+        normalized.iter().map(|&n| i32::from(n.abs())).sum::<i32>(),
+        512
+    );
+    assert_eq!(normalized[7], -1);
+
+    let mut writer = BitWriter::new(Vec::new());
+    table.write_header(&mut writer).unwrap();
+    writer.byte_align().unwrap();
+    let header = writer.into_inner();
+
+    let read = FseTable::read_header(&mut BitReader::new(&header[..])).unwrap();
+    assert_eq!(read.table_log(), 9);
+    assert_eq!(read.normalized_counts(), normalized);
+
+    let table = FseTable::new(&LITERALS_LENGTH_DEFAULT, 6).unwrap();
+    let mut writer = BitWriter::new(Vec::new());
+    table.write_header(&mut writer).unwrap();
+    writer.byte_align().unwrap();
+    let header = writer.into_inner();
+
+    let read = FseTable::read_header(&mut BitReader::new(&header[..])).unwrap();
+    assert_eq!(read.normalized_counts(), LITERALS_LENGTH_DEFAULT);
+}
+
+#[test]
+fn roundtrip_symbols() {
+    use crate::test_util::{assert_near_cost, random_symbols};
+
+    // long enough for several blocks
+    let symbols: Vec<u8> = random_symbols(3 * BLOCK_SIZE + 123, 64)
+        .into_iter()
+        .map(|s| s as u8)
+        .collect();
+
+    let mut counts = [0u32; 64];
+    for &s in &symbols {
+        counts[usize::from(s)] += 1;
+    }
+    let table = FseTable::from_counts(&counts, 11).unwrap();
+
+    let mut output = Vec::new();
+    let mut writer = FseWriter::new(&mut output, &table);
+    for &s in &symbols {
+        writer.put_symbol(s).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // a count of -1 takes a single state
+    let freqs: Vec<u32> = table
+        .normalized_counts()
+        .iter()
+        .map(|&n| u32::from(n.unsigned_abs()))
+        .collect();
+    let indices: Vec<usize> = symbols.iter().map(|&s| usize::from(s)).collect();
+    // the final state of each of the 4 blocks and the padding of the last byte
+    assert_near_cost(&indices, &freqs, 11, output.len(), 4 * 2 + 1);
+
+    let mut reader = FseReader::new(&output[..], &table);
+    for &s in &symbols {
+        assert_eq!(reader.get_symbol().unwrap(), s);
+    }
+}
+
+#[test]
+fn known_answer() {
+    let text = b"abracadabra arbadacarba abracadabra";
+    let mut counts = [0u32; 256];
+    text.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let table = FseTable::from_counts(&counts[..=usize::from(b'r')], 6).unwrap();
+
+    let mut output = Vec::new();
+    let mut writer = FseWriter::new(&mut output, &table);
+    for &s in text {
+        writer.put_symbol(s).unwrap();
+    }
+    writer.finish().unwrap();
+    assert_eq!(
+        output,
+        [0x62, 0x46, 0x70, 0x5c, 0xda, 0x4e, 0xea, 0x1f, 0x1d, 0x22, 0x60]
+    );
+
+    let mut reader = FseReader::new(&output[..], &table);
+    for &s in text {
+        assert_eq!(reader.get_symbol().unwrap(), s);
+    }
+}
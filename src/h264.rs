@@ -0,0 +1,629 @@
+//! H.264 (AVC) CABAC syntax elements on top of the H.265 arithmetic coding engine.
+//!
+//! The arithmetic coding engine and the probability state machine of H.264 are the same ones
+//! that H.265 uses, so `H265Writer`/`H265Reader` and `H265Context` can code H.264 slice data
+//! as well. What differs are the binarizations and the context selection, which are provided
+//! here for residual_block_cabac() and the motion vector differences.
+//!
+//! All contexts are kept in one array that is indexed by the ctxIdx of the spec (0 to 1023), so
+//! that they can be initialized directly from the (m, n) tables 9-12 to 9-33.
+use std::io::{Error, ErrorKind, Result};
+
+use crate::h265::H265Context;
+use crate::syntax::{get_exp_golomb, put_exp_golomb};
+use crate::traits::{CabacReader, CabacWriter};
+
+/// ctxBlockCat, the kind of block that residual_block_cabac() is coding (table 9-42)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockCategory {
+    LumaDc = 0,
+    LumaAc = 1,
+    Luma4x4 = 2,
+    ChromaDc = 3,
+    ChromaAc = 4,
+    Luma8x8 = 5,
+    CbDc = 6,
+    CbAc = 7,
+    Cb4x4 = 8,
+    Cb8x8 = 9,
+    CrDc = 10,
+    CrAc = 11,
+    Cr4x4 = 12,
+    Cr8x8 = 13,
+}
+
+/// ctxIdxOffset of coded_block_flag, significant_coeff_flag (frame and field),
+/// last_significant_coeff_flag (frame and field) and coeff_abs_level_minus1 for each category
+const CTX_IDX_OFFSET: [[usize; 6]; 14] = [
+    [85, 105, 277, 166, 338, 227],
+    [85, 105, 277, 166, 338, 227],
+    [85, 105, 277, 166, 338, 227],
+    [85, 105, 277, 166, 338, 227],
+    [85, 105, 277, 166, 338, 227],
+    [1012, 402, 436, 417, 451, 426],
+    [460, 484, 776, 572, 864, 952],
+    [460, 484, 776, 572, 864, 952],
+    [460, 484, 776, 572, 864, 952],
+    [1012, 660, 675, 690, 699, 708],
+    [472, 528, 820, 616, 908, 982],
+    [472, 528, 820, 616, 908, 982],
+    [472, 528, 820, 616, 908, 982],
+    [1012, 718, 733, 748, 757, 766],
+];
+
+/// ctxBlockCatOffset of coded_block_flag, the significance map and coeff_abs_level_minus1
+/// (table 9-40)
+const CTX_BLOCK_CAT_OFFSET: [[usize; 3]; 14] = [
+    [0, 0, 0],
+    [4, 15, 10],
+    [8, 29, 20],
+    [12, 44, 30],
+    [16, 47, 39],
+    [0, 0, 0],
+    [0, 0, 0],
+    [4, 15, 10],
+    [8, 29, 20],
+    [4, 0, 0],
+    [0, 0, 0],
+    [4, 15, 10],
+    [8, 29, 20],
+    [8, 0, 0],
+];
+
+/// ctxIdxInc of significant_coeff_flag in 8x8 blocks for frame and field coding (table 9-43)
+const SIG_COEFF_FLAG_8X8: [[u8; 63]; 2] = [
+    [
+        0, 1, 2, 3, 4, 5, 5, 4, 4, 3, 3, 4, 4, 4, 5, 5, 4, 4, 4, 4, 3, 3, 6, 7, 7, 7, 8, 9, 10, 9,
+        8, 7, 7, 6, 11, 12, 13, 11, 6, 7, 8, 9, 14, 10, 9, 8, 6, 11, 12, 13, 11, 6, 9, 14, 10, 9,
+        11, 12, 13, 11, 14, 10, 12,
+    ],
+    [
+        0, 1, 1, 2, 2, 3, 3, 4, 5, 6, 7, 7, 7, 8, 4, 5, 6, 9, 10, 10, 8, 11, 12, 11, 9, 9, 10, 10,
+        8, 11, 12, 11, 9, 9, 10, 10, 8, 11, 12, 11, 9, 9, 10, 10, 8, 13, 13, 9, 9, 10, 10, 8, 13,
+        13, 9, 9, 10, 10, 14, 14, 14, 14, 14,
+    ],
+];
+
+/// ctxIdxInc of last_significant_coeff_flag in 8x8 blocks (table 9-43)
+const LAST_SIG_COEFF_FLAG_8X8: [u8; 63] = [
+    0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+    3, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8,
+];
+
+/// ctxIdxOffset of the prefix of mvd_l0/mvd_l1 for the horizontal and vertical component
+const MVD_CTX_IDX_OFFSET: [usize; 2] = [40, 47];
+
+/// the prefix of coeff_abs_level_minus1 is unary up to this value (uCoff of UEG0)
+const COEFF_ABS_LEVEL_U_COFF: u32 = 14;
+
+/// the prefix of mvd is unary up to this value (uCoff of UEG3)
+const MVD_U_COFF: u32 = 9;
+
+impl BlockCategory {
+    fn is_8x8(self) -> bool {
+        matches!(
+            self,
+            BlockCategory::Luma8x8 | BlockCategory::Cb8x8 | BlockCategory::Cr8x8
+        )
+    }
+
+    /// maxNumCoeff of the category. Chroma DC blocks have 4 coefficients for 4:2:0 and 8 for
+    /// 4:2:2, so any of them is accepted.
+    fn valid_num_coeff(self, num_coeff: usize) -> bool {
+        match self {
+            BlockCategory::ChromaDc => num_coeff == 4 || num_coeff == 8,
+            BlockCategory::LumaAc
+            | BlockCategory::ChromaAc
+            | BlockCategory::CbAc
+            | BlockCategory::CrAc => num_coeff == 15,
+            BlockCategory::Luma8x8 | BlockCategory::Cb8x8 | BlockCategory::Cr8x8 => num_coeff == 64,
+            _ => num_coeff == 16,
+        }
+    }
+}
+
+/// All the contexts of an H.264 slice, indexed by ctxIdx.
+#[derive(Clone)]
+pub struct H264Contexts {
+    contexts: Box<[H265Context; 1024]>,
+}
+
+impl Default for H264Contexts {
+    fn default() -> Self {
+        H264Contexts {
+            contexts: Box::new([H265Context::default(); 1024]),
+        }
+    }
+}
+
+impl H264Contexts {
+    /// initializes the contexts for the start of a slice, `init` returns the (m, n) pair for
+    /// a ctxIdx from the tables that match the slice type and cabac_init_idc.
+    pub fn new(init: impl Fn(usize) -> (i32, i32), slice_qp: i32) -> Self {
+        let mut contexts = Self::default();
+        for (ctx_idx, context) in contexts.contexts.iter_mut().enumerate() {
+            let (m, n) = init(ctx_idx);
+            *context = H265Context::from_m_n(m, n, slice_qp);
+        }
+        contexts
+    }
+
+    /// returns the context for a ctxIdx, for the syntax elements that aren't coded by this module
+    pub fn get_mut(&mut self, ctx_idx: usize) -> &mut H265Context {
+        &mut self.contexts[ctx_idx]
+    }
+}
+
+/// describes how a block is coded by residual_block_cabac()
+#[derive(Clone, Copy, Debug)]
+pub struct ResidualBlock {
+    pub category: BlockCategory,
+    /// true for field pictures and field macroblock pairs, which use separate contexts for
+    /// the significance map
+    pub field: bool,
+    /// condTermFlagA and condTermFlagB for coded_block_flag, derived from the blocks to the
+    /// left and above. None if coded_block_flag isn't present, which is the case for 8x8
+    /// luma blocks unless ChromaArrayType is 3.
+    pub coded_block_flag_neighbours: Option<(bool, bool)>,
+}
+
+impl ResidualBlock {
+    fn offsets(&self) -> &'static [usize; 6] {
+        &CTX_IDX_OFFSET[self.category as usize]
+    }
+
+    fn cat_offsets(&self) -> &'static [usize; 3] {
+        &CTX_BLOCK_CAT_OFFSET[self.category as usize]
+    }
+
+    fn coded_block_flag_ctx(&self, neighbours: (bool, bool)) -> usize {
+        self.offsets()[0]
+            + self.cat_offsets()[0]
+            + usize::from(neighbours.0)
+            + 2 * usize::from(neighbours.1)
+    }
+
+    /// ctxIdx of significant_coeff_flag (last == false) or last_significant_coeff_flag
+    /// (last == true) for the coefficient at levelListIdx
+    fn significance_ctx(&self, last: bool, level_list_idx: usize, num_coeff: usize) -> usize {
+        let field = usize::from(self.field);
+        let offset = self.offsets()[1 + 2 * usize::from(last) + field] + self.cat_offsets()[1];
+
+        let ctx_inc = if self.category.is_8x8() {
+            if last {
+                LAST_SIG_COEFF_FLAG_8X8[level_list_idx]
+            } else {
+                SIG_COEFF_FLAG_8X8[field][level_list_idx]
+            }
+            .into()
+        } else if self.category == BlockCategory::ChromaDc {
+            // NumC8x8 is 1 for 4:2:0 and 2 for 4:2:2
+            (level_list_idx / (num_coeff / 4)).min(2)
+        } else {
+            level_list_idx
+        };
+
+        offset + ctx_inc
+    }
+
+    /// ctxIdx of bin `bin_idx` of coeff_abs_level_minus1 given the number of previously coded
+    /// levels in the block that are equal to 1 and greater than 1
+    fn coeff_abs_level_ctx(&self, bin_idx: u32, num_eq1: usize, num_gt1: usize) -> usize {
+        let ctx_inc = if bin_idx == 0 {
+            if num_gt1 != 0 {
+                0
+            } else {
+                (1 + num_eq1).min(4)
+            }
+        } else {
+            let max = if self.category == BlockCategory::ChromaDc {
+                3
+            } else {
+                4
+            };
+            5 + num_gt1.min(max)
+        };
+
+        self.offsets()[5] + self.cat_offsets()[2] + ctx_inc
+    }
+}
+
+/// writes residual_block_cabac() for a block. The coefficients are in scanning order (zig-zag
+/// or field scan) and the number of them has to match the category.
+///
+/// If coded_block_flag isn't present for the block, at least one coefficient has to be non-zero.
+pub fn put_residual_block<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut H264Contexts,
+    block: &ResidualBlock,
+    coefficients: &[i32],
+) -> Result<()> {
+    let num_coeff = coefficients.len();
+    assert!(
+        block.category.valid_num_coeff(num_coeff),
+        "wrong number of coefficients for the block category"
+    );
+
+    let last = coefficients.iter().rposition(|&c| c != 0);
+
+    if let Some(neighbours) = block.coded_block_flag_neighbours {
+        writer.put(
+            last.is_some(),
+            &mut contexts.contexts[block.coded_block_flag_ctx(neighbours)],
+        )?;
+    }
+
+    let Some(last) = last else {
+        if block.coded_block_flag_neighbours.is_some() {
+            return Ok(());
+        }
+
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "block without coded_block_flag has no coefficients",
+        ));
+    };
+
+    // significance map, the last coefficient doesn't need any flags
+    for (i, &c) in coefficients[..num_coeff - 1].iter().enumerate() {
+        let sig = c != 0;
+        writer.put(
+            sig,
+            &mut contexts.contexts[block.significance_ctx(false, i, num_coeff)],
+        )?;
+
+        if sig {
+            writer.put(
+                i == last,
+                &mut contexts.contexts[block.significance_ctx(true, i, num_coeff)],
+            )?;
+
+            if i == last {
+                break;
+            }
+        }
+    }
+
+    // levels in reverse scanning order
+    let mut num_eq1 = 0;
+    let mut num_gt1 = 0;
+    for &c in coefficients[..=last].iter().rev().filter(|&&c| c != 0) {
+        let abs_level_minus1 = c.unsigned_abs() - 1;
+
+        // UEG0 with signedValFlag = 0 and uCoff = 14
+        let prefix = abs_level_minus1.min(COEFF_ABS_LEVEL_U_COFF);
+        for bin_idx in 0..prefix.min(COEFF_ABS_LEVEL_U_COFF - 1) + 1 {
+            writer.put(
+                bin_idx < prefix,
+                &mut contexts.contexts[block.coeff_abs_level_ctx(bin_idx, num_eq1, num_gt1)],
+            )?;
+        }
+        if abs_level_minus1 >= COEFF_ABS_LEVEL_U_COFF {
+            put_exp_golomb(writer, abs_level_minus1 - COEFF_ABS_LEVEL_U_COFF, 0)?;
+        }
+
+        writer.put_bypass(c < 0)?;
+
+        if abs_level_minus1 == 0 {
+            num_eq1 += 1;
+        } else {
+            num_gt1 += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// reads residual_block_cabac() for a block into the coefficients, which are in scanning
+/// order. Returns coded_block_flag, which is needed for the neighbouring blocks.
+pub fn get_residual_block<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut H264Contexts,
+    block: &ResidualBlock,
+    coefficients: &mut [i32],
+) -> Result<bool> {
+    let num_coeff = coefficients.len();
+    assert!(
+        block.category.valid_num_coeff(num_coeff),
+        "wrong number of coefficients for the block category"
+    );
+    coefficients.fill(0);
+
+    if let Some(neighbours) = block.coded_block_flag_neighbours {
+        if !reader.get(&mut contexts.contexts[block.coded_block_flag_ctx(neighbours)])? {
+            return Ok(false);
+        }
+    }
+
+    // significance map, if no coefficient was marked as last the last one is significant
+    let mut last = num_coeff - 1;
+    for (i, c) in coefficients[..num_coeff - 1].iter_mut().enumerate() {
+        if reader.get(&mut contexts.contexts[block.significance_ctx(false, i, num_coeff)])? {
+            *c = 1;
+
+            if reader.get(&mut contexts.contexts[block.significance_ctx(true, i, num_coeff)])? {
+                last = i;
+                break;
+            }
+        }
+    }
+    coefficients[last] = 1;
+
+    let mut num_eq1 = 0;
+    let mut num_gt1 = 0;
+    for c in coefficients[..=last].iter_mut().rev().filter(|c| **c != 0) {
+        let mut abs_level_minus1 = 0;
+        while abs_level_minus1 < COEFF_ABS_LEVEL_U_COFF
+            && reader.get(
+                &mut contexts.contexts
+                    [block.coeff_abs_level_ctx(abs_level_minus1, num_eq1, num_gt1)],
+            )?
+        {
+            abs_level_minus1 += 1;
+        }
+        if abs_level_minus1 == COEFF_ABS_LEVEL_U_COFF {
+            abs_level_minus1 = get_exp_golomb(reader, 0)?
+                .checked_add(COEFF_ABS_LEVEL_U_COFF)
+                .filter(|&v| v < i32::MAX as u32)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "coefficient out of range"))?;
+        }
+
+        let level = (abs_level_minus1 + 1) as i32;
+        *c = if reader.get_bypass()? { -level } else { level };
+
+        if abs_level_minus1 == 0 {
+            num_eq1 += 1;
+        } else {
+            num_gt1 += 1;
+        }
+    }
+
+    Ok(true)
+}
+
+/// ctxIdx of bin `bin_idx` of the mvd prefix. `abs_mvd_sum` is the sum of the absolute values
+/// of the same component of the mvd in the neighbouring partitions A and B (absMvdComp).
+fn mvd_ctx(component: usize, bin_idx: u32, abs_mvd_sum: u32) -> usize {
+    let ctx_inc = match bin_idx {
+        0 if abs_mvd_sum < 3 => 0,
+        0 if abs_mvd_sum <= 32 => 1,
+        0 => 2,
+        1..=3 => bin_idx as usize + 2,
+        _ => 6,
+    };
+
+    MVD_CTX_IDX_OFFSET[component] + ctx_inc
+}
+
+/// writes mvd_l0/mvd_l1 for the horizontal (component 0) or vertical (component 1) part of a
+/// motion vector difference, binarized as UEG3 with signedValFlag = 1.
+pub fn put_mvd<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut H264Contexts,
+    component: usize,
+    abs_mvd_sum: u32,
+    mvd: i32,
+) -> Result<()> {
+    let abs_mvd = mvd.unsigned_abs();
+
+    let prefix = abs_mvd.min(MVD_U_COFF);
+    for bin_idx in 0..prefix.min(MVD_U_COFF - 1) + 1 {
+        writer.put(
+            bin_idx < prefix,
+            &mut contexts.contexts[mvd_ctx(component, bin_idx, abs_mvd_sum)],
+        )?;
+    }
+    if abs_mvd >= MVD_U_COFF {
+        put_exp_golomb(writer, abs_mvd - MVD_U_COFF, 3)?;
+    }
+
+    if abs_mvd != 0 {
+        writer.put_bypass(mvd < 0)?;
+    }
+
+    Ok(())
+}
+
+/// reads mvd_l0/mvd_l1 for the horizontal (component 0) or vertical (component 1) part of a
+/// motion vector difference.
+pub fn get_mvd<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut H264Contexts,
+    component: usize,
+    abs_mvd_sum: u32,
+) -> Result<i32> {
+    let mut abs_mvd = 0;
+    while abs_mvd < MVD_U_COFF
+        && reader.get(&mut contexts.contexts[mvd_ctx(component, abs_mvd, abs_mvd_sum)])?
+    {
+        abs_mvd += 1;
+    }
+    if abs_mvd == MVD_U_COFF {
+        abs_mvd = get_exp_golomb(reader, 3)?
+            .checked_add(MVD_U_COFF)
+            .filter(|&v| v <= i32::MAX as u32)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "mvd out of range"))?;
+    }
+
+    if abs_mvd != 0 && reader.get_bypass()? {
+        Ok(-(abs_mvd as i32))
+    } else {
+        Ok(abs_mvd as i32)
+    }
+}
+
+#[test]
+fn roundtrip_residual_blocks_and_mvds() {
+    use crate::h265::{H265Reader, H265Writer};
+    use rand::Rng;
+
+    let categories = [
+        BlockCategory::LumaDc,
+        BlockCategory::LumaAc,
+        BlockCategory::Luma4x4,
+        BlockCategory::ChromaDc,
+        BlockCategory::ChromaAc,
+        BlockCategory::Luma8x8,
+        BlockCategory::CbDc,
+        BlockCategory::CbAc,
+        BlockCategory::Cb4x4,
+        BlockCategory::Cb8x8,
+        BlockCategory::CrDc,
+        BlockCategory::CrAc,
+        BlockCategory::Cr4x4,
+        BlockCategory::Cr8x8,
+    ];
+
+    let mut rng = rand::thread_rng();
+
+    // contexts initialized the same way with some arbitrary (m, n) values
+    let init = H264Contexts::new(
+        |ctx_idx| ((ctx_idx % 41) as i32 - 20, (ctx_idx % 97) as i32 + 10),
+        26,
+    );
+
+    enum Element {
+        Residual(ResidualBlock, Vec<i32>),
+        Mvd(usize, u32, i32),
+    }
+
+    let mut elements = Vec::new();
+    for _ in 0..3000 {
+        if rng.gen_bool(0.3) {
+            let mvd = match rng.gen_range(0..3) {
+                0 => rng.gen_range(-10..=10),
+                1 => rng.gen_range(-2000..=2000),
+                _ => rng.gen_range(i32::MIN + 1..=i32::MAX),
+            };
+            elements.push(Element::Mvd(rng.gen_range(0..2), rng.gen_range(0..70), mvd));
+            continue;
+        }
+
+        let category = categories[rng.gen_range(0..categories.len())];
+        let num_coeff = match category {
+            BlockCategory::ChromaDc => [4, 8][rng.gen_range(0..2)],
+            BlockCategory::LumaAc
+            | BlockCategory::ChromaAc
+            | BlockCategory::CbAc
+            | BlockCategory::CrAc => 15,
+            BlockCategory::Luma8x8 | BlockCategory::Cb8x8 | BlockCategory::Cr8x8 => 64,
+            _ => 16,
+        };
+
+        let density = rng.gen_range(0.0..1.0);
+        let max_level = [1, 2, 15, 16, 1000, 1 << 20][rng.gen_range(0..6)];
+        let mut coefficients: Vec<i32> = (0..num_coeff)
+            .map(|_| {
+                if rng.gen_bool(density) {
+                    rng.gen_range(-max_level..=max_level)
+                } else {
+                    0
+                }
+            })
+            .collect();
+
+        let coded_block_flag_neighbours = if rng.gen_bool(0.2) {
+            None
+        } else {
+            Some((rng.gen(), rng.gen()))
+        };
+        if coded_block_flag_neighbours.is_none() && coefficients.iter().all(|&c| c == 0) {
+            coefficients[num_coeff - 1] = -1;
+        }
+
+        let block = ResidualBlock {
+            category,
+            field: rng.gen(),
+            coded_block_flag_neighbours,
+        };
+        elements.push(Element::Residual(block, coefficients));
+    }
+
+    let mut write_contexts = init.clone();
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new(&mut output);
+    for element in &elements {
+        match element {
+            Element::Residual(block, coefficients) => {
+                put_residual_block(&mut writer, &mut write_contexts, block, coefficients).unwrap()
+            }
+            &Element::Mvd(component, abs_mvd_sum, mvd) => put_mvd(
+                &mut writer,
+                &mut write_contexts,
+                component,
+                abs_mvd_sum,
+                mvd,
+            )
+            .unwrap(),
+        }
+    }
+    writer.finish().unwrap();
+
+    let mut read_contexts = init;
+    let mut reader = H265Reader::new(&output[..]).unwrap();
+    for element in &elements {
+        match element {
+            Element::Residual(block, coefficients) => {
+                let mut decoded = vec![0; coefficients.len()];
+                let coded =
+                    get_residual_block(&mut reader, &mut read_contexts, block, &mut decoded)
+                        .unwrap();
+                assert_eq!(coded, coefficients.iter().any(|&c| c != 0));
+                assert_eq!(&decoded, coefficients, "{block:?}");
+            }
+            &Element::Mvd(component, abs_mvd_sum, mvd) => {
+                assert_eq!(
+                    get_mvd(&mut reader, &mut read_contexts, component, abs_mvd_sum).unwrap(),
+                    mvd
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn mvd_binarization() {
+    /// records the bins as (value, bypass)
+    struct BinRecorder(Vec<(bool, bool)>);
+
+    impl CabacWriter<H265Context> for BinRecorder {
+        fn put_bypass(&mut self, bin_value: bool) -> Result<()> {
+            self.0.push((bin_value, true));
+            Ok(())
+        }
+
+        fn put(&mut self, value: bool, _cur_ctx: &mut H265Context) -> Result<()> {
+            self.0.push((value, false));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // (mvd, context coded prefix, bypass coded suffix and sign)
+    let cases: [(i32, &[bool], &[bool]); 4] = [
+        (0, &[false], &[]),
+        (-2, &[true, true, false], &[true]),
+        (-9, &[true; 9], &[false, false, false, false, true]),
+        // suffix is EG3 of 11: escape bit, then 3 in 4 bits
+        (
+            20,
+            &[true; 9],
+            &[true, false, false, false, true, true, false],
+        ),
+    ];
+
+    for (mvd, prefix, suffix) in cases {
+        let mut recorder = BinRecorder(Vec::new());
+        put_mvd(&mut recorder, &mut H264Contexts::default(), 0, 0, mvd).unwrap();
+
+        let expected: Vec<(bool, bool)> = prefix
+            .iter()
+            .map(|&b| (b, false))
+            .chain(suffix.iter().map(|&b| (b, true)))
+            .collect();
+        assert_eq!(recorder.0, expected, "{mvd}");
+    }
+}
@@ -18,9 +18,11 @@
  * You should have received a copy of the GNU Lesser General Public License
  * along with libde265.  If not, see <http://www.gnu.org/licenses/>.
  */
-use std::io::{Read, Result, Write};
+pub mod residual;
+pub mod syntax;
+pub mod wpp;
 
-use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::io::{Chain, Cursor, Error, ErrorKind, Read, Result, Write};
 
 use crate::traits::{CabacReader, CabacWriter};
 
@@ -42,6 +44,9 @@ const NEXT_STATE_LPS: [u8; 128] = [
     77, 76, 77, 126, 127,
 ];
 
+/// next context state indexed by [is_lps][uc_state]
+const NEXT_STATE: [[u8; 128]; 2] = [NEXT_STATE_MPS, NEXT_STATE_LPS];
+
 const LPST_TABLE: [[u8; 4]; 64] = [
     [128, 176, 208, 240],
     [128, 167, 197, 227],
@@ -109,10 +114,6 @@ const LPST_TABLE: [[u8; 4]; 64] = [
     [2, 2, 2, 2],
 ];
 
-const RENORM_TABLE: [u8; 32] = [
-    6, 5, 4, 4, 3, 3, 3, 3, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-];
-
 /// context that tracks the probability of the next most probable symbol (either 1 or 0). Uses 6 bits.
 #[derive(Default, Copy, Clone, Eq, PartialEq)]
 pub struct H265Context {
@@ -120,36 +121,60 @@ pub struct H265Context {
 }
 
 impl H265Context {
-    fn get_state(&self) -> u8 {
-        self.uc_state >> 1
-    }
+    /// initializes the context from an initValue of the spec tables and the slice QP
+    /// (9.3.2.2), which is how all the contexts are set up at the start of a slice.
+    pub fn from_init_value(init_value: u8, slice_qp: i32) -> Self {
+        let slope_idx = i32::from(init_value >> 4);
+        let offset_idx = i32::from(init_value & 15);
 
-    fn get_mps(&self) -> bool {
-        (self.uc_state & 1) == 1
-    }
-    fn update_lps(&mut self) {
-        self.uc_state = NEXT_STATE_LPS[usize::from(self.uc_state)];
+        Self::from_m_n(slope_idx * 5 - 45, (offset_idx << 3) - 16, slice_qp)
     }
-    fn update_mps(&mut self) {
-        self.uc_state = NEXT_STATE_MPS[usize::from(self.uc_state)];
+
+    /// initializes the context from the (m, n) pair used by the H.264 tables (9.3.1.1 in H.264),
+    /// which H.265 derives from the initValue.
+    pub fn from_m_n(m: i32, n: i32, slice_qp: i32) -> Self {
+        let pre_ctx_state = (((m * slice_qp.clamp(0, 51)) >> 4) + n).clamp(1, 126);
+
+        let (state, mps) = if pre_ctx_state <= 63 {
+            (63 - pre_ctx_state, 0)
+        } else {
+            (pre_ctx_state - 64, 1)
+        };
+
+        H265Context {
+            uc_state: ((state << 1) | mps) as u8,
+        }
     }
 }
 
+/// `H265Writer` writes out its buffer once it has grown to this many bytes
+const WRITE_THRESHOLD: usize = 4096;
+
 /// CABAC encoder from H264/H265
+///
+/// low is kept in a 64-bit register and 32 bits at a time are moved from it into an internal
+/// buffer. A carry out of low is added to the buffered bytes, so the bytes are only handed to
+/// the underlying writer once no carry can reach them anymore (everything before the last byte
+/// that isn't 0xff), and all of them after a terminate bin of 1 or `finish`.
 pub struct H265Writer<W> {
     writer: W,
-    low: u32,
+    low: u64,
     range: u32,
-    buffered_byte: u32,
-    num_buffered_bytes: i32,
+    /// number of bits in low that are still free, the bits below them are pending output
     bits_left: i32,
+    /// bytes that have been moved out of low but not written yet
+    buffer: Vec<u8>,
+    bin_count: u64,
+    conformant: bool,
 }
 
 impl<W: Write> CabacWriter<H265Context> for H265Writer<W> {
+    #[inline(always)]
     fn put_bypass(&mut self, value: bool) -> Result<()> {
+        self.bin_count += 1;
         self.low <<= 1;
         if value {
-            self.low += self.range;
+            self.low += u64::from(self.range);
         }
 
         self.bits_left -= 1;
@@ -161,32 +186,26 @@ impl<W: Write> CabacWriter<H265Context> for H265Writer<W> {
         Ok(())
     }
 
+    #[inline(always)]
     fn put(&mut self, value: bool, cur_ctx: &mut H265Context) -> Result<()> {
-        let lps = LPST_TABLE[usize::from(cur_ctx.get_state())][((self.range >> 6) & 3) as usize];
-
-        self.range -= u32::from(lps);
-
-        if value != cur_ctx.get_mps() {
-            let num_bits = RENORM_TABLE[usize::from(lps >> 3)];
-            self.low = (self.low + self.range) << num_bits;
-            self.range = u32::from(lps) << num_bits;
-
-            cur_ctx.update_lps();
-
-            self.bits_left -= i32::from(num_bits);
-        } else {
-            cur_ctx.update_mps();
+        self.bin_count += 1;
+        let state = usize::from(cur_ctx.uc_state);
+        let lps = u32::from(LPST_TABLE[state >> 1][((self.range >> 6) & 3) as usize]);
+        let range_mps = self.range - lps;
 
-            // renorm
+        // select between the MPS and LPS path without branches, like H265Reader::get
+        let is_lps = value != (state & 1 != 0);
+        let lps_mask = u32::from(is_lps).wrapping_neg();
+        self.low += u64::from(range_mps & lps_mask);
+        let range = range_mps ^ ((lps ^ range_mps) & lps_mask);
 
-            if self.range >= 256 {
-                return Ok(());
-            }
+        cur_ctx.uc_state = NEXT_STATE[usize::from(is_lps)][state];
 
-            self.low <<= 1;
-            self.range <<= 1;
-            self.bits_left -= 1;
-        }
+        // renormalize so that the range has 9 bits again
+        let shift = range.leading_zeros() - 23;
+        self.range = range << shift;
+        self.low <<= shift;
+        self.bits_left -= shift as i32;
 
         if self.bits_left < 12 {
             self.flush_completed()?;
@@ -195,44 +214,30 @@ impl<W: Write> CabacWriter<H265Context> for H265Writer<W> {
     }
 
     fn finish(&mut self) -> Result<()> {
-        assert!(self.bits_left <= 32);
-
-        if (self.low >> (32 - self.bits_left)) != 0 {
-            self.writer.write_u8((self.buffered_byte + 1) as u8)?;
-            while self.num_buffered_bytes > 1 {
-                self.writer.write_u8(0x00)?;
-                self.num_buffered_bytes -= 1;
-            }
-
-            self.low -= 1 << (32 - self.bits_left);
-        } else {
-            if self.num_buffered_bytes > 0 {
-                self.writer.write_u8(self.buffered_byte as u8)?;
-            }
-
-            while self.num_buffered_bytes > 1 {
-                self.writer.write_u8(0xff)?;
-                self.num_buffered_bytes -= 1;
-            }
+        if self.conformant {
+            // end_of_slice_segment_flag, the flush writes the rbsp_stop_one_bit and alignment
+            return self.put_terminate(true);
         }
 
+        self.resolve_carry();
+
         // libde256 skips the last 8 bits, but this causes the last read to fail in some cases. It does
         // append a 1 bit to the end of the stream, but that still leaves some cases where the last
         // symbol will fail to decode properly.
-        let mut bits = 32 - self.bits_left;
+        let mut bits = 64 - self.bits_left;
 
         let data = self.low;
 
         while bits >= 8 {
-            self.writer.write_u8((data >> (bits - 8)) as u8)?;
+            self.buffer.push((data >> (bits - 8)) as u8);
             bits -= 8;
         }
 
         if bits > 0 {
-            self.writer.write_u8((data << (8 - bits)) as u8)?;
+            self.buffer.push((data << (8 - bits)) as u8);
         }
 
-        Ok(())
+        self.write_buffer()
     }
 }
 
@@ -242,144 +247,1038 @@ impl<W: Write> H265Writer<W> {
             writer,
             low: 0,
             range: 510,
-            bits_left: 23,
-            num_buffered_bytes: 0,
-            buffered_byte: 0xff,
+            bits_left: 55,
+            buffer: Vec::new(),
+            bin_count: 0,
+            conformant: false,
+        }
+    }
+
+    /// creates a writer whose output is spec conformant slice segment data. Instead of
+    /// writing out all the remaining bits, `finish` codes `end_of_slice_segment_flag` as 1,
+    /// does the EncodeFlush procedure and then writes `rbsp_slice_segment_trailing_bits`
+    /// (without any cabac_zero_words, see `put_cabac_zero_words`).
+    ///
+    /// The output can still be decoded by `H265Reader`, the final terminate bin can be
+    /// read with `get_terminate`.
+    pub fn new_conformant(writer: W) -> Self {
+        H265Writer {
+            conformant: true,
+            ..Self::new(writer)
+        }
+    }
+
+    /// number of bins that have been coded so far, including bypass and terminate bins. This
+    /// is needed to calculate the number of cabac_zero_words for the picture.
+    pub fn bin_count(&self) -> u64 {
+        self.bin_count
+    }
+
+    /// appends cabac_zero_words (0x0000) after the slice segment data. This must only be done
+    /// after the slice data has been terminated, and the emulation prevention that is applied
+    /// to the NAL unit will turn each word into 0x000003.
+    pub fn put_cabac_zero_words(&mut self, count: usize) -> Result<()> {
+        for _ in 0..count {
+            self.writer.write_all(&[0, 0])?;
+        }
+
+        Ok(())
+    }
+
+    /// returns the underlying writer, for example to write the PCM samples that follow a
+    /// `pcm_flag` of 1. Only valid directly after `put_terminate(true)`, since otherwise
+    /// there are still bits pending in the encoder.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// write a terminate bin, which is used for `end_of_slice_segment_flag`,
+    /// `end_of_subset_one_bit` and `pcm_flag`.
+    ///
+    /// If the bin is 1, the arithmetic codeword is flushed as described by EncodeFlush in the
+    /// spec, followed by the stop bit and zero bits up to the next byte boundary. The encoder
+    /// is then re-initialized so that a new codeword can be started (after the PCM samples or at
+    /// the start of the next substream). `finish` should not be called after a terminating 1 bin
+    /// unless more bins have been written since.
+    pub fn put_terminate(&mut self, bit: bool) -> Result<()> {
+        self.bin_count += 1;
+        self.range -= 2;
+
+        if bit {
+            self.low += u64::from(self.range);
+            self.low <<= 7;
+            self.range = 2 << 7;
+            self.bits_left -= 7;
+        } else if self.range >= 256 {
+            return Ok(());
+        } else {
+            self.low <<= 1;
+            self.range <<= 1;
+            self.bits_left -= 1;
+        }
+
+        if self.bits_left < 12 {
+            self.flush_completed()?;
+        }
+
+        if bit {
+            self.flush_terminated()?;
+        }
+
+        Ok(())
+    }
+
+    /// aligns the bypass bins as done when `cabac_bypass_alignment_enabled_flag` is set in the
+    /// high throughput profiles of the range extensions. The range is set to 256 before the
+    /// bypass bins of coeff_abs_level_remaining and the sign flags, so that every bypass bin
+    /// that follows maps to exactly one bit of the offset. The decoder has to call
+    /// `H265Reader::align_bypass` at the same point.
+    pub fn align_bypass(&mut self) {
+        self.range = 256;
+    }
+
+    /// writes the lower `num_bits` bits of value as bypass bins, MSB first, after the bypass
+    /// bins have been aligned. Up to 8 bins are added to low at once.
+    pub fn put_aligned_bypass_bits(&mut self, value: u32, num_bits: u32) -> Result<()> {
+        assert_eq!(self.range, 256, "bypass bins not aligned");
+        assert!(num_bits <= 32);
+
+        self.bin_count += u64::from(num_bits);
+
+        let mut remaining = num_bits;
+        while remaining > 0 {
+            let n = remaining.min(8);
+            remaining -= n;
+
+            let bins = (value >> remaining) & ((1 << n) - 1);
+            self.low = (self.low << n) + (u64::from(bins) << 8);
+            self.bits_left -= n as i32;
+
+            if self.bits_left < 12 {
+                self.flush_completed()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// adds a carry to the buffered bytes. The carry stops at the last byte that isn't 0xff,
+    /// which is never written out before the end of the codeword.
+    fn propagate_carry(&mut self) {
+        for byte in self.buffer.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                return;
+            }
+        }
+
+        unreachable!("carry out of the first byte of the codeword");
+    }
+
+    /// moves the carry out of the pending bits of low into the buffered bytes
+    fn resolve_carry(&mut self) {
+        let carry_bit = 64 - self.bits_left;
+
+        if (self.low >> carry_bit) != 0 {
+            self.propagate_carry();
+            self.low -= 1 << carry_bit;
         }
     }
 
+    /// writes out all buffered bytes, which must no longer be able to receive a carry
+    fn write_buffer(&mut self) -> Result<()> {
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+
+        Ok(())
+    }
+
+    /// flushes the codeword after a terminate bin of 1. Everything except the last 8 bits of low
+    /// is written, followed by a 1 bit (the rbsp_stop_one_bit or alignment_bit_equal_to_one) and
+    /// zero bits until the output is byte aligned. This is what the decoder expects, since the
+    /// last bit that it reads into its offset register is the stop bit.
+    fn flush_terminated(&mut self) -> Result<()> {
+        self.resolve_carry();
+
+        let num_bits = 56 - self.bits_left + 1;
+        let padding = (8 - num_bits % 8) % 8;
+        let data = (((self.low >> 8) << 1) | 1) << padding;
+
+        let mut bits = num_bits + padding;
+        while bits > 0 {
+            self.buffer.push((data >> (bits - 8)) as u8);
+            bits -= 8;
+        }
+
+        self.write_buffer()?;
+
+        self.low = 0;
+        self.range = 510;
+        self.bits_left = 55;
+
+        Ok(())
+    }
+
+    /// moves the top 32 of the pending bits of low into the buffer, together with the carry
+    /// out of them
+    #[cold]
     fn flush_completed(&mut self) -> Result<()> {
-        let lead_byte = self.low >> (24 - self.bits_left);
-        self.bits_left += 8;
-        self.low &= 0xffffffff >> self.bits_left;
-
-        if lead_byte == 0xff {
-            self.num_buffered_bytes += 1;
-        } else if self.num_buffered_bytes > 0 {
-            let carry = lead_byte >> 8;
-            let mut byte = self.buffered_byte + carry;
-            self.buffered_byte = lead_byte & 0xff;
-
-            self.writer.write_u8(byte as u8)?;
-
-            byte = (0xff + carry) & 0xff;
-            while self.num_buffered_bytes > 1 {
-                self.writer.write_u8(byte as u8)?;
-                self.num_buffered_bytes -= 1;
+        let word = self.low >> (32 - self.bits_left);
+        self.bits_left += 32;
+        self.low &= u64::MAX >> self.bits_left;
+
+        if word >> 32 != 0 {
+            self.propagate_carry();
+        }
+
+        self.buffer.extend_from_slice(&(word as u32).to_be_bytes());
+
+        if self.buffer.len() >= WRITE_THRESHOLD {
+            // a carry can only reach the bytes from the last one that isn't 0xff onwards
+            if let Some(settled) = self.buffer.iter().rposition(|&b| b != 0xff) {
+                self.writer.write_all(&self.buffer[..settled])?;
+                self.buffer.copy_within(settled.., 0);
+                self.buffer.truncate(self.buffer.len() - settled);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// returns the number of cabac_zero_words that need to be appended to the last slice segment of a
+/// picture so that the bin count stays within the limit given by the spec:
+///
+/// BinCountsInNalUnits <= (32 ÷ 3) * NumBytesInVclNalUnits + (RawMinCuBits * PicSizeInMinCbsY) ÷ 32
+///
+/// `num_bytes_in_vcl_nal_units` is the size of all the VCL NAL units of the picture including the
+/// emulation prevention bytes. Each cabac_zero_word adds 3 bytes, since it becomes 0x000003 after
+/// emulation prevention.
+pub fn cabac_zero_words_needed(
+    bin_counts_in_nal_units: u64,
+    num_bytes_in_vcl_nal_units: u64,
+    raw_min_cu_bits: u64,
+    pic_size_in_min_cbs: u64,
+) -> u64 {
+    // multiply everything by 3 * 32 to avoid rounding
+    let bins = 3 * 32 * bin_counts_in_nal_units;
+    let raw = 3 * raw_min_cu_bits * pic_size_in_min_cbs;
+    let per_byte = 32 * 32;
+
+    let allowed = per_byte * num_bytes_in_vcl_nal_units + raw;
+    if bins <= allowed {
+        return 0;
+    }
+
+    let missing_bytes = (bins - allowed).div_ceil(per_byte);
+    missing_bytes.div_ceil(3)
+}
+
+/// position of the 9 bit offset in the value register of `H265Reader`. The top bit is kept
+/// free so that the offset can be doubled by a bypass bin before it is compared to the range.
+const OFFSET_SHIFT: u32 = 54;
+
+/// `H265Reader` refills its value register once the marker bit that follows the bits of the
+/// stream reaches the upper half, which leaves room for the largest renormalization and the
+/// 32 bits of a refill. The check is then a test of the lower half of the register rather
+/// than a separate count of the bits.
+const REFILL_MASK: u64 = u32::MAX as u64;
+
+/// size of the buffer that `H265Reader` reads into, so that the underlying reader is only called
+/// once for several refills
+const READ_AHEAD: usize = 64;
+
+/// the reader of `H265Reader` together with the bytes that were read ahead from it, which are
+/// the ones from pos to len. This is kept in a box so that the cold path that reads more can
+/// borrow it without the value register of `H265Reader` having to be kept in memory.
+struct ReadAhead<R> {
+    reader: R,
+    buffer: [u8; READ_AHEAD],
+    pos: usize,
+    len: usize,
+}
+
+impl<R: Read> ReadAhead<R> {
+    /// moves the unread bytes to the front and reads from the reader until there are at least
+    /// 4 bytes or the stream has ended
+    #[cold]
+    fn fill(&mut self) -> Result<()> {
+        self.buffer.copy_within(self.pos..self.len, 0);
+        self.len -= self.pos;
+        self.pos = 0;
+
+        while self.len < 4 {
+            match self.reader.read(&mut self.buffer[self.len..]) {
+                Ok(0) => break,
+                Ok(n) => self.len += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
             }
-        } else {
-            self.num_buffered_bytes = 1;
-            self.buffered_byte = lead_byte;
         }
         Ok(())
     }
+
+    /// takes the last bytes of the stream, which are less than a word, padded with zeros
+    #[cold]
+    fn take_tail(&mut self) -> (u32, usize) {
+        let len = self.len - self.pos;
+        let mut word = [0u8; 4];
+        word[..len].copy_from_slice(&self.buffer[self.pos..self.len]);
+        self.pos = self.len;
+
+        (u32::from_be_bytes(word), len)
+    }
 }
 
 /// CABAC decoder from H265/H265
+///
+/// The offset is kept at the top of a 64-bit value register together with the bits that follow
+/// it in the stream, which is refilled 32 bits at a time from a read ahead buffer. Because of
+/// this the decoder reads up to `READ_AHEAD` + 4 bytes ahead of the current position, so the
+/// data that follows a terminated codeword has to be read through `read_bytes` or `into_inner`
+/// rather than from the underlying reader.
 pub struct H265Reader<R> {
-    reader: R,
-    value: u32,
+    source: Box<ReadAhead<R>>,
+    /// offset in bits 62 to 54, followed by the next bits of the stream and a marker bit set
+    /// after the last of them, like the low register of ffmpeg's CABAC decoder
+    value: u64,
     range: u32,
-    bits_needed: i32,
+    /// the range at the position of the offset, negated, so that the bypass bins can subtract
+    /// it in the same instruction as the shift
+    neg_scaled_range: u64,
+    /// true once the end of the stream was reached, after which every refill checks that the
+    /// offset didn't run past it
+    ended: bool,
+    /// true after a terminate bin of 1, until the codeword has been skipped
+    terminated: bool,
 }
 
 impl<R: Read> CabacReader<H265Context> for H265Reader<R> {
+    #[inline(always)]
     fn get_bypass(&mut self) -> Result<bool> {
-        self.value <<= 1;
-        self.bits_needed += 1;
+        let scaled_range = u64::from(self.range) << OFFSET_SHIFT;
+
+        // compare before shifting, so that the comparison and both candidates for the new
+        // value don't depend on each other and the new value is a single select
+        let bit = self.value >= scaled_range >> 1;
+        let shifted = self.value.rotate_left(1);
+        let reduced = (self.value << 1).wrapping_add(self.neg_scaled_range);
+        self.value = if bit { reduced } else { shifted };
+
+        if self.value & REFILL_MASK == 0 {
+            self.refill()?;
+        }
+
+        Ok(bit)
+    }
+
+    #[inline(always)]
+    fn get(&mut self, cur_ctx: &mut H265Context) -> Result<bool> {
+        let state = usize::from(cur_ctx.uc_state);
+        let lps = u32::from(LPST_TABLE[state >> 1][((self.range >> 6) & 3) as usize]);
+
+        let range_mps = self.range - lps;
+        let scaled_range = u64::from(range_mps) << OFFSET_SHIFT;
+
+        // select between the MPS and LPS path with masks instead of branches, since the outcome
+        // is hard to predict
+        let is_lps = self.value >= scaled_range;
+        let lps_mask = u64::from(is_lps).wrapping_neg();
+        self.value -= scaled_range & lps_mask;
+        let range = range_mps ^ ((lps ^ range_mps) & lps_mask as u32);
+
+        cur_ctx.uc_state = NEXT_STATE[usize::from(is_lps)][state];
+        let bit = (state & 1 != 0) ^ is_lps;
+
+        // renormalize so that the range has 9 bits again
+        let shift = range.leading_zeros() - 23;
+        self.set_range(range << shift);
+        self.value <<= shift;
+
+        if self.value & REFILL_MASK == 0 {
+            self.refill()?;
+        }
+
+        Ok(bit)
+    }
+}
+
+impl<R: Read> H265Reader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut r = H265Reader {
+            source: Box::new(ReadAhead {
+                reader,
+                buffer: [0; READ_AHEAD],
+                pos: 0,
+                len: 0,
+            }),
+            value: 1 << 62,
+            range: 510,
+            neg_scaled_range: 0,
+            ended: false,
+            terminated: false,
+        };
+
+        r.reset()?;
+
+        Ok(r)
+    }
+
+    /// appends the next 32 bits of the stream to the value register. At the end of the stream
+    /// zero bits are appended instead, and an error is returned once they reach the offset.
+    #[inline(always)]
+    fn refill(&mut self) -> Result<()> {
+        if !self.ended {
+            let source = &mut *self.source;
+            if source.len - source.pos < 4 {
+                source.fill()?;
+            }
+
+            let pos = source.pos;
+            if source.len - pos >= 4 {
+                let word = u32::from_be_bytes(source.buffer[pos..pos + 4].try_into().unwrap());
+                source.pos += 4;
+
+                let marker = self.value.trailing_zeros();
+                self.value ^= 1 << marker;
+                self.value |= ((u64::from(word) << 1) | 1) << (marker - 32);
+                return Ok(());
+            }
+
+            // the stream ends within the word
+            let (word, len) = source.take_tail();
+            let marker = self.value.trailing_zeros();
+            self.value ^= 1 << marker;
+            self.value |= (u64::from(word) << (marker - 31)) | (1 << (marker - 8 * len as u32));
+            self.ended = true;
+        }
+
+        self.check_end_of_data()
+    }
+
+    #[inline(always)]
+    fn set_range(&mut self, range: u32) {
+        self.range = range;
+        self.neg_scaled_range = (u64::from(range) << OFFSET_SHIFT).wrapping_neg();
+    }
+
+    /// number of bits of the stream in value starting at the top of the offset, which is the
+    /// position of the marker bit
+    fn bits(&self) -> i32 {
+        62 - self.value.trailing_zeros() as i32
+    }
+
+    #[cold]
+    fn check_end_of_data(&self) -> Result<()> {
+        if self.bits() < 9 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "CABAC data ended unexpectedly",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// after a terminate bin of 1, drops the offset and the alignment bits that follow the
+    /// stop bit, so that the next bit in the value register is at the start of a byte.
+    fn skip_terminated_codeword(&mut self) {
+        if self.terminated {
+            let skip = 9 + (self.bits() - 9) % 8;
+            self.value = (self.value << skip) & (u64::MAX >> 1);
+            self.terminated = false;
+        }
+    }
+
+    /// returns the underlying reader. Since the decoder reads ahead, the reader is positioned
+    /// up to `READ_AHEAD` + 4 bytes after the bits that have been decoded so far.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source.reader
+    }
+
+    /// reads raw bytes (for example the PCM samples that follow a `pcm_flag` of 1) from the
+    /// position directly after the terminated codeword. Only valid after `get_terminate`
+    /// returned true, and `reset` has to be called afterwards to start the next codeword.
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<()> {
+        assert!(
+            self.terminated || self.range == 0,
+            "codeword not terminated"
+        );
+        self.skip_terminated_codeword();
+        // mark the decoder as unusable until reset is called
+        self.set_range(0);
+
+        let mut pos = 0;
+        while pos < buf.len() && self.bits() >= 8 {
+            buf[pos] = (self.value >> 55) as u8;
+            self.value = (self.value << 8) & (u64::MAX >> 1);
+            pos += 1;
+        }
+
+        let source = &mut *self.source;
+        let buffered = (source.len - source.pos).min(buf.len() - pos);
+        buf[pos..pos + buffered].copy_from_slice(&source.buffer[source.pos..source.pos + buffered]);
+        source.pos += buffered;
+        pos += buffered;
+
+        source.reader.read_exact(&mut buf[pos..])
+    }
+
+    /// returns a reader that starts directly after the terminated codeword, made up of the
+    /// bytes that the decoder had already read ahead followed by the underlying reader. This is
+    /// used to hand the stream over to a different decoder after `get_terminate` returned true.
+    pub fn into_inner(mut self) -> Chain<Cursor<Vec<u8>>, R> {
+        assert!(self.terminated, "codeword not terminated");
+        self.skip_terminated_codeword();
+
+        let mut prefetched = Vec::new();
+        while self.bits() >= 8 {
+            prefetched.push((self.value >> 55) as u8);
+            self.value <<= 8;
+        }
+        let source = *self.source;
+        prefetched.extend_from_slice(&source.buffer[source.pos..source.len]);
+
+        Cursor::new(prefetched).chain(source.reader)
+    }
+
+    /// re-initializes the decoder from the position after the terminated codeword (or the PCM
+    /// samples read with `read_bytes`). This is used to start decoding the next codeword after
+    /// a terminate bin of 1.
+    pub fn reset(&mut self) -> Result<()> {
+        self.skip_terminated_codeword();
+        self.set_range(510);
+
+        if self.value & REFILL_MASK == 0 {
+            self.refill()?;
+        }
+
+        Ok(())
+    }
+
+    /// reads a terminate bin, which is used for `end_of_slice_segment_flag`,
+    /// `end_of_subset_one_bit` and `pcm_flag`.
+    ///
+    /// If the bin is 1, the codeword has ended. The stop bit was the last bit read into the
+    /// offset register and the rest of the last byte is alignment, so `reset` has to be called
+    /// before any further bins can be read.
+    pub fn get_terminate(&mut self) -> Result<bool> {
+        self.set_range(self.range - 2);
+
+        let scaled_range = u64::from(self.range) << OFFSET_SHIFT;
+
+        if self.value >= scaled_range {
+            self.terminated = true;
+            return Ok(true);
+        }
 
-        if self.bits_needed >= 0 {
-            self.bits_needed = -8;
-            self.value |= u32::from(self.reader.read_u8()?);
+        if self.range < 256 {
+            self.set_range(self.range << 1);
+            self.value <<= 1;
+
+            if self.value & REFILL_MASK == 0 {
+                self.refill()?;
+            }
         }
 
-        let scaled_range = self.range << 7;
+        Ok(false)
+    }
+
+    /// aligns the bypass bins, see `H265Writer::align_bypass`
+    pub fn align_bypass(&mut self) {
+        self.set_range(256);
+    }
+
+    /// reads `num_bits` bypass bins after the bypass bins have been aligned, returning them
+    /// MSB first. Since the range is 256, the bins are the top bits of the offset and up to 16
+    /// of them can be taken from the value register at once.
+    pub fn get_aligned_bypass_bits(&mut self, num_bits: u32) -> Result<u32> {
+        assert_eq!(self.range, 256, "bypass bins not aligned");
+        assert!(num_bits <= 32);
+
+        let mut bins = 0;
+        let mut remaining = num_bits;
+        while remaining > 0 {
+            let n = remaining.min(16);
+            remaining -= n;
+
+            bins = (bins << n) | ((self.value >> (OFFSET_SHIFT + 8 - n)) as u32 & ((1 << n) - 1));
+            self.value = (self.value << n) & ((1 << (OFFSET_SHIFT + 8)) - 1);
+
+            if self.value & REFILL_MASK == 0 {
+                self.refill()?;
+            }
+        }
+
+        Ok(bins)
+    }
+}
+
+/// Literal implementation of the arithmetic encoder as described in the spec (9.3.4.3), with
+/// PutBit, bitsOutstanding and EncodeFlush. Used to verify that the conformant output is bit exact.
+#[cfg(test)]
+struct SpecEncoder {
+    low: u32,
+    range: u32,
+    first_bit_flag: bool,
+    bits_outstanding: u32,
+    bits: Vec<bool>,
+}
+
+#[cfg(test)]
+impl SpecEncoder {
+    const TRANS_IDX_LPS: [u8; 64] = [
+        0, 0, 1, 2, 2, 4, 4, 5, 6, 7, 8, 9, 9, 11, 11, 12, 13, 13, 15, 15, 16, 16, 18, 18, 19, 19,
+        21, 21, 22, 22, 23, 24, 24, 25, 26, 26, 27, 27, 28, 29, 29, 30, 30, 30, 31, 32, 32, 33, 33,
+        33, 34, 34, 35, 35, 35, 36, 36, 36, 37, 37, 37, 38, 38, 63,
+    ];
 
-        let r = self.value.overflowing_sub(scaled_range);
+    fn new() -> Self {
+        SpecEncoder {
+            low: 0,
+            range: 510,
+            first_bit_flag: true,
+            bits_outstanding: 0,
+            bits: Vec::new(),
+        }
+    }
 
-        if r.1 {
-            Ok(false)
+    fn put_bit(&mut self, b: bool) {
+        if self.first_bit_flag {
+            self.first_bit_flag = false;
         } else {
-            self.value = r.0;
-            Ok(true)
+            self.bits.push(b);
+        }
+
+        while self.bits_outstanding > 0 {
+            self.bits.push(!b);
+            self.bits_outstanding -= 1;
         }
     }
 
-    fn get(&mut self, cur_ctx: &mut H265Context) -> Result<bool> {
-        let mut range = self.range;
-        let mut value = self.value;
+    fn renorm(&mut self) {
+        while self.range < 256 {
+            if self.low < 256 {
+                self.put_bit(false);
+            } else if self.low >= 512 {
+                self.low -= 512;
+                self.put_bit(true);
+            } else {
+                self.low -= 256;
+                self.bits_outstanding += 1;
+            }
+            self.range <<= 1;
+            self.low <<= 1;
+        }
+    }
+
+    /// context is (pStateIdx, valMps)
+    fn decision(&mut self, bin: bool, ctx: &mut (u8, bool)) {
+        let q_range_idx = (self.range >> 6) & 3;
+        let lps = u32::from(LPST_TABLE[usize::from(ctx.0)][q_range_idx as usize]);
+        self.range -= lps;
+
+        if bin != ctx.1 {
+            self.low += self.range;
+            self.range = lps;
+            if ctx.0 == 0 {
+                ctx.1 = !ctx.1;
+            }
+            ctx.0 = Self::TRANS_IDX_LPS[usize::from(ctx.0)];
+        } else {
+            ctx.0 = (ctx.0 + 1).min(62);
+        }
 
-        let lps = LPST_TABLE[usize::from(cur_ctx.get_state())][((range >> 6) & 3) as usize];
+        self.renorm();
+    }
 
-        range -= u32::from(lps);
+    fn bypass(&mut self, bin: bool) {
+        self.low <<= 1;
+        if bin {
+            self.low += self.range;
+        }
 
-        let scaled_range = range << 7;
+        if self.low >= 1024 {
+            self.put_bit(true);
+            self.low -= 1024;
+        } else if self.low < 512 {
+            self.put_bit(false);
+        } else {
+            self.low -= 512;
+            self.bits_outstanding += 1;
+        }
+    }
 
-        let bit;
+    fn terminate(&mut self, bin: bool) {
+        self.range -= 2;
 
-        let r = value.overflowing_sub(scaled_range);
+        if bin {
+            self.low += self.range;
 
-        if r.1 {
-            // MPS path
+            // EncodeFlush
+            self.range = 2;
+            self.renorm();
+            self.put_bit(((self.low >> 9) & 1) != 0);
+            self.bits.push(((self.low >> 8) & 1) != 0);
+            self.bits.push(true);
+        } else {
+            self.renorm();
+        }
+    }
 
-            bit = cur_ctx.get_mps();
+    /// returns the bits followed by alignment zero bits as bytes
+    fn to_bytes(&self) -> Vec<u8> {
+        self.bits
+            .chunks(8)
+            .map(|c| {
+                c.iter()
+                    .enumerate()
+                    .fold(0u8, |acc, (i, &b)| acc | (u8::from(b) << (7 - i)))
+            })
+            .collect()
+    }
+}
 
-            cur_ctx.update_mps();
+#[test]
+fn conformant_matches_spec_encoder() {
+    use rand::Rng;
 
-            if scaled_range < (256 << 7) {
-                // scaled range, highest bit (15) not set
+    let mut rng = rand::thread_rng();
 
-                range = scaled_range >> 6; // shift range by one bit
-                value <<= 1; // shift value by one bit
-                self.bits_needed += 1;
+    for _ in 0..200 {
+        let mut spec = SpecEncoder::new();
+        let mut spec_contexts = [(0u8, false); 8];
 
-                if self.bits_needed == 0 {
-                    self.bits_needed = -8;
-                    value |= u32::from(self.reader.read_u8()?);
+        let mut output = Vec::new();
+        let mut writer = H265Writer::new_conformant(&mut output);
+        let mut contexts = [H265Context::default(); 8];
+
+        let len = rng.gen_range(0..3000);
+        for i in 0..len {
+            let ctx = rng.gen_range(0..8);
+            match rng.gen_range(0..10) {
+                0..=5 => {
+                    let bin = rng.gen_bool(0.02 + ctx as f64 / 8.0);
+                    spec.decision(bin, &mut spec_contexts[ctx]);
+                    writer.put(bin, &mut contexts[ctx]).unwrap();
+                }
+                6..=8 => {
+                    let bin = rng.gen();
+                    spec.bypass(bin);
+                    writer.put_bypass(bin).unwrap();
+                }
+                _ => {
+                    // end_of_subset_one_bit somewhere in the middle of the stream
+                    let bin = i % 97 == 0;
+                    spec.terminate(bin);
+                    writer.put_terminate(bin).unwrap();
+                    if bin {
+                        spec.bits.resize((spec.bits.len() + 7) & !7, false);
+                        spec.low = 0;
+                        spec.range = 510;
+                        spec.first_bit_flag = true;
+                    }
                 }
             }
+        }
+
+        spec.terminate(true);
+        writer.finish().unwrap();
+
+        assert_eq!(writer.bin_count(), len as u64 + 1);
+        assert_eq!(spec.to_bytes(), output);
+    }
+}
+
+#[test]
+fn long_codeword_matches_spec_encoder() {
+    use rand::Rng;
+
+    // a single codeword that is several times WRITE_THRESHOLD long, so that the buffered
+    // bytes are written out while carries can still arrive
+    let mut rng = rand::thread_rng();
+
+    let mut spec = SpecEncoder::new();
+    let mut spec_context = (0u8, false);
+
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new_conformant(&mut output);
+    let mut context = H265Context::default();
+
+    for _ in 0..200_000 {
+        let bin = rng.gen_bool(0.3);
+        if rng.gen_bool(0.5) {
+            spec.decision(bin, &mut spec_context);
+            writer.put(bin, &mut context).unwrap();
         } else {
-            // LPS path
+            spec.bypass(bin);
+            writer.put_bypass(bin).unwrap();
+        }
+    }
+
+    spec.terminate(true);
+    writer.finish().unwrap();
+
+    assert!(output.len() > 4 * WRITE_THRESHOLD);
+    assert_eq!(spec.to_bytes(), output);
+}
+
+/// slice data written by the CABAC engine of x265 (libx265 3.5-2+b1 from Debian) for the bins
+/// of `x265_reference_bins(3000, 0x2545f491)`, generated with `tests/data/x265_cabac.c 3000
+/// 0x2545f491`. The engine of x265 is the one of the HM reference encoder.
+#[cfg(test)]
+const X265_SLICE_DATA: [u8; 335] = [
+    0xe2, 0x36, 0xca, 0x9e, 0x0f, 0x04, 0x47, 0x8f, 0x29, 0xf5, 0x68, 0x38, 0x29, 0x2a, 0x30, 0x4c,
+    0x38, 0xc6, 0x40, 0xfe, 0xe0, 0xb8, 0xd4, 0x8a, 0x30, 0x3f, 0x02, 0xd4, 0x20, 0xfb, 0xa5, 0x5b,
+    0xfc, 0x07, 0x3e, 0x12, 0x90, 0x27, 0x01, 0x74, 0xfd, 0x03, 0x3a, 0xd0, 0xc2, 0x2e, 0x91, 0x30,
+    0x80, 0x24, 0xfd, 0x26, 0xb8, 0x30, 0x17, 0x04, 0x08, 0x0a, 0x8b, 0xd4, 0xba, 0xf6, 0xad, 0x3f,
+    0xec, 0xf5, 0xd0, 0xad, 0x94, 0x33, 0x31, 0x83, 0x98, 0xbc, 0x6d, 0xe4, 0xf5, 0x77, 0x57, 0xce,
+    0xe5, 0xfb, 0x5d, 0x48, 0x2c, 0x35, 0x99, 0x03, 0x67, 0x1a, 0x30, 0xa7, 0x5b, 0x3c, 0x08, 0xdf,
+    0xaa, 0x96, 0x0f, 0xac, 0x8e, 0x14, 0x16, 0xa9, 0x68, 0x0d, 0x83, 0xe8, 0x46, 0x67, 0x28, 0xbe,
+    0xef, 0xb1, 0xc1, 0x79, 0xd6, 0xb9, 0x40, 0x49, 0xc9, 0xc1, 0x17, 0x24, 0x2c, 0xf2, 0x48, 0xa2,
+    0x9f, 0xa4, 0x75, 0x8b, 0xa8, 0x34, 0x1e, 0xd2, 0xd2, 0xef, 0x69, 0x24, 0xd9, 0x20, 0x6d, 0xff,
+    0x31, 0x5f, 0x55, 0xfc, 0x7f, 0x09, 0xc0, 0xfe, 0x80, 0xcb, 0x17, 0x58, 0xa1, 0xf4, 0xf9, 0x74,
+    0x3b, 0x05, 0x14, 0x58, 0x6d, 0x8a, 0x07, 0x32, 0x05, 0x1e, 0x90, 0x58, 0xea, 0x76, 0xd0, 0xba,
+    0x50, 0x32, 0xfd, 0xe2, 0x80, 0x0f, 0x56, 0xd7, 0x32, 0xdd, 0x92, 0x5b, 0xbe, 0x65, 0xc0, 0x5f,
+    0x18, 0xb0, 0x22, 0xbd, 0xa8, 0xf9, 0xf3, 0x1a, 0x1f, 0x39, 0xc5, 0x6f, 0xb8, 0x0b, 0xc3, 0x41,
+    0x25, 0x1a, 0xdd, 0x9b, 0x7b, 0xe9, 0x49, 0x68, 0x69, 0x87, 0x77, 0xed, 0xb8, 0x39, 0x1f, 0xc8,
+    0xa0, 0x38, 0x60, 0xd7, 0x81, 0xfe, 0x1d, 0x4a, 0x1b, 0xdf, 0x90, 0xb0, 0xea, 0x4e, 0x8b, 0xf7,
+    0x42, 0x43, 0xea, 0x76, 0xdd, 0x56, 0x6b, 0xe9, 0xb2, 0x57, 0xbc, 0xad, 0x9e, 0x62, 0x9d, 0x39,
+    0x70, 0x63, 0xa0, 0xc4, 0x30, 0x49, 0x2c, 0x8e, 0x94, 0x43, 0x7f, 0x0f, 0x46, 0x26, 0xdb, 0xd4,
+    0x10, 0xd4, 0x89, 0xfb, 0xbe, 0x57, 0xc2, 0x96, 0x56, 0x26, 0x28, 0x20, 0xca, 0x85, 0x7c, 0xba,
+    0x2c, 0x95, 0xab, 0xdd, 0xfd, 0x54, 0x4e, 0x4c, 0x77, 0x6f, 0xe4, 0xf7, 0xa0, 0x25, 0x14, 0xce,
+    0xce, 0x39, 0x92, 0x00, 0x85, 0xf3, 0xbd, 0x80, 0x23, 0x52, 0xe0, 0x0d, 0x38, 0xe8, 0xc0, 0x22,
+    0x42, 0xca, 0xa7, 0x22, 0x44, 0xe8, 0x9a, 0x70, 0x12, 0x33, 0xa0, 0xe3, 0x60, 0x5a, 0xa0,
+];
+
+/// the bins coded by `tests/data/x265_cabac.c` as (kind, context, bin), where kind is 0 for a
+/// context coded bin, 1 for a bypass bin and 2 for a terminate bin
+#[cfg(test)]
+fn x265_reference_bins(len: usize, mut seed: u32) -> Vec<(u8, usize, bool)> {
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+
+            let ctx = ((seed >> 8) & 7) as usize;
+            match seed % 10 {
+                0..=5 => (0, ctx, ((seed >> 16) & 63) < 2 + 8 * ctx as u32),
+                6..=8 => (1, 0, seed >> 31 != 0),
+                _ => (2, 0, (seed >> 16) & 7 == 0),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn x265_reference_payload() {
+    const INIT_VALUES: [u8; 8] = [139, 141, 157, 154, 197, 185, 201, 110];
 
-            value = r.0;
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new_conformant(&mut output);
+    let mut contexts = INIT_VALUES.map(|v| H265Context::from_init_value(v, 32));
 
-            let num_bits = RENORM_TABLE[usize::from(lps >> 3)];
-            value <<= num_bits;
-            range = u32::from(lps) << num_bits; /* this is always >= 0x100 except for state 63,
-                                                but state 63 is never used */
+    for (kind, ctx, bin) in x265_reference_bins(3000, 0x2545f491) {
+        match kind {
+            0 => writer.put(bin, &mut contexts[ctx]).unwrap(),
+            1 => writer.put_bypass(bin).unwrap(),
+            _ => writer.put_terminate(bin).unwrap(),
+        }
+    }
+    writer.finish().unwrap();
 
-            bit = !cur_ctx.get_mps();
+    assert_eq!(output, X265_SLICE_DATA);
+}
 
-            cur_ctx.update_lps();
+/// output of the H265Writer from before the encoder was rewritten for the bins of
+/// `writer_unchanged`, which the rewrite has to reproduce exactly
+#[cfg(test)]
+const PREVIOUS_WRITER_OUTPUT: [u8; 101] = [
+    0xf8, 0x7e, 0xf7, 0xfe, 0x42, 0x5e, 0x84, 0xe1, 0x61, 0x2c, 0x7b, 0xa1, 0x85, 0xe1, 0x44, 0x3d,
+    0x81, 0x44, 0xed, 0x51, 0xe1, 0xdb, 0xf9, 0xe4, 0x71, 0xbb, 0x39, 0x77, 0xe8, 0x04, 0x38, 0x46,
+    0x83, 0x57, 0xe2, 0xde, 0xa4, 0x21, 0xef, 0x14, 0xd5, 0x07, 0xc4, 0x76, 0xd0, 0x86, 0xdf, 0x95,
+    0x89, 0x77, 0x57, 0x4c, 0x76, 0x41, 0xd0, 0xf1, 0x45, 0xc3, 0x2b, 0x36, 0x8a, 0xb7, 0xf8, 0x53,
+    0x66, 0x44, 0xbd, 0x3e, 0x81, 0x26, 0x07, 0x47, 0x58, 0xe6, 0xf8, 0x37, 0xa0, 0xad, 0x24, 0x18,
+    0x7a, 0x75, 0xf0, 0xf2, 0xfa, 0xd1, 0x20, 0xbc, 0xb8, 0x1e, 0x07, 0x3a, 0x49, 0x36, 0x6a, 0xe7,
+    0x1e, 0xd5, 0x48, 0xa2, 0x00,
+];
 
-            self.bits_needed += i32::from(num_bits);
+#[test]
+fn writer_unchanged() {
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new(&mut output);
+    let mut contexts = [H265Context::default(); 4];
+    for i in 0..600u32 {
+        let bit = (i % 5 == 0) ^ (i % 11 == 0) ^ (i % 97 < 20);
+        writer.put(bit, &mut contexts[(i & 3) as usize]).unwrap();
+        if i % 3 == 0 {
+            writer.put_bypass(i % 7 < 3).unwrap();
+        }
+    }
+    writer.finish().unwrap();
 
-            if self.bits_needed >= 0 {
-                value |= u32::from(self.reader.read_u8()?) << self.bits_needed;
+    assert_eq!(output, PREVIOUS_WRITER_OUTPUT);
+}
 
-                self.bits_needed -= 8;
+#[test]
+fn bypass_alignment() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..100 {
+        let mut spec = SpecEncoder::new();
+        let mut spec_contexts = [(0u8, false); 8];
+
+        let mut output = Vec::new();
+        let mut writer = H265Writer::new_conformant(&mut output);
+        let mut contexts = [H265Context::default(); 8];
+
+        // (context, bin) for context coded bins, (None, bins, count) for aligned bypass runs
+        let mut coded = Vec::new();
+
+        for _ in 0..rng.gen_range(0..1000) {
+            if rng.gen_bool(0.8) {
+                let ctx = rng.gen_range(0..8);
+                let bin = rng.gen_bool(0.02 + ctx as f64 / 8.0);
+                spec.decision(bin, &mut spec_contexts[ctx]);
+                writer.put(bin, &mut contexts[ctx]).unwrap();
+                coded.push((Some(ctx), u32::from(bin), 1));
+            } else {
+                let num_bits = rng.gen_range(1..=32);
+                let bins = rng.gen::<u32>() >> (32 - num_bits);
+
+                spec.range = 256;
+                for i in (0..num_bits).rev() {
+                    spec.bypass((bins >> i) & 1 != 0);
+                }
+
+                writer.align_bypass();
+                writer.put_aligned_bypass_bits(bins, num_bits).unwrap();
+                coded.push((None, bins, num_bits));
             }
         }
 
-        self.range = range;
-        self.value = value;
+        spec.terminate(true);
+        writer.finish().unwrap();
+        assert_eq!(spec.to_bytes(), output);
+
+        // read back the runs alternately with the fast path and with single bypass bins
+        let mut reader = H265Reader::new(&output[..]).unwrap();
+        let mut contexts = [H265Context::default(); 8];
+        for (i, &(ctx, bins, num_bits)) in coded.iter().enumerate() {
+            if let Some(ctx) = ctx {
+                assert_eq!(reader.get(&mut contexts[ctx]).unwrap(), bins != 0);
+            } else {
+                reader.align_bypass();
+                let decoded = if i % 2 == 0 {
+                    reader.get_aligned_bypass_bits(num_bits).unwrap()
+                } else {
+                    (0..num_bits).fold(0, |acc, _| {
+                        (acc << 1) | u32::from(reader.get_bypass().unwrap())
+                    })
+                };
+                assert_eq!(decoded, bins);
+            }
+        }
+        assert!(reader.get_terminate().unwrap());
+    }
+}
 
-        Ok(bit)
+#[test]
+fn truncated_input() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new(&mut output);
+    let mut context = H265Context::default();
+    let bins: Vec<bool> = (0..10000).map(|_| rng.gen_bool(0.3)).collect();
+    for &bin in &bins {
+        writer.put(bin, &mut context).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // reading past the end of the data is an error rather than a stream of zeros
+    for len in [0, 1, output.len() / 2, output.len() - 2] {
+        let result = H265Reader::new(&output[..len]).and_then(|mut reader| {
+            let mut context = H265Context::default();
+            for _ in &bins {
+                let _ = reader.get(&mut context)?;
+            }
+            Ok(())
+        });
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
     }
 }
 
-impl<R: Read> H265Reader<R> {
-    pub fn new(reader: R) -> Result<Self> {
-        let mut r = H265Reader {
-            reader: reader,
-            value: 0,
-            range: 510,
-            bits_needed: 8,
-        };
+/// reader that returns at most 3 bytes at a time and is interrupted every other call
+#[cfg(test)]
+struct ShortReader<'a> {
+    data: &'a [u8],
+    calls: usize,
+}
 
-        r.value = (u32::from(r.reader.read_u8()?) << 8) | u32::from(r.reader.read_u8()?);
-        r.bits_needed -= 16;
+#[cfg(test)]
+impl Read for ShortReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.calls += 1;
+        if self.calls & 1 == 0 {
+            return Err(Error::new(ErrorKind::Interrupted, "interrupted"));
+        }
+        let len = buf.len().min(self.calls % 4).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
 
-        Ok(r)
+#[test]
+fn short_reads() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new(&mut output);
+    let mut context = H265Context::default();
+    let bins: Vec<bool> = (0..10000).map(|_| rng.gen_bool(0.3)).collect();
+    for &bin in &bins {
+        writer.put(bin, &mut context).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = H265Reader::new(ShortReader {
+        data: &output,
+        calls: 0,
+    })
+    .unwrap();
+    let mut context = H265Context::default();
+    for &bin in &bins {
+        assert_eq!(reader.get(&mut context).unwrap(), bin);
     }
 }
+
+#[test]
+fn cabac_zero_words() {
+    // 1000 bytes allow 10666 bins
+    assert_eq!(cabac_zero_words_needed(10666, 1000, 0, 0), 0);
+    assert_eq!(cabac_zero_words_needed(10667, 1000, 0, 0), 1);
+
+    // each word adds 3 bytes, which allows 32 more bins
+    assert_eq!(cabac_zero_words_needed(10666 + 32, 1000, 0, 0), 1);
+    assert_eq!(cabac_zero_words_needed(10666 + 33, 1000, 0, 0), 2);
+
+    // the raw bits of the picture give another allowance
+    assert_eq!(cabac_zero_words_needed(10666 + 100, 1000, 3200, 1), 0);
+    assert_eq!(cabac_zero_words_needed(10667 + 100, 1000, 3200, 1), 1);
+
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new_conformant(&mut output);
+    writer.finish().unwrap();
+    writer.put_cabac_zero_words(2).unwrap();
+    assert_eq!(output[output.len() - 4..], [0, 0, 0, 0]);
+}
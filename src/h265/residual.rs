@@ -0,0 +1,999 @@
+//! HEVC residual coding syntax (residual_coding() in 7.3.8.11) for a single transform block.
+//!
+//! Codes the position of the last significant coefficient, the coded_sub_block_flag and
+//! sig_coeff_flag significance map, the greater1/greater2 flags, the signs and
+//! coeff_abs_level_remaining with the adaptive Rice parameter, using the context selection
+//! rules of 9.3.4.2. Transform skip, RDPCM and the other range extension tools are not
+//! supported.
+use std::io::{Error, ErrorKind, Result};
+
+use super::H265Context;
+use crate::traits::{CabacReader, CabacWriter};
+
+/// scan order of the coefficients within the transform block (scanIdx)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanOrder {
+    Diagonal = 0,
+    Horizontal = 1,
+    Vertical = 2,
+}
+
+/// describes how a transform block is coded
+#[derive(Clone, Copy, Debug)]
+pub struct TransformBlock {
+    /// log2 of the width and height of the block, from 2 (4x4) to 5 (32x32)
+    pub log2_size: u32,
+    /// true for the chroma components (cIdx > 0)
+    pub chroma: bool,
+    pub scan: ScanOrder,
+    /// sign_data_hiding_enabled_flag, must be false if the block uses cu_transquant_bypass
+    pub sign_data_hiding: bool,
+}
+
+/// builds the scan order (6.5.3 to 6.5.5) for a block of 1 << log2_size
+const fn build_scan(log2_size: usize, scan_idx: usize) -> [(u8, u8); 64] {
+    let size = 1 << log2_size;
+    let mut scan = [(0u8, 0u8); 64];
+    let mut i = 0;
+
+    if scan_idx == 0 {
+        // up-right diagonal
+        let mut x = 0i32;
+        let mut y = 0i32;
+        while i < size * size {
+            while y >= 0 {
+                if x < size as i32 && y < size as i32 {
+                    scan[i] = (x as u8, y as u8);
+                    i += 1;
+                }
+                y -= 1;
+                x += 1;
+            }
+            y = x;
+            x = 0;
+        }
+    } else {
+        while i < size * size {
+            let (a, b) = ((i % size) as u8, (i / size) as u8);
+            scan[i] = if scan_idx == 1 { (a, b) } else { (b, a) };
+            i += 1;
+        }
+    }
+
+    scan
+}
+
+const fn build_scans() -> [[[(u8, u8); 64]; 3]; 4] {
+    let mut scans = [[[(0u8, 0u8); 64]; 3]; 4];
+    let mut log2_size = 0;
+    while log2_size < 4 {
+        let mut scan_idx = 0;
+        while scan_idx < 3 {
+            scans[log2_size][scan_idx] = build_scan(log2_size, scan_idx);
+            scan_idx += 1;
+        }
+        log2_size += 1;
+    }
+    scans
+}
+
+/// ScanOrder[log2BlockSize][scanIdx][sPos] for the sub-block grid and the 4x4 sub-blocks
+static SCAN_ORDER: [[[(u8, u8); 64]; 3]; 4] = build_scans();
+
+/// sigCtx for 4x4 blocks
+const CTX_IDX_MAP: [u8; 16] = [0, 1, 4, 5, 2, 3, 4, 5, 6, 6, 8, 8, 7, 7, 8, 8];
+
+const LAST_SIG_COEFF_PREFIX_INIT: [[u8; 18]; 3] = [
+    [
+        110, 110, 124, 125, 140, 153, 125, 127, 140, 109, 111, 143, 127, 111, 79, 108, 123, 63,
+    ],
+    [
+        125, 110, 94, 110, 95, 79, 125, 111, 110, 78, 110, 111, 111, 95, 94, 108, 123, 108,
+    ],
+    [
+        125, 110, 124, 110, 95, 94, 125, 111, 111, 79, 125, 126, 111, 111, 79, 108, 123, 93,
+    ],
+];
+
+const CODED_SUB_BLOCK_FLAG_INIT: [[u8; 4]; 3] = [
+    [91, 171, 134, 141],
+    [121, 140, 61, 154],
+    [121, 140, 61, 154],
+];
+
+const SIG_COEFF_FLAG_INIT: [[u8; 42]; 3] = [
+    [
+        111, 111, 125, 110, 110, 94, 124, 108, 124, 107, 125, 141, 179, 153, 125, 107, 125, 141,
+        179, 153, 125, 107, 125, 141, 179, 153, 125, 140, 139, 182, 182, 152, 136, 152, 136, 153,
+        136, 139, 111, 136, 139, 111,
+    ],
+    [
+        155, 154, 139, 153, 139, 123, 123, 63, 153, 166, 183, 140, 136, 153, 154, 166, 183, 140,
+        136, 153, 154, 166, 183, 140, 136, 153, 154, 170, 153, 123, 123, 107, 121, 107, 121, 167,
+        151, 183, 140, 151, 183, 140,
+    ],
+    [
+        170, 154, 139, 153, 139, 123, 123, 63, 124, 166, 183, 140, 136, 153, 154, 166, 183, 140,
+        136, 153, 154, 166, 183, 140, 136, 153, 154, 170, 153, 138, 138, 122, 121, 122, 121, 167,
+        151, 183, 140, 151, 183, 140,
+    ],
+];
+
+const GREATER1_FLAG_INIT: [[u8; 24]; 3] = [
+    [
+        140, 92, 137, 138, 140, 152, 138, 139, 153, 74, 149, 92, 139, 107, 122, 152, 140, 179, 166,
+        182, 140, 227, 122, 197,
+    ],
+    [
+        154, 196, 196, 167, 154, 152, 167, 182, 182, 134, 149, 136, 153, 121, 136, 137, 169, 194,
+        166, 167, 154, 167, 137, 182,
+    ],
+    [
+        154, 196, 167, 167, 154, 152, 167, 182, 182, 134, 149, 136, 153, 121, 136, 122, 169, 208,
+        166, 167, 154, 152, 167, 182,
+    ],
+];
+
+const GREATER2_FLAG_INIT: [[u8; 6]; 3] = [
+    [138, 153, 136, 167, 152, 152],
+    [107, 167, 91, 122, 107, 167],
+    [107, 167, 91, 107, 107, 167],
+];
+
+/// initializes an array of contexts from the init values
+fn init_contexts<const N: usize>(init_values: &[u8; N], slice_qp: i32) -> [H265Context; N] {
+    init_values.map(|v| H265Context::from_init_value(v, slice_qp))
+}
+
+/// All the contexts used by residual coding.
+#[derive(Clone, Copy)]
+pub struct ResidualContexts {
+    last_sig_coeff_x_prefix: [H265Context; 18],
+    last_sig_coeff_y_prefix: [H265Context; 18],
+    coded_sub_block_flag: [H265Context; 4],
+    sig_coeff_flag: [H265Context; 42],
+    greater1_flag: [H265Context; 24],
+    greater2_flag: [H265Context; 6],
+}
+
+impl Default for ResidualContexts {
+    fn default() -> Self {
+        ResidualContexts {
+            last_sig_coeff_x_prefix: [H265Context::default(); 18],
+            last_sig_coeff_y_prefix: [H265Context::default(); 18],
+            coded_sub_block_flag: [H265Context::default(); 4],
+            sig_coeff_flag: [H265Context::default(); 42],
+            greater1_flag: [H265Context::default(); 24],
+            greater2_flag: [H265Context::default(); 6],
+        }
+    }
+}
+
+impl ResidualContexts {
+    /// initializes the contexts as done at the start of the slice. `init_type` is 0 for I
+    /// slices, 1 for P slices and 2 for B slices (with P and B swapped if cabac_init_flag is set).
+    pub fn new(init_type: usize, slice_qp: i32) -> Self {
+        ResidualContexts {
+            last_sig_coeff_x_prefix: init_contexts(
+                &LAST_SIG_COEFF_PREFIX_INIT[init_type],
+                slice_qp,
+            ),
+            last_sig_coeff_y_prefix: init_contexts(
+                &LAST_SIG_COEFF_PREFIX_INIT[init_type],
+                slice_qp,
+            ),
+            coded_sub_block_flag: init_contexts(&CODED_SUB_BLOCK_FLAG_INIT[init_type], slice_qp),
+            sig_coeff_flag: init_contexts(&SIG_COEFF_FLAG_INIT[init_type], slice_qp),
+            greater1_flag: init_contexts(&GREATER1_FLAG_INIT[init_type], slice_qp),
+            greater2_flag: init_contexts(&GREATER2_FLAG_INIT[init_type], slice_qp),
+        }
+    }
+}
+
+impl TransformBlock {
+    fn size(&self) -> usize {
+        assert!((2..=5).contains(&self.log2_size), "invalid transform size");
+        1 << self.log2_size
+    }
+
+    /// ctxInc for bin `bin_idx` of last_sig_coeff_x_prefix/last_sig_coeff_y_prefix (9.3.4.2.3)
+    fn last_prefix_ctx(&self, bin_idx: u32) -> usize {
+        let (ctx_offset, ctx_shift) = if self.chroma {
+            (15, self.log2_size - 2)
+        } else {
+            (
+                3 * (self.log2_size - 2) + ((self.log2_size - 1) >> 2),
+                (self.log2_size + 1) >> 2,
+            )
+        };
+
+        ((bin_idx >> ctx_shift) + ctx_offset) as usize
+    }
+
+    /// ctxInc of coded_sub_block_flag (9.3.4.2.4) from the flags to the right and below
+    fn coded_sub_block_ctx(&self, prev_csbf: u32) -> usize {
+        usize::from(prev_csbf != 0) + if self.chroma { 2 } else { 0 }
+    }
+
+    /// ctxInc of sig_coeff_flag (9.3.4.2.5)
+    fn sig_coeff_ctx(&self, x_c: usize, y_c: usize, prev_csbf: u32) -> usize {
+        let sig_ctx = if self.log2_size == 2 {
+            usize::from(CTX_IDX_MAP[(y_c << 2) + x_c])
+        } else if x_c + y_c == 0 {
+            0
+        } else {
+            let (x_p, y_p) = (x_c & 3, y_c & 3);
+
+            let mut sig_ctx = match prev_csbf {
+                0 => match x_p + y_p {
+                    0 => 2,
+                    1 | 2 => 1,
+                    _ => 0,
+                },
+                1 => 2 - y_p.min(2),
+                2 => 2 - x_p.min(2),
+                _ => 2,
+            };
+
+            if self.chroma {
+                sig_ctx += if self.log2_size == 3 { 9 } else { 12 };
+            } else {
+                if (x_c >> 2) + (y_c >> 2) > 0 {
+                    sig_ctx += 3;
+                }
+
+                sig_ctx += if self.log2_size == 3 {
+                    if self.scan == ScanOrder::Diagonal {
+                        9
+                    } else {
+                        15
+                    }
+                } else {
+                    21
+                };
+            }
+
+            sig_ctx
+        };
+
+        if self.chroma {
+            27 + sig_ctx
+        } else {
+            sig_ctx
+        }
+    }
+}
+
+/// tracks the coded_sub_block_flags of the block for the context selection
+struct CodedSubBlocks {
+    flags: [[bool; 8]; 8],
+    width: usize,
+}
+
+impl CodedSubBlocks {
+    fn new(block: &TransformBlock) -> Self {
+        CodedSubBlocks {
+            flags: [[false; 8]; 8],
+            width: block.size() >> 2,
+        }
+    }
+
+    /// csbf to the right plus twice the csbf below the sub-block
+    fn prev_csbf(&self, x_s: usize, y_s: usize) -> u32 {
+        let mut prev_csbf = 0;
+        if x_s + 1 < self.width && self.flags[x_s + 1][y_s] {
+            prev_csbf |= 1;
+        }
+        if y_s + 1 < self.width && self.flags[x_s][y_s + 1] {
+            prev_csbf |= 2;
+        }
+        prev_csbf
+    }
+}
+
+/// splits a last position into the prefix and the suffix with its length
+fn last_position_prefix(pos: u32) -> (u32, u32, u32) {
+    if pos < 4 {
+        return (pos, 0, 0);
+    }
+
+    let msb = 31 - pos.leading_zeros();
+    let prefix = 2 * msb + ((pos >> (msb - 1)) & 1);
+    (prefix, pos & ((1 << (msb - 1)) - 1), msb - 1)
+}
+
+fn last_position(prefix: u32, suffix: u32) -> u32 {
+    if prefix < 4 {
+        prefix
+    } else {
+        (1 << ((prefix >> 1) - 1)) * (2 + (prefix & 1)) + suffix
+    }
+}
+
+/// selects the greater1 context set for a sub-block, c1 is the greater1Ctx left over from the
+/// previous sub-block that had greater1 flags
+fn greater1_ctx_set(block: &TransformBlock, sub_block: usize, c1: usize) -> usize {
+    let mut ctx_set = if sub_block == 0 || block.chroma { 0 } else { 2 };
+    if c1 == 0 {
+        ctx_set += 1;
+    }
+    ctx_set
+}
+
+fn greater1_ctx(block: &TransformBlock, ctx_set: usize, c1: usize) -> usize {
+    ctx_set * 4 + c1 + if block.chroma { 16 } else { 0 }
+}
+
+fn greater2_ctx(block: &TransformBlock, ctx_set: usize) -> usize {
+    ctx_set + if block.chroma { 4 } else { 0 }
+}
+
+/// updates greater1Ctx after a greater1 flag
+fn update_c1(c1: usize, greater1: bool) -> usize {
+    if greater1 {
+        0
+    } else if c1 > 0 && c1 < 3 {
+        c1 + 1
+    } else {
+        c1
+    }
+}
+
+/// number of ones in the prefix of coeff_abs_level_remaining after which the escape code is used
+const COEF_REMAIN_BIN_REDUCTION: u32 = 3;
+
+fn put_coeff_abs_level_remaining<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    value: u32,
+    rice: u32,
+) -> Result<()> {
+    let (prefix, suffix, suffix_len) = if value < (COEF_REMAIN_BIN_REDUCTION << rice) {
+        (value >> rice, value & ((1 << rice) - 1), rice)
+    } else {
+        let mut code = value - (COEF_REMAIN_BIN_REDUCTION << rice);
+        let mut length = rice;
+        while code >= (1 << length) {
+            code -= 1 << length;
+            length += 1;
+        }
+        (COEF_REMAIN_BIN_REDUCTION + length - rice, code, length)
+    };
+
+    for _ in 0..prefix {
+        writer.put_bypass(true)?;
+    }
+    writer.put_bypass(false)?;
+
+    for i in (0..suffix_len).rev() {
+        writer.put_bypass((suffix >> i) & 1 != 0)?;
+    }
+
+    Ok(())
+}
+
+fn get_coeff_abs_level_remaining<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    rice: u32,
+) -> Result<u32> {
+    let mut prefix = 0;
+    while reader.get_bypass()? {
+        prefix += 1;
+
+        if prefix > 32 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "coeff_abs_level_remaining prefix too long",
+            ));
+        }
+    }
+
+    let (suffix_len, base) = if prefix < COEF_REMAIN_BIN_REDUCTION {
+        (rice, u64::from(prefix) << rice)
+    } else {
+        let length = prefix - COEF_REMAIN_BIN_REDUCTION + rice;
+        (
+            length,
+            ((1u64 << (prefix - COEF_REMAIN_BIN_REDUCTION)) + COEF_REMAIN_BIN_REDUCTION as u64 - 1)
+                << rice,
+        )
+    };
+
+    let mut suffix = 0u64;
+    for _ in 0..suffix_len {
+        suffix = (suffix << 1) | u64::from(reader.get_bypass()?);
+    }
+
+    u32::try_from(base + suffix)
+        .ok()
+        .filter(|&v| v < i32::MAX as u32)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "coefficient out of range"))
+}
+
+/// updates cRiceParam after a coeff_abs_level_remaining
+fn update_rice(rice: u32, abs_level: u32) -> u32 {
+    if abs_level > 3 * (1 << rice) {
+        (rice + 1).min(4)
+    } else {
+        rice
+    }
+}
+
+/// writes the residual_coding() syntax for a transform block. The coefficients are in raster
+/// order (y * width + x) and at least one of them has to be non-zero, since otherwise the block
+/// is signalled with a coded block flag of zero.
+///
+/// If sign data hiding is enabled, the sign of the first coefficient of each sub-block where it
+/// is hidden has to match the parity of the sum of the levels, otherwise an error is returned.
+pub fn put_residual<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut ResidualContexts,
+    block: &TransformBlock,
+    coefficients: &[i32],
+) -> Result<()> {
+    let size = block.size();
+    assert_eq!(coefficients.len(), size * size);
+
+    let log2_sb_width = block.log2_size as usize - 2;
+    let sub_block_scan = &SCAN_ORDER[log2_sb_width][block.scan as usize];
+    let scan = &SCAN_ORDER[2][block.scan as usize];
+
+    // the levels of each sub-block in scan order
+    let sub_block_levels = |i: usize| -> [i32; 16] {
+        let (x_s, y_s) = sub_block_scan[i];
+        let mut levels = [0; 16];
+        for (n, level) in levels.iter_mut().enumerate() {
+            let (x_p, y_p) = scan[n];
+            let x_c = (usize::from(x_s) << 2) + usize::from(x_p);
+            let y_c = (usize::from(y_s) << 2) + usize::from(y_p);
+            *level = coefficients[y_c * size + x_c];
+        }
+        levels
+    };
+
+    let num_sub_blocks = 1 << (2 * log2_sb_width);
+    let (last_sub_block, last_scan_pos) = (0..num_sub_blocks)
+        .rev()
+        .find_map(|i| {
+            sub_block_levels(i)
+                .iter()
+                .rposition(|&c| c != 0)
+                .map(|n| (i, n))
+        })
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "transform block has no coefficients",
+            )
+        })?;
+
+    // last_sig_coeff_x/y, swapped for the vertical scan
+    let mut last_x =
+        (u32::from(sub_block_scan[last_sub_block].0) << 2) + u32::from(scan[last_scan_pos].0);
+    let mut last_y =
+        (u32::from(sub_block_scan[last_sub_block].1) << 2) + u32::from(scan[last_scan_pos].1);
+    if block.scan == ScanOrder::Vertical {
+        std::mem::swap(&mut last_x, &mut last_y);
+    }
+
+    let c_max = (block.log2_size << 1) - 1;
+    let (x_prefix, x_suffix, x_suffix_len) = last_position_prefix(last_x);
+    let (y_prefix, y_suffix, y_suffix_len) = last_position_prefix(last_y);
+
+    for (prefix, prefix_contexts) in [
+        (x_prefix, &mut contexts.last_sig_coeff_x_prefix),
+        (y_prefix, &mut contexts.last_sig_coeff_y_prefix),
+    ] {
+        for bin_idx in 0..prefix.min(c_max - 1) + 1 {
+            writer.put(
+                bin_idx < prefix,
+                &mut prefix_contexts[block.last_prefix_ctx(bin_idx)],
+            )?;
+        }
+    }
+
+    for (suffix, suffix_len) in [(x_suffix, x_suffix_len), (y_suffix, y_suffix_len)] {
+        for i in (0..suffix_len).rev() {
+            writer.put_bypass((suffix >> i) & 1 != 0)?;
+        }
+    }
+
+    let mut coded_sub_blocks = CodedSubBlocks::new(block);
+    let mut c1 = 1;
+
+    for i in (0..=last_sub_block).rev() {
+        let (x_s, y_s) = (
+            usize::from(sub_block_scan[i].0),
+            usize::from(sub_block_scan[i].1),
+        );
+        let levels = sub_block_levels(i);
+        let prev_csbf = coded_sub_blocks.prev_csbf(x_s, y_s);
+
+        let mut infer_sb_dc_sig_coeff = false;
+        if i < last_sub_block && i > 0 {
+            let coded = levels.iter().any(|&c| c != 0);
+            writer.put(
+                coded,
+                &mut contexts.coded_sub_block_flag[block.coded_sub_block_ctx(prev_csbf)],
+            )?;
+            coded_sub_blocks.flags[x_s][y_s] = coded;
+
+            if !coded {
+                continue;
+            }
+            infer_sb_dc_sig_coeff = true;
+        } else {
+            coded_sub_blocks.flags[x_s][y_s] = true;
+        }
+
+        // significance map
+        let first_pos = if i == last_sub_block {
+            last_scan_pos
+        } else {
+            16
+        };
+        for n in (0..first_pos).rev() {
+            if n > 0 || !infer_sb_dc_sig_coeff {
+                let (x_c, y_c) = (
+                    (x_s << 2) + usize::from(scan[n].0),
+                    (y_s << 2) + usize::from(scan[n].1),
+                );
+                let sig = levels[n] != 0;
+                writer.put(
+                    sig,
+                    &mut contexts.sig_coeff_flag[block.sig_coeff_ctx(x_c, y_c, prev_csbf)],
+                )?;
+
+                if sig {
+                    infer_sb_dc_sig_coeff = false;
+                }
+            }
+        }
+
+        // significant positions in the order they are coded
+        let sig_positions: Vec<usize> = (0..16).rev().filter(|&n| levels[n] != 0).collect();
+        if sig_positions.is_empty() {
+            // the first sub-block is always coded, but may still be all zeros
+            continue;
+        }
+
+        // greater1 flags for the first 8 coefficients, and greater2 flag for the first one above 1
+        let ctx_set = greater1_ctx_set(block, i, c1);
+        c1 = 1;
+
+        let mut last_greater1_scan_pos = None;
+        for &n in sig_positions.iter().take(8) {
+            let greater1 = levels[n].unsigned_abs() > 1;
+            writer.put(
+                greater1,
+                &mut contexts.greater1_flag[greater1_ctx(block, ctx_set, c1)],
+            )?;
+            c1 = update_c1(c1, greater1);
+
+            if greater1 && last_greater1_scan_pos.is_none() {
+                last_greater1_scan_pos = Some(n);
+            }
+        }
+
+        if let Some(n) = last_greater1_scan_pos {
+            writer.put(
+                levels[n].unsigned_abs() > 2,
+                &mut contexts.greater2_flag[greater2_ctx(block, ctx_set)],
+            )?;
+        }
+
+        // signs
+        let first_sig_scan_pos = *sig_positions.last().unwrap();
+        let sign_hidden = block.sign_data_hiding && sig_positions[0] - first_sig_scan_pos > 3;
+
+        if sign_hidden {
+            let sum_abs_level: u64 = levels.iter().map(|l| u64::from(l.unsigned_abs())).sum();
+            if (sum_abs_level % 2 == 1) != (levels[first_sig_scan_pos] < 0) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "hidden sign doesn't match the parity of the sum of the levels",
+                ));
+            }
+        }
+
+        for &n in sig_positions.iter() {
+            if !sign_hidden || n != first_sig_scan_pos {
+                writer.put_bypass(levels[n] < 0)?;
+            }
+        }
+
+        // remaining absolute levels
+        let mut rice = 0;
+        for (num_sig_coeff, &n) in sig_positions.iter().enumerate() {
+            let abs_level = levels[n].unsigned_abs();
+            let base_level = if num_sig_coeff < 8 {
+                if Some(n) == last_greater1_scan_pos {
+                    3
+                } else {
+                    2
+                }
+            } else {
+                1
+            };
+
+            if abs_level >= base_level {
+                put_coeff_abs_level_remaining(writer, abs_level - base_level, rice)?;
+                rice = update_rice(rice, abs_level);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// reads the residual_coding() syntax for a transform block into the coefficients, which are
+/// in raster order (y * width + x).
+pub fn get_residual<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut ResidualContexts,
+    block: &TransformBlock,
+    coefficients: &mut [i32],
+) -> Result<()> {
+    let size = block.size();
+    assert_eq!(coefficients.len(), size * size);
+    coefficients.fill(0);
+
+    let log2_sb_width = block.log2_size as usize - 2;
+    let sub_block_scan = &SCAN_ORDER[log2_sb_width][block.scan as usize];
+    let scan = &SCAN_ORDER[2][block.scan as usize];
+
+    let c_max = (block.log2_size << 1) - 1;
+    let mut prefixes = [0u32; 2];
+    for (prefix, prefix_contexts) in prefixes.iter_mut().zip([
+        &mut contexts.last_sig_coeff_x_prefix,
+        &mut contexts.last_sig_coeff_y_prefix,
+    ]) {
+        while *prefix < c_max && reader.get(&mut prefix_contexts[block.last_prefix_ctx(*prefix)])? {
+            *prefix += 1;
+        }
+    }
+
+    let mut last = [0u32; 2];
+    for (last, &prefix) in last.iter_mut().zip(prefixes.iter()) {
+        let mut suffix = 0;
+        if prefix > 3 {
+            for _ in 0..(prefix >> 1) - 1 {
+                suffix = (suffix << 1) | u32::from(reader.get_bypass()?);
+            }
+        }
+        *last = last_position(prefix, suffix);
+    }
+
+    let (mut last_x, mut last_y) = (last[0] as usize, last[1] as usize);
+    if block.scan == ScanOrder::Vertical {
+        std::mem::swap(&mut last_x, &mut last_y);
+    }
+
+    // find the scan position of the last coefficient
+    let num_sub_blocks = 1 << (2 * log2_sb_width);
+    let last_sub_block = (0..num_sub_blocks)
+        .find(|&i| {
+            usize::from(sub_block_scan[i].0) == last_x >> 2
+                && usize::from(sub_block_scan[i].1) == last_y >> 2
+        })
+        .unwrap();
+    let last_scan_pos = (0..16)
+        .find(|&n| usize::from(scan[n].0) == last_x & 3 && usize::from(scan[n].1) == last_y & 3)
+        .unwrap();
+
+    let mut coded_sub_blocks = CodedSubBlocks::new(block);
+    let mut c1 = 1;
+
+    for i in (0..=last_sub_block).rev() {
+        let (x_s, y_s) = (
+            usize::from(sub_block_scan[i].0),
+            usize::from(sub_block_scan[i].1),
+        );
+        let prev_csbf = coded_sub_blocks.prev_csbf(x_s, y_s);
+
+        let mut infer_sb_dc_sig_coeff = false;
+        if i < last_sub_block && i > 0 {
+            let coded = reader
+                .get(&mut contexts.coded_sub_block_flag[block.coded_sub_block_ctx(prev_csbf)])?;
+            coded_sub_blocks.flags[x_s][y_s] = coded;
+
+            if !coded {
+                continue;
+            }
+            infer_sb_dc_sig_coeff = true;
+        } else {
+            coded_sub_blocks.flags[x_s][y_s] = true;
+        }
+
+        // significance map, in coding order
+        let mut sig_positions = Vec::with_capacity(16);
+        let first_pos = if i == last_sub_block {
+            sig_positions.push(last_scan_pos);
+            last_scan_pos
+        } else {
+            16
+        };
+        for n in (0..first_pos).rev() {
+            let sig = if n > 0 || !infer_sb_dc_sig_coeff {
+                let (x_c, y_c) = (
+                    (x_s << 2) + usize::from(scan[n].0),
+                    (y_s << 2) + usize::from(scan[n].1),
+                );
+                let sig = reader
+                    .get(&mut contexts.sig_coeff_flag[block.sig_coeff_ctx(x_c, y_c, prev_csbf)])?;
+                if sig {
+                    infer_sb_dc_sig_coeff = false;
+                }
+                sig
+            } else {
+                true
+            };
+
+            if sig {
+                sig_positions.push(n);
+            }
+        }
+
+        if sig_positions.is_empty() {
+            continue;
+        }
+
+        let mut levels = [0u32; 16];
+        for &n in sig_positions.iter() {
+            levels[n] = 1;
+        }
+
+        let ctx_set = greater1_ctx_set(block, i, c1);
+        c1 = 1;
+
+        let mut last_greater1_scan_pos = None;
+        for &n in sig_positions.iter().take(8) {
+            let greater1 =
+                reader.get(&mut contexts.greater1_flag[greater1_ctx(block, ctx_set, c1)])?;
+            c1 = update_c1(c1, greater1);
+
+            if greater1 {
+                levels[n] += 1;
+                if last_greater1_scan_pos.is_none() {
+                    last_greater1_scan_pos = Some(n);
+                }
+            }
+        }
+
+        if let Some(n) = last_greater1_scan_pos {
+            if reader.get(&mut contexts.greater2_flag[greater2_ctx(block, ctx_set)])? {
+                levels[n] += 1;
+            }
+        }
+
+        let first_sig_scan_pos = *sig_positions.last().unwrap();
+        let sign_hidden = block.sign_data_hiding && sig_positions[0] - first_sig_scan_pos > 3;
+
+        let mut negative = [false; 16];
+        for &n in sig_positions.iter() {
+            if !sign_hidden || n != first_sig_scan_pos {
+                negative[n] = reader.get_bypass()?;
+            }
+        }
+
+        let mut rice = 0;
+        for (num_sig_coeff, &n) in sig_positions.iter().enumerate() {
+            let base_level = if num_sig_coeff < 8 {
+                if Some(n) == last_greater1_scan_pos {
+                    3
+                } else {
+                    2
+                }
+            } else {
+                1
+            };
+
+            if levels[n] == base_level {
+                levels[n] += get_coeff_abs_level_remaining(reader, rice)?;
+                rice = update_rice(rice, levels[n]);
+            }
+        }
+
+        if sign_hidden {
+            let sum_abs_level: u64 = levels.iter().map(|&l| u64::from(l)).sum();
+            negative[first_sig_scan_pos] = sum_abs_level % 2 == 1;
+        }
+
+        for &n in sig_positions.iter() {
+            let x_c = (x_s << 2) + usize::from(scan[n].0);
+            let y_c = (y_s << 2) + usize::from(scan[n].1);
+
+            let level = i32::try_from(levels[n])
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "coefficient out of range"))?;
+            coefficients[y_c * size + x_c] = if negative[n] { -level } else { level };
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn scan_orders() {
+    // 4x4 up-right diagonal scan from the spec
+    let diagonal: Vec<(u8, u8)> = SCAN_ORDER[2][0][..16].to_vec();
+    assert_eq!(
+        diagonal,
+        [
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (0, 2),
+            (1, 1),
+            (2, 0),
+            (0, 3),
+            (1, 2),
+            (2, 1),
+            (3, 0),
+            (1, 3),
+            (2, 2),
+            (3, 1),
+            (2, 3),
+            (3, 2),
+            (3, 3)
+        ]
+    );
+
+    for p in 0..32 {
+        let (prefix, suffix, suffix_len) = last_position_prefix(p);
+        assert!(suffix < 1 << suffix_len);
+        assert_eq!(last_position(prefix, suffix), p);
+    }
+}
+
+#[test]
+fn roundtrip_random_blocks() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    let init = ResidualContexts::new(0, 32);
+    let mut write_contexts = init;
+    let mut output = Vec::new();
+    let mut writer = super::H265Writer::new(&mut output);
+
+    let mut blocks = Vec::new();
+    for _ in 0..2000 {
+        let log2_size = rng.gen_range(2..=5);
+        let block = TransformBlock {
+            log2_size,
+            chroma: rng.gen(),
+            scan: if log2_size <= 3 {
+                [
+                    ScanOrder::Diagonal,
+                    ScanOrder::Horizontal,
+                    ScanOrder::Vertical,
+                ][rng.gen_range(0..3)]
+            } else {
+                ScanOrder::Diagonal
+            },
+            sign_data_hiding: rng.gen(),
+        };
+
+        let size = 1 << log2_size;
+        let density = rng.gen_range(0.001..1.0);
+        let max_level = [1, 2, 3, 10, 100, 100000][rng.gen_range(0..6)];
+        let mut coefficients: Vec<i32> = (0..size * size)
+            .map(|_| {
+                if rng.gen_bool(density) {
+                    rng.gen_range(-max_level..=max_level)
+                } else {
+                    0
+                }
+            })
+            .collect();
+        if coefficients.iter().all(|&c| c == 0) {
+            coefficients[rng.gen_range(0..size * size)] = 1;
+        }
+
+        // make the hidden signs consistent with the parity
+        if block.sign_data_hiding {
+            let log2_sb_width = log2_size as usize - 2;
+            for &(x_s, y_s) in
+                &SCAN_ORDER[log2_sb_width][block.scan as usize][..1 << (2 * log2_sb_width)]
+            {
+                let positions: Vec<usize> = SCAN_ORDER[2][block.scan as usize][..16]
+                    .iter()
+                    .map(|&(x_p, y_p)| {
+                        (usize::from(y_s) * 4 + usize::from(y_p)) * size
+                            + usize::from(x_s) * 4
+                            + usize::from(x_p)
+                    })
+                    .filter(|&p| coefficients[p] != 0)
+                    .collect();
+
+                if positions.len() > 1 {
+                    // positions are in scan order, so the first one is first_sig_scan_pos
+                    let sum: u64 = positions
+                        .iter()
+                        .map(|&p| u64::from(coefficients[p].unsigned_abs()))
+                        .sum();
+                    let first = positions[0];
+                    coefficients[first] =
+                        coefficients[first].abs() * if sum % 2 == 1 { -1 } else { 1 };
+                }
+            }
+        }
+
+        put_residual(&mut writer, &mut write_contexts, &block, &coefficients).unwrap();
+        blocks.push((block, coefficients));
+    }
+    writer.finish().unwrap();
+
+    let mut read_contexts = init;
+    let mut reader = super::H265Reader::new(&output[..]).unwrap();
+    for (block, coefficients) in blocks {
+        let mut decoded = vec![0; coefficients.len()];
+        get_residual(&mut reader, &mut read_contexts, &block, &mut decoded).unwrap();
+        assert_eq!(decoded, coefficients, "{block:?}");
+    }
+}
+
+#[test]
+fn sign_hiding_parity_mismatch() {
+    let block = TransformBlock {
+        log2_size: 2,
+        chroma: false,
+        scan: ScanOrder::Diagonal,
+        sign_data_hiding: true,
+    };
+
+    // first and last coefficient far enough apart to hide the sign, sum is even so the sign
+    // of the DC coefficient has to be positive
+    let mut coefficients = [0; 16];
+    coefficients[0] = -1;
+    coefficients[15] = 1;
+
+    let mut output = Vec::new();
+    let mut writer = super::H265Writer::new(&mut output);
+    let mut contexts = ResidualContexts::default();
+    assert!(put_residual(&mut writer, &mut contexts, &block, &coefficients).is_err());
+
+    // the sum of the levels doesn't fit in 32 bits, only its parity (even) matters
+    let mut coefficients = [0; 16];
+    coefficients[0] = i32::MAX - 1;
+    coefficients[1] = i32::MAX - 1;
+    coefficients[15] = -(i32::MAX - 1);
+
+    let mut output = Vec::new();
+    let mut writer = super::H265Writer::new(&mut output);
+    let mut contexts = ResidualContexts::default();
+    put_residual(&mut writer, &mut contexts, &block, &coefficients).unwrap();
+}
+
+#[test]
+fn level_out_of_range() {
+    let block = TransformBlock {
+        log2_size: 2,
+        chroma: false,
+        scan: ScanOrder::Diagonal,
+        sign_data_hiding: false,
+    };
+
+    // the level of 2^31 can be written, but doesn't fit the decoded coefficient
+    let mut coefficients = [0; 16];
+    coefficients[0] = i32::MIN;
+
+    let mut output = Vec::new();
+    let mut writer = super::H265Writer::new(&mut output);
+    let mut contexts = ResidualContexts::default();
+    put_residual(&mut writer, &mut contexts, &block, &coefficients).unwrap();
+    writer.finish().unwrap();
+
+    let mut reader = super::H265Reader::new(&output[..]).unwrap();
+    let mut contexts = ResidualContexts::default();
+    let mut decoded = [0; 16];
+    let error = get_residual(&mut reader, &mut contexts, &block, &mut decoded).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn context_init() {
+    // 154 is the "equiprobable" init value for all QPs: state 0, MPS 1
+    for qp in 0..=51 {
+        assert_eq!(H265Context::from_init_value(154, qp).uc_state, 1);
+    }
+}
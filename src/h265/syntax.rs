@@ -0,0 +1,769 @@
+//! HEVC coding unit and prediction unit syntax elements (7.3.8.4 to 7.3.8.9).
+//!
+//! Provides the binarizations (9.3.3) and the context selection (9.3.4.2) for the syntax
+//! elements of a CTU other than the residuals, so that coding tree and prediction data can be
+//! written and read without going back to the spec tables. The neighbour dependent context
+//! selection takes the values of the left and above neighbours as `Option`, where None means
+//! that the neighbour isn't available.
+use std::io::{Error, ErrorKind, Result};
+
+use super::H265Context;
+use crate::syntax::{get_exp_golomb, put_exp_golomb};
+use crate::traits::{CabacReader, CabacWriter};
+
+/// partitioning of a coding unit into prediction units (part_mode)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartMode {
+    Part2Nx2N,
+    Part2NxN,
+    PartNx2N,
+    PartNxN,
+    Part2NxnU,
+    Part2NxnD,
+    PartnLx2N,
+    PartnRx2N,
+}
+
+/// the size of the coding unit and the tools that determine the binarization of part_mode
+#[derive(Clone, Copy, Debug)]
+pub struct PartModeParams {
+    /// true for intra coded units (CuPredMode == MODE_INTRA)
+    pub intra: bool,
+    pub log2_cb_size: u32,
+    /// MinCbLog2SizeY
+    pub min_cb_log2_size: u32,
+    /// amp_enabled_flag
+    pub amp_enabled: bool,
+}
+
+/// how the luma intra prediction mode is signalled, either as index into the candidate list
+/// (mpm_idx) or as one of the other 32 modes (rem_intra_luma_pred_mode)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntraLumaPredMode {
+    Mpm(u8),
+    Remaining(u8),
+}
+
+/// INTRA_PLANAR
+const PLANAR: u8 = 0;
+/// INTRA_DC
+const DC: u8 = 1;
+/// INTRA_ANGULAR26
+const VERTICAL: u8 = 26;
+
+// init values for initType 0 to 2, elements that don't exist in I slices use 154 (equiprobable)
+const SPLIT_CU_FLAG_INIT: [[u8; 3]; 3] = [[139, 141, 157], [107, 139, 126], [107, 139, 126]];
+const CU_SKIP_FLAG_INIT: [[u8; 3]; 3] = [[154, 154, 154], [197, 185, 201], [197, 185, 201]];
+const PART_MODE_INIT: [[u8; 4]; 3] = [
+    [184, 154, 154, 154],
+    [154, 139, 154, 154],
+    [154, 139, 154, 154],
+];
+const PREV_INTRA_LUMA_PRED_FLAG_INIT: [u8; 3] = [184, 154, 183];
+const INTRA_CHROMA_PRED_MODE_INIT: [u8; 3] = [63, 152, 152];
+const MERGE_FLAG_INIT: [u8; 3] = [154, 110, 154];
+const MERGE_IDX_INIT: [u8; 3] = [154, 122, 137];
+const ABS_MVD_GREATER0_FLAG_INIT: [u8; 3] = [154, 140, 169];
+const ABS_MVD_GREATER1_FLAG_INIT: [u8; 3] = [154, 198, 198];
+
+/// The contexts of the coding unit and prediction unit syntax elements.
+#[derive(Clone, Copy, Default)]
+pub struct CodingUnitContexts {
+    split_cu_flag: [H265Context; 3],
+    cu_skip_flag: [H265Context; 3],
+    part_mode: [H265Context; 4],
+    prev_intra_luma_pred_flag: H265Context,
+    intra_chroma_pred_mode: H265Context,
+    merge_flag: H265Context,
+    merge_idx: H265Context,
+    abs_mvd_greater0_flag: H265Context,
+    abs_mvd_greater1_flag: H265Context,
+}
+
+impl CodingUnitContexts {
+    /// initializes the contexts as done at the start of the slice. `init_type` is 0 for I
+    /// slices, 1 for P slices and 2 for B slices (with P and B swapped if cabac_init_flag is set).
+    pub fn new(init_type: usize, slice_qp: i32) -> Self {
+        let init = |v| H265Context::from_init_value(v, slice_qp);
+
+        CodingUnitContexts {
+            split_cu_flag: SPLIT_CU_FLAG_INIT[init_type].map(init),
+            cu_skip_flag: CU_SKIP_FLAG_INIT[init_type].map(init),
+            part_mode: PART_MODE_INIT[init_type].map(init),
+            prev_intra_luma_pred_flag: init(PREV_INTRA_LUMA_PRED_FLAG_INIT[init_type]),
+            intra_chroma_pred_mode: init(INTRA_CHROMA_PRED_MODE_INIT[init_type]),
+            merge_flag: init(MERGE_FLAG_INIT[init_type]),
+            merge_idx: init(MERGE_IDX_INIT[init_type]),
+            abs_mvd_greater0_flag: init(ABS_MVD_GREATER0_FLAG_INIT[init_type]),
+            abs_mvd_greater1_flag: init(ABS_MVD_GREATER1_FLAG_INIT[init_type]),
+        }
+    }
+}
+
+/// ctxInc of split_cu_flag from the depths of the left and above neighbours
+fn split_cu_flag_ctx(cqt_depth: u32, left_depth: Option<u32>, above_depth: Option<u32>) -> usize {
+    usize::from(left_depth.is_some_and(|d| d > cqt_depth))
+        + usize::from(above_depth.is_some_and(|d| d > cqt_depth))
+}
+
+/// writes split_cu_flag for a coding quadtree at depth `cqt_depth`. `left_depth` and
+/// `above_depth` are the CtDepth of the neighbouring coding units.
+pub fn put_split_cu_flag<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    split: bool,
+    cqt_depth: u32,
+    left_depth: Option<u32>,
+    above_depth: Option<u32>,
+) -> Result<()> {
+    let ctx = split_cu_flag_ctx(cqt_depth, left_depth, above_depth);
+    writer.put(split, &mut contexts.split_cu_flag[ctx])
+}
+
+pub fn get_split_cu_flag<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+    cqt_depth: u32,
+    left_depth: Option<u32>,
+    above_depth: Option<u32>,
+) -> Result<bool> {
+    let ctx = split_cu_flag_ctx(cqt_depth, left_depth, above_depth);
+    reader.get(&mut contexts.split_cu_flag[ctx])
+}
+
+fn cu_skip_flag_ctx(left_skip: Option<bool>, above_skip: Option<bool>) -> usize {
+    usize::from(left_skip == Some(true)) + usize::from(above_skip == Some(true))
+}
+
+/// writes cu_skip_flag, `left_skip` and `above_skip` are the flags of the neighbouring coding
+/// units.
+pub fn put_cu_skip_flag<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    skip: bool,
+    left_skip: Option<bool>,
+    above_skip: Option<bool>,
+) -> Result<()> {
+    let ctx = cu_skip_flag_ctx(left_skip, above_skip);
+    writer.put(skip, &mut contexts.cu_skip_flag[ctx])
+}
+
+pub fn get_cu_skip_flag<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+    left_skip: Option<bool>,
+    above_skip: Option<bool>,
+) -> Result<bool> {
+    let ctx = cu_skip_flag_ctx(left_skip, above_skip);
+    reader.get(&mut contexts.cu_skip_flag[ctx])
+}
+
+impl PartModeParams {
+    /// returns the bins of part_mode (table 9-43), or None if the mode isn't allowed
+    fn binarize(&self, part_mode: PartMode) -> Option<&'static [bool]> {
+        const T: bool = true;
+        const F: bool = false;
+
+        let at_min_size = self.log2_cb_size == self.min_cb_log2_size;
+
+        if self.intra {
+            return match part_mode {
+                PartMode::Part2Nx2N => Some(&[T]),
+                PartMode::PartNxN if at_min_size => Some(&[F]),
+                _ => None,
+            };
+        }
+
+        match part_mode {
+            PartMode::Part2Nx2N => Some(&[T]),
+            PartMode::Part2NxN if !at_min_size && self.amp_enabled => Some(&[F, T, T]),
+            PartMode::Part2NxN => Some(&[F, T]),
+            PartMode::PartNx2N if at_min_size && self.log2_cb_size == 3 => Some(&[F, F]),
+            PartMode::PartNx2N if at_min_size || self.amp_enabled => Some(&[F, F, T]),
+            PartMode::PartNx2N => Some(&[F, F]),
+            PartMode::PartNxN if at_min_size && self.log2_cb_size > 3 => Some(&[F, F, F]),
+            PartMode::Part2NxnU if !at_min_size && self.amp_enabled => Some(&[F, T, F, F]),
+            PartMode::Part2NxnD if !at_min_size && self.amp_enabled => Some(&[F, T, F, T]),
+            PartMode::PartnLx2N if !at_min_size && self.amp_enabled => Some(&[F, F, F, F]),
+            PartMode::PartnRx2N if !at_min_size && self.amp_enabled => Some(&[F, F, F, T]),
+            _ => None,
+        }
+    }
+
+    /// all the part modes that are allowed with these parameters
+    fn allowed_modes(&self) -> impl Iterator<Item = PartMode> + '_ {
+        [
+            PartMode::Part2Nx2N,
+            PartMode::Part2NxN,
+            PartMode::PartNx2N,
+            PartMode::PartNxN,
+            PartMode::Part2NxnU,
+            PartMode::Part2NxnD,
+            PartMode::PartnLx2N,
+            PartMode::PartnRx2N,
+        ]
+        .into_iter()
+        .filter(|&m| self.binarize(m).is_some())
+    }
+
+    /// the first three bins are context coded, with the third one using a separate context
+    /// for the AMP split, and the fourth one is bypass coded
+    fn ctx(&self, bin_idx: usize) -> Option<usize> {
+        match bin_idx {
+            0 | 1 => Some(bin_idx),
+            2 if self.log2_cb_size == self.min_cb_log2_size => Some(2),
+            2 => Some(3),
+            _ => None,
+        }
+    }
+}
+
+/// writes part_mode, returns an InvalidInput error if the mode isn't allowed for the coding unit
+pub fn put_part_mode<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    params: &PartModeParams,
+    part_mode: PartMode,
+) -> Result<()> {
+    let bins = params.binarize(part_mode).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "part_mode not allowed for this coding unit",
+        )
+    })?;
+
+    for (bin_idx, &bin) in bins.iter().enumerate() {
+        match params.ctx(bin_idx) {
+            Some(ctx) => writer.put(bin, &mut contexts.part_mode[ctx])?,
+            None => writer.put_bypass(bin)?,
+        }
+    }
+
+    Ok(())
+}
+
+pub fn get_part_mode<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+    params: &PartModeParams,
+) -> Result<PartMode> {
+    // read bins until only a single mode matches, the binarization is a prefix code
+    let mut bins = Vec::with_capacity(4);
+    loop {
+        let bin = match params.ctx(bins.len()) {
+            Some(ctx) => reader.get(&mut contexts.part_mode[ctx])?,
+            None => reader.get_bypass()?,
+        };
+        bins.push(bin);
+
+        let mut candidates = params
+            .allowed_modes()
+            .filter(|&m| params.binarize(m).unwrap().starts_with(&bins));
+
+        match (candidates.next(), candidates.next()) {
+            (Some(mode), None) => return Ok(mode),
+            (Some(_), Some(_)) => {}
+            (None, _) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid part_mode for this coding unit",
+                ))
+            }
+        }
+    }
+}
+
+/// derives candModeList (8.4.2) from the intra prediction modes of the left and above
+/// prediction blocks. A neighbour has to be passed as None if it isn't available, isn't intra
+/// coded, uses pcm, or (for the above one) lies in the CTB row above.
+pub fn candidate_mode_list(left: Option<u8>, above: Option<u8>) -> [u8; 3] {
+    let cand_a = left.unwrap_or(DC);
+    let cand_b = above.unwrap_or(DC);
+
+    if cand_a == cand_b {
+        if cand_a < 2 {
+            [PLANAR, DC, VERTICAL]
+        } else {
+            [
+                cand_a,
+                2 + ((cand_a + 29) % 32),
+                2 + ((cand_a - 2 + 1) % 32),
+            ]
+        }
+    } else {
+        let third = if cand_a != PLANAR && cand_b != PLANAR {
+            PLANAR
+        } else if cand_a != DC && cand_b != DC {
+            DC
+        } else {
+            VERTICAL
+        };
+
+        [cand_a, cand_b, third]
+    }
+}
+
+impl IntraLumaPredMode {
+    /// returns how to signal the intra prediction mode (0 to 34) given the candidate list
+    pub fn from_mode(mode: u8, candidates: &[u8; 3]) -> Self {
+        assert!(mode < 35, "invalid intra prediction mode");
+
+        if let Some(i) = candidates.iter().position(|&c| c == mode) {
+            IntraLumaPredMode::Mpm(i as u8)
+        } else {
+            let smaller = candidates.iter().filter(|&&c| c < mode).count() as u8;
+            IntraLumaPredMode::Remaining(mode - smaller)
+        }
+    }
+
+    /// derives IntraPredModeY from the signalled values and the candidate list
+    pub fn to_mode(self, candidates: &[u8; 3]) -> u8 {
+        match self {
+            IntraLumaPredMode::Mpm(i) => candidates[usize::from(i)],
+            IntraLumaPredMode::Remaining(rem) => {
+                let mut sorted = *candidates;
+                sorted.sort_unstable();
+
+                sorted
+                    .iter()
+                    .fold(rem, |mode, &c| if mode >= c { mode + 1 } else { mode })
+            }
+        }
+    }
+}
+
+/// writes prev_intra_luma_pred_flag, which is true if the mode is signalled with mpm_idx
+pub fn put_prev_intra_luma_pred_flag<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    mode: IntraLumaPredMode,
+) -> Result<()> {
+    writer.put(
+        matches!(mode, IntraLumaPredMode::Mpm(_)),
+        &mut contexts.prev_intra_luma_pred_flag,
+    )
+}
+
+pub fn get_prev_intra_luma_pred_flag<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+) -> Result<bool> {
+    reader.get(&mut contexts.prev_intra_luma_pred_flag)
+}
+
+/// writes mpm_idx or rem_intra_luma_pred_mode. In the coding unit syntax, the
+/// prev_intra_luma_pred_flags of all the prediction blocks come first, followed by these.
+pub fn put_intra_luma_pred_mode<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    mode: IntraLumaPredMode,
+) -> Result<()> {
+    match mode {
+        IntraLumaPredMode::Mpm(idx) => {
+            assert!(idx < 3);
+
+            // truncated rice with cMax = 2
+            writer.put_bypass(idx > 0)?;
+            if idx > 0 {
+                writer.put_bypass(idx > 1)?;
+            }
+        }
+        IntraLumaPredMode::Remaining(rem) => {
+            assert!(rem < 32);
+
+            for i in (0..5).rev() {
+                writer.put_bypass((rem >> i) & 1 != 0)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// reads mpm_idx if `prev_intra_luma_pred_flag` is set, rem_intra_luma_pred_mode otherwise
+pub fn get_intra_luma_pred_mode<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    prev_intra_luma_pred_flag: bool,
+) -> Result<IntraLumaPredMode> {
+    if prev_intra_luma_pred_flag {
+        let mut idx = 0;
+        while idx < 2 && reader.get_bypass()? {
+            idx += 1;
+        }
+
+        Ok(IntraLumaPredMode::Mpm(idx))
+    } else {
+        let mut rem = 0;
+        for _ in 0..5 {
+            rem = (rem << 1) | u8::from(reader.get_bypass()?);
+        }
+
+        Ok(IntraLumaPredMode::Remaining(rem))
+    }
+}
+
+/// writes intra_chroma_pred_mode (0 to 4), where 4 means the chroma mode is derived from luma
+pub fn put_intra_chroma_pred_mode<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    mode: u8,
+) -> Result<()> {
+    assert!(mode < 5);
+
+    writer.put(mode != 4, &mut contexts.intra_chroma_pred_mode)?;
+    if mode != 4 {
+        writer.put_bypass(mode & 2 != 0)?;
+        writer.put_bypass(mode & 1 != 0)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_intra_chroma_pred_mode<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+) -> Result<u8> {
+    if !reader.get(&mut contexts.intra_chroma_pred_mode)? {
+        return Ok(4);
+    }
+
+    let high = u8::from(reader.get_bypass()?);
+    let low = u8::from(reader.get_bypass()?);
+    Ok((high << 1) | low)
+}
+
+pub fn put_merge_flag<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    merge: bool,
+) -> Result<()> {
+    writer.put(merge, &mut contexts.merge_flag)
+}
+
+pub fn get_merge_flag<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+) -> Result<bool> {
+    reader.get(&mut contexts.merge_flag)
+}
+
+/// writes merge_idx as truncated rice with cMax = MaxNumMergeCand - 1, where only the first
+/// bin is context coded. Nothing is written if there is only one merge candidate.
+pub fn put_merge_idx<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    merge_idx: u32,
+    max_num_merge_cand: u32,
+) -> Result<()> {
+    assert!(merge_idx < max_num_merge_cand);
+
+    let c_max = max_num_merge_cand - 1;
+    let num_bins = if merge_idx < c_max {
+        merge_idx + 1
+    } else {
+        c_max
+    };
+
+    for bin_idx in 0..num_bins {
+        let bin = bin_idx < merge_idx;
+        if bin_idx == 0 {
+            writer.put(bin, &mut contexts.merge_idx)?;
+        } else {
+            writer.put_bypass(bin)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn get_merge_idx<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+    max_num_merge_cand: u32,
+) -> Result<u32> {
+    let c_max = max_num_merge_cand - 1;
+
+    let mut merge_idx = 0;
+    while merge_idx < c_max {
+        let bin = if merge_idx == 0 {
+            reader.get(&mut contexts.merge_idx)?
+        } else {
+            reader.get_bypass()?
+        };
+
+        if !bin {
+            break;
+        }
+        merge_idx += 1;
+    }
+
+    Ok(merge_idx)
+}
+
+/// writes mvd_coding() for the horizontal and vertical motion vector difference
+pub fn put_mvd<W: CabacWriter<H265Context>>(
+    writer: &mut W,
+    contexts: &mut CodingUnitContexts,
+    mvd: [i32; 2],
+) -> Result<()> {
+    let abs_mvd = mvd.map(|v| v.unsigned_abs());
+
+    for &a in &abs_mvd {
+        writer.put(a > 0, &mut contexts.abs_mvd_greater0_flag)?;
+    }
+    for &a in &abs_mvd {
+        if a > 0 {
+            writer.put(a > 1, &mut contexts.abs_mvd_greater1_flag)?;
+        }
+    }
+
+    for (&v, &a) in mvd.iter().zip(abs_mvd.iter()) {
+        if a > 0 {
+            if a > 1 {
+                // abs_mvd_minus2
+                put_exp_golomb(writer, a - 2, 1)?;
+            }
+            writer.put_bypass(v < 0)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn get_mvd<R: CabacReader<H265Context>>(
+    reader: &mut R,
+    contexts: &mut CodingUnitContexts,
+) -> Result<[i32; 2]> {
+    let mut abs_mvd = [0u32; 2];
+
+    for a in abs_mvd.iter_mut() {
+        *a = u32::from(reader.get(&mut contexts.abs_mvd_greater0_flag)?);
+    }
+    for a in abs_mvd.iter_mut() {
+        if *a > 0 {
+            *a += u32::from(reader.get(&mut contexts.abs_mvd_greater1_flag)?);
+        }
+    }
+
+    let mut mvd = [0i32; 2];
+    for (v, &a) in mvd.iter_mut().zip(abs_mvd.iter()) {
+        if a > 0 {
+            let a = if a > 1 {
+                get_exp_golomb(reader, 1)?
+                    .checked_add(2)
+                    .filter(|&v| v <= i32::MAX as u32)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "mvd out of range"))?
+            } else {
+                a
+            };
+
+            *v = if reader.get_bypass()? {
+                -(a as i32)
+            } else {
+                a as i32
+            };
+        }
+    }
+
+    Ok(mvd)
+}
+
+#[test]
+fn intra_mode_candidates() {
+    let neighbours = (0..35).map(Some).chain([None]);
+
+    for left in neighbours.clone() {
+        for above in neighbours.clone() {
+            let candidates = candidate_mode_list(left, above);
+            assert!(
+                candidates[0] != candidates[1]
+                    && candidates[1] != candidates[2]
+                    && candidates[0] != candidates[2],
+                "{candidates:?}"
+            );
+
+            for mode in 0..35 {
+                let signalled = IntraLumaPredMode::from_mode(mode, &candidates);
+                if let IntraLumaPredMode::Remaining(rem) = signalled {
+                    assert!(rem < 32);
+                }
+                assert_eq!(signalled.to_mode(&candidates), mode);
+            }
+        }
+    }
+
+    assert_eq!(candidate_mode_list(None, None), [PLANAR, DC, VERTICAL]);
+    assert_eq!(candidate_mode_list(Some(10), Some(10)), [10, 9, 11]);
+    assert_eq!(candidate_mode_list(Some(2), Some(2)), [2, 33, 3]);
+    assert_eq!(candidate_mode_list(Some(0), Some(26)), [0, 26, 1]);
+}
+
+#[test]
+fn part_mode_prefix_code() {
+    // in each configuration, the binarizations of the allowed modes have to form a prefix code
+    for intra in [false, true] {
+        for amp_enabled in [false, true] {
+            for (log2_cb_size, min_cb_log2_size) in [(3, 3), (4, 4), (4, 3), (6, 3)] {
+                let params = PartModeParams {
+                    intra,
+                    log2_cb_size,
+                    min_cb_log2_size,
+                    amp_enabled,
+                };
+
+                let codes: Vec<_> = params
+                    .allowed_modes()
+                    .map(|m| params.binarize(m).unwrap())
+                    .collect();
+
+                for (i, a) in codes.iter().enumerate() {
+                    for (j, b) in codes.iter().enumerate() {
+                        assert!(i == j || !b.starts_with(a), "{params:?}");
+                    }
+                }
+
+                let expected = match (intra, log2_cb_size == min_cb_log2_size) {
+                    (true, true) => 2,
+                    (true, false) => 1,
+                    (false, true) if log2_cb_size == 3 => 3,
+                    (false, true) => 4,
+                    (false, false) if amp_enabled => 7,
+                    (false, false) => 3,
+                };
+                assert_eq!(codes.len(), expected, "{params:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn roundtrip_syntax_elements() {
+    use super::{H265Reader, H265Writer};
+    use rand::Rng;
+
+    #[derive(Debug)]
+    enum Element {
+        Split(bool, u32, Option<u32>, Option<u32>),
+        Skip(bool, Option<bool>, Option<bool>),
+        Part(PartModeParams, PartMode),
+        IntraLuma(IntraLumaPredMode),
+        IntraChroma(u8),
+        Merge(bool, u32, u32),
+        Mvd([i32; 2]),
+    }
+
+    let mut rng = rand::thread_rng();
+
+    let mut elements = Vec::new();
+    for _ in 0..5000 {
+        let element = match rng.gen_range(0..7) {
+            0 => {
+                let depth = rng.gen_range(0..4);
+                let neighbour = |rng: &mut rand::rngs::ThreadRng| {
+                    if rng.gen() {
+                        Some(rng.gen_range(0..4))
+                    } else {
+                        None
+                    }
+                };
+                Element::Split(rng.gen(), depth, neighbour(&mut rng), neighbour(&mut rng))
+            }
+            1 => Element::Skip(
+                rng.gen(),
+                [None, Some(false), Some(true)][rng.gen_range(0..3)],
+                [None, Some(false), Some(true)][rng.gen_range(0..3)],
+            ),
+            2 => {
+                let min_cb_log2_size = rng.gen_range(3..=4);
+                let params = PartModeParams {
+                    intra: rng.gen(),
+                    log2_cb_size: min_cb_log2_size + rng.gen_range(0..=2),
+                    min_cb_log2_size,
+                    amp_enabled: rng.gen(),
+                };
+                let modes: Vec<_> = params.allowed_modes().collect();
+                Element::Part(params, modes[rng.gen_range(0..modes.len())])
+            }
+            3 => Element::IntraLuma(if rng.gen() {
+                IntraLumaPredMode::Mpm(rng.gen_range(0..3))
+            } else {
+                IntraLumaPredMode::Remaining(rng.gen_range(0..32))
+            }),
+            4 => Element::IntraChroma(rng.gen_range(0..5)),
+            5 => {
+                let max_num_merge_cand = rng.gen_range(1..=5);
+                Element::Merge(
+                    rng.gen(),
+                    rng.gen_range(0..max_num_merge_cand),
+                    max_num_merge_cand,
+                )
+            }
+            _ => {
+                let mut component = || match rng.gen_range(0..3) {
+                    0 => rng.gen_range(-2..=2),
+                    1 => rng.gen_range(-1000..=1000),
+                    _ => rng.gen_range(-(1 << 15)..(1 << 15)),
+                };
+                Element::Mvd([component(), component()])
+            }
+        };
+        elements.push(element);
+    }
+
+    let init = CodingUnitContexts::new(1, 30);
+
+    let mut write_contexts = init;
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new(&mut output);
+    let w = &mut writer;
+    let c = &mut write_contexts;
+    for element in &elements {
+        match *element {
+            Element::Split(split, depth, left, above) => {
+                put_split_cu_flag(w, c, split, depth, left, above).unwrap()
+            }
+            Element::Skip(skip, left, above) => put_cu_skip_flag(w, c, skip, left, above).unwrap(),
+            Element::Part(params, mode) => put_part_mode(w, c, &params, mode).unwrap(),
+            Element::IntraLuma(mode) => {
+                put_prev_intra_luma_pred_flag(w, c, mode).unwrap();
+                put_intra_luma_pred_mode(w, mode).unwrap();
+            }
+            Element::IntraChroma(mode) => put_intra_chroma_pred_mode(w, c, mode).unwrap(),
+            Element::Merge(merge, idx, max) => {
+                put_merge_flag(w, c, merge).unwrap();
+                put_merge_idx(w, c, idx, max).unwrap();
+            }
+            Element::Mvd(mvd) => put_mvd(w, c, mvd).unwrap(),
+        }
+    }
+    writer.finish().unwrap();
+
+    let mut read_contexts = init;
+    let mut reader = H265Reader::new(&output[..]).unwrap();
+    let r = &mut reader;
+    let c = &mut read_contexts;
+    for element in &elements {
+        match *element {
+            Element::Split(split, depth, left, above) => {
+                assert_eq!(get_split_cu_flag(r, c, depth, left, above).unwrap(), split)
+            }
+            Element::Skip(skip, left, above) => {
+                assert_eq!(get_cu_skip_flag(r, c, left, above).unwrap(), skip)
+            }
+            Element::Part(params, mode) => {
+                assert_eq!(get_part_mode(r, c, &params).unwrap(), mode, "{params:?}")
+            }
+            Element::IntraLuma(mode) => {
+                let flag = get_prev_intra_luma_pred_flag(r, c).unwrap();
+                assert_eq!(get_intra_luma_pred_mode(r, flag).unwrap(), mode);
+            }
+            Element::IntraChroma(mode) => {
+                assert_eq!(get_intra_chroma_pred_mode(r, c).unwrap(), mode)
+            }
+            Element::Merge(merge, idx, max) => {
+                assert_eq!(get_merge_flag(r, c).unwrap(), merge);
+                assert_eq!(get_merge_idx(r, c, max).unwrap(), idx);
+            }
+            Element::Mvd(mvd) => assert_eq!(get_mvd(r, c).unwrap(), mvd),
+        }
+    }
+}
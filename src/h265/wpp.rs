@@ -0,0 +1,277 @@
+//! Wavefront parallel processing (WPP) support for H.265 slices.
+//!
+//! When `entropy_coding_sync_enabled_flag` is set, every CTU row of a slice is coded as a
+//! separate substream that ends with `end_of_subset_one_bit`. The first CTU of each row starts
+//! with the context states that were stored after the second CTU of the row above, which
+//! allows the rows to be decoded in parallel as long as each row stays two CTUs behind the
+//! previous one.
+//!
+//! The context set is a user defined type (for example a struct with arrays of
+//! `H265Context`) that is cloned whenever it needs to be stored.
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::{Condvar, Mutex},
+};
+
+use super::{H265Reader, H265Writer};
+
+/// Encodes the CTUs of a slice into one substream per CTU row, taking care of the context
+/// synchronization between the rows.
+pub struct WppWriter<C> {
+    width_in_ctbs: usize,
+    ctb_x: usize,
+    initial_contexts: C,
+    contexts: C,
+    saved_contexts: Option<C>,
+    writer: H265Writer<Vec<u8>>,
+    substreams: Vec<Vec<u8>>,
+    end_of_slice_segment: bool,
+}
+
+impl<C: Clone> WppWriter<C> {
+    /// creates a writer for a slice that starts at column `first_ctb_x` of a picture that is
+    /// `width_in_ctbs` CTUs wide. `initial_contexts` are the contexts as initialized for
+    /// the slice, which are used for rows that don't have synchronized contexts available.
+    pub fn new(width_in_ctbs: usize, first_ctb_x: usize, initial_contexts: C) -> Self {
+        assert!(first_ctb_x < width_in_ctbs);
+
+        WppWriter {
+            width_in_ctbs,
+            ctb_x: first_ctb_x,
+            contexts: initial_contexts.clone(),
+            initial_contexts,
+            saved_contexts: None,
+            writer: H265Writer::new(Vec::new()),
+            substreams: Vec::new(),
+            end_of_slice_segment: false,
+        }
+    }
+
+    /// starts the next CTU, returning the writer and the contexts to code it with
+    pub fn start_ctu(&mut self) -> (&mut H265Writer<Vec<u8>>, &mut C) {
+        assert!(!self.end_of_slice_segment, "slice segment already ended");
+
+        if self.ctb_x == 0 && !self.substreams.is_empty() {
+            self.contexts = self
+                .saved_contexts
+                .take()
+                .unwrap_or_else(|| self.initial_contexts.clone());
+        }
+
+        (&mut self.writer, &mut self.contexts)
+    }
+
+    /// ends the current CTU by writing `end_of_slice_segment_flag`, and if the CTU was the last
+    /// one in the row, `end_of_subset_one_bit` to finish the substream.
+    pub fn end_ctu(&mut self, end_of_slice_segment: bool) -> Result<()> {
+        assert!(!self.end_of_slice_segment, "slice segment already ended");
+
+        if self.ctb_x == 1 {
+            self.saved_contexts = Some(self.contexts.clone());
+        }
+
+        self.writer.put_terminate(end_of_slice_segment)?;
+        self.ctb_x += 1;
+
+        if end_of_slice_segment {
+            self.end_of_slice_segment = true;
+            self.finish_substream();
+        } else if self.ctb_x == self.width_in_ctbs {
+            // end_of_subset_one_bit, the stop bit of the flush is the alignment_bit_equal_to_one
+            self.writer.put_terminate(true)?;
+            self.ctb_x = 0;
+            self.finish_substream();
+        }
+
+        Ok(())
+    }
+
+    fn finish_substream(&mut self) {
+        let writer = std::mem::replace(&mut self.writer, H265Writer::new(Vec::new()));
+        self.substreams.push(writer.writer);
+    }
+
+    /// returns the substreams of the slice segment, one for each CTU row it touched. The
+    /// slice segment has to be ended by passing true to `end_ctu`.
+    pub fn into_substreams(self) -> Vec<Vec<u8>> {
+        assert!(self.end_of_slice_segment, "slice segment not ended");
+
+        self.substreams
+    }
+}
+
+/// returns the `entry_point_offset_minus1` values for the slice header, which are the sizes
+/// of all the substreams except the last one minus one.
+///
+/// If emulation prevention bytes are inserted into the slice data, they count towards the
+/// substream sizes, so the sizes have to be taken after escaping.
+pub fn entry_point_offsets_minus1(substream_sizes: &[usize]) -> Vec<u32> {
+    let count = substream_sizes.len().saturating_sub(1);
+
+    substream_sizes[..count]
+        .iter()
+        .map(|&size| {
+            assert!(size > 0);
+            (size - 1) as u32
+        })
+        .collect()
+}
+
+/// returns `offset_len_minus1` for the slice header, which is the number of bits needed to
+/// code the largest `entry_point_offset_minus1` minus one.
+pub fn offset_len_minus1(entry_point_offsets_minus1: &[u32]) -> u32 {
+    let max = entry_point_offsets_minus1
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0);
+
+    (32 - max.leading_zeros()).max(1) - 1
+}
+
+/// splits the slice segment data into its substreams using the entry points from the slice
+/// header, so that they can be handed to different threads for decoding.
+pub fn split_substreams<'a>(data: &'a [u8], entry_point_offsets_minus1: &[u32]) -> Vec<&'a [u8]> {
+    let mut substreams = Vec::with_capacity(entry_point_offsets_minus1.len() + 1);
+    let mut rest = data;
+
+    for &offset in entry_point_offsets_minus1 {
+        let (substream, remaining) = rest.split_at(offset as usize + 1);
+        substreams.push(substream);
+        rest = remaining;
+    }
+    substreams.push(rest);
+
+    substreams
+}
+
+/// Shares the synchronized contexts between the readers of the different CTU rows. Each
+/// row waits until the row above it has stored its contexts (or finished without storing
+/// any), so the rows can be decoded on separate threads.
+pub struct WppSync<C> {
+    width_in_ctbs: usize,
+    first_ctb_x: usize,
+    initial_contexts: C,
+    /// for each row, None while the row is still running, Some(None) if the row ended without
+    /// storing contexts
+    rows: Mutex<Vec<Option<Option<C>>>>,
+    stored: Condvar,
+}
+
+impl<C: Clone> WppSync<C> {
+    /// creates the synchronization state for a slice that starts at column `first_ctb_x`
+    /// of a picture that is `width_in_ctbs` CTUs wide.
+    pub fn new(width_in_ctbs: usize, first_ctb_x: usize, initial_contexts: C) -> Self {
+        assert!(first_ctb_x < width_in_ctbs);
+
+        WppSync {
+            width_in_ctbs,
+            first_ctb_x,
+            initial_contexts,
+            rows: Mutex::new(Vec::new()),
+            stored: Condvar::new(),
+        }
+    }
+
+    fn store(&self, row: usize, contexts: Option<C>) {
+        let mut rows = self.rows.lock().unwrap();
+        if rows.len() <= row {
+            rows.resize(row + 1, None);
+        }
+        rows[row] = Some(contexts);
+
+        self.stored.notify_all();
+    }
+
+    fn load(&self, row: usize) -> C {
+        if row == 0 {
+            return self.initial_contexts.clone();
+        }
+
+        let mut rows = self.rows.lock().unwrap();
+        loop {
+            if let Some(Some(stored)) = rows.get(row - 1) {
+                return stored
+                    .clone()
+                    .unwrap_or_else(|| self.initial_contexts.clone());
+            }
+
+            rows = self.stored.wait(rows).unwrap();
+        }
+    }
+}
+
+/// Decodes the substream of a single CTU row.
+pub struct WppReader<'a, C: Clone> {
+    sync: &'a WppSync<C>,
+    row: usize,
+    ctb_x: usize,
+    contexts: C,
+    reader: H265Reader<&'a [u8]>,
+    stored: bool,
+}
+
+impl<'a, C: Clone> WppReader<'a, C> {
+    /// creates a reader for the substream of the given row of the slice. Blocks until the row
+    /// above has stored its synchronized contexts.
+    pub fn new(sync: &'a WppSync<C>, row: usize, substream: &'a [u8]) -> Result<Self> {
+        let reader = match H265Reader::new(substream) {
+            Ok(reader) => reader,
+            Err(e) => {
+                sync.store(row, None);
+                return Err(e);
+            }
+        };
+
+        Ok(WppReader {
+            sync,
+            row,
+            ctb_x: if row == 0 { sync.first_ctb_x } else { 0 },
+            contexts: sync.load(row),
+            reader,
+            stored: false,
+        })
+    }
+
+    /// starts the next CTU, returning the reader and the contexts to decode it with
+    pub fn start_ctu(&mut self) -> (&mut H265Reader<&'a [u8]>, &mut C) {
+        (&mut self.reader, &mut self.contexts)
+    }
+
+    /// ends the current CTU by reading `end_of_slice_segment_flag` and at the end of the row
+    /// `end_of_subset_one_bit`. Returns true if there are more CTUs in this substream.
+    pub fn end_ctu(&mut self) -> Result<bool> {
+        if self.ctb_x == 1 {
+            self.sync.store(self.row, Some(self.contexts.clone()));
+            self.stored = true;
+        }
+
+        if self.reader.get_terminate()? {
+            // end_of_slice_segment_flag
+            return Ok(false);
+        }
+
+        self.ctb_x += 1;
+        if self.ctb_x == self.sync.width_in_ctbs {
+            if !self.reader.get_terminate()? {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "end_of_subset_one_bit not set at the end of the row",
+                ));
+            }
+
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+impl<'a, C: Clone> Drop for WppReader<'a, C> {
+    /// make sure that the next row doesn't wait forever if this row never reached its second CTU
+    fn drop(&mut self) {
+        if !self.stored {
+            self.sync.store(self.row, None);
+        }
+    }
+}
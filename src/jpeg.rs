@@ -0,0 +1,1036 @@
+//! JPEG arithmetic coding (QM-coder) as specified in ITU T.81 Annex D, along with the
+//! statistics areas and binarization of DCT coefficients for sequential mode from Annex F.
+//!
+//! The QM-coder uses a 16 bit interval register with a probability estimation state machine
+//! (Table D.2) per context, with a conditional exchange of the MPS and LPS when the interval
+//! of the LPS would be larger. The entropy coded segment stuffs a zero after each 0xFF byte so
+//! it can't be confused with a marker, and the decoder stops consuming input once it hits a
+//! marker and supplies zeros from then on.
+//!
+//! The output is byte-identical to the arithmetic coder in libjpeg (jcarith.c/jdarith.c).
+/*
+ * Copyright (C) 2009-2011, Guido Vollbeding.
+ * This file is part of the Independent JPEG Group's software.
+ * For conditions of distribution and use, see the accompanying README file.
+ */
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::traits::{CabacReader, CabacWriter};
+
+struct QeEntry {
+    qe: u32,
+    next_lps: u8,
+    next_mps: u8,
+    switch_mps: bool,
+}
+
+impl QeEntry {
+    const fn new(qe: u32, next_lps: u8, next_mps: u8, switch_mps: bool) -> Self {
+        QeEntry {
+            qe,
+            next_lps,
+            next_mps,
+            switch_mps,
+        }
+    }
+}
+
+/// Qe values and probability estimation state machine from Table D.2. The last entry is not
+/// part of T.81, it is a fixed probability of 0.5 that never changes (as recommended in
+/// T.851 and used by libjpeg for the sign of AC coefficients).
+const QE_TABLE: [QeEntry; 114] = [
+    QeEntry::new(0x5a1d, 1, 1, true),
+    QeEntry::new(0x2586, 14, 2, false),
+    QeEntry::new(0x1114, 16, 3, false),
+    QeEntry::new(0x080b, 18, 4, false),
+    QeEntry::new(0x03d8, 20, 5, false),
+    QeEntry::new(0x01da, 23, 6, false),
+    QeEntry::new(0x00e5, 25, 7, false),
+    QeEntry::new(0x006f, 28, 8, false),
+    QeEntry::new(0x0036, 30, 9, false),
+    QeEntry::new(0x001a, 33, 10, false),
+    QeEntry::new(0x000d, 35, 11, false),
+    QeEntry::new(0x0006, 9, 12, false),
+    QeEntry::new(0x0003, 10, 13, false),
+    QeEntry::new(0x0001, 12, 13, false),
+    QeEntry::new(0x5a7f, 15, 15, true),
+    QeEntry::new(0x3f25, 36, 16, false),
+    QeEntry::new(0x2cf2, 38, 17, false),
+    QeEntry::new(0x207c, 39, 18, false),
+    QeEntry::new(0x17b9, 40, 19, false),
+    QeEntry::new(0x1182, 42, 20, false),
+    QeEntry::new(0x0cef, 43, 21, false),
+    QeEntry::new(0x09a1, 45, 22, false),
+    QeEntry::new(0x072f, 46, 23, false),
+    QeEntry::new(0x055c, 48, 24, false),
+    QeEntry::new(0x0406, 49, 25, false),
+    QeEntry::new(0x0303, 51, 26, false),
+    QeEntry::new(0x0240, 52, 27, false),
+    QeEntry::new(0x01b1, 54, 28, false),
+    QeEntry::new(0x0144, 56, 29, false),
+    QeEntry::new(0x00f5, 57, 30, false),
+    QeEntry::new(0x00b7, 59, 31, false),
+    QeEntry::new(0x008a, 60, 32, false),
+    QeEntry::new(0x0068, 62, 33, false),
+    QeEntry::new(0x004e, 63, 34, false),
+    QeEntry::new(0x003b, 32, 35, false),
+    QeEntry::new(0x002c, 33, 9, false),
+    QeEntry::new(0x5ae1, 37, 37, true),
+    QeEntry::new(0x484c, 64, 38, false),
+    QeEntry::new(0x3a0d, 65, 39, false),
+    QeEntry::new(0x2ef1, 67, 40, false),
+    QeEntry::new(0x261f, 68, 41, false),
+    QeEntry::new(0x1f33, 69, 42, false),
+    QeEntry::new(0x19a8, 70, 43, false),
+    QeEntry::new(0x1518, 72, 44, false),
+    QeEntry::new(0x1177, 73, 45, false),
+    QeEntry::new(0x0e74, 74, 46, false),
+    QeEntry::new(0x0bfb, 75, 47, false),
+    QeEntry::new(0x09f8, 77, 48, false),
+    QeEntry::new(0x0861, 78, 49, false),
+    QeEntry::new(0x0706, 79, 50, false),
+    QeEntry::new(0x05cd, 48, 51, false),
+    QeEntry::new(0x04de, 50, 52, false),
+    QeEntry::new(0x040f, 50, 53, false),
+    QeEntry::new(0x0363, 51, 54, false),
+    QeEntry::new(0x02d4, 52, 55, false),
+    QeEntry::new(0x025c, 53, 56, false),
+    QeEntry::new(0x01f8, 54, 57, false),
+    QeEntry::new(0x01a4, 55, 58, false),
+    QeEntry::new(0x0160, 56, 59, false),
+    QeEntry::new(0x0125, 57, 60, false),
+    QeEntry::new(0x00f6, 58, 61, false),
+    QeEntry::new(0x00cb, 59, 62, false),
+    QeEntry::new(0x00ab, 61, 63, false),
+    QeEntry::new(0x008f, 61, 32, false),
+    QeEntry::new(0x5b12, 65, 65, true),
+    QeEntry::new(0x4d04, 80, 66, false),
+    QeEntry::new(0x412c, 81, 67, false),
+    QeEntry::new(0x37d8, 82, 68, false),
+    QeEntry::new(0x2fe8, 83, 69, false),
+    QeEntry::new(0x293c, 84, 70, false),
+    QeEntry::new(0x2379, 86, 71, false),
+    QeEntry::new(0x1edf, 87, 72, false),
+    QeEntry::new(0x1aa9, 87, 73, false),
+    QeEntry::new(0x174e, 72, 74, false),
+    QeEntry::new(0x1424, 72, 75, false),
+    QeEntry::new(0x119c, 74, 76, false),
+    QeEntry::new(0x0f6b, 74, 77, false),
+    QeEntry::new(0x0d51, 75, 78, false),
+    QeEntry::new(0x0bb6, 77, 79, false),
+    QeEntry::new(0x0a40, 77, 48, false),
+    QeEntry::new(0x5832, 80, 81, true),
+    QeEntry::new(0x4d1c, 88, 82, false),
+    QeEntry::new(0x438e, 89, 83, false),
+    QeEntry::new(0x3bdd, 90, 84, false),
+    QeEntry::new(0x34ee, 91, 85, false),
+    QeEntry::new(0x2eae, 92, 86, false),
+    QeEntry::new(0x299a, 93, 87, false),
+    QeEntry::new(0x2516, 86, 71, false),
+    QeEntry::new(0x5570, 88, 89, true),
+    QeEntry::new(0x4ca9, 95, 90, false),
+    QeEntry::new(0x44d9, 96, 91, false),
+    QeEntry::new(0x3e22, 97, 92, false),
+    QeEntry::new(0x3824, 99, 93, false),
+    QeEntry::new(0x32b4, 99, 94, false),
+    QeEntry::new(0x2e17, 93, 86, false),
+    QeEntry::new(0x56a8, 95, 96, true),
+    QeEntry::new(0x4f46, 101, 97, false),
+    QeEntry::new(0x47e5, 102, 98, false),
+    QeEntry::new(0x41cf, 103, 99, false),
+    QeEntry::new(0x3c3d, 104, 100, false),
+    QeEntry::new(0x375e, 99, 93, false),
+    QeEntry::new(0x5231, 105, 102, false),
+    QeEntry::new(0x4c0f, 106, 103, false),
+    QeEntry::new(0x4639, 107, 104, false),
+    QeEntry::new(0x415e, 103, 99, false),
+    QeEntry::new(0x5627, 105, 106, true),
+    QeEntry::new(0x50e7, 108, 107, false),
+    QeEntry::new(0x4b85, 109, 103, false),
+    QeEntry::new(0x5597, 110, 109, false),
+    QeEntry::new(0x504f, 111, 107, false),
+    QeEntry::new(0x5a10, 110, 111, true),
+    QeEntry::new(0x5522, 112, 109, false),
+    QeEntry::new(0x59eb, 112, 111, true),
+    QeEntry::new(0x5a1d, 113, 113, false),
+];
+
+/// state of the fixed probability entry
+const FIXED_STATE: u8 = 113;
+
+/// context for the QM-coder. Stores the index into the probability estimation table in the
+/// lower 7 bits and the more probable symbol in the top bit, same as libjpeg.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct QMContext {
+    state: u8,
+}
+
+impl QMContext {
+    /// context that always codes with a probability of 0.5 and never adapts
+    pub const fn fixed() -> Self {
+        QMContext { state: FIXED_STATE }
+    }
+
+    /// returns the index into the probability estimation table
+    pub fn get_index(&self) -> u8 {
+        self.state & 0x7f
+    }
+
+    /// returns the more probable symbol
+    pub fn get_mps(&self) -> bool {
+        self.state & 0x80 != 0
+    }
+
+    #[inline(always)]
+    fn estimate_after_lps(&mut self) {
+        let e = &QE_TABLE[usize::from(self.get_index())];
+        self.state = (self.state & 0x80) ^ (u8::from(e.switch_mps) << 7) ^ e.next_lps;
+    }
+
+    #[inline(always)]
+    fn estimate_after_mps(&mut self) {
+        let e = &QE_TABLE[usize::from(self.get_index())];
+        self.state = (self.state & 0x80) | e.next_mps;
+    }
+
+    #[inline(always)]
+    fn qe(&self) -> u32 {
+        QE_TABLE[usize::from(self.get_index())].qe
+    }
+}
+
+pub struct QMWriter<W> {
+    writer: W,
+    c: u32,
+    a: u32,
+    /// number of stacked 0xFF bytes that might still be changed by a carry
+    sc: u32,
+    /// number of pending zero bytes, which are dropped if they are at the end of the segment
+    zc: u32,
+    ct: i32,
+    /// byte waiting to be written, or None at the start of the segment
+    buffer: Option<u8>,
+}
+
+impl<W: Write> QMWriter<W> {
+    pub fn new(writer: W) -> Self {
+        QMWriter {
+            writer,
+            c: 0,
+            a: 0x10000,
+            sc: 0,
+            zc: 0,
+            ct: 11,
+            buffer: None,
+        }
+    }
+
+    /// writes the bit with the given context and updates the probability estimate
+    /// (sections D.1.4 and D.1.5)
+    #[inline(always)]
+    pub fn put_with_context(&mut self, bit: bool, context: &mut QMContext) -> Result<()> {
+        let qe = context.qe();
+
+        self.a -= qe;
+        if bit != context.get_mps() {
+            // code the LPS, unless its interval would be larger than the one of the MPS in
+            // which case the symbols are exchanged
+            if self.a >= qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            context.estimate_after_lps();
+        } else {
+            if self.a >= 0x8000 {
+                return Ok(());
+            }
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            context.estimate_after_mps();
+        }
+
+        self.renormalize()
+    }
+
+    /// section D.1.6
+    fn renormalize(&mut self) -> Result<()> {
+        loop {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                self.byte_out()?;
+                self.c &= 0x7ffff;
+                self.ct += 8;
+            }
+
+            if self.a >= 0x8000 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// moves the next byte out of the C register, handling the carry into the buffered and
+    /// stacked bytes (section D.1.6.1)
+    fn byte_out(&mut self) -> Result<()> {
+        let temp = self.c >> 19;
+        if temp > 0xff {
+            if let Some(b) = self.buffer {
+                self.emit_zeros()?;
+                self.emit_stuffed(b + 1)?;
+            }
+
+            // the carry turns the stacked 0xFF bytes into zeros
+            self.zc += self.sc;
+            self.sc = 0;
+
+            // the 3 spacer bits in C guarantee this can't be 0xFF
+            self.buffer = Some(temp as u8);
+        } else if temp == 0xff {
+            self.sc += 1;
+        } else {
+            self.emit_buffer_and_stack()?;
+            self.buffer = Some(temp as u8);
+        }
+        Ok(())
+    }
+
+    /// writes out the buffered byte and the stacked 0xFF bytes once no carry can reach them
+    fn emit_buffer_and_stack(&mut self) -> Result<()> {
+        match self.buffer {
+            Some(0) => self.zc += 1,
+            Some(b) => {
+                self.emit_zeros()?;
+                self.writer.write_all(&[b])?;
+            }
+            None => {}
+        }
+
+        if self.sc > 0 {
+            self.emit_zeros()?;
+            for _ in 0..self.sc {
+                self.writer.write_all(&[0xff, 0])?;
+            }
+            self.sc = 0;
+        }
+        Ok(())
+    }
+
+    fn emit_zeros(&mut self) -> Result<()> {
+        for _ in 0..self.zc {
+            self.writer.write_all(&[0])?;
+        }
+        self.zc = 0;
+        Ok(())
+    }
+
+    /// writes a byte, followed by a stuffed zero if it is 0xFF so that it isn't a marker
+    fn emit_stuffed(&mut self, b: u8) -> Result<()> {
+        if b == 0xff {
+            self.writer.write_all(&[0xff, 0])
+        } else {
+            self.writer.write_all(&[b])
+        }
+    }
+
+    /// writes a bit with a fixed probability of 0.5
+    pub fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_with_context(bit, &mut QMContext::fixed())
+    }
+
+    /// terminates the entropy coded segment (section D.1.8), choosing the value in the final
+    /// interval with the most trailing zeros and dropping trailing zero bytes. Afterwards the
+    /// writer is ready for the next segment (for example after a restart marker).
+    pub fn finish(&mut self) -> Result<()> {
+        let temp = (self.a - 1 + self.c) & 0xffff0000;
+        self.c = if temp < self.c { temp + 0x8000 } else { temp };
+
+        self.c <<= self.ct;
+        if self.c & 0xf8000000 != 0 {
+            // one final carry
+            if let Some(b) = self.buffer {
+                self.emit_zeros()?;
+                self.emit_stuffed(b + 1)?;
+            }
+            self.zc += self.sc;
+            self.sc = 0;
+        } else {
+            self.emit_buffer_and_stack()?;
+        }
+
+        // only write the final bytes if they aren't zero
+        if self.c & 0x7fff800 != 0 {
+            self.emit_zeros()?;
+            self.emit_stuffed((self.c >> 19) as u8)?;
+            if self.c & 0x7f800 != 0 {
+                self.emit_stuffed((self.c >> 11) as u8)?;
+            }
+        }
+
+        self.c = 0;
+        self.a = 0x10000;
+        self.sc = 0;
+        self.zc = 0;
+        self.ct = 11;
+        self.buffer = None;
+        Ok(())
+    }
+}
+
+impl<W: Write> CabacWriter<QMContext> for QMWriter<W> {
+    #[inline(always)]
+    fn put(&mut self, bit: bool, context: &mut QMContext) -> Result<()> {
+        self.put_with_context(bit, context)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+pub struct QMReader<R> {
+    reader: R,
+    c: u32,
+    a: u32,
+    ct: i32,
+    marker: Option<u8>,
+}
+
+impl<R: Read> QMReader<R> {
+    pub fn new(reader: R) -> Self {
+        QMReader {
+            reader,
+            c: 0,
+            a: 0,
+            // forces reading the 2 initial bytes
+            ct: -16,
+            marker: None,
+        }
+    }
+
+    /// returns the marker that ended the entropy coded segment if one was reached. Hitting a
+    /// marker is legal, the rest of the segment is decoded as if it were zeros.
+    pub fn get_marker(&self) -> Option<u8> {
+        self.marker
+    }
+
+    /// prepares for decoding the next entropy coded segment, for example after a restart
+    /// marker was read
+    pub fn reset(&mut self) {
+        self.c = 0;
+        self.a = 0;
+        self.ct = -16;
+        self.marker = None;
+    }
+
+    /// reads a bit with the given context and updates the probability estimate
+    /// (sections D.2.4 to D.2.6)
+    #[inline(always)]
+    pub fn get_with_context(&mut self, context: &mut QMContext) -> Result<bool> {
+        while self.a < 0x8000 {
+            self.ct -= 1;
+            if self.ct < 0 {
+                let data = self.byte_in()?;
+                self.c = (self.c << 8) | u32::from(data);
+                self.ct += 8;
+                if self.ct < 0 {
+                    self.ct += 1;
+                    if self.ct == 0 {
+                        // got the 2 initial bytes, A becomes 0x10000 below
+                        self.a = 0x8000;
+                    }
+                }
+            }
+            self.a <<= 1;
+        }
+
+        let qe = context.qe();
+        let mut bit = context.get_mps();
+
+        self.a -= qe;
+        let temp = self.a << self.ct;
+        if self.c >= temp {
+            self.c -= temp;
+
+            // conditional exchange of the LPS
+            if self.a < qe {
+                context.estimate_after_mps();
+            } else {
+                context.estimate_after_lps();
+                bit = !bit;
+            }
+            self.a = qe;
+        } else if self.a < 0x8000 {
+            // conditional exchange of the MPS
+            if self.a < qe {
+                context.estimate_after_lps();
+                bit = !bit;
+            } else {
+                context.estimate_after_mps();
+            }
+        }
+
+        Ok(bit)
+    }
+
+    /// reads the next byte of the segment, removing the stuffed zero after 0xFF and
+    /// supplying zeros once a marker or the end of the input is reached
+    fn byte_in(&mut self) -> Result<u8> {
+        if self.marker.is_some() {
+            return Ok(0);
+        }
+
+        let mut data = self.read_byte()?;
+        if data == Some(0xff) {
+            // swallow fill bytes
+            while data == Some(0xff) {
+                data = self.read_byte()?;
+            }
+
+            match data {
+                Some(0) => return Ok(0xff),
+                Some(m) => self.marker = Some(m),
+                None => {}
+            }
+            return Ok(0);
+        }
+
+        Ok(data.unwrap_or(0))
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut v = [0u8; 1];
+        if self.reader.read(&mut v)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(v[0]))
+    }
+
+    /// reads a bit with a fixed probability of 0.5
+    pub fn get_bypass(&mut self) -> Result<bool> {
+        self.get_with_context(&mut QMContext::fixed())
+    }
+}
+
+impl<R: Read> CabacReader<QMContext> for QMReader<R> {
+    #[inline(always)]
+    fn get(&mut self, context: &mut QMContext) -> Result<bool> {
+        self.get_with_context(context)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
+
+/// number of bins in a DC statistics area (Table F.4): 5 conditioning categories of 4 bins
+/// (S0, SS, SP and SN) at 0-19, the magnitude categories X1-X15 at 20-34 and the magnitude
+/// bits M2-M15 at 34-48, padded to 64 like libjpeg
+const DC_STAT_BINS: usize = 64;
+
+/// number of bins in an AC statistics area (Table F.5): SE, S0 and SP/SN/X1 for each of the
+/// 63 coefficients at 0-188, followed by X2-X15 and M2-M15 for the low frequencies (k <= Kx)
+/// at 189 and for the high frequencies at 217
+const AC_STAT_BINS: usize = 256;
+
+/// bin of X1 in a DC statistics area
+const DC_X1: usize = 20;
+
+/// statistics area for coding DC differences with one table, along with the conditioning
+/// bounds L and U from the DAC marker
+#[derive(Clone)]
+pub struct JpegDcContexts {
+    bins: [QMContext; DC_STAT_BINS],
+    lower: u8,
+    upper: u8,
+}
+
+impl Default for JpegDcContexts {
+    /// default conditioning from section F.1.4.4.1.4 (L = 0, U = 1)
+    fn default() -> Self {
+        Self::new(0, 1)
+    }
+}
+
+impl JpegDcContexts {
+    pub fn new(lower: u8, upper: u8) -> Self {
+        assert!(lower <= upper && upper <= 15);
+        JpegDcContexts {
+            bins: [QMContext::default(); DC_STAT_BINS],
+            lower,
+            upper,
+        }
+    }
+
+    /// returns the conditioning category for the next difference of the component from the
+    /// magnitude category of the current one (section F.1.4.4.1.2)
+    fn category(&self, m: u32, sign: bool) -> usize {
+        if m < (1 << self.lower) >> 1 {
+            0
+        } else if m > (1 << self.upper) >> 1 {
+            12 + usize::from(sign) * 4
+        } else {
+            4 + usize::from(sign) * 4
+        }
+    }
+}
+
+/// statistics area for coding AC coefficients with one table, along with the Kx boundary
+/// between low and high frequencies from the DAC marker
+#[derive(Clone)]
+pub struct JpegAcContexts {
+    bins: [QMContext; AC_STAT_BINS],
+    kx: u8,
+}
+
+impl Default for JpegAcContexts {
+    /// default Kx from section F.1.4.4.2.1
+    fn default() -> Self {
+        Self::new(5)
+    }
+}
+
+impl JpegAcContexts {
+    pub fn new(kx: u8) -> Self {
+        assert!((1..=63).contains(&kx));
+        JpegAcContexts {
+            bins: [QMContext::default(); AC_STAT_BINS],
+            kx,
+        }
+    }
+
+    /// start of the X2-X15 bins for coefficient k
+    fn x2(&self, k: usize) -> usize {
+        if k <= usize::from(self.kx) {
+            189
+        } else {
+            217
+        }
+    }
+}
+
+fn bad_code() -> Error {
+    Error::new(ErrorKind::InvalidData, "corrupt arithmetic coded data")
+}
+
+/// returns |value| - 1 of a nonzero DC difference or AC coefficient, whose magnitude
+/// categories only go up to 2^15
+fn magnitude(value: i32) -> Result<u32> {
+    let v = value.unsigned_abs() - 1;
+    if v >= 1 << 15 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "magnitude must be at most 32768",
+        ));
+    }
+    Ok(v)
+}
+
+impl<W: Write> QMWriter<W> {
+    /// writes the difference of a DC coefficient to the prediction (section F.1.4.1).
+    /// `category` is the conditioning category of the previous difference of the same
+    /// component, which starts at 0 at the beginning of each scan and restart interval.
+    pub fn put_dc_diff(
+        &mut self,
+        diff: i32,
+        contexts: &mut JpegDcContexts,
+        category: &mut usize,
+    ) -> Result<()> {
+        let mut st = *category;
+        if diff == 0 {
+            self.put_with_context(false, &mut contexts.bins[st])?;
+            *category = 0;
+            return Ok(());
+        }
+
+        let v = magnitude(diff)?;
+        self.put_with_context(true, &mut contexts.bins[st])?;
+
+        // sign is coded with SS, the magnitude category starts at SP or SN
+        let sign = diff < 0;
+        self.put_with_context(sign, &mut contexts.bins[st + 1])?;
+        st += 2 + usize::from(sign);
+
+        let (m, st) = self.put_magnitude_category(v, &mut contexts.bins, st, DC_X1)?;
+        *category = contexts.category(m, sign);
+
+        self.put_magnitude_bits(v, m, &mut contexts.bins, st)
+    }
+
+    /// writes the AC coefficients 1-63 of a block in zigzag order (section F.1.4.2), the
+    /// coefficient at index 0 is ignored
+    pub fn put_ac_block(&mut self, block: &[i16; 64], contexts: &mut JpegAcContexts) -> Result<()> {
+        let Some(eob) = (1..64).rev().find(|&k| block[k] != 0) else {
+            return self.put_with_context(true, &mut contexts.bins[0]);
+        };
+
+        let mut k = 1;
+        while k <= eob {
+            let mut st = 3 * (k - 1);
+            self.put_with_context(false, &mut contexts.bins[st])?;
+
+            while block[k] == 0 {
+                self.put_with_context(false, &mut contexts.bins[st + 1])?;
+                st += 3;
+                k += 1;
+            }
+            self.put_with_context(true, &mut contexts.bins[st + 1])?;
+
+            let v = magnitude(i32::from(block[k]))?;
+            self.put_bypass(block[k] < 0)?;
+
+            let x2 = contexts.x2(k);
+            let (m, st) = self.put_magnitude_category(v, &mut contexts.bins, st + 2, x2)?;
+            self.put_magnitude_bits(v, m, &mut contexts.bins, st)?;
+
+            k += 1;
+        }
+
+        if k <= 63 {
+            self.put_with_context(true, &mut contexts.bins[3 * (k - 1)])?;
+        }
+        Ok(())
+    }
+
+    /// writes the magnitude category of v = |value| - 1 in unary (Figure F.8). The first
+    /// decision uses the bin at `first`, the rest the X1.. (DC) or X2.. (AC) bins at `x`.
+    /// Returns the top bit of v (or 0 if v is 0) and the bin of the magnitude bits.
+    fn put_magnitude_category(
+        &mut self,
+        v: u32,
+        bins: &mut [QMContext],
+        first: usize,
+        x: usize,
+    ) -> Result<(u32, usize)> {
+        if v == 0 {
+            self.put_with_context(false, &mut bins[first])?;
+            return Ok((0, first + 14));
+        }
+        self.put_with_context(true, &mut bins[first])?;
+
+        // DC continues at X1 while AC codes X1 in the same bin as the first decision
+        let mut m = 1;
+        if x != DC_X1 {
+            if v == 1 {
+                self.put_with_context(false, &mut bins[first])?;
+                return Ok((1, first + 14));
+            }
+            self.put_with_context(true, &mut bins[first])?;
+            m = 2;
+        }
+
+        let mut st = x;
+        while v >= m << 1 {
+            self.put_with_context(true, &mut bins[st])?;
+            m <<= 1;
+            st += 1;
+        }
+        self.put_with_context(false, &mut bins[st])?;
+        Ok((m, st + 14))
+    }
+
+    /// writes the bits of v below the top bit m (Figure F.9) with the bin `st`
+    fn put_magnitude_bits(
+        &mut self,
+        v: u32,
+        mut m: u32,
+        bins: &mut [QMContext],
+        st: usize,
+    ) -> Result<()> {
+        while m > 1 {
+            m >>= 1;
+            self.put_with_context(v & m != 0, &mut bins[st])?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> QMReader<R> {
+    /// reads the difference of a DC coefficient to the prediction (section F.2.4.1), see
+    /// `QMWriter::put_dc_diff` for the meaning of category
+    pub fn get_dc_diff(
+        &mut self,
+        contexts: &mut JpegDcContexts,
+        category: &mut usize,
+    ) -> Result<i32> {
+        let mut st = *category;
+        if !self.get_with_context(&mut contexts.bins[st])? {
+            *category = 0;
+            return Ok(0);
+        }
+
+        let sign = self.get_with_context(&mut contexts.bins[st + 1])?;
+        st += 2 + usize::from(sign);
+
+        let (m, st) = self.get_magnitude_category(&mut contexts.bins, st, DC_X1)?;
+        *category = contexts.category(m, sign);
+
+        let v = self.get_magnitude_bits(m, &mut contexts.bins, st)? as i32 + 1;
+        Ok(if sign { -v } else { v })
+    }
+
+    /// reads the AC coefficients 1-63 of a block in zigzag order (section F.2.4.2), the
+    /// coefficients after the end of block are set to zero and index 0 is left alone
+    pub fn get_ac_block(
+        &mut self,
+        block: &mut [i16; 64],
+        contexts: &mut JpegAcContexts,
+    ) -> Result<()> {
+        block[1..].fill(0);
+
+        let mut k = 1;
+        while k <= 63 {
+            let mut st = 3 * (k - 1);
+            if self.get_with_context(&mut contexts.bins[st])? {
+                break;
+            }
+
+            while !self.get_with_context(&mut contexts.bins[st + 1])? {
+                st += 3;
+                k += 1;
+                if k > 63 {
+                    return Err(bad_code());
+                }
+            }
+
+            let sign = self.get_bypass()?;
+
+            let x2 = contexts.x2(k);
+            let (m, st) = self.get_magnitude_category(&mut contexts.bins, st + 2, x2)?;
+            // a magnitude of 32768 only fits as a negative coefficient
+            let v = self.get_magnitude_bits(m, &mut contexts.bins, st)? as i32 + 1;
+            block[k] = i16::try_from(if sign { -v } else { v }).map_err(|_| bad_code())?;
+
+            k += 1;
+        }
+        Ok(())
+    }
+
+    /// reads the magnitude category (Figure F.23), returns the top bit of the magnitude and
+    /// the bin of the magnitude bits
+    fn get_magnitude_category(
+        &mut self,
+        bins: &mut [QMContext],
+        first: usize,
+        x: usize,
+    ) -> Result<(u32, usize)> {
+        if !self.get_with_context(&mut bins[first])? {
+            return Ok((0, first + 14));
+        }
+
+        let mut st = x;
+        let mut m = 1;
+        if x != DC_X1 {
+            if !self.get_with_context(&mut bins[first])? {
+                return Ok((1, first + 14));
+            }
+            m = 2;
+        }
+
+        while self.get_with_context(&mut bins[st])? {
+            m <<= 1;
+            if m == 0x8000 {
+                return Err(bad_code());
+            }
+            st += 1;
+        }
+        Ok((m, st + 14))
+    }
+
+    /// reads the bits of the magnitude below the top bit m (Figure F.24)
+    fn get_magnitude_bits(&mut self, mut m: u32, bins: &mut [QMContext], st: usize) -> Result<u32> {
+        let mut v = m;
+        while m > 1 {
+            m >>= 1;
+            if self.get_with_context(&mut bins[st])? {
+                v |= m;
+            }
+        }
+        Ok(v)
+    }
+}
+
+/// entropy coded segment written by libjpeg-turbo 2.1.5 (Debian libjpeg62-turbo 1:2.1.5-2)
+/// for a 16x16 grayscale image at quality 75 with the default conditioning, terminated by the
+/// EOI marker. It is printed by `tests/data/libjpeg_arith.c`, which also describes the image.
+#[cfg(test)]
+const LIBJPEG_SEGMENT: [u8; 94] = [
+    0xff, 0x00, 0x76, 0xd2, 0x8f, 0x94, 0x00, 0x42, 0x07, 0xfb, 0x23, 0x38, 0x40, 0x59, 0xce, 0x60,
+    0x64, 0x7d, 0xca, 0xda, 0xfe, 0xc8, 0x0c, 0xf4, 0xcd, 0xdc, 0x29, 0x50, 0x91, 0x7e, 0xf3, 0x07,
+    0x02, 0xc5, 0x27, 0x45, 0xad, 0xdc, 0xc4, 0x88, 0x7a, 0x86, 0xc3, 0x02, 0xf9, 0xa6, 0x44, 0xdc,
+    0x09, 0xcd, 0x5f, 0xde, 0x3e, 0x66, 0xbc, 0xb1, 0x6b, 0x2e, 0xbe, 0xf0, 0xa9, 0xf4, 0x46, 0x81,
+    0xcf, 0xf5, 0x49, 0x50, 0x3b, 0x31, 0x54, 0xbb, 0xf8, 0x90, 0x42, 0x5a, 0x45, 0x7b, 0x15, 0xcb,
+    0xd0, 0x60, 0x94, 0xda, 0x66, 0x49, 0x7b, 0x94, 0x24, 0x1c, 0x65, 0x80, 0xff, 0xd9,
+];
+
+/// coefficients of the 4 blocks in zigzag order, as read back by libjpeg
+#[cfg(test)]
+const LIBJPEG_BLOCKS: [[i16; 64]; 4] = [
+    [
+        -42, -15, 0, 3, 3, 4, -2, -2, -2, -1, 1, 2, 2, 1, 1, 0, -1, -1, -1, -1, -1, 0, 1, 1, 1, 1,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+    ],
+    [
+        33, -39, -32, 9, 7, 2, -4, 3, 9, 1, 0, -6, -4, 0, 0, -1, 1, 0, -2, 0, 0, 0, 1, 2, 1, 0, 0,
+        0, -1, 0, 0, 0, 0, 0, 0, 0, 0, 0, -1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        -1, 0, 0, 0, 0, 0, 0,
+    ],
+    [
+        27, -25, -26, -8, -14, 0, -1, -1, -7, -6, -1, -1, 2, 0, -1, 0, -1, 1, 3, 1, 0, 0, 1, 2, 1,
+        0, 0, 0, -1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, -1, 0, 0, 0, -1, 0, 0, 0, -1, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ],
+    [
+        83, -18, -3, -5, -10, -7, 3, 8, 8, 4, -3, -6, -7, -5, -3, 1, 3, 4, 4, 4, 2, -1, -2, -2, -3,
+        -2, -1, -1, 0, 1, 1, 1, 1, 1, 1, 0, 0, -1, -1, -1, -1, -1, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ],
+];
+
+#[test]
+fn matches_libjpeg() {
+    let mut reader = QMReader::new(&LIBJPEG_SEGMENT[..]);
+    let mut dc = JpegDcContexts::default();
+    let mut ac = JpegAcContexts::default();
+    let mut category = 0;
+    let mut prediction = 0;
+
+    for expected in LIBJPEG_BLOCKS.iter() {
+        let mut block = [0; 64];
+        prediction += reader.get_dc_diff(&mut dc, &mut category).unwrap();
+        block[0] = prediction as i16;
+        reader.get_ac_block(&mut block, &mut ac).unwrap();
+        assert_eq!(&block, expected);
+    }
+    assert_eq!(reader.get_marker(), Some(0xd9));
+
+    let mut output = Vec::new();
+    let mut writer = QMWriter::new(&mut output);
+    let mut dc = JpegDcContexts::default();
+    let mut ac = JpegAcContexts::default();
+    let mut category = 0;
+    let mut prediction = 0;
+
+    for block in LIBJPEG_BLOCKS.iter() {
+        let diff = i32::from(block[0]) - prediction;
+        prediction = i32::from(block[0]);
+        writer.put_dc_diff(diff, &mut dc, &mut category).unwrap();
+        writer.put_ac_block(block, &mut ac).unwrap();
+    }
+    writer.finish().unwrap();
+
+    assert_eq!(output[..], LIBJPEG_SEGMENT[..LIBJPEG_SEGMENT.len() - 2]);
+}
+
+#[test]
+fn roundtrip_bits() {
+    let mut output = Vec::new();
+    let mut writer = QMWriter::new(&mut output);
+    let mut contexts = [QMContext::default(); 4];
+
+    for i in 0..10000u32 {
+        let bit = (i % 7 == 0) ^ (i % 13 == 0);
+        writer.put(bit, &mut contexts[(i & 3) as usize]).unwrap();
+        writer.put_bypass(i % 3 == 0).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // no marker may appear in the entropy coded segment
+    assert!(output.windows(2).all(|w| w[0] != 0xff || w[1] == 0));
+
+    let mut reader = QMReader::new(&output[..]);
+    let mut contexts = [QMContext::default(); 4];
+    for i in 0..10000u32 {
+        let bit = (i % 7 == 0) ^ (i % 13 == 0);
+        assert_eq!(reader.get(&mut contexts[(i & 3) as usize]).unwrap(), bit);
+        assert_eq!(reader.get_bypass().unwrap(), i % 3 == 0);
+    }
+    assert_eq!(reader.get_marker(), None);
+}
+
+#[test]
+fn ac_magnitude_limit() {
+    // codes the first coefficient with a magnitude of 32768, which is only valid if negative
+    for sign in [true, false] {
+        let mut output = Vec::new();
+        let mut writer = QMWriter::new(&mut output);
+        let mut ac = JpegAcContexts::default();
+        writer.put_with_context(false, &mut ac.bins[0]).unwrap();
+        writer.put_with_context(true, &mut ac.bins[1]).unwrap();
+        writer.put_bypass(sign).unwrap();
+        let x2 = ac.x2(1);
+        let (m, st) = writer
+            .put_magnitude_category(32767, &mut ac.bins, 2, x2)
+            .unwrap();
+        writer
+            .put_magnitude_bits(32767, m, &mut ac.bins, st)
+            .unwrap();
+        writer.put_with_context(true, &mut ac.bins[3]).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = QMReader::new(&output[..]);
+        let mut block = [0; 64];
+        let result = reader.get_ac_block(&mut block, &mut JpegAcContexts::default());
+        if sign {
+            result.unwrap();
+            assert_eq!(block[1], i16::MIN);
+        } else {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
+
+#[test]
+fn dc_magnitude_limit() {
+    let mut output = Vec::new();
+    let mut writer = QMWriter::new(&mut output);
+    let mut dc = JpegDcContexts::default();
+    let mut category = 0;
+    for diff in [32769, -32769, i32::MIN] {
+        let error = writer
+            .put_dc_diff(diff, &mut dc, &mut category)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+    for diff in [32768, -32768] {
+        writer.put_dc_diff(diff, &mut dc, &mut category).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = QMReader::new(&output[..]);
+    let mut dc = JpegDcContexts::default();
+    let mut category = 0;
+    for diff in [32768, -32768] {
+        assert_eq!(reader.get_dc_diff(&mut dc, &mut category).unwrap(), diff);
+    }
+}
+
+#[test]
+fn stops_at_marker() {
+    let mut output = Vec::new();
+    let mut writer = QMWriter::new(&mut output);
+    let mut context = QMContext::default();
+    for i in 0..100 {
+        writer.put(i % 5 == 0, &mut context).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // restart marker followed by data of the next interval
+    output.extend_from_slice(&[0xff, 0xd0, 0x12, 0x34]);
+
+    let mut input = &output[..];
+    let mut reader = QMReader::new(&mut input);
+    let mut context = QMContext::default();
+    for i in 0..100 {
+        assert_eq!(reader.get(&mut context).unwrap(), i % 5 == 0);
+    }
+    assert_eq!(reader.get_marker(), Some(0xd0));
+    assert_eq!(input, [0x12, 0x34]);
+}
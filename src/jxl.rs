@@ -0,0 +1,600 @@
+//! Integer coding in the style of the JPEG XL entropy coder.
+//!
+//! Values are split into a token from a small alphabet and raw extra bits ("hybrid uint").
+//! Values below `1 << split_exponent` are coded as the token itself, larger values code the
+//! position of the highest bit, `msb_in_token` bits below it and the lowest `lsb_in_token` bits
+//! in the token, and the bits in between are written as they are. The tokens are coded with
+//! 12-bit rANS and alias tables, using the state of the `rans32` module.
+//!
+//! Each value is coded in one of many contexts, and a context map assigns the contexts to a
+//! smaller number of clusters that share a distribution. `HybridUintCode::from_values` clusters
+//! the histograms of the contexts by how much it costs to merge them.
+//!
+//! As in JPEG XL, the ANS state starts at 0x130000 so that the decoder can check that it ended
+//! up in the right place, and the renormalization words and the extra bits are interleaved in a
+//! single bit stream in the order the decoder reads them. Since the encoder has to run in
+//! reverse, all values are buffered until `finish`. The histograms and the alias tables are not
+//! the ones of JPEG XL, so the output can't be read by a JPEG XL decoder.
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{
+    bitstream::{BitReader, BitWriter},
+    rans32::{Rans32State, RansLookup, RansSymbolTable, WriteU16},
+};
+
+/// precision of the token distributions
+const ANS_LOG_TAB_SIZE: u32 = 12;
+
+/// initial state of the encoder and final state of the decoder
+const ANS_SIGNATURE: u32 = 0x130000;
+
+/// extra cost in bits below which a histogram is merged into an existing cluster, which is
+/// about the size of a small table in the header
+const MIN_DISTANCE_FOR_DISTINCT: f64 = 64.0;
+
+type AnsState = Rans32State<ANS_LOG_TAB_SIZE>;
+type AnsTable = RansSymbolTable<ANS_LOG_TAB_SIZE>;
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// how values are split into a token and extra bits
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HybridUintConfig {
+    split_exponent: u32,
+    msb_in_token: u32,
+    lsb_in_token: u32,
+}
+
+impl HybridUintConfig {
+    /// `msb_in_token + lsb_in_token` can't be larger than `split_exponent`, and the tokens of
+    /// all u32 values have to fit into 256 symbols
+    pub fn new(split_exponent: u32, msb_in_token: u32, lsb_in_token: u32) -> Result<Self> {
+        if split_exponent > 8
+            || msb_in_token > split_exponent
+            || lsb_in_token > split_exponent - msb_in_token
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid hybrid uint configuration",
+            ));
+        }
+
+        let config = HybridUintConfig {
+            split_exponent,
+            msb_in_token,
+            lsb_in_token,
+        };
+        if config.encode(u32::MAX).0 > 255 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "tokens of the hybrid uint configuration don't fit into a byte",
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn split_exponent(&self) -> u32 {
+        self.split_exponent
+    }
+
+    pub fn msb_in_token(&self) -> u32 {
+        self.msb_in_token
+    }
+
+    pub fn lsb_in_token(&self) -> u32 {
+        self.lsb_in_token
+    }
+
+    /// returns the token, the number of extra bits and the extra bits of the value
+    pub fn encode(&self, value: u32) -> (u32, u32, u32) {
+        let split_token = 1 << self.split_exponent;
+        if value < split_token {
+            return (value, 0, 0);
+        }
+
+        let in_token = self.msb_in_token + self.lsb_in_token;
+        let n = 31 - value.leading_zeros();
+        let m = value - (1 << n);
+        let token = split_token
+            + ((n - self.split_exponent) << in_token)
+            + ((m >> (n - self.msb_in_token)) << self.lsb_in_token)
+            + (m & ((1 << self.lsb_in_token) - 1));
+        let nbits = n - in_token;
+        let bits = (value >> self.lsb_in_token) & ((1u64 << nbits) - 1) as u32;
+
+        (token, nbits, bits)
+    }
+
+    /// reads the extra bits of the token and returns the value
+    fn decode<R: Read>(&self, token: u32, reader: &mut BitReader<R>) -> Result<u32> {
+        let split_token = 1 << self.split_exponent;
+        if token < split_token {
+            return Ok(token);
+        }
+
+        let in_token = self.msb_in_token + self.lsb_in_token;
+        let n = self.split_exponent + ((token - split_token) >> in_token);
+        if n > 31 {
+            return Err(invalid_data("hybrid uint token out of range"));
+        }
+        let nbits = n - in_token;
+
+        let low = token & ((1 << self.lsb_in_token) - 1);
+        let high = (1 << self.msb_in_token)
+            | ((token >> self.lsb_in_token) & ((1 << self.msb_in_token) - 1));
+        let bits = reader.get_bits(nbits)?;
+
+        Ok((((high << nbits) | bits) << self.lsb_in_token) | low)
+    }
+
+    fn write(&self, writer: &mut BitWriter<impl Write>) -> Result<()> {
+        writer.put_ue(self.split_exponent)?;
+        writer.put_ue(self.msb_in_token)?;
+        writer.put_ue(self.lsb_in_token)
+    }
+
+    fn read(reader: &mut BitReader<impl Read>) -> Result<Self> {
+        let split_exponent = reader.get_ue()?;
+        let msb_in_token = reader.get_ue()?;
+        let lsb_in_token = reader.get_ue()?;
+        Self::new(split_exponent, msb_in_token, lsb_in_token)
+            .map_err(|_| invalid_data("invalid hybrid uint configuration"))
+    }
+}
+
+/// reads and writes whole bytes in the bit stream, for the tables and the rANS state
+struct BitBytes<'a, T>(&'a mut T);
+
+impl<R: Read> Read for BitBytes<'_, BitReader<R>> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        for b in buf.iter_mut() {
+            *b = self.0.get_bits(8)? as u8;
+        }
+        Ok(buf.len())
+    }
+}
+
+impl<W: Write> Write for BitBytes<'_, BitWriter<W>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for &b in buf {
+            self.0.put_bits(u32::from(b), 8)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// renormalization word of a single token
+impl WriteU16 for Option<u16> {
+    fn write_u16(&mut self, value: u16) {
+        debug_assert!(self.is_none());
+        *self = Some(value);
+    }
+}
+
+/// cost in bits of coding the counts with their own distribution
+fn histogram_cost(counts: &[u32]) -> f64 {
+    let total: u32 = counts.iter().sum();
+    counts
+        .iter()
+        .filter(|&&c| c != 0)
+        .map(|&c| f64::from(c) * (f64::from(total) / f64::from(c)).log2())
+        .sum()
+}
+
+/// extra cost in bits of coding two histograms with a shared distribution
+fn merge_cost(a: &[u32], b: &[u32]) -> f64 {
+    let merged: Vec<u32> = (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0) + b.get(i).unwrap_or(&0))
+        .collect();
+    histogram_cost(&merged) - histogram_cost(a) - histogram_cost(b)
+}
+
+/// picks up to `max_clusters` histograms that are far apart as cluster centers and assigns
+/// every context to the center it is cheapest to merge with. Returns the context map and the
+/// number of clusters.
+fn cluster_histograms(histograms: &[Vec<u32>], max_clusters: usize) -> (Vec<u8>, usize) {
+    let totals: Vec<u32> = histograms.iter().map(|h| h.iter().sum()).collect();
+    let Some(first) = (0..histograms.len())
+        .filter(|&i| totals[i] != 0)
+        .max_by_key(|&i| totals[i])
+    else {
+        return (vec![0; histograms.len()], 1);
+    };
+
+    let mut centers = vec![first];
+    let mut distance: Vec<f64> = histograms
+        .iter()
+        .map(|h| merge_cost(h, &histograms[first]))
+        .collect();
+
+    while centers.len() < max_clusters {
+        let (farthest, &d) = distance
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        if d < MIN_DISTANCE_FOR_DISTINCT {
+            break;
+        }
+
+        centers.push(farthest);
+        for (i, h) in histograms.iter().enumerate() {
+            distance[i] = distance[i].min(merge_cost(h, &histograms[farthest]));
+        }
+    }
+
+    let assignment: Vec<usize> = histograms
+        .iter()
+        .map(|h| {
+            (0..centers.len())
+                .min_by(|&a, &b| {
+                    merge_cost(h, &histograms[centers[a]])
+                        .total_cmp(&merge_cost(h, &histograms[centers[b]]))
+                })
+                .unwrap()
+        })
+        .collect();
+
+    // number the clusters in the order they are first used, dropping empty ones
+    let mut renumber = vec![None; centers.len()];
+    let mut num_clusters = 0;
+    let context_map = assignment
+        .iter()
+        .map(|&c| {
+            *renumber[c].get_or_insert_with(|| {
+                num_clusters += 1;
+                num_clusters - 1
+            }) as u8
+        })
+        .collect();
+
+    (context_map, num_clusters)
+}
+
+/// context map and the token distribution of each cluster
+pub struct HybridUintCode {
+    context_map: Vec<u8>,
+    configs: Vec<HybridUintConfig>,
+    tables: Vec<AnsTable>,
+}
+
+impl HybridUintCode {
+    /// builds the code for values given as (context, value), with up to `max_clusters`
+    /// distributions (at most 256)
+    pub fn from_values(
+        num_contexts: usize,
+        max_clusters: usize,
+        config: HybridUintConfig,
+        values: &[(usize, u32)],
+    ) -> Result<Self> {
+        if num_contexts == 0 || !(1..=256).contains(&max_clusters) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid number of contexts or clusters",
+            ));
+        }
+
+        let mut histograms = vec![vec![0u32; 256]; num_contexts];
+        for &(context, value) in values {
+            let h = histograms
+                .get_mut(context)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "context out of range"))?;
+            h[config.encode(value).0 as usize] += 1;
+        }
+
+        let (context_map, num_clusters) = cluster_histograms(&histograms, max_clusters);
+
+        let mut cluster_histograms = vec![vec![0u32; 256]; num_clusters];
+        for (h, &c) in histograms.iter().zip(context_map.iter()) {
+            for (sum, &count) in cluster_histograms[usize::from(c)].iter_mut().zip(h) {
+                *sum += count;
+            }
+        }
+
+        let tables = cluster_histograms
+            .iter_mut()
+            .map(|h| {
+                // clusters without values still need a valid table
+                match h.iter().rposition(|&c| c != 0) {
+                    Some(last) => h.truncate(last + 1),
+                    None => *h = vec![1],
+                }
+                AnsTable::from_counts(h, RansLookup::Alias)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(HybridUintCode {
+            context_map,
+            configs: vec![config; num_clusters],
+            tables,
+        })
+    }
+
+    pub fn num_contexts(&self) -> usize {
+        self.context_map.len()
+    }
+
+    pub fn num_clusters(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// returns the cluster whose distribution is used for the context, or an error if the
+    /// context is not in the context map
+    pub fn get_cluster(&self, context: usize) -> Result<usize> {
+        self.context_map
+            .get(context)
+            .map(|&c| usize::from(c))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "context out of range"))
+    }
+
+    /// writes the context map with a fixed number of bits per context, followed by the
+    /// configuration and the frequency table of each cluster
+    pub fn write_header(&self, writer: &mut BitWriter<impl Write>) -> Result<()> {
+        writer.put_ue(self.context_map.len() as u32 - 1)?;
+        writer.put_ue(self.tables.len() as u32 - 1)?;
+
+        let bits = usize::BITS - (self.tables.len() - 1).leading_zeros();
+        for &c in &self.context_map {
+            writer.put_bits(u32::from(c), bits)?;
+        }
+
+        for (config, table) in self.configs.iter().zip(self.tables.iter()) {
+            config.write(writer)?;
+            table.write_table(&mut BitBytes(writer))?;
+        }
+        Ok(())
+    }
+
+    /// reads a header written by `write_header`
+    pub fn read_header(reader: &mut BitReader<impl Read>) -> Result<Self> {
+        let num_contexts = reader.get_ue()? as usize + 1;
+        let num_clusters = reader.get_ue()? as usize + 1;
+        if num_clusters > 256 {
+            return Err(invalid_data("too many clusters"));
+        }
+
+        let bits = usize::BITS - (num_clusters - 1).leading_zeros();
+        let mut context_map = Vec::new();
+        for _ in 0..num_contexts {
+            let c = reader.get_bits(bits)?;
+            if c as usize >= num_clusters {
+                return Err(invalid_data("context mapped to a missing cluster"));
+            }
+            context_map.push(c as u8);
+        }
+
+        let mut configs = Vec::with_capacity(num_clusters);
+        let mut tables = Vec::with_capacity(num_clusters);
+        for _ in 0..num_clusters {
+            configs.push(HybridUintConfig::read(reader)?);
+            tables.push(AnsTable::read_table(
+                &mut BitBytes(reader),
+                RansLookup::Alias,
+            )?);
+        }
+
+        Ok(HybridUintCode {
+            context_map,
+            configs,
+            tables,
+        })
+    }
+}
+
+struct Token {
+    cluster: u8,
+    token: u8,
+    nbits: u8,
+    bits: u32,
+}
+
+/// writes values with a `HybridUintCode`. The values are buffered and written by `finish`.
+pub struct HybridUintWriter<'a, W: Write> {
+    writer: BitWriter<W>,
+    code: &'a HybridUintCode,
+    tokens: Vec<Token>,
+}
+
+impl<'a, W: Write> HybridUintWriter<'a, W> {
+    pub fn new(writer: W, code: &'a HybridUintCode) -> Self {
+        HybridUintWriter {
+            writer: BitWriter::new(writer),
+            code,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// writes the value in the context, whose token must have a nonzero frequency in the
+    /// distribution of the cluster
+    pub fn put(&mut self, context: usize, value: u32) -> Result<()> {
+        let cluster = self.code.get_cluster(context)?;
+        let (token, nbits, bits) = self.code.configs[cluster].encode(value);
+
+        let table = &self.code.tables[cluster];
+        if table.freq.get(token as usize).copied().unwrap_or(0) == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "token has a frequency of zero",
+            ));
+        }
+
+        self.tokens.push(Token {
+            cluster: cluster as u8,
+            token: token as u8,
+            nbits: nbits as u8,
+            bits,
+        });
+        Ok(())
+    }
+
+    /// encodes the tokens in reverse and writes the final state, followed by the
+    /// renormalization words and extra bits of each value. The output is padded to a byte.
+    pub fn finish(&mut self) -> Result<()> {
+        let mut state: AnsState = Rans32State(ANS_SIGNATURE);
+        let mut renormalization = vec![None; self.tokens.len()];
+
+        for (t, word) in self.tokens.iter().zip(renormalization.iter_mut()).rev() {
+            let table = &self.code.tables[usize::from(t.cluster)];
+            let s = usize::from(t.token);
+            let (start, freq) = (table.start[s] as usize, table.freq[s] as usize);
+            state.encode_slots(word, &table.alias_slots[start..start + freq]);
+        }
+
+        let mut out = BitBytes(&mut self.writer);
+        out.write_all(&state.0.to_le_bytes())?;
+        for (t, word) in self.tokens.iter().zip(renormalization) {
+            if let Some(word) = word {
+                out.0.put_bits(u32::from(word & 0xff), 8)?;
+                out.0.put_bits(u32::from(word >> 8), 8)?;
+            }
+            out.0.put_bits(t.bits, u32::from(t.nbits))?;
+        }
+
+        self.tokens.clear();
+        self.writer.byte_align()
+    }
+
+    /// returns the underlying writer, after `finish`
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+/// reads values written by `HybridUintWriter` with the same code
+pub struct HybridUintReader<'a, R: Read> {
+    reader: BitReader<R>,
+    code: &'a HybridUintCode,
+    state: AnsState,
+}
+
+impl<'a, R: Read> HybridUintReader<'a, R> {
+    pub fn new(reader: R, code: &'a HybridUintCode) -> Result<Self> {
+        let mut reader = BitReader::new(reader);
+        let state = AnsState::new_decoder(&mut BitBytes(&mut reader))?;
+        Ok(HybridUintReader {
+            reader,
+            code,
+            state,
+        })
+    }
+
+    /// reads a value in the context
+    pub fn get(&mut self, context: usize) -> Result<u32> {
+        let cluster = self.code.get_cluster(context)?;
+        let table = &self.code.tables[cluster];
+
+        let (token, start) = table.decode_slot(self.state.dec_get());
+        let freq = table.freq[usize::from(token)].try_into().unwrap();
+        self.state
+            .dec_advance(&mut BitBytes(&mut self.reader), start, freq)?;
+
+        self.code.configs[cluster].decode(u32::from(token), &mut self.reader)
+    }
+
+    /// checks that the decoder ended in the initial state of the encoder, which is the case
+    /// if all values were read and the data wasn't corrupted
+    pub fn check_final_state(&self) -> Result<()> {
+        if self.state.0 != ANS_SIGNATURE {
+            return Err(invalid_data("ANS state doesn't match the signature"));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn hybrid_uint_tokens() {
+    // 1000 = 0b1111101000: highest bit 9, the next 2 bits in the token, 7 extra bits
+    let config = HybridUintConfig::new(4, 2, 0).unwrap();
+    assert_eq!(config.encode(15), (15, 0, 0));
+    assert_eq!(config.encode(1000), (16 + (5 << 2) + 3, 7, 1000 & 127));
+
+    for (split_exponent, msb, lsb) in [(0, 0, 0), (4, 2, 0), (4, 1, 1), (5, 1, 1), (6, 0, 0)] {
+        let config = HybridUintConfig::new(split_exponent, msb, lsb).unwrap();
+
+        let values: Vec<u32> = (0..32)
+            .flat_map(|b| {
+                [
+                    1u32 << b,
+                    (1u32 << b) - 1,
+                    (1u32 << b) | 0x5555_5555 >> (31 - b),
+                ]
+            })
+            .chain([u32::MAX, 12345])
+            .collect();
+
+        let mut writer = BitWriter::new(Vec::new());
+        for &v in &values {
+            let (token, nbits, bits) = config.encode(v);
+            assert!(token < 256);
+            writer.put_bits(token, 8).unwrap();
+            writer.put_bits(bits, nbits).unwrap();
+        }
+        writer.byte_align().unwrap();
+        let output = writer.into_inner();
+
+        let mut reader = BitReader::new(&output[..]);
+        for &v in &values {
+            let token = reader.get_bits(8).unwrap();
+            assert_eq!(config.decode(token, &mut reader).unwrap(), v);
+        }
+    }
+
+    assert!(HybridUintConfig::new(4, 3, 2).is_err());
+    assert!(HybridUintConfig::new(8, 0, 0).is_err());
+}
+
+#[test]
+fn hybrid_uint_roundtrip_clustered() {
+    // 8 contexts with values from 2 different ranges, context 7 stays empty
+    let mut values = Vec::new();
+    let mut x = 1u32;
+    for i in 0..20000 {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+
+        let context = i % 7;
+        let value = if context % 2 == 0 {
+            x % 16
+        } else {
+            1000 + (x >> (x % 20)) % 4096
+        };
+        values.push((context, value));
+    }
+
+    let config = HybridUintConfig::new(4, 1, 1).unwrap();
+    let code = HybridUintCode::from_values(8, 16, config, &values).unwrap();
+    assert_eq!(code.num_clusters(), 2);
+    assert_eq!(code.get_cluster(2).unwrap(), code.get_cluster(4).unwrap());
+    assert_ne!(code.get_cluster(1).unwrap(), code.get_cluster(2).unwrap());
+    assert!(code.get_cluster(8).is_err());
+
+    let mut header = BitWriter::new(Vec::new());
+    code.write_header(&mut header).unwrap();
+    header.byte_align().unwrap();
+    let mut writer = HybridUintWriter::new(header.into_inner(), &code);
+    for &(context, value) in &values {
+        writer.put(context, value).unwrap();
+    }
+    assert!(writer.put(8, 0).is_err());
+    writer.finish().unwrap();
+    let output = writer.into_inner();
+
+    let mut header = BitReader::new(&output[..]);
+    let code = HybridUintCode::read_header(&mut header).unwrap();
+    header.byte_align();
+    assert_eq!(code.num_contexts(), 8);
+
+    let mut reader = HybridUintReader::new(header.into_inner(), &code).unwrap();
+    for &(context, value) in &values {
+        assert_eq!(reader.get(context).unwrap(), value);
+    }
+    reader.check_final_state().unwrap();
+    assert!(reader.get(8).is_err());
+}
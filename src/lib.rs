@@ -5,13 +5,31 @@
 #![forbid(unreachable_pub)]
 #![forbid(deprecated_in_future)]
 
+pub mod av1;
+pub mod bitstream;
 pub mod debug;
+pub mod fenwick;
 pub mod fpaq0;
 pub mod fpaq0parallel;
+pub mod fse;
+pub mod h264;
 pub mod h265;
+pub mod jpeg;
+pub mod jxl;
+pub mod lzma;
+pub mod mq;
+pub mod nal;
 pub mod perf;
 pub mod rans32;
+pub mod rans32x;
+pub mod rans64;
+pub mod syntax;
+#[cfg(test)]
+mod test_util;
 mod traits;
 pub mod vp8;
+pub mod vp9;
 
-pub use traits::{CabacReader, CabacWriter};
+pub use traits::{
+    CabacReader, CabacWriter, ProbabilityReader, ProbabilityWriter, SymbolReader, SymbolWriter,
+};
@@ -0,0 +1,447 @@
+//! Range coder layer of LZMA as used in .lzma and .xz streams, compatible with the range
+//! encoder and decoder in liblzma and the LZMA SDK.
+//!
+//! Probabilities are 11 bits and move 1/32 of the distance towards the coded bit. The encoder
+//! keeps a 33 bit low value along with a cached byte and a count of pending 0xFF bytes that a
+//! carry can still change. The stream starts with a 0 byte from the cache, so the decoder
+//! reads 5 bytes to initialize, and the encoder flushes 5 bytes at the end.
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::traits::{CabacReader, CabacWriter};
+
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP_VALUE: u32 = 1 << 24;
+
+/// probability of a bit being 0 in units of 1/2048, initialized to 0.5
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LzmaContext {
+    prob: u16,
+}
+
+impl Default for LzmaContext {
+    fn default() -> Self {
+        LzmaContext { prob: PROB_INIT }
+    }
+}
+
+impl LzmaContext {
+    pub fn get_probability(&self) -> u16 {
+        self.prob
+    }
+
+    #[inline(always)]
+    fn bound(&self, range: u32) -> u32 {
+        (range >> PROB_BITS) * u32::from(self.prob)
+    }
+
+    #[inline(always)]
+    fn update(&mut self, bit: bool) {
+        if bit {
+            self.prob -= self.prob >> MOVE_BITS;
+        } else {
+            self.prob += ((1 << PROB_BITS) - self.prob) >> MOVE_BITS;
+        }
+    }
+}
+
+pub struct LzmaWriter<W> {
+    writer: W,
+    low: u64,
+    range: u32,
+    cache: u8,
+    /// number of bytes waiting for a possible carry, the cached byte and the 0xFF bytes after it
+    cache_size: u64,
+}
+
+impl<W: Write> LzmaWriter<W> {
+    pub fn new(writer: W) -> Self {
+        LzmaWriter {
+            writer,
+            low: 0,
+            range: 0xffffffff,
+            cache: 0,
+            cache_size: 1,
+        }
+    }
+
+    /// rc_bit: writes the bit with the given context and updates the probability
+    #[inline(always)]
+    pub fn put_with_context(&mut self, bit: bool, context: &mut LzmaContext) -> Result<()> {
+        let bound = context.bound(self.range);
+        if bit {
+            self.low += u64::from(bound);
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        context.update(bit);
+
+        self.normalize()
+    }
+
+    /// rc_direct: writes the lowest `bits` bits of value, most significant first, with a
+    /// fixed probability of 0.5
+    pub fn put_direct_bits(&mut self, value: u32, bits: u32) -> Result<()> {
+        for i in (0..bits).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += u64::from(self.range);
+            }
+            self.normalize()?;
+        }
+        Ok(())
+    }
+
+    /// writes the interval [start, start + size) out of total, which can be at most 1 << 16.
+    /// This is the multi-symbol interface used by PPMd in 7-Zip.
+    pub(crate) fn put_frequency(&mut self, start: u32, size: u32, total: u32) -> Result<()> {
+        debug_assert!(size > 0 && start + size <= total && total <= 1 << 16);
+
+        self.range /= total;
+        self.low += u64::from(start) * u64::from(self.range);
+        self.range *= size;
+
+        self.normalize()
+    }
+
+    /// writes the lowest `bits` bits of value most significant bit first, each with the
+    /// context selected by the bits written so far. `contexts` needs `1 << bits` entries, the
+    /// first one is unused.
+    pub fn put_bit_tree(
+        &mut self,
+        value: u32,
+        bits: u32,
+        contexts: &mut [LzmaContext],
+    ) -> Result<()> {
+        let mut m = 1;
+        for i in (0..bits).rev() {
+            let bit = (value >> i) & 1;
+            self.put_with_context(bit != 0, &mut contexts[m])?;
+            m = (m << 1) | bit as usize;
+        }
+        Ok(())
+    }
+
+    /// like `put_bit_tree` but writes the least significant bit first, as used for the low
+    /// bits of match distances
+    pub fn put_reverse_bit_tree(
+        &mut self,
+        mut value: u32,
+        bits: u32,
+        contexts: &mut [LzmaContext],
+    ) -> Result<()> {
+        let mut m = 1;
+        for _ in 0..bits {
+            let bit = value & 1;
+            value >>= 1;
+            self.put_with_context(bit != 0, &mut contexts[m])?;
+            m = (m << 1) | bit as usize;
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn normalize(&mut self) -> Result<()> {
+        while self.range < TOP_VALUE {
+            self.range <<= 8;
+            self.shift_low()?;
+        }
+        Ok(())
+    }
+
+    /// moves the top byte out of low, holding back the bytes that a carry can still change
+    fn shift_low(&mut self) -> Result<()> {
+        if (self.low as u32) < 0xff000000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut temp = self.cache;
+            loop {
+                self.writer.write_all(&[temp.wrapping_add(carry)])?;
+                temp = 0xff;
+
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ffffff) << 8;
+        Ok(())
+    }
+
+    /// writes out the remaining 5 bytes. Afterwards the writer is ready for a new stream.
+    pub fn finish(&mut self) -> Result<()> {
+        for _ in 0..5 {
+            self.shift_low()?;
+        }
+
+        self.low = 0;
+        self.range = 0xffffffff;
+        self.cache = 0;
+        self.cache_size = 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> CabacWriter<LzmaContext> for LzmaWriter<W> {
+    #[inline(always)]
+    fn put(&mut self, bit: bool, context: &mut LzmaContext) -> Result<()> {
+        self.put_with_context(bit, context)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_direct_bits(u32::from(bit), 1)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+pub struct LzmaReader<R> {
+    reader: R,
+    range: u32,
+    code: u32,
+}
+
+impl<R: Read> LzmaReader<R> {
+    /// reads the 5 initial bytes, the first of which is always 0
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut init = [0u8; 5];
+        reader.read_exact(&mut init)?;
+        if init[0] != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "range coder stream doesn't start with 0",
+            ));
+        }
+
+        Ok(LzmaReader {
+            reader,
+            range: 0xffffffff,
+            code: u32::from_be_bytes([init[1], init[2], init[3], init[4]]),
+        })
+    }
+
+    /// reads a bit with the given context and updates the probability
+    #[inline(always)]
+    pub fn get_with_context(&mut self, context: &mut LzmaContext) -> Result<bool> {
+        let bound = context.bound(self.range);
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        context.update(bit);
+
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    /// reads `bits` bits with a fixed probability of 0.5, most significant first
+    pub fn get_direct_bits(&mut self, bits: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            value = (value << 1) | u32::from(bit);
+            self.normalize()?;
+        }
+        Ok(value)
+    }
+
+    /// returns the position of the next interval written by `LzmaWriter::put_frequency` within
+    /// total, which has to be followed by `remove_frequency` with the interval it falls into
+    pub(crate) fn get_threshold(&mut self, total: u32) -> Result<u32> {
+        self.range /= total;
+        let threshold = self.code / self.range;
+        if threshold >= total {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "range coder position outside of the total",
+            ));
+        }
+        Ok(threshold)
+    }
+
+    pub(crate) fn remove_frequency(&mut self, start: u32, size: u32) -> Result<()> {
+        self.code -= start * self.range;
+        self.range *= size;
+
+        while self.range < TOP_VALUE {
+            self.normalize()?;
+        }
+        Ok(())
+    }
+
+    /// reads a value written with `LzmaWriter::put_bit_tree`
+    pub fn get_bit_tree(&mut self, bits: u32, contexts: &mut [LzmaContext]) -> Result<u32> {
+        let mut m = 1;
+        for _ in 0..bits {
+            m = (m << 1) | usize::from(self.get_with_context(&mut contexts[m])?);
+        }
+        Ok((m - (1 << bits)) as u32)
+    }
+
+    /// reads a value written with `LzmaWriter::put_reverse_bit_tree`
+    pub fn get_reverse_bit_tree(&mut self, bits: u32, contexts: &mut [LzmaContext]) -> Result<u32> {
+        let mut m = 1;
+        let mut value = 0;
+        for i in 0..bits {
+            let bit = self.get_with_context(&mut contexts[m])?;
+            m = (m << 1) | usize::from(bit);
+            value |= u32::from(bit) << i;
+        }
+        Ok(value)
+    }
+
+    #[inline(always)]
+    fn normalize(&mut self) -> Result<()> {
+        if self.range < TOP_VALUE {
+            let mut v = [0u8; 1];
+            self.reader.read_exact(&mut v)?;
+            self.range <<= 8;
+            self.code = (self.code << 8) | u32::from(v[0]);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> CabacReader<LzmaContext> for LzmaReader<R> {
+    #[inline(always)]
+    fn get(&mut self, context: &mut LzmaContext) -> Result<bool> {
+        self.get_with_context(context)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        Ok(self.get_direct_bits(1)? != 0)
+    }
+}
+
+/// range coded part of the .lzma stream that liblzma writes for the 16 bytes "ABCDEFGHIJKLMNOP"
+/// with lc=3, lp=0, pb=2 and an unknown size, so all literals followed by the end marker
+#[cfg(test)]
+const LIBLZMA_LITERALS: [u8; 26] = [
+    0x00, 0x20, 0x90, 0x84, 0x76, 0xba, 0x8a, 0x75, 0xcf, 0xb4, 0x0d, 0xb2, 0xe8, 0x9f, 0x13, 0x87,
+    0xf8, 0x18, 0x4c, 0xae, 0x7f, 0xff, 0xf3, 0x4a, 0xc0, 0x00,
+];
+
+/// the parts of the LZMA model needed to code literals and the end marker
+#[cfg(test)]
+struct LiteralModel {
+    is_match: [LzmaContext; 12 * 16],
+    is_rep: [LzmaContext; 12],
+    literal: Vec<LzmaContext>,
+    len_choice: LzmaContext,
+    len_low: [[LzmaContext; 8]; 16],
+    pos_slot: [[LzmaContext; 64]; 4],
+    align: [LzmaContext; 16],
+}
+
+#[cfg(test)]
+impl LiteralModel {
+    fn new() -> Self {
+        LiteralModel {
+            is_match: [LzmaContext::default(); 12 * 16],
+            is_rep: [LzmaContext::default(); 12],
+            literal: vec![LzmaContext::default(); 0x300 << 3],
+            len_choice: LzmaContext::default(),
+            len_low: [[LzmaContext::default(); 8]; 16],
+            pos_slot: [[LzmaContext::default(); 64]; 4],
+            align: [LzmaContext::default(); 16],
+        }
+    }
+
+    /// contexts for the literal after prev with lc=3
+    fn literal(&mut self, prev: u8) -> &mut [LzmaContext] {
+        let start = usize::from(prev >> 5) * 0x300;
+        &mut self.literal[start..start + 0x100]
+    }
+}
+
+#[test]
+fn matches_liblzma() {
+    let data = b"ABCDEFGHIJKLMNOP";
+
+    // the state stays at 0 since there are only literals
+    let mut output = Vec::new();
+    let mut writer = LzmaWriter::new(&mut output);
+    let mut model = LiteralModel::new();
+    let mut prev = 0;
+    for (pos, &b) in data.iter().enumerate() {
+        writer.put(false, &mut model.is_match[pos & 3]).unwrap();
+        writer
+            .put_bit_tree(u32::from(b), 8, model.literal(prev))
+            .unwrap();
+        prev = b;
+    }
+
+    // end marker: a match of length 2 with the distance 0xFFFFFFFF, which is coded as the
+    // position slot 63 followed by 26 direct bits and 4 align bits
+    writer
+        .put(true, &mut model.is_match[data.len() & 3])
+        .unwrap();
+    writer.put(false, &mut model.is_rep[0]).unwrap();
+    writer.put(false, &mut model.len_choice).unwrap();
+    writer.put_bit_tree(0, 3, &mut model.len_low[0]).unwrap();
+    writer.put_bit_tree(63, 6, &mut model.pos_slot[0]).unwrap();
+    writer.put_direct_bits(0x3ffffff, 26).unwrap();
+    writer
+        .put_reverse_bit_tree(15, 4, &mut model.align)
+        .unwrap();
+    writer.finish().unwrap();
+
+    assert_eq!(output, LIBLZMA_LITERALS);
+
+    let mut reader = LzmaReader::new(&LIBLZMA_LITERALS[..]).unwrap();
+    let mut model = LiteralModel::new();
+    let mut prev = 0;
+    for (pos, &b) in data.iter().enumerate() {
+        assert!(!reader.get(&mut model.is_match[pos & 3]).unwrap());
+        let literal = reader.get_bit_tree(8, model.literal(prev)).unwrap();
+        assert_eq!(literal, u32::from(b));
+        prev = b;
+    }
+
+    assert!(reader.get(&mut model.is_match[0]).unwrap());
+    assert!(!reader.get(&mut model.is_rep[0]).unwrap());
+    assert!(!reader.get(&mut model.len_choice).unwrap());
+    assert_eq!(reader.get_bit_tree(3, &mut model.len_low[0]).unwrap(), 0);
+    assert_eq!(reader.get_bit_tree(6, &mut model.pos_slot[0]).unwrap(), 63);
+    assert_eq!(reader.get_direct_bits(26).unwrap(), 0x3ffffff);
+    assert_eq!(
+        reader.get_reverse_bit_tree(4, &mut model.align).unwrap(),
+        15
+    );
+}
+
+#[test]
+fn carry_propagation() {
+    // long runs of the likely bit produce 0xFF bytes that a later carry turns into zeros
+    let bits = |i: u32| i % 61 == 60 || (i / 500) % 3 == 1;
+
+    let mut output = Vec::new();
+    let mut writer = LzmaWriter::new(&mut output);
+    let mut context = LzmaContext::default();
+    for i in 0..20000 {
+        writer.put(bits(i), &mut context).unwrap();
+        writer.put_bypass(i & 4 != 0).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = LzmaReader::new(&output[..]).unwrap();
+    let mut context = LzmaContext::default();
+    for i in 0..20000 {
+        assert_eq!(reader.get(&mut context).unwrap(), bits(i));
+        assert_eq!(reader.get_bypass().unwrap(), i & 4 != 0);
+    }
+}
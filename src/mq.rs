@@ -0,0 +1,557 @@
+//! MQ-coder, the binary arithmetic coder of JPEG 2000 (ITU T.800 Annex C) and JBIG2
+//! (ITU T.88 Annex E).
+//!
+//! It is a descendant of the QM-coder with a 47 state probability estimation table and bit
+//! stuffing instead of byte stuffing: after a 0xFF byte only 7 bits of data are written to
+//! the next byte, so the byte after 0xFF is always below 0x90 and can't be confused with a
+//! marker. The decoder treats a 0xFF followed by a byte above 0x8F (or the end of the input)
+//! as the end of the segment and feeds itself 1 bits from then on.
+use std::io::{Read, Result, Write};
+
+use crate::traits::{CabacReader, CabacWriter};
+
+struct QeEntry {
+    qe: u32,
+    next_mps: u8,
+    next_lps: u8,
+    switch_mps: bool,
+}
+
+impl QeEntry {
+    const fn new(qe: u32, next_mps: u8, next_lps: u8, switch_mps: bool) -> Self {
+        QeEntry {
+            qe,
+            next_mps,
+            next_lps,
+            switch_mps,
+        }
+    }
+}
+
+/// Qe values and probability estimation state machine (T.800 Table C.2, T.88 Table E.1)
+const QE_TABLE: [QeEntry; 47] = [
+    QeEntry::new(0x5601, 1, 1, true),
+    QeEntry::new(0x3401, 2, 6, false),
+    QeEntry::new(0x1801, 3, 9, false),
+    QeEntry::new(0x0ac1, 4, 12, false),
+    QeEntry::new(0x0521, 5, 29, false),
+    QeEntry::new(0x0221, 38, 33, false),
+    QeEntry::new(0x5601, 7, 6, true),
+    QeEntry::new(0x5401, 8, 14, false),
+    QeEntry::new(0x4801, 9, 14, false),
+    QeEntry::new(0x3801, 10, 14, false),
+    QeEntry::new(0x3001, 11, 17, false),
+    QeEntry::new(0x2401, 12, 18, false),
+    QeEntry::new(0x1c01, 13, 20, false),
+    QeEntry::new(0x1601, 29, 21, false),
+    QeEntry::new(0x5601, 15, 14, true),
+    QeEntry::new(0x5401, 16, 14, false),
+    QeEntry::new(0x5101, 17, 15, false),
+    QeEntry::new(0x4801, 18, 16, false),
+    QeEntry::new(0x3801, 19, 17, false),
+    QeEntry::new(0x3401, 20, 18, false),
+    QeEntry::new(0x3001, 21, 19, false),
+    QeEntry::new(0x2801, 22, 19, false),
+    QeEntry::new(0x2401, 23, 20, false),
+    QeEntry::new(0x2201, 24, 21, false),
+    QeEntry::new(0x1c01, 25, 22, false),
+    QeEntry::new(0x1801, 26, 23, false),
+    QeEntry::new(0x1601, 27, 24, false),
+    QeEntry::new(0x1401, 28, 25, false),
+    QeEntry::new(0x1201, 29, 26, false),
+    QeEntry::new(0x1101, 30, 27, false),
+    QeEntry::new(0x0ac1, 31, 28, false),
+    QeEntry::new(0x09c1, 32, 29, false),
+    QeEntry::new(0x08a1, 33, 30, false),
+    QeEntry::new(0x0521, 34, 31, false),
+    QeEntry::new(0x0441, 35, 32, false),
+    QeEntry::new(0x02a1, 36, 33, false),
+    QeEntry::new(0x0221, 37, 34, false),
+    QeEntry::new(0x0141, 38, 35, false),
+    QeEntry::new(0x0111, 39, 36, false),
+    QeEntry::new(0x0085, 40, 37, false),
+    QeEntry::new(0x0049, 41, 38, false),
+    QeEntry::new(0x0025, 42, 39, false),
+    QeEntry::new(0x0015, 43, 40, false),
+    QeEntry::new(0x0009, 44, 41, false),
+    QeEntry::new(0x0005, 45, 42, false),
+    QeEntry::new(0x0001, 45, 43, false),
+    QeEntry::new(0x5601, 46, 46, false),
+];
+
+/// state that never adapts, used by the JPEG 2000 uniform context
+const UNIFORM_STATE: u8 = 46;
+
+/// first of the 9 zero coding contexts of JPEG 2000 (T.800 Table D.1)
+pub const JPEG2000_CTX_ZC: usize = 0;
+/// first of the 5 sign coding contexts (T.800 Table D.3)
+pub const JPEG2000_CTX_SC: usize = 9;
+/// first of the 3 magnitude refinement contexts (T.800 Table D.4)
+pub const JPEG2000_CTX_MAG: usize = 14;
+/// run length context of the cleanup pass
+pub const JPEG2000_CTX_RUN: usize = 17;
+/// uniform context for the position of the first significant coefficient after a run
+pub const JPEG2000_CTX_UNIFORM: usize = 18;
+/// number of context labels used by the JPEG 2000 coefficient bit modeling
+pub const JPEG2000_CONTEXTS: usize = 19;
+
+/// context for the MQ-coder. Stores the index into the probability estimation table in the
+/// lower 7 bits and the more probable symbol in the top bit.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MqContext {
+    state: u8,
+}
+
+impl MqContext {
+    pub const fn new(index: u8, mps: bool) -> Self {
+        assert!(index < 47);
+        MqContext {
+            state: index | ((mps as u8) << 7),
+        }
+    }
+
+    /// context that always codes with the probability 0x5601 and never adapts
+    pub const fn uniform() -> Self {
+        MqContext {
+            state: UNIFORM_STATE,
+        }
+    }
+
+    /// returns the contexts of JPEG 2000 in their initial state at the start of each coding
+    /// pass (T.800 Table D.7)
+    pub fn jpeg2000_contexts() -> [MqContext; JPEG2000_CONTEXTS] {
+        let mut contexts = [MqContext::default(); JPEG2000_CONTEXTS];
+        contexts[JPEG2000_CTX_ZC] = MqContext::new(4, false);
+        contexts[JPEG2000_CTX_RUN] = MqContext::new(3, false);
+        contexts[JPEG2000_CTX_UNIFORM] = MqContext::uniform();
+        contexts
+    }
+
+    /// returns the index into the probability estimation table
+    pub fn get_index(&self) -> u8 {
+        self.state & 0x7f
+    }
+
+    /// returns the more probable symbol
+    pub fn get_mps(&self) -> bool {
+        self.state & 0x80 != 0
+    }
+
+    #[inline(always)]
+    fn estimate_after_lps(&mut self) {
+        let e = &QE_TABLE[usize::from(self.get_index())];
+        self.state = (self.state & 0x80) ^ (u8::from(e.switch_mps) << 7) ^ e.next_lps;
+    }
+
+    #[inline(always)]
+    fn estimate_after_mps(&mut self) {
+        let e = &QE_TABLE[usize::from(self.get_index())];
+        self.state = (self.state & 0x80) | e.next_mps;
+    }
+
+    #[inline(always)]
+    fn qe(&self) -> u32 {
+        QE_TABLE[usize::from(self.get_index())].qe
+    }
+}
+
+pub struct MqWriter<W> {
+    writer: W,
+    c: u32,
+    a: u32,
+    ct: u32,
+    /// byte that can still be changed by a carry, or None at the start of the segment
+    b: Option<u8>,
+}
+
+impl<W: Write> MqWriter<W> {
+    pub fn new(writer: W) -> Self {
+        MqWriter {
+            writer,
+            c: 0,
+            a: 0x8000,
+            ct: 12,
+            b: None,
+        }
+    }
+
+    /// writes the bit with the given context and updates the probability estimate
+    /// (CODEMPS and CODELPS, T.800 C.2.4 and C.2.5)
+    #[inline(always)]
+    pub fn put_with_context(&mut self, bit: bool, context: &mut MqContext) -> Result<()> {
+        let qe = context.qe();
+
+        self.a -= qe;
+        if bit == context.get_mps() {
+            if self.a & 0x8000 != 0 {
+                self.c += qe;
+                return Ok(());
+            }
+
+            // conditional exchange if the interval of the MPS became smaller
+            if self.a < qe {
+                self.a = qe;
+            } else {
+                self.c += qe;
+            }
+            context.estimate_after_mps();
+        } else {
+            if self.a < qe {
+                self.c += qe;
+            } else {
+                self.a = qe;
+            }
+            context.estimate_after_lps();
+        }
+
+        self.renormalize()
+    }
+
+    /// RENORME (T.800 C.2.6)
+    fn renormalize(&mut self) -> Result<()> {
+        loop {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                self.byte_out()?;
+            }
+
+            if self.a & 0x8000 != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// BYTEOUT (T.800 C.2.7), propagates a carry into the previous byte unless it is 0xFF,
+    /// in which case the carry bit was stuffed and is part of the next byte
+    fn byte_out(&mut self) -> Result<()> {
+        if self.b == Some(0xff) {
+            return self.next_byte(7);
+        }
+
+        if self.c & 0x8000000 != 0 {
+            self.c &= 0x7ffffff;
+            if let Some(b) = self.b.as_mut() {
+                *b += 1;
+                if *b == 0xff {
+                    return self.next_byte(7);
+                }
+            }
+        }
+        self.next_byte(8)
+    }
+
+    /// moves on to the next byte, which takes the top `bits` bits of C
+    fn next_byte(&mut self, bits: u32) -> Result<()> {
+        if let Some(b) = self.b {
+            self.writer.write_all(&[b])?;
+        }
+        self.b = Some((self.c >> (27 - bits)) as u8);
+        self.c &= (1 << (27 - bits)) - 1;
+        self.ct = bits;
+        Ok(())
+    }
+
+    /// writes a bit with the uniform context
+    pub fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_with_context(bit, &mut MqContext::uniform())
+    }
+
+    /// terminates the segment with the standard flush (T.800 C.2.9), which sets as many of
+    /// the remaining bits to 1 as possible and drops a final 0xFF. Afterwards the writer is
+    /// ready for the next segment.
+    pub fn finish(&mut self) -> Result<()> {
+        // SETBITS
+        let temp = self.c + self.a;
+        self.c |= 0xffff;
+        if self.c >= temp {
+            self.c -= 0x8000;
+        }
+
+        self.c <<= self.ct;
+        self.byte_out()?;
+        self.c <<= self.ct;
+        self.byte_out()?;
+
+        self.end_segment()
+    }
+
+    /// terminates the segment with the predictable termination of JPEG 2000 (T.800 D.4.2),
+    /// which writes only the bits needed to decode the segment and leaves the remaining bits
+    /// in a known state so that the decoder can detect errors.
+    pub fn finish_predictable(&mut self) -> Result<()> {
+        let mut k = 12 - self.ct as i32;
+        while k > 0 {
+            self.c <<= self.ct;
+            self.ct = 0;
+            self.byte_out()?;
+            k -= self.ct as i32;
+        }
+
+        self.end_segment()
+    }
+
+    fn end_segment(&mut self) -> Result<()> {
+        if let Some(b) = self.b.filter(|&b| b != 0xff) {
+            self.writer.write_all(&[b])?;
+        }
+
+        self.c = 0;
+        self.a = 0x8000;
+        self.ct = 12;
+        self.b = None;
+        Ok(())
+    }
+}
+
+impl<W: Write> CabacWriter<MqContext> for MqWriter<W> {
+    #[inline(always)]
+    fn put(&mut self, bit: bool, context: &mut MqContext) -> Result<()> {
+        self.put_with_context(bit, context)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+pub struct MqReader<R> {
+    reader: R,
+    c: u32,
+    a: u32,
+    ct: u32,
+    /// byte at the current position of the decoder
+    b: u8,
+    /// true once a marker or the end of the input was reached
+    end: bool,
+    marker: Option<u8>,
+}
+
+impl<R: Read> MqReader<R> {
+    /// INITDEC (T.800 C.3.5)
+    pub fn new(reader: R) -> Result<Self> {
+        let mut r = MqReader {
+            reader,
+            c: 0,
+            a: 0x8000,
+            ct: 0,
+            b: 0,
+            end: false,
+            marker: None,
+        };
+
+        r.b = r.read_byte()?.unwrap_or(0xff);
+        r.c = u32::from(r.b) << 16;
+        r.byte_in()?;
+        r.c <<= 7;
+        r.ct -= 7;
+        Ok(r)
+    }
+
+    /// returns the marker that ended the segment if one was reached
+    pub fn get_marker(&self) -> Option<u8> {
+        self.marker
+    }
+
+    /// reads a bit with the given context and updates the probability estimate
+    /// (DECODE, T.800 C.3.2)
+    #[inline(always)]
+    pub fn get_with_context(&mut self, context: &mut MqContext) -> Result<bool> {
+        let qe = context.qe();
+        let mut bit = context.get_mps();
+
+        // the LPS occupies the bottom of the interval
+        self.a -= qe;
+        if (self.c >> 16) < qe {
+            // LPS_EXCHANGE
+            if self.a < qe {
+                context.estimate_after_mps();
+            } else {
+                bit = !bit;
+                context.estimate_after_lps();
+            }
+            self.a = qe;
+        } else {
+            self.c -= qe << 16;
+            if self.a & 0x8000 != 0 {
+                return Ok(bit);
+            }
+
+            // MPS_EXCHANGE
+            if self.a < qe {
+                bit = !bit;
+                context.estimate_after_lps();
+            } else {
+                context.estimate_after_mps();
+            }
+        }
+
+        self.renormalize()?;
+        Ok(bit)
+    }
+
+    /// RENORMD (T.800 C.3.3)
+    fn renormalize(&mut self) -> Result<()> {
+        loop {
+            if self.ct == 0 {
+                self.byte_in()?;
+            }
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+
+            if self.a & 0x8000 != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// BYTEIN (T.800 C.3.4), after a 0xFF the next byte has a stuffed bit unless it is a
+    /// marker, in which case 1 bits are fed to the decoder
+    fn byte_in(&mut self) -> Result<()> {
+        if self.b == 0xff {
+            let next = if self.end { None } else { self.read_byte()? };
+            match next {
+                Some(b) if b <= 0x8f => {
+                    self.b = b;
+                    self.c += u32::from(b) << 9;
+                    self.ct = 7;
+                }
+                _ => {
+                    // keep the marker that ended the segment, later calls don't read further
+                    if !self.end {
+                        self.marker = next;
+                    }
+                    self.end = true;
+                    self.c += 0xff00;
+                    self.ct = 8;
+                }
+            }
+        } else {
+            // the end of the input is handled as if it was followed by 0xFF 0xFF
+            self.b = self.read_byte()?.unwrap_or(0xff);
+            self.c += u32::from(self.b) << 8;
+            self.ct = 8;
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut v = [0u8; 1];
+        if self.reader.read(&mut v)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(v[0]))
+    }
+
+    /// reads a bit with the uniform context
+    pub fn get_bypass(&mut self) -> Result<bool> {
+        self.get_with_context(&mut MqContext::uniform())
+    }
+}
+
+impl<R: Read> CabacReader<MqContext> for MqReader<R> {
+    #[inline(always)]
+    fn get(&mut self, context: &mut MqContext) -> Result<bool> {
+        self.get_with_context(context)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
+
+/// test sequence from T.88 H.2, coded with a single context
+#[cfg(test)]
+const T88_INPUT: [u8; 32] = [
+    0x00, 0x02, 0x00, 0x51, 0x00, 0x00, 0x00, 0xc0, 0x03, 0x52, 0x87, 0x2a, 0xaa, 0xaa, 0xaa, 0xaa,
+    0x82, 0xc0, 0x20, 0x00, 0xfc, 0xd7, 0x9e, 0xf6, 0xbf, 0x7f, 0xed, 0x90, 0x4f, 0x46, 0xa3, 0xbf,
+];
+
+/// encoded test sequence from T.88 H.2
+#[cfg(test)]
+const T88_OUTPUT: [u8; 30] = [
+    0x84, 0xc7, 0x3b, 0xfc, 0xe1, 0xa1, 0x43, 0x04, 0x02, 0x20, 0x00, 0x00, 0x41, 0x0d, 0xbb, 0x86,
+    0xf4, 0x31, 0x7f, 0xff, 0x88, 0xff, 0x37, 0x47, 0x1a, 0xdb, 0x6a, 0xdf, 0xff, 0xac,
+];
+
+#[cfg(test)]
+fn t88_bits() -> impl Iterator<Item = bool> {
+    T88_INPUT
+        .iter()
+        .flat_map(|&b| (0..8).rev().map(move |i| (b >> i) & 1 != 0))
+}
+
+#[test]
+fn t88_encode() {
+    let mut output = Vec::new();
+    let mut writer = MqWriter::new(&mut output);
+    let mut context = MqContext::default();
+    for bit in t88_bits() {
+        writer.put(bit, &mut context).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // JBIG2 terminates the segment with the 0xFFAC marker after the flush
+    output.extend_from_slice(&[0xff, 0xac]);
+    assert_eq!(output, T88_OUTPUT);
+}
+
+#[test]
+fn t88_decode() {
+    let mut reader = MqReader::new(&T88_OUTPUT[..]).unwrap();
+    let mut context = MqContext::default();
+    for bit in t88_bits() {
+        assert_eq!(reader.get(&mut context).unwrap(), bit);
+    }
+    assert_eq!(reader.get_marker(), Some(0xac));
+
+    // decoding past the end of the segment feeds 1 bits and keeps the marker
+    for _ in 0..1000 {
+        let _ = reader.get(&mut context).unwrap();
+    }
+    assert_eq!(reader.get_marker(), Some(0xac));
+}
+
+#[cfg(test)]
+fn jpeg2000_roundtrip(predictable: bool) {
+    use crate::test_util::random_symbols;
+
+    // a context and a bit that is set a quarter of the time
+    let symbols: Vec<(usize, bool)> = random_symbols(20000, 2 * JPEG2000_CONTEXTS)
+        .into_iter()
+        .map(|s| (s % JPEG2000_CONTEXTS, s >= JPEG2000_CONTEXTS))
+        .collect();
+
+    let mut output = Vec::new();
+    let mut writer = MqWriter::new(&mut output);
+    let mut contexts = MqContext::jpeg2000_contexts();
+    for &(cx, bit) in &symbols {
+        writer.put(bit, &mut contexts[cx]).unwrap();
+    }
+    if predictable {
+        writer.finish_predictable().unwrap();
+    } else {
+        writer.finish().unwrap();
+    }
+
+    // bit stuffing keeps the byte after 0xFF out of the marker range
+    assert!(output.windows(2).all(|w| w[0] != 0xff || w[1] <= 0x8f));
+
+    let mut reader = MqReader::new(&output[..]).unwrap();
+    let mut contexts = MqContext::jpeg2000_contexts();
+    for &(cx, bit) in &symbols {
+        assert_eq!(reader.get(&mut contexts[cx]).unwrap(), bit);
+    }
+}
+
+#[test]
+fn jpeg2000_terminations() {
+    jpeg2000_roundtrip(false);
+    jpeg2000_roundtrip(true);
+}
@@ -0,0 +1,198 @@
+//! Emulation prevention for H.264/H.265 NAL units.
+//!
+//! Inside a NAL unit, the byte patterns 0x000000, 0x000001, 0x000002 and 0x000003 are not allowed
+//! since they would be confused with start codes. The encoder inserts an emulation_prevention_three_byte
+//! (0x03) after every two zero bytes that are followed by a byte less than or equal to 0x03, and the
+//! decoder removes them again.
+//!
+//! The adapters here wrap the sink or source of `H265Writer`/`H265Reader` (or any other
+//! writer/reader) and do this on the fly without buffering the NAL unit.
+use std::io::{Read, Result, Write};
+
+/// Inserts emulation prevention bytes into everything written to it.
+pub struct EmulationPreventionWriter<W> {
+    writer: W,
+    zero_count: u32,
+    last_byte_zero: bool,
+    inserted_bytes: u64,
+}
+
+impl<W: Write> EmulationPreventionWriter<W> {
+    pub fn new(writer: W) -> Self {
+        EmulationPreventionWriter {
+            writer,
+            zero_count: 0,
+            last_byte_zero: false,
+            inserted_bytes: 0,
+        }
+    }
+
+    /// number of emulation prevention bytes that have been inserted so far
+    pub fn inserted_bytes(&self) -> u64 {
+        self.inserted_bytes
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// ends the NAL unit. If the last byte written was zero (which can only happen if the data ends
+    /// with a cabac_zero_word), a final 0x03 byte is appended as required by the spec. The state is
+    /// reset so that the writer can be used for the next NAL unit.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.last_byte_zero {
+            self.writer.write_all(&[0x03])?;
+            self.inserted_bytes += 1;
+        }
+
+        self.zero_count = 0;
+        self.last_byte_zero = false;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Write for EmulationPreventionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // write out the runs between the escapes directly from the buffer
+        let mut run_start = 0;
+
+        for (i, &b) in buf.iter().enumerate() {
+            if self.zero_count >= 2 && b <= 0x03 {
+                self.writer.write_all(&buf[run_start..i])?;
+                self.writer.write_all(&[0x03])?;
+                self.inserted_bytes += 1;
+                self.zero_count = 0;
+                run_start = i;
+            }
+
+            if b == 0 {
+                self.zero_count += 1;
+            } else {
+                self.zero_count = 0;
+            }
+        }
+
+        self.writer.write_all(&buf[run_start..])?;
+
+        if let Some(&last) = buf.last() {
+            self.last_byte_zero = last == 0;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Removes emulation prevention bytes from everything read through it.
+pub struct EmulationPreventionReader<R> {
+    reader: R,
+    zero_count: u32,
+    removed_bytes: u64,
+}
+
+impl<R: Read> EmulationPreventionReader<R> {
+    pub fn new(reader: R) -> Self {
+        EmulationPreventionReader {
+            reader,
+            zero_count: 0,
+            removed_bytes: 0,
+        }
+    }
+
+    /// number of emulation prevention bytes that have been removed so far
+    pub fn removed_bytes(&self) -> u64 {
+        self.removed_bytes
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Read for EmulationPreventionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let bytes_read = self.reader.read(buf)?;
+            if bytes_read == 0 {
+                return Ok(0);
+            }
+
+            // remove the escapes in place
+            let mut len = 0;
+            for i in 0..bytes_read {
+                let b = buf[i];
+
+                if self.zero_count >= 2 && b == 0x03 {
+                    self.removed_bytes += 1;
+                    self.zero_count = 0;
+                    continue;
+                }
+
+                if b == 0 {
+                    self.zero_count += 1;
+                } else {
+                    self.zero_count = 0;
+                }
+
+                buf[len] = b;
+                len += 1;
+            }
+
+            // only return if there is something left, since returning 0 would mean end of stream
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+#[test]
+fn known_patterns() {
+    let cases: [(&[u8], &[u8]); 8] = [
+        (&[0, 0, 0, 5], &[0, 0, 3, 0, 5]),
+        (&[0, 0, 1], &[0, 0, 3, 1]),
+        (&[0, 0, 2], &[0, 0, 3, 2]),
+        (&[0, 0, 3], &[0, 0, 3, 3]),
+        (&[0, 0, 4], &[0, 0, 4]),
+        (&[0, 0, 0, 0, 0, 7], &[0, 0, 3, 0, 0, 3, 0, 7]),
+        // ends with a cabac_zero_word, so a final 0x03 is appended
+        (&[1, 0, 0, 0, 1, 0, 0], &[1, 0, 0, 3, 0, 1, 0, 0, 3]),
+        (&[0, 0, 3, 0, 0, 3], &[0, 0, 3, 3, 0, 0, 3, 3]),
+    ];
+
+    for (raw, escaped) in cases {
+        // write one byte at a time as well as all at once to check the state across calls
+        for chunk_size in [1, raw.len()] {
+            let mut writer = EmulationPreventionWriter::new(Vec::new());
+            for chunk in raw.chunks(chunk_size) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+
+            assert_eq!(
+                writer.inserted_bytes() as usize,
+                escaped.len() - raw.len(),
+                "{raw:?}"
+            );
+            assert_eq!(writer.into_inner(), escaped, "{raw:?}");
+        }
+
+        let mut reader = EmulationPreventionReader::new(escaped);
+        let mut unescaped = Vec::new();
+        let _ = reader.read_to_end(&mut unescaped).unwrap();
+
+        assert_eq!(unescaped, raw, "{raw:?}");
+        assert_eq!(reader.removed_bytes() as usize, escaped.len() - raw.len());
+    }
+}
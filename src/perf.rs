@@ -9,10 +9,20 @@ use std::io::Cursor;
 use crate::fpaq0parallel::Fpaq0DecoderParallelSimd;
 
 use crate::{
+    av1::{AV1Context, AV1Reader, AV1Writer},
+    bitstream::{BitReader, BitWriter},
+    fenwick::{FenwickModel, FenwickReader, FenwickWriter},
     fpaq0::{Fpaq0Decoder, Fpaq0Encoder},
     fpaq0parallel::{Fpaq0DecoderParallel, Fpaq0EncoderParallel, ParallelEncoderOutput},
+    fse::{FseReader, FseTable, FseWriter},
     h265::{H265Reader, H265Writer},
-    rans32::{RansReader32, RansWriter32},
+    jxl::{HybridUintCode, HybridUintConfig, HybridUintReader, HybridUintWriter},
+    rans32::{
+        RansLookup, RansReader32, RansSymbolReader32, RansSymbolTable, RansSymbolWriter32,
+        RansWriter32,
+    },
+    rans32x::{RansSymbolReader32x, RansSymbolWriter32x},
+    rans64::{RansReader64, RansWriter64},
     traits::{CabacReader, CabacWriter},
     vp8::{VP8Context, VP8Reader, VP8Writer},
 };
@@ -131,6 +141,28 @@ fn rans32_test_pattern() {
     generic_test_pattern(rans32_get_pattern_bypass, rans32_put_pattern_bypass);
 }
 
+// rans64
+#[inline(never)]
+#[allow(dead_code)]
+pub fn rans64_put_pattern(pattern: &[bool]) -> Vec<u8> {
+    let mut output = Vec::new();
+    generic_put_pattern(false, pattern, RansWriter64::new(&mut output));
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn rans64_get_pattern(pattern: &[bool], source: &[u8]) -> Box<[bool]> {
+    generic_get_pattern(false, pattern, source, |vec| {
+        RansReader64::new(Cursor::new(vec)).unwrap()
+    })
+}
+
+#[test]
+fn rans64_test_pattern() {
+    generic_test_pattern(rans64_get_pattern, rans64_put_pattern);
+}
+
 #[inline(never)]
 #[allow(dead_code)]
 pub fn vp8_put_pattern(pattern: &[bool]) -> Vec<u8> {
@@ -228,6 +260,335 @@ fn fpaq_test_pattern() {
     generic_test_pattern(fpaq_get_pattern, fpaq_put_pattern);
 }
 
+#[inline(never)]
+#[allow(dead_code)]
+pub fn av1_put_pattern(pattern: &[bool]) -> Vec<u8> {
+    let mut output = Vec::new();
+    generic_put_pattern(false, pattern, AV1Writer::new(&mut output));
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn av1_get_pattern(pattern: &[bool], source: &[u8]) -> Box<[bool]> {
+    generic_get_pattern(false, pattern, source, |vec| {
+        AV1Reader::new(Cursor::new(vec)).unwrap()
+    })
+}
+
+#[test]
+fn av1_test_pattern() {
+    generic_test_pattern(av1_get_pattern, av1_put_pattern);
+}
+
+/// writes 4 bit symbols with a 16 symbol alphabet, one adaptive CDF per position mod 4
+#[inline(never)]
+#[allow(dead_code)]
+pub fn av1_put_symbols(symbols: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut context = [AV1Context::<16>::default(); 4];
+
+    let mut writer = AV1Writer::new(&mut output);
+    symbols.chunks_exact(4).for_each(|chunk| {
+        for i in 0..4 {
+            writer
+                .put_symbol(usize::from(chunk[i]), &mut context[i])
+                .unwrap();
+        }
+    });
+    writer.finish().unwrap();
+
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn av1_get_symbols(symbols: &[u8], source: &[u8]) -> Box<[u8]> {
+    let mut context = [AV1Context::<16>::default(); 4];
+    let mut output = vec![0; symbols.len()].into_boxed_slice();
+
+    let mut reader = AV1Reader::new(Cursor::new(source)).unwrap();
+    output.chunks_exact_mut(4).for_each(|chunk| {
+        for i in 0..4 {
+            chunk[i] = reader.get_symbol(&mut context[i]).unwrap() as u8;
+        }
+    });
+
+    output
+}
+
+/// same as `av1_put_symbols` but binarized for VP8 as a balanced tree of 4 bins, with a
+/// context for each node of the tree
+#[inline(never)]
+#[allow(dead_code)]
+pub fn vp8_put_symbols(symbols: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut context = [[VP8Context::default(); 16]; 4];
+
+    let mut writer = VP8Writer::new(&mut output).unwrap();
+    symbols.chunks_exact(4).for_each(|chunk| {
+        for i in 0..4 {
+            let mut node = 1;
+            for b in (0..4).rev() {
+                let bit = (chunk[i] >> b) & 1 != 0;
+                writer.put(bit, &mut context[i][node]).unwrap();
+                node = (node << 1) | usize::from(bit);
+            }
+        }
+    });
+    writer.finish().unwrap();
+
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn vp8_get_symbols(symbols: &[u8], source: &[u8]) -> Box<[u8]> {
+    let mut context = [[VP8Context::default(); 16]; 4];
+    let mut output = vec![0; symbols.len()].into_boxed_slice();
+
+    let mut reader = VP8Reader::new(Cursor::new(source)).unwrap();
+    output.chunks_exact_mut(4).for_each(|chunk| {
+        for i in 0..4 {
+            let mut node = 1;
+            for _ in 0..4 {
+                let bit = reader.get(&mut context[i][node]).unwrap();
+                node = (node << 1) | usize::from(bit);
+            }
+            chunk[i] = (node - 16) as u8;
+        }
+    });
+
+    output
+}
+
+/// same symbols as `av1_put_symbols` but with a static FSE table built from the histogram,
+/// which is written as a header in front of the data
+#[inline(never)]
+#[allow(dead_code)]
+pub fn fse_put_symbols(symbols: &[u8]) -> Vec<u8> {
+    let mut counts = [0u32; 16];
+    symbols.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let table = FseTable::from_counts(&counts, 10).unwrap();
+
+    let mut header = BitWriter::new(Vec::new());
+    table.write_header(&mut header).unwrap();
+    header.byte_align().unwrap();
+    let mut output = header.into_inner();
+
+    let mut writer = FseWriter::new(&mut output, &table);
+    symbols.iter().for_each(|&s| writer.put_symbol(s).unwrap());
+    writer.finish().unwrap();
+
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn fse_get_symbols(symbols: &[u8], source: &[u8]) -> Box<[u8]> {
+    let mut output = vec![0; symbols.len()].into_boxed_slice();
+
+    let mut header = BitReader::new(Cursor::new(source));
+    let table = FseTable::read_header(&mut header).unwrap();
+    header.byte_align();
+
+    let mut reader = FseReader::new(header.into_inner(), &table);
+    output
+        .iter_mut()
+        .for_each(|s| *s = reader.get_symbol().unwrap());
+
+    output
+}
+
+/// same symbols as `av1_put_symbols` but with the Fenwick tree range coder, one adaptive model
+/// per position mod 4
+#[inline(never)]
+#[allow(dead_code)]
+pub fn fenwick_put_symbols(symbols: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut model: [_; 4] = std::array::from_fn(|_| FenwickModel::new(16).unwrap());
+
+    let mut writer = FenwickWriter::new(&mut output);
+    symbols.chunks_exact(4).for_each(|chunk| {
+        for i in 0..4 {
+            writer
+                .put_symbol(usize::from(chunk[i]), &mut model[i])
+                .unwrap();
+        }
+    });
+    writer.finish().unwrap();
+
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn fenwick_get_symbols(symbols: &[u8], source: &[u8]) -> Box<[u8]> {
+    let mut model: [_; 4] = std::array::from_fn(|_| FenwickModel::new(16).unwrap());
+    let mut output = vec![0; symbols.len()].into_boxed_slice();
+
+    let mut reader = FenwickReader::new(Cursor::new(source)).unwrap();
+    output.chunks_exact_mut(4).for_each(|chunk| {
+        for i in 0..4 {
+            chunk[i] = reader.get_symbol(&mut model[i]).unwrap() as u8;
+        }
+    });
+
+    output
+}
+
+#[test]
+fn symbols_test_pattern() {
+    let mut symbols = Vec::new();
+    for _ in 0..200 {
+        let a: u8 = rand::Rng::gen_range(&mut rand::thread_rng(), 0..16);
+        let b: u8 = rand::Rng::gen_range(&mut rand::thread_rng(), 0..16);
+        symbols.push(a.min(b));
+    }
+
+    let encoded = av1_put_symbols(&symbols);
+    assert!(symbols[..] == av1_get_symbols(&symbols, &encoded)[..]);
+
+    let encoded = vp8_put_symbols(&symbols);
+    assert!(symbols[..] == vp8_get_symbols(&symbols, &encoded)[..]);
+
+    let encoded = fse_put_symbols(&symbols);
+    assert!(symbols[..] == fse_get_symbols(&symbols, &encoded)[..]);
+
+    let encoded = fenwick_put_symbols(&symbols);
+    assert!(symbols[..] == fenwick_get_symbols(&symbols, &encoded)[..]);
+
+    let encoded = rans32_put_symbols(&symbols);
+    assert!(symbols[..] == rans32_get_symbols(&symbols, &encoded)[..]);
+
+    let encoded = rans32x_put_symbols::<8>(&symbols);
+    assert!(symbols[..] == rans32x_get_symbols::<8>(&symbols, &encoded)[..]);
+
+    let encoded = rans32x_put_symbols::<32>(&symbols);
+    assert!(symbols[..] == rans32x_get_symbols::<32>(&symbols, &encoded)[..]);
+}
+
+/// same symbols as `av1_put_symbols` with a static rANS table and the two states of
+/// `RansSymbolWriter32`, with the frequencies written as a header in front of the data
+#[inline(never)]
+#[allow(dead_code)]
+pub fn rans32_put_symbols(symbols: &[u8]) -> Vec<u8> {
+    let mut counts = [0u32; 16];
+    symbols.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let table = RansSymbolTable::<12>::from_counts(&counts, RansLookup::Alias).unwrap();
+
+    let mut output = Vec::new();
+    table.write_table(&mut output).unwrap();
+
+    let mut writer = RansSymbolWriter32::new(&mut output, &table);
+    symbols.iter().for_each(|&s| writer.put_symbol(s).unwrap());
+    writer.finish().unwrap();
+
+    output
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn rans32_get_symbols(symbols: &[u8], mut source: &[u8]) -> Box<[u8]> {
+    let mut output = vec![0; symbols.len()].into_boxed_slice();
+
+    let table = RansSymbolTable::<12>::read_table(&mut source, RansLookup::Alias).unwrap();
+    let mut reader = RansSymbolReader32::new(source, &table);
+    output
+        .iter_mut()
+        .for_each(|s| *s = reader.get_symbol().unwrap());
+
+    output
+}
+
+/// same as `rans32_put_symbols` but with N interleaved states
+#[inline(never)]
+#[allow(dead_code)]
+pub fn rans32x_put_symbols<const N: usize>(symbols: &[u8]) -> Vec<u8> {
+    let mut counts = [0u32; 16];
+    symbols.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let table = RansSymbolTable::<12>::from_counts(&counts, RansLookup::Alias).unwrap();
+
+    let mut output = Vec::new();
+    table.write_table(&mut output).unwrap();
+
+    let mut writer = RansSymbolWriter32x::<_, N, 12>::new(&mut output, &table);
+    symbols.iter().for_each(|&s| writer.put_symbol(s).unwrap());
+    writer.finish().unwrap();
+
+    output
+}
+
+/// reads the symbols with `get_symbols`, which uses SIMD lanes with the `simd` feature
+#[inline(never)]
+#[allow(dead_code)]
+pub fn rans32x_get_symbols<const N: usize>(symbols: &[u8], mut source: &[u8]) -> Box<[u8]> {
+    let mut output = vec![0; symbols.len()].into_boxed_slice();
+
+    let table = RansSymbolTable::<12>::read_table(&mut source, RansLookup::Alias).unwrap();
+    let mut reader = RansSymbolReader32x::<_, N, 12>::new(source, &table);
+    reader.get_symbols(&mut output).unwrap();
+
+    output
+}
+
+/// writes integers of any size as JPEG XL style hybrid uints, with one context per position
+/// mod 4 and the code written as a header in front of the data
+#[inline(never)]
+#[allow(dead_code)]
+pub fn hybrid_uint_put_values(values: &[u32]) -> Vec<u8> {
+    let values: Vec<(usize, u32)> = values
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| (i & 3, v))
+        .collect();
+    let config = HybridUintConfig::new(4, 1, 1).unwrap();
+    let code = HybridUintCode::from_values(4, 4, config, &values).unwrap();
+
+    let mut header = BitWriter::new(Vec::new());
+    code.write_header(&mut header).unwrap();
+    header.byte_align().unwrap();
+
+    let mut writer = HybridUintWriter::new(header.into_inner(), &code);
+    for &(context, value) in &values {
+        writer.put(context, value).unwrap();
+    }
+    writer.finish().unwrap();
+
+    writer.into_inner()
+}
+
+#[inline(never)]
+#[allow(dead_code)]
+pub fn hybrid_uint_get_values(values: &[u32], source: &[u8]) -> Box<[u32]> {
+    let mut output = vec![0; values.len()].into_boxed_slice();
+
+    let mut header = BitReader::new(Cursor::new(source));
+    let code = HybridUintCode::read_header(&mut header).unwrap();
+    header.byte_align();
+
+    let mut reader = HybridUintReader::new(header.into_inner(), &code).unwrap();
+    for (i, v) in output.iter_mut().enumerate() {
+        *v = reader.get(i & 3).unwrap();
+    }
+    reader.check_final_state().unwrap();
+
+    output
+}
+
+#[test]
+fn hybrid_uint_test_values() {
+    let mut values = Vec::new();
+    for _ in 0..200 {
+        let v: u32 = rand::Rng::gen(&mut rand::thread_rng());
+        let shift = rand::Rng::gen_range(&mut rand::thread_rng(), 0..32);
+        values.push(v >> shift);
+    }
+
+    let encoded = hybrid_uint_put_values(&values);
+    assert!(values[..] == hybrid_uint_get_values(&values, &encoded)[..]);
+}
+
 #[inline(never)]
 #[allow(dead_code)]
 pub fn fpaq_parallel_put_pattern(pattern: &[bool]) -> Vec<u8> {
@@ -3,20 +3,24 @@
 //! Speedwise it is similar to the arithmetic encoders despite
 //! having to reverse the symbols on encoding. The advantage of rANS is that it can be written
 //! into a buffer in parallel, which can be useful for multiplexing multiple streams into a single buffer.
+//!
+//! Besides the binary coder, `RansSymbolWriter32`/`RansSymbolReader32` code bytes directly with a
+//! static frequency table, which avoids binarizing every symbol.
 use std::{
     collections::VecDeque,
-    io::{Read, Result, Write},
+    io::{Error, ErrorKind, Read, Result, Write},
+    marker::PhantomData,
     num::{NonZeroU32, NonZeroU8},
 };
 
-use bytemuck::cast_slice;
+use bytemuck::{cast_slice, Pod};
 
 use crate::{
-    traits::{CabacReader, CabacWriter},
+    traits::{CabacReader, CabacWriter, ProbabilityReader, ProbabilityWriter},
     vp8::VP8Context,
 };
 
-trait WriteU16 {
+pub(crate) trait WriteU16 {
     fn write_u16(&mut self, value: u16);
 }
 
@@ -37,7 +41,7 @@ fn read_u16(r: &mut impl Read) -> Result<u16> {
 /// Rans32State is a 32 bit rANS state.
 /// The SCALE_BITS is the number of bits of resolution needed.
 #[derive(Clone, Copy)]
-struct Rans32State<const SCALE_BITS: u32>(u32);
+pub struct Rans32State<const SCALE_BITS: u32>(pub(crate) u32);
 
 impl<const SCALE_BITS: u32> std::fmt::Debug for Rans32State<SCALE_BITS> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -49,13 +53,13 @@ const RANS_WORD_L: u32 = 1 << 16; // Lower bound of our normalization interval
 
 impl<const SCALE_BITS: u32> Rans32State<SCALE_BITS> {
     #[inline]
-    fn new_encoder() -> Self {
+    pub(crate) fn new_encoder() -> Self {
         Rans32State(RANS_WORD_L)
     }
 
     /// Initializes a rANS decoder.
     #[inline]
-    fn new_decoder(source: &mut impl Read) -> Result<Self> {
+    pub(crate) fn new_decoder(source: &mut impl Read) -> Result<Self> {
         Ok(Rans32State(
             u32::from(read_u16(source)?) | (u32::from(read_u16(source)?) << 16),
         ))
@@ -63,7 +67,7 @@ impl<const SCALE_BITS: u32> Rans32State<SCALE_BITS> {
 
     /// Flushes the rANS encoder.
     #[inline]
-    fn enc_flush(&mut self, buffer: &mut impl WriteU16) {
+    pub(crate) fn enc_flush(&mut self, buffer: &mut impl WriteU16) {
         let x = self.0;
 
         buffer.write_u16((x >> 16) as u16);
@@ -72,13 +76,18 @@ impl<const SCALE_BITS: u32> Rans32State<SCALE_BITS> {
 
     // Returns the current cumulative frequency.
     #[inline]
-    fn dec_get(&self) -> u32 {
+    pub(crate) fn dec_get(&self) -> u32 {
         self.0 & ((1u32 << SCALE_BITS) - 1)
     }
 
     // Advances in the bit stream by "popping" a single symbol.
     #[inline]
-    fn dec_advance(&mut self, source: &mut impl Read, start: u32, freq: NonZeroU32) -> Result<()> {
+    pub(crate) fn dec_advance(
+        &mut self,
+        source: &mut impl Read,
+        start: u32,
+        freq: NonZeroU32,
+    ) -> Result<()> {
         let mask = (1u32 << SCALE_BITS) - 1;
 
         // s, x = D(x)
@@ -97,7 +106,7 @@ impl<const SCALE_BITS: u32> Rans32State<SCALE_BITS> {
 
     /// Encodes a single symbol
     #[inline]
-    fn encode(&mut self, output: &mut impl WriteU16, start: u32, freq: NonZeroU32) {
+    pub(crate) fn encode(&mut self, output: &mut impl WriteU16, start: u32, freq: NonZeroU32) {
         let mut x = self.0;
         let x_max = ((RANS_WORD_L >> SCALE_BITS) << 16) * u32::from(freq);
 
@@ -110,6 +119,23 @@ impl<const SCALE_BITS: u32> Rans32State<SCALE_BITS> {
         self.0 = (x / freq) << SCALE_BITS | (x % freq) + start;
     }
 
+    /// Encodes a symbol whose slots are not contiguous, `slots` lists the slot for each
+    /// offset within the frequency of the symbol
+    #[inline]
+    pub(crate) fn encode_slots(&mut self, output: &mut impl WriteU16, slots: &[u16]) {
+        let freq = slots.len() as u32;
+        let mut x = self.0;
+        let x_max = ((RANS_WORD_L >> SCALE_BITS) << 16) * freq;
+
+        if x >= x_max {
+            output.write_u16(x as u16);
+            x >>= 16;
+            debug_assert!(x < x_max);
+        }
+
+        self.0 = (x / freq) << SCALE_BITS | u32::from(slots[(x % freq) as usize]);
+    }
+
     /// Encodes 2 symbols in parallel
     #[inline]
     fn encode_2(
@@ -141,44 +167,188 @@ impl<const SCALE_BITS: u32> Rans32State<SCALE_BITS> {
     }
 }
 
+/// rANS state that `RansBinaryWriter`/`RansBinaryReader` interleave two of to code bits with a
+/// fixed probability. It sets the precision of the probabilities and the size of the words that
+/// are written out on renormalization.
+pub trait RansBinaryState: Copy {
+    /// word that is written out or read in on renormalization
+    type Word: Pod;
+
+    /// number of bits of the probabilities
+    const SCALE_BITS: u32;
+
+    fn new_encoder() -> Self;
+
+    fn new_decoder(source: &mut impl Read) -> Result<Self>;
+
+    fn enc_flush(&mut self, buffer: &mut VecDeque<Self::Word>);
+
+    fn encode(&mut self, output: &mut VecDeque<Self::Word>, start: u32, freq: NonZeroU32);
+
+    /// encodes a symbol with each of the two states
+    #[inline]
+    fn encode_2(output: &mut VecDeque<Self::Word>, v: [(&mut Self, u32, NonZeroU32); 2]) {
+        let [(rans0, start0, freq0), (rans1, start1, freq1)] = v;
+        rans0.encode(output, start0, freq0);
+        rans1.encode(output, start1, freq1);
+    }
+
+    fn dec_get(&self) -> u32;
+
+    fn dec_advance(&mut self, source: &mut impl Read, start: u32, freq: NonZeroU32) -> Result<()>;
+}
+
+impl<const SCALE_BITS: u32> RansBinaryState for Rans32State<SCALE_BITS> {
+    type Word = u16;
+
+    const SCALE_BITS: u32 = SCALE_BITS;
+
+    #[inline]
+    fn new_encoder() -> Self {
+        Rans32State::new_encoder()
+    }
+
+    #[inline]
+    fn new_decoder(source: &mut impl Read) -> Result<Self> {
+        Rans32State::new_decoder(source)
+    }
+
+    #[inline]
+    fn enc_flush(&mut self, buffer: &mut VecDeque<u16>) {
+        Rans32State::enc_flush(self, buffer)
+    }
+
+    #[inline]
+    fn encode(&mut self, output: &mut VecDeque<u16>, start: u32, freq: NonZeroU32) {
+        Rans32State::encode(self, output, start, freq)
+    }
+
+    #[inline]
+    fn encode_2(output: &mut VecDeque<u16>, v: [(&mut Self, u32, NonZeroU32); 2]) {
+        Rans32State::encode_2(output, v)
+    }
+
+    #[inline]
+    fn dec_get(&self) -> u32 {
+        Rans32State::dec_get(self)
+    }
+
+    #[inline]
+    fn dec_advance(&mut self, source: &mut impl Read, start: u32, freq: NonZeroU32) -> Result<()> {
+        Rans32State::dec_advance(self, source, start, freq)
+    }
+}
+
+/// bit and frequency of a buffered symbol, with the bit on top of the 31 bits of the frequency
+/// so that the buffers take as little of the cache as possible
 #[derive(Copy, Clone)]
-struct Symbol {
-    bit: bool,
-    prob: NonZeroU8,
+pub(crate) struct Symbol(u32);
+
+impl Symbol {
+    #[inline]
+    pub(crate) fn new(bit: bool, freq: NonZeroU32) -> Self {
+        Symbol(u32::from(bit) << 31 | freq.get())
+    }
+
+    #[inline]
+    pub(crate) fn bit(self) -> bool {
+        self.0 >> 31 != 0
+    }
+
+    #[inline]
+    pub(crate) fn freq(self) -> NonZeroU32 {
+        NonZeroU32::new(self.0 & !(1 << 31)).unwrap()
+    }
 }
 
-impl Default for Symbol {
-    fn default() -> Self {
-        Symbol {
-            bit: false,
-            prob: NonZeroU8::new(128).unwrap(),
-        }
+pub(crate) const STACK_SIZE: usize = 16386;
+
+/// returns the start and frequency of the bit, where zero has a frequency of freq out of
+/// 1 << scale_bits
+#[inline]
+pub(crate) fn start_freq(bit: bool, freq: NonZeroU32, scale_bits: u32) -> (u32, NonZeroU32) {
+    // the frequency of a one is nonzero since freq is checked to be below 1 << scale_bits, so
+    // the fallback is never taken and there is no panic that would keep this from being
+    // compiled to conditional moves
+    let other = NonZeroU32::new((1 << scale_bits) - freq.get()).unwrap_or(freq);
+    if bit {
+        (freq.get(), other)
+    } else {
+        (0, freq)
     }
 }
 
-const STACK_SIZE: usize = 16386;
+/// scales a probability of prob / 256 to the precision of the state
+#[inline]
+fn scale_probability<S: RansBinaryState>(prob: NonZeroU8) -> NonZeroU32 {
+    NonZeroU32::from(prob)
+        .checked_mul(NonZeroU32::new(1 << (S::SCALE_BITS - 8)).unwrap())
+        .unwrap()
+}
+
+/// the frequency of a probability of 1/2
+#[inline]
+fn half<S: RansBinaryState>() -> NonZeroU32 {
+    NonZeroU32::new(1 << (S::SCALE_BITS - 1)).unwrap()
+}
+
+fn check_frequency<S: RansBinaryState>(freq: NonZeroU32) -> Result<()> {
+    if freq.get() >= 1 << S::SCALE_BITS {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "probability must be below 1 << SCALE_BITS",
+        ));
+    }
+    Ok(())
+}
 
-pub struct RansWriter32<W> {
+/// writes bits with a fixed probability with two alternating rANS states. The bits are buffered
+/// and encoded in reverse in blocks of `STACK_SIZE`, each followed by the final states.
+pub struct RansBinaryWriter<S, W> {
     upstream_writer: W,
     symbol_buffer: Box<[Symbol; STACK_SIZE]>,
     symbol_buffer_stack: usize,
+    state: PhantomData<S>,
 }
 
-impl<W: Write> RansWriter32<W> {
+/// binary rANS writer with 32 bit states and 8 bit probabilities
+pub type RansWriter32<W> = RansBinaryWriter<Rans32State<8>, W>;
+
+impl<S: RansBinaryState, W: Write> RansBinaryWriter<S, W> {
     pub fn new(writer: W) -> Self {
-        RansWriter32 {
+        RansBinaryWriter {
             upstream_writer: writer,
-            symbol_buffer: Box::new([Symbol::default(); STACK_SIZE]),
+            symbol_buffer: Box::new([Symbol(1); STACK_SIZE]),
             symbol_buffer_stack: STACK_SIZE,
+            state: PhantomData,
+        }
+    }
+
+    /// writes a bit with a fixed probability of it being zero of freq / (1 << S::SCALE_BITS)
+    pub fn put_with_frequency(&mut self, bit: bool, freq: NonZeroU32) -> Result<()> {
+        check_frequency::<S>(freq)?;
+
+        if self.symbol_buffer_stack == 0 {
+            self.flush()?;
         }
+
+        self.symbol_buffer_stack -= 1;
+        self.symbol_buffer[self.symbol_buffer_stack] = Symbol::new(bit, freq);
+        Ok(())
+    }
+
+    /// writes a bit with a fixed probability of it being zero of prob / 256, rather than with
+    /// an adaptive context
+    pub fn put_with_probability(&mut self, bit: bool, prob: NonZeroU8) -> Result<()> {
+        self.put_with_frequency(bit, scale_probability::<S>(prob))
     }
 
     #[cold]
     fn flush(&mut self) -> Result<()> {
-        let mut rans0 = Rans32State::<8>::new_encoder();
-        let mut rans1 = Rans32State::<8>::new_encoder();
+        let mut rans0 = S::new_encoder();
+        let mut rans1 = S::new_encoder();
 
-        let mut write_buffer: VecDeque<u16> = VecDeque::new();
+        let mut write_buffer: VecDeque<S::Word> = VecDeque::new();
 
         assert!(self.symbol_buffer_stack < STACK_SIZE);
 
@@ -189,7 +359,7 @@ impl<W: Write> RansWriter32<W> {
         // and then swap the rans states so that we can encode the rest aligned
         if odd {
             let s0 = self.symbol_buffer[i];
-            let (start, freq) = start_freq(s0.bit, s0.prob);
+            let (start, freq) = start_freq(s0.bit(), s0.freq(), S::SCALE_BITS);
             rans0.encode(&mut write_buffer, start, freq);
             i += 1;
 
@@ -198,11 +368,11 @@ impl<W: Write> RansWriter32<W> {
 
         while i < STACK_SIZE {
             let s0 = self.symbol_buffer[i];
-            let (start0, freq0) = start_freq(s0.bit, s0.prob);
+            let (start0, freq0) = start_freq(s0.bit(), s0.freq(), S::SCALE_BITS);
             let s1 = self.symbol_buffer[i + 1];
-            let (start1, freq1) = start_freq(s1.bit, s1.prob);
+            let (start1, freq1) = start_freq(s1.bit(), s1.freq(), S::SCALE_BITS);
 
-            Rans32State::<8>::encode_2(
+            S::encode_2(
                 &mut write_buffer,
                 [(&mut rans0, start0, freq0), (&mut rans1, start1, freq1)],
             );
@@ -214,72 +384,60 @@ impl<W: Write> RansWriter32<W> {
 
         let slices = write_buffer.as_slices();
 
-        self.upstream_writer.write_all(cast_slice(&slices.0))?;
-        self.upstream_writer.write_all(cast_slice(&slices.1))?;
+        self.upstream_writer.write_all(cast_slice(slices.0))?;
+        self.upstream_writer.write_all(cast_slice(slices.1))?;
 
         self.symbol_buffer_stack = STACK_SIZE;
         Ok(())
     }
+
+    /// writes a bit with a probability of 1/2 without a context
+    pub fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_with_frequency(bit, half::<S>())
+    }
+
+    /// encodes the buffered bits and writes them out
+    pub fn finish(&mut self) -> Result<()> {
+        self.flush()
+    }
 }
 
-impl<W: Write> CabacWriter<VP8Context> for RansWriter32<W> {
+impl<S: RansBinaryState, W: Write> CabacWriter<VP8Context> for RansBinaryWriter<S, W> {
     fn put(&mut self, bit: bool, branch: &mut VP8Context) -> Result<()> {
         let prob = branch.get_probability();
         let b = branch.record_and_update_bit(bit);
 
-        if self.symbol_buffer_stack == 0 {
-            self.flush()?;
-        }
+        self.put_with_probability(bit, prob)?;
 
         *branch = b;
-        self.symbol_buffer_stack -= 1;
-        self.symbol_buffer[self.symbol_buffer_stack] = Symbol { bit, prob };
         Ok(())
     }
 
     fn put_bypass(&mut self, bit: bool) -> Result<()> {
-        if self.symbol_buffer_stack == 0 {
-            self.flush()?;
-        }
-
-        self.symbol_buffer_stack -= 1;
-        self.symbol_buffer[self.symbol_buffer_stack] = Symbol {
-            bit,
-            prob: NonZeroU8::new(128).unwrap(),
-        };
-        Ok(())
+        self.put_bypass(bit)
     }
 
     fn finish(&mut self) -> Result<()> {
-        self.flush()
-    }
-}
-
-#[inline]
-fn start_freq(bit: bool, prob: NonZeroU8) -> (u32, NonZeroU32) {
-    if bit {
-        (
-            prob.get() as u32,
-            NonZeroU32::new(256 - u32::from(prob.get())).unwrap(),
-        )
-    } else {
-        (0, NonZeroU32::from(prob))
+        self.finish()
     }
 }
 
 /// implements two parallel RANS readers that alternate
-pub struct RansReader32<R> {
-    rans0: Rans32State<8>,
-    rans1: Rans32State<8>,
+pub struct RansBinaryReader<S, R> {
+    rans0: S,
+    rans1: S,
     upstream_reader: R,
     bits_read: usize,
 }
 
-impl<R: Read> RansReader32<R> {
+/// binary rANS reader with 32 bit states and 8 bit probabilities
+pub type RansReader32<R> = RansBinaryReader<Rans32State<8>, R>;
+
+impl<S: RansBinaryState, R: Read> RansBinaryReader<S, R> {
     pub fn new(mut reader: R) -> Result<Self> {
-        let rans0 = Rans32State::new_decoder(&mut reader)?;
-        let rans1 = Rans32State::new_decoder(&mut reader)?;
-        Ok(RansReader32 {
+        let rans0 = S::new_decoder(&mut reader)?;
+        let rans1 = S::new_decoder(&mut reader)?;
+        Ok(RansBinaryReader {
             rans0,
             rans1,
             upstream_reader: reader,
@@ -287,58 +445,671 @@ impl<R: Read> RansReader32<R> {
         })
     }
 
+    /// reads a bit that was coded with a fixed probability of it being zero of
+    /// freq / (1 << S::SCALE_BITS)
+    pub fn get_with_frequency(&mut self, freq: NonZeroU32) -> Result<bool> {
+        check_frequency::<S>(freq)?;
+        self.check_reset_stream()?;
+
+        let mut local_state = self.rans0;
+        self.rans0 = self.rans1;
+
+        let bit = local_state.dec_get() >= freq.get();
+
+        let (start, freq) = start_freq(bit, freq, S::SCALE_BITS);
+        local_state.dec_advance(&mut self.upstream_reader, start, freq)?;
+
+        self.rans1 = local_state;
+        Ok(bit)
+    }
+
+    /// reads a bit that was coded with a fixed probability of it being zero of prob / 256,
+    /// rather than with an adaptive context
+    pub fn get_with_probability(&mut self, prob: NonZeroU8) -> Result<bool> {
+        self.get_with_frequency(scale_probability::<S>(prob))
+    }
+
     /// sees if we read enough bits to reset the stream to avoid the reverse buffers
     /// from growing too large
     pub fn check_reset_stream(&mut self) -> Result<()> {
         if self.bits_read == STACK_SIZE {
             self.bits_read = 0;
-            self.rans0 = Rans32State::new_decoder(&mut self.upstream_reader)?;
-            self.rans1 = Rans32State::new_decoder(&mut self.upstream_reader)?;
+            self.rans0 = S::new_decoder(&mut self.upstream_reader)?;
+            self.rans1 = S::new_decoder(&mut self.upstream_reader)?;
         }
         self.bits_read += 1;
         Ok(())
     }
-}
 
-impl<R: Read> CabacReader<VP8Context> for RansReader32<R> {
-    /// reads a bit and then swaps the rans states
-    fn get(&mut self, branch: &mut VP8Context) -> Result<bool> {
+    /// reads a bit without updating the probability
+    pub fn get_bypass(&mut self) -> Result<bool> {
         self.check_reset_stream()?;
 
         let mut local_state = self.rans0;
         self.rans0 = self.rans1;
 
-        let prob = branch.get_probability();
+        // the start of the interval is the top bit of the cumulative frequency, which avoids a
+        // branch on the bit
+        let start = local_state.dec_get() & half::<S>().get();
 
-        let cumulative_freq = local_state.dec_get();
+        local_state.dec_advance(&mut self.upstream_reader, start, half::<S>())?;
 
-        let bit = cumulative_freq >= u32::from(prob.get());
+        self.rans1 = local_state;
+        Ok(start != 0)
+    }
+}
 
-        let b = branch.record_and_update_bit(bit);
-        let (start, freq) = start_freq(bit, prob);
-        local_state.dec_advance(&mut self.upstream_reader, start, freq)?;
+impl<S: RansBinaryState, R: Read> CabacReader<VP8Context> for RansBinaryReader<S, R> {
+    /// reads a bit and then swaps the rans states
+    fn get(&mut self, branch: &mut VP8Context) -> Result<bool> {
+        let bit = self.get_with_probability(branch.get_probability())?;
 
-        *branch = b;
-        self.rans1 = local_state;
+        *branch = branch.record_and_update_bit(bit);
         Ok(bit)
     }
 
     /// reads a bit without updating the probability
     fn get_bypass(&mut self) -> Result<bool> {
-        self.check_reset_stream()?;
+        self.get_bypass()
+    }
+}
 
-        let mut local_state = self.rans0;
-        self.rans0 = self.rans1;
+impl<S: RansBinaryState, W: Write> ProbabilityWriter for RansBinaryWriter<S, W> {
+    fn put_with_probability(&mut self, bit: bool, probability: NonZeroU8) -> Result<()> {
+        self.put_with_probability(bit, probability)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<S: RansBinaryState, R: Read> ProbabilityReader for RansBinaryReader<S, R> {
+    fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool> {
+        self.get_with_probability(probability)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
+
+/// how the decoder finds the symbol for a slot of the cumulative frequencies
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RansLookup {
+    /// table with the symbol of every slot, 1 << SCALE_BITS bytes
+    Cumulative,
+    /// alias table with 2 symbols per bucket (Vose's alias method), which stays small enough
+    /// for the L1 cache with large SCALE_BITS. The slots are rearranged, so data written with
+    /// one lookup can't be read with the other.
+    Alias,
+}
+
+#[derive(Clone, Copy, Default)]
+struct AliasEntry {
+    /// slot where the second symbol of the bucket starts
+    divider: u32,
+    symbols: [u8; 2],
+    /// subtracted from the slot to get the offset within the frequency of the symbol
+    adjust: [u32; 2],
+}
 
-        let start = local_state.0 & 0x80;
+/// static frequency table for coding bytes with rANS. The frequencies add up to
+/// 1 << SCALE_BITS, which can be at most 15.
+pub struct RansSymbolTable<const SCALE_BITS: u32> {
+    pub(crate) freq: Vec<u32>,
+    pub(crate) start: Vec<u32>,
+    lookup: RansLookup,
+    /// symbol for each slot with the cumulative lookup
+    slot_symbols: Box<[u8]>,
+    /// buckets of the alias table
+    alias: Vec<AliasEntry>,
+    log_bucket_size: u32,
+    /// slot for each cumulative position with the alias lookup
+    pub(crate) alias_slots: Box<[u16]>,
+}
+
+fn invalid_table(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
 
-        local_state.dec_advance(
-            &mut self.upstream_reader,
+impl<const SCALE_BITS: u32> RansSymbolTable<SCALE_BITS> {
+    /// builds the table from normalized frequencies for the symbols 0..freq.len(), which must
+    /// add up to 1 << SCALE_BITS. No symbol can have the full range.
+    pub fn new(freq: &[u32], lookup: RansLookup) -> Result<Self> {
+        assert!(
+            (1..=15).contains(&SCALE_BITS),
+            "SCALE_BITS must be between 1 and 15"
+        );
+
+        let total = 1u32 << SCALE_BITS;
+        if freq.is_empty() || freq.len() > 256 {
+            return Err(invalid_table("invalid number of symbols"));
+        }
+        if freq.iter().map(|&f| u64::from(f)).sum::<u64>() != u64::from(total) {
+            return Err(invalid_table("frequencies don't add up to the scale"));
+        }
+        if freq.contains(&total) {
+            return Err(invalid_table("symbol can't have the full range"));
+        }
+        if lookup == RansLookup::Alias && freq.len() > total as usize {
+            return Err(invalid_table("more symbols than alias buckets"));
+        }
+
+        let mut start = Vec::with_capacity(freq.len());
+        let mut cumulative = 0;
+        for &f in freq {
+            start.push(cumulative);
+            cumulative += f;
+        }
+
+        let mut table = RansSymbolTable {
+            freq: freq.to_vec(),
             start,
-            NonZeroU32::new(128).unwrap(),
-        )?;
+            lookup,
+            slot_symbols: Box::default(),
+            alias: Vec::new(),
+            log_bucket_size: 0,
+            alias_slots: Box::default(),
+        };
+
+        match lookup {
+            RansLookup::Cumulative => {
+                let mut slot_symbols = vec![0u8; total as usize];
+                for (s, (&f, &st)) in freq.iter().zip(table.start.iter()).enumerate() {
+                    slot_symbols[st as usize..(st + f) as usize].fill(s as u8);
+                }
+                table.slot_symbols = slot_symbols.into_boxed_slice();
+            }
+            RansLookup::Alias => table.build_alias(),
+        }
+
+        Ok(table)
+    }
+
+    /// normalizes the symbol counts and builds the table
+    pub fn from_counts(counts: &[u32], lookup: RansLookup) -> Result<Self> {
+        Self::new(&normalize_frequencies(counts, SCALE_BITS)?, lookup)
+    }
+
+    pub fn get_frequencies(&self) -> &[u32] {
+        &self.freq
+    }
+
+    /// splits the range into one bucket per symbol (rounded up to a power of 2), where each
+    /// bucket holds the rest of the symbol it belongs to and fills up with a symbol that
+    /// has more than a bucket
+    fn build_alias(&mut self) {
+        let total = 1u32 << SCALE_BITS;
+        let buckets = self.freq.len().next_power_of_two();
+        let bucket_size = total / buckets as u32;
+        self.log_bucket_size = bucket_size.trailing_zeros();
+
+        let mut remaining: Vec<u32> = (0..buckets)
+            .map(|i| self.freq.get(i).copied().unwrap_or(0))
+            .collect();
+        self.alias = (0..buckets)
+            .map(|i| AliasEntry {
+                divider: (i as u32 + 1) * bucket_size,
+                symbols: [i as u8, i as u8],
+                adjust: [0, 0],
+            })
+            .collect();
+
+        let mut small: Vec<usize> = (0..buckets)
+            .filter(|&i| remaining[i] < bucket_size)
+            .collect();
+        let mut large: Vec<usize> = (0..buckets)
+            .filter(|&i| remaining[i] > bucket_size)
+            .collect();
+
+        while let Some(s) = small.pop() {
+            let l = large.pop().expect("frequencies add up to the scale");
+
+            self.alias[s].divider = s as u32 * bucket_size + remaining[s];
+            self.alias[s].symbols[1] = l as u8;
+
+            remaining[l] -= bucket_size - remaining[s];
+            if remaining[l] < bucket_size {
+                small.push(l);
+            } else if remaining[l] > bucket_size {
+                large.push(l);
+            }
+        }
+
+        // number the slots of each symbol in the order of the buckets
+        let mut next_offset = vec![0u32; self.freq.len()];
+        let mut alias_slots = vec![0u16; total as usize];
+        for (b, entry) in self.alias.iter_mut().enumerate() {
+            let bucket_start = b as u32 * bucket_size;
+            let pieces = [
+                (bucket_start, entry.divider),
+                (entry.divider, bucket_start + bucket_size),
+            ];
+
+            for (i, &(first, end)) in pieces.iter().enumerate() {
+                if first == end {
+                    continue;
+                }
+                let s = usize::from(entry.symbols[i]);
+                let offset = next_offset[s];
+                entry.adjust[i] = first - offset;
+
+                for slot in first..end {
+                    alias_slots[(self.start[s] + slot - entry.adjust[i]) as usize] = slot as u16;
+                }
+                next_offset[s] += end - first;
+            }
+        }
+        self.alias_slots = alias_slots.into_boxed_slice();
+    }
+
+    /// returns the slot that codes the cumulative position, which the alias lookup rearranges
+    #[cfg(feature = "simd")]
+    pub(crate) fn position_slot(&self, position: u32) -> u32 {
+        match self.lookup {
+            RansLookup::Cumulative => position,
+            RansLookup::Alias => u32::from(self.alias_slots[position as usize]),
+        }
+    }
+
+    /// returns the symbol and the start to subtract from the slot for decoding
+    #[inline(always)]
+    pub(crate) fn decode_slot(&self, slot: u32) -> (u8, u32) {
+        match self.lookup {
+            RansLookup::Cumulative => {
+                let s = self.slot_symbols[slot as usize];
+                (s, self.start[usize::from(s)])
+            }
+            RansLookup::Alias => {
+                let entry = &self.alias[(slot >> self.log_bucket_size) as usize];
+                let i = usize::from(slot >= entry.divider);
+                (entry.symbols[i], entry.adjust[i])
+            }
+        }
+    }
+
+    /// encodes the symbol into the state with the slots of the lookup
+    #[inline]
+    pub(crate) fn encode(
+        &self,
+        state: &mut Rans32State<SCALE_BITS>,
+        output: &mut impl WriteU16,
+        symbol: u8,
+    ) {
+        let s = usize::from(symbol);
+        let (start, freq) = (self.start[s], self.freq[s]);
+
+        match self.lookup {
+            RansLookup::Cumulative => state.encode(output, start, NonZeroU32::new(freq).unwrap()),
+            RansLookup::Alias => state.encode_slots(
+                output,
+                &self.alias_slots[start as usize..(start + freq) as usize],
+            ),
+        }
+    }
+
+    /// writes the frequencies, with the last one implied by the sum. Each frequency is a
+    /// variable length integer with 7 bits per byte, and a zero is followed by the number of
+    /// further zeros.
+    pub fn write_table(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&[(self.freq.len() - 1) as u8])?;
+
+        let mut i = 0;
+        while i < self.freq.len() - 1 {
+            write_varint(writer, self.freq[i])?;
+            if self.freq[i] == 0 {
+                let run = self.freq[i + 1..self.freq.len() - 1]
+                    .iter()
+                    .take(255)
+                    .take_while(|&&f| f == 0)
+                    .count();
+                writer.write_all(&[run as u8])?;
+                i += run;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// reads a table written by `write_table`
+    pub fn read_table(reader: &mut impl Read, lookup: RansLookup) -> Result<Self> {
+        let mut b = [0u8; 1];
+        reader.read_exact(&mut b)?;
+        let len = usize::from(b[0]) + 1;
+
+        let mut freq = Vec::with_capacity(len);
+        let mut sum = 0u64;
+        while freq.len() < len - 1 {
+            let f = read_varint(reader)?;
+            freq.push(f);
+            sum += u64::from(f);
+
+            if f == 0 {
+                reader.read_exact(&mut b)?;
+                if freq.len() + usize::from(b[0]) > len - 1 {
+                    return Err(invalid_table("zero run past the last symbol"));
+                }
+                freq.resize(freq.len() + usize::from(b[0]), 0);
+            }
+        }
+
+        let total = 1u64 << SCALE_BITS;
+        if sum > total {
+            return Err(invalid_table("frequencies don't add up to the scale"));
+        }
+        freq.push((total - sum) as u32);
+
+        Self::new(&freq, lookup)
+    }
+}
+
+fn write_varint(writer: &mut impl Write, mut value: u32) -> Result<()> {
+    while value >= 0x80 {
+        writer.write_all(&[(value as u8) | 0x80])?;
+        value >>= 7;
+    }
+    writer.write_all(&[value as u8])
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..21).step_by(7) {
+        let mut b = [0u8; 1];
+        reader.read_exact(&mut b)?;
+        value |= u32::from(b[0] & 0x7f) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_table("frequency too large"))
+}
+
+/// scales the counts so that they add up to 1 << scale_bits, keeping every symbol that occurs
+/// at a frequency of at least 1 and no symbol at the full range
+pub fn normalize_frequencies(counts: &[u32], scale_bits: u32) -> Result<Vec<u32>> {
+    let total = 1u64 << scale_bits;
+    let sum: u64 = counts.iter().map(|&c| u64::from(c)).sum();
+    let used = counts.iter().filter(|&&c| c != 0).count() as u64;
+    if sum == 0 || used > total || counts.len() > 256 {
+        return Err(invalid_table("counts can't be normalized"));
+    }
+
+    let mut freq: Vec<u32> = counts
+        .iter()
+        .map(|&c| {
+            if c == 0 {
+                0
+            } else {
+                ((u64::from(c) * total + sum / 2) / sum).max(1) as u32
+            }
+        })
+        .collect();
+
+    // a single symbol still needs a second one so that it doesn't take the full range
+    if used == 1 {
+        if counts.len() == 1 {
+            freq.push(0);
+        }
+        let other = usize::from(freq[0] != 0);
+        freq[other] = 1;
+    }
+
+    let mut distributed: u64 = freq.iter().map(|&f| u64::from(f)).sum();
+    while distributed != total {
+        let largest = (0..freq.len()).max_by_key(|&i| freq[i]).unwrap();
+        if distributed < total {
+            freq[largest] += (total - distributed) as u32;
+            distributed = total;
+        } else {
+            freq[largest] -= 1;
+            distributed -= 1;
+        }
+    }
+
+    Ok(freq)
+}
+
+/// writes bytes with a static frequency table using two interleaved rANS states
+pub struct RansSymbolWriter32<'a, W, const SCALE_BITS: u32> {
+    upstream_writer: W,
+    table: &'a RansSymbolTable<SCALE_BITS>,
+    symbols: Vec<u8>,
+}
+
+impl<'a, W: Write, const SCALE_BITS: u32> RansSymbolWriter32<'a, W, SCALE_BITS> {
+    pub fn new(writer: W, table: &'a RansSymbolTable<SCALE_BITS>) -> Self {
+        RansSymbolWriter32 {
+            upstream_writer: writer,
+            table,
+            symbols: Vec::with_capacity(STACK_SIZE),
+        }
+    }
+
+    /// writes a symbol, which must have a nonzero frequency in the table
+    pub fn put_symbol(&mut self, symbol: u8) -> Result<()> {
+        if self
+            .table
+            .freq
+            .get(usize::from(symbol))
+            .copied()
+            .unwrap_or(0)
+            == 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "symbol has a frequency of zero",
+            ));
+        }
+
+        self.symbols.push(symbol);
+        if self.symbols.len() == STACK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let table = self.table;
+        let mut rans = [
+            Rans32State::<SCALE_BITS>::new_encoder(),
+            Rans32State::<SCALE_BITS>::new_encoder(),
+        ];
+
+        let mut write_buffer: VecDeque<u16> = VecDeque::new();
+
+        // the decoder alternates between the states starting with the first
+        for (i, &s) in self.symbols.iter().enumerate().rev() {
+            table.encode(&mut rans[i & 1], &mut write_buffer, s);
+        }
+
+        rans[1].enc_flush(&mut write_buffer);
+        rans[0].enc_flush(&mut write_buffer);
+
+        let slices = write_buffer.as_slices();
+
+        self.upstream_writer.write_all(cast_slice(slices.0))?;
+        self.upstream_writer.write_all(cast_slice(slices.1))?;
+
+        self.symbols.clear();
+        Ok(())
+    }
+
+    /// encodes the buffered symbols and writes them out
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.symbols.is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// reads bytes written by `RansSymbolWriter32` with the same table
+pub struct RansSymbolReader32<'a, R, const SCALE_BITS: u32> {
+    rans0: Rans32State<SCALE_BITS>,
+    rans1: Rans32State<SCALE_BITS>,
+    upstream_reader: R,
+    table: &'a RansSymbolTable<SCALE_BITS>,
+    symbols_read: usize,
+}
+
+impl<'a, R: Read, const SCALE_BITS: u32> RansSymbolReader32<'a, R, SCALE_BITS> {
+    pub fn new(reader: R, table: &'a RansSymbolTable<SCALE_BITS>) -> Self {
+        RansSymbolReader32 {
+            rans0: Rans32State(0),
+            rans1: Rans32State(0),
+            upstream_reader: reader,
+            table,
+            symbols_read: STACK_SIZE,
+        }
+    }
+
+    pub fn get_symbol(&mut self) -> Result<u8> {
+        if self.symbols_read == STACK_SIZE {
+            self.symbols_read = 0;
+            self.rans0 = Rans32State::new_decoder(&mut self.upstream_reader)?;
+            self.rans1 = Rans32State::new_decoder(&mut self.upstream_reader)?;
+        }
+        self.symbols_read += 1;
+
+        let mut local_state = self.rans0;
+        self.rans0 = self.rans1;
+
+        let (symbol, start) = self.table.decode_slot(local_state.dec_get());
+        let freq = NonZeroU32::new(self.table.freq[usize::from(symbol)]).unwrap();
+        local_state.dec_advance(&mut self.upstream_reader, start, freq)?;
 
         self.rans1 = local_state;
-        Ok(start != 0)
+        Ok(symbol)
+    }
+}
+
+/// round trips the symbols with a table made from their counts and checks the size of the
+/// output against the cost of the symbols under the table
+#[cfg(test)]
+fn symbol_roundtrip<const SCALE_BITS: u32>(symbols: &[u8], lookup: RansLookup) {
+    use crate::test_util::assert_near_cost;
+
+    let mut counts = [0u32; 256];
+    symbols.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let last = counts.iter().rposition(|&c| c != 0).unwrap();
+    let table = RansSymbolTable::<SCALE_BITS>::from_counts(&counts[..=last], lookup).unwrap();
+
+    let mut output = Vec::new();
+    table.write_table(&mut output).unwrap();
+    let table_len = output.len();
+    let mut writer = RansSymbolWriter32::new(&mut output, &table);
+    for &s in symbols {
+        writer.put_symbol(s).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // besides the table, each block ends with two 32 bit states
+    let indices: Vec<usize> = symbols.iter().map(|&s| usize::from(s)).collect();
+    let overhead = table_len + 8 * symbols.len().div_ceil(STACK_SIZE);
+    assert_near_cost(
+        &indices,
+        table.get_frequencies(),
+        SCALE_BITS,
+        output.len(),
+        overhead,
+    );
+
+    let mut input = &output[..];
+    let read = RansSymbolTable::<SCALE_BITS>::read_table(&mut input, lookup).unwrap();
+    assert_eq!(read.get_frequencies(), table.get_frequencies());
+
+    let mut reader = RansSymbolReader32::new(input, &read);
+    for &s in symbols {
+        assert_eq!(reader.get_symbol().unwrap(), s);
+    }
+}
+
+#[test]
+fn rans_symbols_roundtrip() {
+    use crate::test_util::random_symbols;
+
+    // long enough for several blocks
+    let symbols: Vec<u8> = random_symbols(3 * STACK_SIZE + 17, 256)
+        .into_iter()
+        .map(|s| s as u8)
+        .collect();
+
+    for lookup in [RansLookup::Cumulative, RansLookup::Alias] {
+        symbol_roundtrip::<15>(&symbols, lookup);
+        symbol_roundtrip::<12>(&symbols, lookup);
+        symbol_roundtrip::<9>(&symbols, lookup);
+    }
+
+    // a single symbol and tiny alphabets
+    symbol_roundtrip::<4>(&[7; 100], RansLookup::Alias);
+    symbol_roundtrip::<4>(&[0; 100], RansLookup::Cumulative);
+    symbol_roundtrip::<1>(&[1, 0, 1, 1], RansLookup::Alias);
+}
+
+#[test]
+fn rans_alias_table_covers_all_slots() {
+    let freq = [1, 700, 3, 0, 40, 280];
+    let table = RansSymbolTable::<10>::new(&freq, RansLookup::Alias).unwrap();
+
+    // every slot of a symbol decodes back to the symbol at a distinct offset
+    let mut seen = vec![false; 1024];
+    for (s, &f) in freq.iter().enumerate() {
+        let start = table.start[s];
+        for offset in 0..f {
+            let slot = table.alias_slots[(start + offset) as usize];
+            assert!(!seen[usize::from(slot)]);
+            seen[usize::from(slot)] = true;
+
+            let (symbol, adjust) = table.decode_slot(u32::from(slot));
+            assert_eq!(usize::from(symbol), s);
+            assert_eq!(u32::from(slot) - adjust, offset);
+        }
+    }
+    assert!(seen.iter().all(|&s| s));
+}
+
+#[test]
+fn rans_symbols_known_answer() {
+    let text = b"abracadabra arbadacarba abracadabra";
+    let mut counts = [0u32; 256];
+    text.iter().for_each(|&s| counts[usize::from(s)] += 1);
+
+    // the lookups arrange the slots of the symbols differently
+    let expected: [(RansLookup, [u8; 16]); 2] = [
+        (
+            RansLookup::Cumulative,
+            [
+                0x72, 0x94, 0xba, 0x0e, 0xc4, 0xb9, 0x05, 0x00, 0x43, 0x56, 0x7b, 0x51, 0xe2, 0xc8,
+                0x92, 0xc3,
+            ],
+        ),
+        (
+            RansLookup::Alias,
+            [
+                0xbf, 0x94, 0xba, 0x0e, 0xf0, 0xb9, 0x05, 0x00, 0x9b, 0x31, 0x83, 0x51, 0x50, 0xc9,
+                0xc1, 0x31,
+            ],
+        ),
+    ];
+
+    for (lookup, expected) in expected {
+        let table = RansSymbolTable::<12>::from_counts(&counts, lookup).unwrap();
+        let mut output = Vec::new();
+        let mut writer = RansSymbolWriter32::new(&mut output, &table);
+        for &s in text {
+            writer.put_symbol(s).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(output, expected);
+
+        let mut reader = RansSymbolReader32::new(&output[..], &table);
+        for &s in text {
+            assert_eq!(reader.get_symbol().unwrap(), s);
+        }
     }
 }
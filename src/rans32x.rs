@@ -0,0 +1,520 @@
+//! N-way interleaved 32 bit rANS, based on the interleaved and SIMD rANS of ryg_rans.
+//!
+//! Instead of alternating between two states like `RansWriter32`/`RansReader32`, symbol i of a
+//! block is coded with state i % N, where N is 4, 8, 16 or 32. The states don't depend on each
+//! other, so the decoder can work on several of them at the same time. With the `simd` feature,
+//! `RansSymbolReader32x::get_symbols` decodes N symbols at a time in SIMD lanes, reading the
+//! renormalization words of the lanes in order. Bits coded with adaptive contexts depend on the
+//! previous bit, so the binary `RansReader32x` always decodes one state at a time.
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Read, Result, Write},
+    num::{NonZeroU32, NonZeroU8},
+};
+
+use bytemuck::cast_slice;
+
+#[cfg(feature = "simd")]
+use wide::u32x4;
+
+use crate::{
+    rans32::{start_freq, Rans32State, RansSymbolTable, Symbol},
+    traits::{CabacReader, CabacWriter, ProbabilityReader, ProbabilityWriter},
+    vp8::VP8Context,
+};
+
+/// number of symbols coded before the states are flushed, a multiple of every N
+const BLOCK_SIZE: usize = 1 << 14;
+
+fn check_ways<const N: usize>() {
+    assert!(matches!(N, 4 | 8 | 16 | 32), "N must be 4, 8, 16 or 32");
+}
+
+/// encodes the states so that the decoder reads state 0 first
+fn flush_states<const SCALE_BITS: u32>(
+    rans: &mut [Rans32State<SCALE_BITS>],
+    mut write_buffer: VecDeque<u16>,
+    writer: &mut impl Write,
+) -> Result<()> {
+    for state in rans.iter_mut().rev() {
+        state.enc_flush(&mut write_buffer);
+    }
+
+    let slices = write_buffer.as_slices();
+
+    writer.write_all(cast_slice(slices.0))?;
+    writer.write_all(cast_slice(slices.1))
+}
+
+fn read_states<const N: usize, const SCALE_BITS: u32>(
+    reader: &mut impl Read,
+) -> Result<[Rans32State<SCALE_BITS>; N]> {
+    let mut states = [Rans32State(0); N];
+    for state in states.iter_mut() {
+        *state = Rans32State::new_decoder(reader)?;
+    }
+    Ok(states)
+}
+
+/// binary rANS coder with N interleaved states
+pub struct RansWriter32x<W, const N: usize> {
+    upstream_writer: W,
+    symbols: Vec<Symbol>,
+}
+
+impl<W: Write, const N: usize> RansWriter32x<W, N> {
+    pub fn new(writer: W) -> Self {
+        check_ways::<N>();
+
+        RansWriter32x {
+            upstream_writer: writer,
+            symbols: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// writes a bit with a fixed probability of it being zero of prob / 256, rather than with
+    /// an adaptive context
+    pub fn put_with_probability(&mut self, bit: bool, prob: NonZeroU8) -> Result<()> {
+        if self.symbols.len() == BLOCK_SIZE {
+            self.flush()?;
+        }
+
+        self.symbols.push(Symbol::new(bit, prob.into()));
+        Ok(())
+    }
+
+    /// writes a bit with a probability of 1/2 without a context
+    pub fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_with_probability(bit, NonZeroU8::new(128).unwrap())
+    }
+
+    #[cold]
+    fn flush(&mut self) -> Result<()> {
+        let mut rans = [Rans32State::<8>::new_encoder(); N];
+        let mut write_buffer: VecDeque<u16> = VecDeque::new();
+
+        for (i, s) in self.symbols.iter().enumerate().rev() {
+            let (start, freq) = start_freq(s.bit(), s.freq(), 8);
+            rans[i % N].encode(&mut write_buffer, start, freq);
+        }
+
+        flush_states(&mut rans, write_buffer, &mut self.upstream_writer)?;
+
+        self.symbols.clear();
+        Ok(())
+    }
+
+    /// encodes the buffered bits and writes them out
+    pub fn finish(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl<W: Write, const N: usize> CabacWriter<VP8Context> for RansWriter32x<W, N> {
+    fn put(&mut self, bit: bool, branch: &mut VP8Context) -> Result<()> {
+        let prob = branch.get_probability();
+        let b = branch.record_and_update_bit(bit);
+
+        self.put_with_probability(bit, prob)?;
+
+        *branch = b;
+        Ok(())
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, const N: usize> ProbabilityWriter for RansWriter32x<W, N> {
+    fn put_with_probability(&mut self, bit: bool, probability: NonZeroU8) -> Result<()> {
+        self.put_with_probability(bit, probability)
+    }
+
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// reads bits written by `RansWriter32x` with the same N, one state at a time
+pub struct RansReader32x<R, const N: usize> {
+    rans: [Rans32State<8>; N],
+    upstream_reader: R,
+    bits_read: usize,
+}
+
+impl<R: Read, const N: usize> RansReader32x<R, N> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        check_ways::<N>();
+
+        Ok(RansReader32x {
+            rans: read_states(&mut reader)?,
+            upstream_reader: reader,
+            bits_read: 0,
+        })
+    }
+
+    /// returns the state of the next bit, and reads the states of the next block at the end
+    /// of the current one
+    #[inline]
+    fn next_state(&mut self) -> Result<usize> {
+        if self.bits_read == BLOCK_SIZE {
+            self.bits_read = 0;
+            self.rans = read_states(&mut self.upstream_reader)?;
+        }
+        self.bits_read += 1;
+        Ok((self.bits_read - 1) % N)
+    }
+
+    /// reads a bit that was coded with a fixed probability of it being zero of prob / 256,
+    /// rather than with an adaptive context
+    pub fn get_with_probability(&mut self, prob: NonZeroU8) -> Result<bool> {
+        let i = self.next_state()?;
+        let state = &mut self.rans[i];
+
+        let bit = state.dec_get() >= u32::from(prob.get());
+
+        let (start, freq) = start_freq(bit, prob.into(), 8);
+        state.dec_advance(&mut self.upstream_reader, start, freq)?;
+        Ok(bit)
+    }
+
+    /// reads a bit without updating the probability
+    pub fn get_bypass(&mut self) -> Result<bool> {
+        self.get_with_probability(NonZeroU8::new(128).unwrap())
+    }
+}
+
+impl<R: Read, const N: usize> CabacReader<VP8Context> for RansReader32x<R, N> {
+    fn get(&mut self, branch: &mut VP8Context) -> Result<bool> {
+        let bit = self.get_with_probability(branch.get_probability())?;
+
+        *branch = branch.record_and_update_bit(bit);
+        Ok(bit)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
+
+impl<R: Read, const N: usize> ProbabilityReader for RansReader32x<R, N> {
+    fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool> {
+        self.get_with_probability(probability)
+    }
+
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
+
+/// writes bytes with a static frequency table using N interleaved rANS states
+pub struct RansSymbolWriter32x<'a, W, const N: usize, const SCALE_BITS: u32> {
+    upstream_writer: W,
+    table: &'a RansSymbolTable<SCALE_BITS>,
+    symbols: Vec<u8>,
+}
+
+impl<'a, W: Write, const N: usize, const SCALE_BITS: u32>
+    RansSymbolWriter32x<'a, W, N, SCALE_BITS>
+{
+    pub fn new(writer: W, table: &'a RansSymbolTable<SCALE_BITS>) -> Self {
+        check_ways::<N>();
+
+        RansSymbolWriter32x {
+            upstream_writer: writer,
+            table,
+            symbols: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// writes a symbol, which must have a nonzero frequency in the table
+    pub fn put_symbol(&mut self, symbol: u8) -> Result<()> {
+        if self
+            .table
+            .freq
+            .get(usize::from(symbol))
+            .copied()
+            .unwrap_or(0)
+            == 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "symbol has a frequency of zero",
+            ));
+        }
+
+        self.symbols.push(symbol);
+        if self.symbols.len() == BLOCK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut rans = [Rans32State::<SCALE_BITS>::new_encoder(); N];
+        let mut write_buffer: VecDeque<u16> = VecDeque::new();
+
+        for (i, &s) in self.symbols.iter().enumerate().rev() {
+            self.table.encode(&mut rans[i % N], &mut write_buffer, s);
+        }
+
+        flush_states(&mut rans, write_buffer, &mut self.upstream_writer)?;
+
+        self.symbols.clear();
+        Ok(())
+    }
+
+    /// encodes the buffered symbols and writes them out
+    pub fn finish(&mut self) -> Result<()> {
+        if !self.symbols.is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// reads bytes written by `RansSymbolWriter32x` with the same N and table
+pub struct RansSymbolReader32x<'a, R, const N: usize, const SCALE_BITS: u32> {
+    rans: [Rans32State<SCALE_BITS>; N],
+    upstream_reader: R,
+    table: &'a RansSymbolTable<SCALE_BITS>,
+    symbols_read: usize,
+    /// symbol << 32 | (slot - start) << 16 | freq for every slot, so that the SIMD decoder
+    /// needs a single load per lane instead of the lookup of the table
+    #[cfg(feature = "simd")]
+    slots: Box<[u64]>,
+}
+
+impl<'a, R: Read, const N: usize, const SCALE_BITS: u32> RansSymbolReader32x<'a, R, N, SCALE_BITS> {
+    pub fn new(reader: R, table: &'a RansSymbolTable<SCALE_BITS>) -> Self {
+        check_ways::<N>();
+
+        RansSymbolReader32x {
+            rans: [Rans32State(0); N],
+            upstream_reader: reader,
+            table,
+            symbols_read: BLOCK_SIZE,
+            #[cfg(feature = "simd")]
+            slots: Self::slot_entries(table),
+        }
+    }
+
+    /// fills the slot entries symbol by symbol, which is much cheaper than a lookup per slot
+    #[cfg(feature = "simd")]
+    fn slot_entries(table: &RansSymbolTable<SCALE_BITS>) -> Box<[u64]> {
+        let mut slots = vec![0u64; 1 << SCALE_BITS].into_boxed_slice();
+        for (symbol, (&freq, &start)) in table.freq.iter().zip(&table.start).enumerate() {
+            for offset in 0..freq {
+                let slot = table.position_slot(start + offset);
+                slots[slot as usize] =
+                    (symbol as u64) << 32 | u64::from(offset) << 16 | u64::from(freq);
+            }
+        }
+        slots
+    }
+
+    #[inline]
+    fn check_reset_stream(&mut self) -> Result<()> {
+        if self.symbols_read == BLOCK_SIZE {
+            self.symbols_read = 0;
+            self.rans = read_states(&mut self.upstream_reader)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_symbol(&mut self) -> Result<u8> {
+        self.check_reset_stream()?;
+        let state = &mut self.rans[self.symbols_read % N];
+        self.symbols_read += 1;
+
+        let (symbol, start) = self.table.decode_slot(state.dec_get());
+        let freq = NonZeroU32::new(self.table.freq[usize::from(symbol)]).unwrap();
+        state.dec_advance(&mut self.upstream_reader, start, freq)?;
+
+        Ok(symbol)
+    }
+
+    /// fills the output with the next symbols. With the `simd` feature, the symbols are
+    /// decoded N at a time whenever the next symbol belongs to the first state.
+    pub fn get_symbols(&mut self, output: &mut [u8]) -> Result<()> {
+        let mut i = 0;
+        while i < output.len() {
+            #[cfg(feature = "simd")]
+            if self.symbols_read & (N - 1) == 0 && output.len() - i >= N {
+                self.check_reset_stream()?;
+                self.get_lanes(&mut output[i..i + N])?;
+                self.symbols_read += N;
+                i += N;
+                continue;
+            }
+
+            output[i] = self.get_symbol()?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// decodes a symbol from every state, 4 lanes at a time. The slot entries are gathered
+    /// with one load per lane, and the lanes that need a new word take it from a single read
+    /// of all the words of the group, in the order of the states like the scalar decoder.
+    #[cfg(feature = "simd")]
+    #[inline(always)]
+    fn get_lanes(&mut self, output: &mut [u8]) -> Result<()> {
+        let scale_mask = u32x4::splat((1 << SCALE_BITS) - 1);
+        let low_mask = u32x4::splat(0xffff);
+
+        for (states, output) in self
+            .rans
+            .chunks_exact_mut(4)
+            .zip(output.chunks_exact_mut(4))
+        {
+            let x = u32x4::from([states[0].0, states[1].0, states[2].0, states[3].0]);
+
+            let slots = (x & scale_mask).to_array();
+            let entries: [u64; 4] = std::array::from_fn(|lane| self.slots[slots[lane] as usize]);
+            for (o, &e) in output.iter_mut().zip(&entries) {
+                *o = (e >> 32) as u8;
+            }
+
+            let entries = u32x4::from(entries.map(|e| e as u32));
+            let x = (entries & low_mask) * (x >> SCALE_BITS) + (entries >> 16u32);
+
+            let renormalize = (x >> 16u32).cmp_eq(u32x4::splat(0));
+            let mask = bytemuck::cast::<_, wide::i32x4>(renormalize).move_mask();
+
+            let x = if mask != 0 {
+                let mut words = [0u16; 4];
+                let count = mask.count_ones() as usize;
+                self.upstream_reader
+                    .read_exact(&mut bytemuck::cast_slice_mut(&mut words)[..2 * count])?;
+
+                // lane i takes the word after those of the renormalizing lanes before it
+                let words = u32x4::from(std::array::from_fn(|lane| {
+                    u32::from(u16::from_le(
+                        words[(mask & ((1 << lane) - 1)).count_ones() as usize],
+                    ))
+                }));
+                renormalize.blend((x << 16u32) | words, x)
+            } else {
+                x
+            };
+
+            for (state, &x) in states.iter_mut().zip(x.as_array_ref()) {
+                state.0 = x;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn symbols_roundtrip<const N: usize>(symbols: &[u8], lookup: crate::rans32::RansLookup) {
+    use crate::test_util::assert_near_cost;
+
+    let mut counts = [0u32; 256];
+    symbols.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let table = RansSymbolTable::<12>::from_counts(&counts, lookup).unwrap();
+
+    let mut output = Vec::new();
+    let mut writer = RansSymbolWriter32x::<_, N, 12>::new(&mut output, &table);
+    for &s in symbols {
+        writer.put_symbol(s).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // each block ends with N 32 bit states
+    let indices: Vec<usize> = symbols.iter().map(|&s| usize::from(s)).collect();
+    let overhead = 4 * N * symbols.len().div_ceil(BLOCK_SIZE);
+    assert_near_cost(
+        &indices,
+        table.get_frequencies(),
+        12,
+        output.len(),
+        overhead,
+    );
+
+    // start with a few single symbols so that the groups don't start at the first state
+    let mut decoded = vec![0u8; symbols.len()];
+    let mut reader = RansSymbolReader32x::<_, N, 12>::new(&output[..], &table);
+    for d in decoded[..3].iter_mut() {
+        *d = reader.get_symbol().unwrap();
+    }
+    reader.get_symbols(&mut decoded[3..1000]).unwrap();
+    reader.get_symbols(&mut decoded[1000..]).unwrap();
+
+    assert!(decoded == symbols, "N = {N}");
+}
+
+#[test]
+fn rans32x_symbols_roundtrip() {
+    use crate::{rans32::RansLookup, test_util::random_symbols};
+
+    let symbols: Vec<u8> = random_symbols(2 * BLOCK_SIZE + 1001, 256)
+        .into_iter()
+        .map(|s| s as u8)
+        .collect();
+
+    for lookup in [RansLookup::Cumulative, RansLookup::Alias] {
+        symbols_roundtrip::<4>(&symbols, lookup);
+        symbols_roundtrip::<8>(&symbols, lookup);
+        symbols_roundtrip::<16>(&symbols, lookup);
+        symbols_roundtrip::<32>(&symbols, lookup);
+    }
+}
+
+#[test]
+fn rans32x_bits_roundtrip() {
+    let bins: Vec<(bool, NonZeroU8)> = (0..BLOCK_SIZE as u32 + 77)
+        .map(|i| (i % 3 == 0, NonZeroU8::new((i * 7 % 255 + 1) as u8).unwrap()))
+        .collect();
+
+    let mut output = Vec::new();
+    let mut writer = RansWriter32x::<_, 16>::new(&mut output);
+    for &(bit, prob) in &bins {
+        writer.put_with_probability(bit, prob).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut reader = RansReader32x::<_, 16>::new(&output[..]).unwrap();
+    for &(bit, prob) in &bins {
+        assert_eq!(reader.get_with_probability(prob).unwrap(), bit);
+    }
+}
+
+#[test]
+fn rans32x_symbols_known_answer() {
+    use crate::rans32::RansLookup;
+
+    let text = b"abracadabra arbadacarba abracadabra";
+    let mut counts = [0u32; 256];
+    text.iter().for_each(|&s| counts[usize::from(s)] += 1);
+    let table = RansSymbolTable::<12>::from_counts(&counts, RansLookup::Alias).unwrap();
+
+    let mut output = Vec::new();
+    let mut writer = RansSymbolWriter32x::<_, 4, 12>::new(&mut output, &table);
+    for &s in text {
+        writer.put_symbol(s).unwrap();
+    }
+    writer.finish().unwrap();
+    assert_eq!(
+        output,
+        [
+            0x84, 0xe5, 0x3d, 0x00, 0x0b, 0xb8, 0x07, 0x00, 0x9f, 0x2f, 0x3d, 0x00, 0x66, 0x43,
+            0x2f, 0xbf, 0x92, 0xbd, 0xe8, 0x84, 0xd4, 0x72
+        ]
+    );
+
+    let mut decoded = [0u8; 35];
+    let mut reader = RansSymbolReader32x::<_, 4, 12>::new(&output[..], &table);
+    reader.get_symbols(&mut decoded).unwrap();
+    assert_eq!(&decoded, text);
+}
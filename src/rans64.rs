@@ -0,0 +1,161 @@
+//! 64 bit rANS implementation with 32 bit renormalization, based on rans64 of ryg_rans.
+//!
+//! The state is kept between 1 << 31 and 1 << 63, so a renormalization only happens about
+//! every 32 bits of output instead of every 16 bits with `Rans32State`, and the probabilities
+//! can have up to 31 bits of precision. It plugs into the same interleaved binary coder as
+//! `RansWriter32`/`RansReader32`.
+use std::{
+    collections::VecDeque,
+    io::{Read, Result},
+    num::NonZeroU32,
+};
+
+use crate::rans32::{RansBinaryReader, RansBinaryState, RansBinaryWriter};
+
+/// number of bits of the probabilities passed to `put_with_frequency`/`get_with_frequency`
+pub const RANS64_SCALE_BITS: u32 = 31;
+
+/// reads a u32 from the reader in little endian format
+#[inline]
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+/// Rans64State is a 64 bit rANS state.
+/// The SCALE_BITS is the number of bits of resolution needed, at most 31.
+#[derive(Clone, Copy)]
+pub struct Rans64State<const SCALE_BITS: u32>(u64);
+
+impl<const SCALE_BITS: u32> std::fmt::Debug for Rans64State<SCALE_BITS> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
+}
+
+const RANS64_L: u64 = 1 << 31; // Lower bound of our normalization interval
+
+impl<const SCALE_BITS: u32> RansBinaryState for Rans64State<SCALE_BITS> {
+    type Word = u32;
+
+    const SCALE_BITS: u32 = SCALE_BITS;
+
+    #[inline]
+    fn new_encoder() -> Self {
+        Rans64State(RANS64_L)
+    }
+
+    /// Initializes a rANS decoder.
+    #[inline]
+    fn new_decoder(source: &mut impl Read) -> Result<Self> {
+        Ok(Rans64State(
+            u64::from(read_u32(source)?) | (u64::from(read_u32(source)?) << 32),
+        ))
+    }
+
+    /// Flushes the rANS encoder.
+    #[inline]
+    fn enc_flush(&mut self, buffer: &mut VecDeque<u32>) {
+        let x = self.0;
+
+        buffer.push_front(((x >> 32) as u32).to_le());
+        buffer.push_front((x as u32).to_le());
+    }
+
+    /// Encodes a single symbol
+    #[inline]
+    fn encode(&mut self, output: &mut VecDeque<u32>, start: u32, freq: NonZeroU32) {
+        let freq = u64::from(freq.get());
+        let mut x = self.0;
+        let x_max = ((RANS64_L >> SCALE_BITS) << 32) * freq;
+
+        if x >= x_max {
+            output.push_front((x as u32).to_le());
+            x >>= 32;
+            debug_assert!(x < x_max);
+        }
+
+        self.0 = ((x / freq) << SCALE_BITS) + (x % freq) + u64::from(start);
+    }
+
+    // Returns the current cumulative frequency.
+    #[inline]
+    fn dec_get(&self) -> u32 {
+        (self.0 & ((1u64 << SCALE_BITS) - 1)) as u32
+    }
+
+    // Advances in the bit stream by "popping" a single symbol.
+    #[inline]
+    fn dec_advance(&mut self, source: &mut impl Read, start: u32, freq: NonZeroU32) -> Result<()> {
+        let mask = (1u64 << SCALE_BITS) - 1;
+
+        // s, x = D(x)
+        let mut x = self.0;
+        x = u64::from(freq.get()) * (x >> SCALE_BITS) + (x & mask) - u64::from(start);
+
+        // Renormalize
+        if x < RANS64_L {
+            x = (x << 32) | u64::from(read_u32(source)?);
+            debug_assert!(x >= RANS64_L);
+        }
+
+        self.0 = x;
+        Ok(())
+    }
+}
+
+/// binary rANS writer with 64 bit states and 31 bit probabilities
+pub type RansWriter64<W> = RansBinaryWriter<Rans64State<RANS64_SCALE_BITS>, W>;
+
+/// binary rANS reader with 64 bit states and 31 bit probabilities
+pub type RansReader64<R> = RansBinaryReader<Rans64State<RANS64_SCALE_BITS>, R>;
+
+#[test]
+fn rans64_precise_probabilities() {
+    use crate::rans32::STACK_SIZE;
+
+    // probabilities far below 1/256 that the 8 bit coders can't represent
+    let freqs = [1u32, 1000, 1 << 20, (1 << 31) - 1, 1 << 30];
+
+    let bins: Vec<(bool, NonZeroU32)> = (0..3 * STACK_SIZE as u32 + 5)
+        .map(|i| {
+            let freq = freqs[i as usize % freqs.len()];
+            // the unlikely value comes up once in a while
+            let bit = if freq < 1 << 30 {
+                i % 997 != 0
+            } else {
+                i % 3 == 0
+            };
+            (bit, NonZeroU32::new(freq).unwrap())
+        })
+        .collect();
+
+    let mut output = Vec::new();
+    let mut writer = RansWriter64::new(&mut output);
+    for &(bit, freq) in &bins {
+        writer.put_with_frequency(bit, freq).unwrap();
+    }
+    writer.finish().unwrap();
+
+    // the cost of every bin in bits
+    let entropy: f64 = bins
+        .iter()
+        .map(|&(bit, freq)| {
+            let p = f64::from(freq.get()) / f64::from(1u32 << 31);
+            -if bit { 1.0 - p } else { p }.log2()
+        })
+        .sum();
+    // each block flushes 2 states of 64 bits
+    assert!(((output.len() * 8) as f64) < entropy + 4.0 * 128.0 + 64.0);
+
+    let mut reader = RansReader64::new(&output[..]).unwrap();
+    for &(bit, freq) in &bins {
+        assert_eq!(reader.get_with_frequency(freq).unwrap(), bit);
+    }
+
+    let mut writer = RansWriter64::new(Vec::new());
+    assert!(writer
+        .put_with_frequency(true, NonZeroU32::new(1 << 31).unwrap())
+        .is_err());
+}
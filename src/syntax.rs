@@ -0,0 +1,75 @@
+//! Binarizations shared by the syntax elements of H.264 and H.265.
+//!
+//! These only use bypass bins, so they work with any of the binary coders. The k-th order
+//! Exp-Golomb binarization (EGk) is the suffix of UEGk in H.264 (9.3.2.3) and is used for
+//! abs_mvd_minus2 and coeff_abs_level_remaining in H.265 (9.3.3.3).
+use std::io::{Error, ErrorKind, Result};
+
+use crate::traits::{CabacReader, CabacWriter};
+
+/// writes a k-th order Exp-Golomb code using bypass bins (EGk)
+pub fn put_exp_golomb<C, W: CabacWriter<C>>(writer: &mut W, value: u32, k: u32) -> Result<()> {
+    let mut value = value;
+    let mut k = k;
+
+    while value >= (1 << k) {
+        writer.put_bypass(true)?;
+        value -= 1 << k;
+        k += 1;
+    }
+    writer.put_bypass(false)?;
+
+    for i in (0..k).rev() {
+        writer.put_bypass((value >> i) & 1 != 0)?;
+    }
+
+    Ok(())
+}
+
+/// reads a k-th order Exp-Golomb code using bypass bins (EGk)
+pub fn get_exp_golomb<C, R: CabacReader<C>>(reader: &mut R, k: u32) -> Result<u32> {
+    let mut value = 0u64;
+    let mut k = k;
+
+    while reader.get_bypass()? {
+        value += 1 << k;
+        k += 1;
+
+        if k > 31 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Exp-Golomb code too long",
+            ));
+        }
+    }
+
+    for i in (0..k).rev() {
+        value += u64::from(reader.get_bypass()?) << i;
+    }
+
+    u32::try_from(value)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Exp-Golomb code out of range"))
+}
+
+#[test]
+fn exp_golomb_roundtrip() {
+    use crate::h265::{H265Context, H265Reader, H265Writer};
+
+    let values = [0, 1, 2, 3, 7, 8, 1000, 65535, 1 << 20];
+
+    let mut output = Vec::new();
+    let mut writer = H265Writer::new(&mut output);
+    for k in [0, 1, 3] {
+        for &v in &values {
+            put_exp_golomb::<H265Context, _>(&mut writer, v, k).unwrap();
+        }
+    }
+    writer.finish().unwrap();
+
+    let mut reader = H265Reader::new(&output[..]).unwrap();
+    for k in [0, 1, 3] {
+        for &v in &values {
+            assert_eq!(get_exp_golomb::<H265Context, _>(&mut reader, k).unwrap(), v);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! helpers shared by the tests of the coders
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// random symbols below `alphabet` for the round trip tests of the coders, where lower values
+/// are more likely (the minimum of two uniform values) so that there is something to compress.
+/// The generator is seeded so that a failure can be reproduced.
+pub(crate) fn random_symbols(len: usize, alphabet: usize) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    (0..len)
+        .map(|_| {
+            let a = rng.gen_range(0..alphabet);
+            let b = rng.gen_range(0..alphabet);
+            a.min(b)
+        })
+        .collect()
+}
+
+/// asserts that `len` bytes of output are close to the cost of coding the symbols with the
+/// frequencies of a static table, which are out of 1 << scale_bits. The coders lose well below
+/// 1% to that cost (rANS next to nothing, tANS a little because of how its states are spread),
+/// so anything beyond 1% and the `overhead` bytes that don't depend on the symbols, like the
+/// table and the final states, is space that the coder wastes.
+pub(crate) fn assert_near_cost(
+    symbols: &[usize],
+    freqs: &[u32],
+    scale_bits: u32,
+    len: usize,
+    overhead: usize,
+) {
+    let cost: f64 = symbols
+        .iter()
+        .map(|&s| f64::from(scale_bits) - f64::from(freqs[s]).log2())
+        .sum();
+
+    assert!(
+        (len * 8) as f64 <= cost * 1.01 + (overhead * 8) as f64,
+        "{len} bytes for a cost of {cost:.0} bits"
+    );
+}
@@ -1,4 +1,8 @@
-use std::{cmp, io::Result};
+use std::{
+    cmp,
+    io::{Error, ErrorKind, Result},
+    num::NonZeroU8,
+};
 
 /// implementation of a context aware binary arithmetic encoder
 pub trait CabacWriter<Context> {
@@ -78,6 +82,64 @@ pub trait CabacWriter<Context> {
 
         Ok(())
     }
+
+    /// writes the leaf `value` of a VP8/VP9 style tree (`vp8_tree_index` in libvpx), which is
+    /// an array of node pairs where a positive entry is the index of the next pair and an entry
+    /// <= 0 is a negated leaf value. There is one context per pair, so the bit at `tree[i]`
+    /// uses `contexts[i >> 1]`. This can express unbalanced trees that `put_branched` can't.
+    fn put_tree(&mut self, value: u8, tree: &[i8], contexts: &mut [Context]) -> Result<()> {
+        self.put_tree_from(value, tree, 0, contexts)
+    }
+
+    /// same as `put_tree` but starts at the pair at index `start` instead of the root, like
+    /// VP8 does for the token after a zero token (which skips the end of block branch)
+    fn put_tree_from(
+        &mut self,
+        value: u8,
+        tree: &[i8],
+        start: usize,
+        contexts: &mut [Context],
+    ) -> Result<()> {
+        // search down from the start pair, which visits every pair below it at most once
+        let (path, len) = tree_path(tree, start, value, 0).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "value is not a leaf of the tree below the start",
+            )
+        })?;
+
+        let mut i = start;
+        for bit in (0..len).rev() {
+            let cur_bit = (path >> bit) & 1 != 0;
+            self.put(cur_bit, &mut contexts[i >> 1])?;
+            i = tree[i + cur_bit as usize] as usize;
+        }
+
+        Ok(())
+    }
+}
+
+/// returns the branches from the pair at index `pair` down to the leaf `value`, with the first
+/// branch in the highest of the returned number of bits, or None if the leaf isn't below the
+/// pair (or is more than 32 branches away)
+fn tree_path(tree: &[i8], pair: usize, value: u8, depth: u32) -> Option<(u32, u32)> {
+    if depth == 32 {
+        return None;
+    }
+
+    for branch in 0..2 {
+        let next = *tree.get(pair + branch)?;
+        let found = if next <= 0 {
+            (next.unsigned_abs() == value).then_some((0, 0))
+        } else {
+            tree_path(tree, next as usize, value, depth + 1)
+        };
+
+        if let Some((path, len)) = found {
+            return Some(((branch as u32) << len | path, len + 1));
+        }
+    }
+    None
 }
 
 /// implementation of a context aware binary arithmetic decoder
@@ -146,4 +208,61 @@ pub trait CabacReader<Context> {
 
         Ok(value)
     }
+
+    /// reads a leaf value of a VP8/VP9 style tree, see `CabacWriter::put_tree` for the layout
+    fn get_tree(&mut self, tree: &[i8], contexts: &mut [Context]) -> Result<u8> {
+        self.get_tree_from(tree, 0, contexts)
+    }
+
+    /// same as `get_tree` but starts at the pair at index `start` instead of the root
+    fn get_tree_from(&mut self, tree: &[i8], start: usize, contexts: &mut [Context]) -> Result<u8> {
+        let mut i = start;
+
+        loop {
+            let next = tree[i + self.get(&mut contexts[i >> 1])? as usize];
+            if next <= 0 {
+                return Ok(next.unsigned_abs());
+            }
+
+            i = next as usize;
+        }
+    }
+}
+
+/// binary arithmetic encoder that can code a bit with a fixed probability of it being zero of
+/// probability / 256, which `StaticCoder` uses to implement `CabacWriter<StaticContext>`
+pub trait ProbabilityWriter {
+    /// write a bit with the given probability of it being zero
+    fn put_with_probability(&mut self, bit: bool, probability: NonZeroU8) -> Result<()>;
+
+    /// write using bypass bin for bits that aren't worth encoding
+    fn put_bypass(&mut self, bit: bool) -> Result<()>;
+
+    /// flush any remaining state
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// binary arithmetic decoder for the bits written by a `ProbabilityWriter`
+pub trait ProbabilityReader {
+    /// read a bit with the given probability of it being zero
+    fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool>;
+
+    /// read from bypass bin
+    fn get_bypass(&mut self) -> Result<bool>;
+}
+
+/// implementation of an adaptive multi-symbol arithmetic encoder, where the model holds the
+/// probabilities of all symbols of the alphabet
+pub trait SymbolWriter<Model> {
+    /// writes the symbol with the probabilities of the model and updates it
+    fn put_symbol(&mut self, symbol: usize, model: &mut Model) -> Result<()>;
+
+    /// flush any remaining state
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// implementation of an adaptive multi-symbol arithmetic decoder
+pub trait SymbolReader<Model> {
+    /// reads a symbol with the probabilities of the model and updates it
+    fn get_symbol(&mut self, model: &mut Model) -> Result<usize>;
 }
@@ -25,7 +25,7 @@ use std::{
 
 use byteorder::WriteBytesExt;
 
-use crate::traits::{CabacReader, CabacWriter};
+use crate::traits::{CabacReader, CabacWriter, ProbabilityReader, ProbabilityWriter};
 
 const BITS_IN_BYTE: i32 = 8;
 const BITS_IN_LONG: i32 = 64;
@@ -190,6 +190,262 @@ impl VP8Context {
     }
 }
 
+/// context with a fixed probability that never adapts, for bits that are coded with a static or
+/// forward-signalled probability (like most of the VP8 and VP9 headers). It can be passed to the
+/// generic `CabacWriter`/`CabacReader` methods of any coder wrapped in a `StaticCoder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticContext {
+    probability: NonZeroU8,
+}
+
+impl Default for StaticContext {
+    /// default value is balanced between zeros or ones
+    fn default() -> Self {
+        StaticContext {
+            probability: NonZeroU8::new(128).unwrap(),
+        }
+    }
+}
+
+impl StaticContext {
+    /// creates a context where the probability of the next symbol being zero is
+    /// probability / 256
+    pub fn new(probability: NonZeroU8) -> Self {
+        StaticContext { probability }
+    }
+
+    /// returns the probability of the next symbol being zero (in the range 1-255)
+    #[inline(always)]
+    pub fn get_probability(&self) -> NonZeroU8 {
+        self.probability
+    }
+}
+
+/// wraps a coder that supports `put_with_probability`/`get_with_probability` so that it
+/// implements `CabacWriter<StaticContext>`/`CabacReader<StaticContext>`. This is a separate type
+/// so that the coders themselves only implement the generic traits for `VP8Context`, and the
+/// context type is still inferred for them.
+pub struct StaticCoder<C> {
+    coder: C,
+}
+
+impl<C> StaticCoder<C> {
+    pub fn new(coder: C) -> Self {
+        StaticCoder { coder }
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.coder
+    }
+
+    pub fn into_inner(self) -> C {
+        self.coder
+    }
+}
+
+impl<C: ProbabilityWriter> CabacWriter<StaticContext> for StaticCoder<C> {
+    #[inline(always)]
+    fn put(&mut self, value: bool, branch: &mut StaticContext) -> Result<()> {
+        self.coder
+            .put_with_probability(value, branch.get_probability())
+    }
+
+    #[inline(always)]
+    fn put_bypass(&mut self, value: bool) -> Result<()> {
+        self.coder.put_bypass(value)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.coder.finish()
+    }
+}
+
+impl<C: ProbabilityReader> CabacReader<StaticContext> for StaticCoder<C> {
+    #[inline(always)]
+    fn get(&mut self, branch: &mut StaticContext) -> Result<bool> {
+        self.coder.get_with_probability(branch.get_probability())
+    }
+
+    #[inline(always)]
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.coder.get_bypass()
+    }
+}
+
+/// DCT token values used as the leaves of `COEFFICIENT_TOKEN_TREE` (same numbering as libvpx)
+pub const ZERO_TOKEN: u8 = 0;
+pub const ONE_TOKEN: u8 = 1;
+pub const TWO_TOKEN: u8 = 2;
+pub const THREE_TOKEN: u8 = 3;
+pub const FOUR_TOKEN: u8 = 4;
+pub const DCT_VAL_CATEGORY1: u8 = 5;
+pub const DCT_VAL_CATEGORY2: u8 = 6;
+pub const DCT_VAL_CATEGORY3: u8 = 7;
+pub const DCT_VAL_CATEGORY4: u8 = 8;
+pub const DCT_VAL_CATEGORY5: u8 = 9;
+pub const DCT_VAL_CATEGORY6: u8 = 10;
+pub const DCT_EOB_TOKEN: u8 = 11;
+
+/// coefficient token tree from RFC 6386 section 13.2, for use with `put_tree`/`get_tree` and
+/// the 11 coefficient probabilities. After a zero token the next token is coded starting at
+/// pair 2, since an end of block can't follow a zero.
+pub const COEFFICIENT_TOKEN_TREE: [i8; 22] = [
+    -(DCT_EOB_TOKEN as i8),
+    2,
+    -(ZERO_TOKEN as i8),
+    4,
+    -(ONE_TOKEN as i8),
+    6,
+    8,
+    12,
+    -(TWO_TOKEN as i8),
+    10,
+    -(THREE_TOKEN as i8),
+    -(FOUR_TOKEN as i8),
+    14,
+    16,
+    -(DCT_VAL_CATEGORY1 as i8),
+    -(DCT_VAL_CATEGORY2 as i8),
+    18,
+    20,
+    -(DCT_VAL_CATEGORY3 as i8),
+    -(DCT_VAL_CATEGORY4 as i8),
+    -(DCT_VAL_CATEGORY5 as i8),
+    -(DCT_VAL_CATEGORY6 as i8),
+];
+
+/// intra prediction modes used as the leaves of the macroblock mode trees
+pub const DC_PRED: u8 = 0;
+pub const V_PRED: u8 = 1;
+pub const H_PRED: u8 = 2;
+pub const TM_PRED: u8 = 3;
+pub const B_PRED: u8 = 4;
+
+/// luma macroblock mode tree for inter frames (RFC 6386 section 16.2)
+pub const YMODE_TREE: [i8; 8] = [
+    -(DC_PRED as i8),
+    2,
+    4,
+    6,
+    -(V_PRED as i8),
+    -(H_PRED as i8),
+    -(TM_PRED as i8),
+    -(B_PRED as i8),
+];
+
+/// luma macroblock mode tree for key frames (RFC 6386 section 11.2)
+pub const KF_YMODE_TREE: [i8; 8] = [
+    -(B_PRED as i8),
+    2,
+    4,
+    6,
+    -(DC_PRED as i8),
+    -(V_PRED as i8),
+    -(H_PRED as i8),
+    -(TM_PRED as i8),
+];
+
+/// chroma macroblock mode tree (RFC 6386 section 11.2)
+pub const UV_MODE_TREE: [i8; 6] = [
+    -(DC_PRED as i8),
+    2,
+    -(V_PRED as i8),
+    4,
+    -(H_PRED as i8),
+    -(TM_PRED as i8),
+];
+
+/// 4x4 subblock intra modes used as the leaves of `BMODE_TREE`
+pub const B_DC_PRED: u8 = 0;
+pub const B_TM_PRED: u8 = 1;
+pub const B_VE_PRED: u8 = 2;
+pub const B_HE_PRED: u8 = 3;
+pub const B_LD_PRED: u8 = 4;
+pub const B_RD_PRED: u8 = 5;
+pub const B_VR_PRED: u8 = 6;
+pub const B_VL_PRED: u8 = 7;
+pub const B_HD_PRED: u8 = 8;
+pub const B_HU_PRED: u8 = 9;
+
+/// 4x4 subblock intra mode tree (RFC 6386 section 11.2)
+pub const BMODE_TREE: [i8; 18] = [
+    -(B_DC_PRED as i8),
+    2,
+    -(B_TM_PRED as i8),
+    4,
+    -(B_VE_PRED as i8),
+    6,
+    8,
+    12,
+    -(B_HE_PRED as i8),
+    10,
+    -(B_RD_PRED as i8),
+    -(B_VR_PRED as i8),
+    -(B_LD_PRED as i8),
+    14,
+    -(B_VL_PRED as i8),
+    16,
+    -(B_HD_PRED as i8),
+    -(B_HU_PRED as i8),
+];
+
+/// segment id tree (RFC 6386 section 10), the leaves are the segment ids 0-3
+pub const MB_SEGMENT_TREE: [i8; 6] = [2, 4, -0, -1, -2, -3];
+
+/// inter macroblock motion vector reference modes used as the leaves of `MV_REF_TREE`
+pub const MV_NEAREST: u8 = 0;
+pub const MV_NEAR: u8 = 1;
+pub const MV_ZERO: u8 = 2;
+pub const MV_NEW: u8 = 3;
+pub const MV_SPLIT: u8 = 4;
+
+/// motion vector reference mode tree (RFC 6386 section 16.3)
+pub const MV_REF_TREE: [i8; 8] = [
+    -(MV_ZERO as i8),
+    2,
+    -(MV_NEAREST as i8),
+    4,
+    -(MV_NEAR as i8),
+    6,
+    -(MV_NEW as i8),
+    -(MV_SPLIT as i8),
+];
+
+/// split motion vector partitionings used as the leaves of `MV_PARTITION_TREE`
+pub const MV_TOP_BOTTOM: u8 = 0;
+pub const MV_LEFT_RIGHT: u8 = 1;
+pub const MV_QUARTERS: u8 = 2;
+pub const MV_16: u8 = 3;
+
+/// split motion vector partitioning tree (RFC 6386 section 16.4)
+pub const MV_PARTITION_TREE: [i8; 6] = [
+    -(MV_16 as i8),
+    2,
+    -(MV_QUARTERS as i8),
+    4,
+    -(MV_TOP_BOTTOM as i8),
+    -(MV_LEFT_RIGHT as i8),
+];
+
+/// sub block motion vector references used as the leaves of `SUB_MV_REF_TREE`
+pub const LEFT_4X4: u8 = 0;
+pub const ABOVE_4X4: u8 = 1;
+pub const ZERO_4X4: u8 = 2;
+pub const NEW_4X4: u8 = 3;
+
+/// sub block motion vector reference tree (RFC 6386 section 16.4)
+pub const SUB_MV_REF_TREE: [i8; 6] = [
+    -(LEFT_4X4 as i8),
+    2,
+    -(ABOVE_4X4 as i8),
+    4,
+    -(ZERO_4X4 as i8),
+    -(NEW_4X4 as i8),
+];
+
+/// tree for the short motion vector component magnitudes 0-7 (RFC 6386 section 17.2)
+pub const SMALL_MV_TREE: [i8; 14] = [2, 8, 4, 6, -0, -1, -2, -3, 10, 12, -4, -5, -6, -7];
+
 pub struct VP8Reader<R> {
     value: u64,
     range: u32,
@@ -200,53 +456,35 @@ pub struct VP8Reader<R> {
 impl<R: Read> CabacReader<VP8Context> for VP8Reader<R> {
     #[inline(always)]
     fn get(&mut self, branch: &mut VP8Context) -> Result<bool> {
-        let mut tmp_value = self.value;
-        let mut tmp_range = self.range;
-        let mut tmp_count = self.count;
+        let bit = self.get_with_probability(branch.get_probability())?;
 
-        if tmp_count < 0 {
-            Self::vpx_reader_fill(&mut tmp_value, &mut tmp_count, &mut self.upstream_reader)?;
-        }
-
-        let probability = branch.get_probability().get() as u32;
-
-        let split = 1 + (((tmp_range - 1) * probability) >> BITS_IN_BYTE);
-        let big_split = (split as u64) << BITS_IN_LONG_MINUS_LAST_BYTE;
-        let bit = tmp_value >= big_split;
-
-        let shift;
-        let b = branch.record_and_update_bit(bit);
-        if bit {
-            tmp_range -= split;
-            tmp_value -= big_split;
-
-            // so optimizer understands that 0 should never happen and uses a cold jump
-            // if we don't have LZCNT on x86 CPUs (older BSR instruction requires check for zero).
-            // This is better since the branch prediction figures quickly this never happens and can run
-            // the code sequentially.
-            #[cfg(all(
-                not(target_feature = "lzcnt"),
-                any(target_arch = "x86", target_arch = "x86_64")
-            ))]
-            assert!(tmp_range > 0);
-
-            shift = tmp_range.leading_zeros() as i32 - 24;
-        } else {
-            tmp_range = split;
+        *branch = branch.record_and_update_bit(bit);
+        Ok(bit)
+    }
 
-            // optimizer understands that split > 0
-            shift = split.leading_zeros() as i32 - 24;
-        }
+    #[inline(always)]
+    fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
 
-        *branch = b;
-        self.value = tmp_value << shift;
-        self.range = tmp_range << shift;
-        self.count = tmp_count - shift;
-        return Ok(bit);
+impl<R: Read> ProbabilityReader for VP8Reader<R> {
+    #[inline(always)]
+    fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool> {
+        self.get_with_probability(probability)
     }
 
     #[inline(always)]
     fn get_bypass(&mut self) -> Result<bool> {
+        self.get_bypass()
+    }
+}
+
+impl<R: Read> VP8Reader<R> {
+    /// reads a bit that was coded with a fixed probability of it being zero of
+    /// probability / 256, rather than with an adaptive context
+    #[inline(always)]
+    pub fn get_with_probability(&mut self, probability: NonZeroU8) -> Result<bool> {
         let mut tmp_value = self.value;
         let mut tmp_range = self.range;
         let mut tmp_count = self.count;
@@ -255,7 +493,9 @@ impl<R: Read> CabacReader<VP8Context> for VP8Reader<R> {
             Self::vpx_reader_fill(&mut tmp_value, &mut tmp_count, &mut self.upstream_reader)?;
         }
 
-        let split = 1 + (tmp_range >> 1);
+        let probability = probability.get() as u32;
+
+        let split = 1 + (((tmp_range - 1) * probability) >> BITS_IN_BYTE);
         let big_split = (split as u64) << BITS_IN_LONG_MINUS_LAST_BYTE;
         let bit = tmp_value >= big_split;
 
@@ -285,11 +525,9 @@ impl<R: Read> CabacReader<VP8Context> for VP8Reader<R> {
         self.value = tmp_value << shift;
         self.range = tmp_range << shift;
         self.count = tmp_count - shift;
-        return Ok(bit);
+        Ok(bit)
     }
-}
 
-impl<R: Read> VP8Reader<R> {
     pub fn new(reader: R) -> Result<Self> {
         let mut r = VP8Reader {
             upstream_reader: reader,
@@ -306,6 +544,21 @@ impl<R: Read> VP8Reader<R> {
         return Ok(r);
     }
 
+    /// creates a reader for data in the format of the RFC 6386 bool_decoder, such as the
+    /// partitions of a VP8 frame or a WebP image. Unlike `new`, no marker bit is read.
+    pub fn new_raw(reader: R) -> Result<Self> {
+        let mut r = VP8Reader {
+            upstream_reader: reader,
+            value: 0,
+            count: -8,
+            range: 255,
+        };
+
+        Self::vpx_reader_fill(&mut r.value, &mut r.count, &mut r.upstream_reader)?;
+
+        Ok(r)
+    }
+
     #[cold]
     #[inline(always)]
     fn vpx_reader_fill(
@@ -330,6 +583,63 @@ impl<R: Read> VP8Reader<R> {
 
         return Ok(());
     }
+
+    /// reads an unsigned literal of `bits` bits, most significant bit first, each with a
+    /// probability of 128 (`L(n)` in RFC 6386 and the VP9 specification). Note that this is
+    /// not the same split as `get_bypass`.
+    pub fn get_literal(&mut self, bits: u32) -> Result<u32> {
+        let half = NonZeroU8::new(128).unwrap();
+
+        let mut value = 0;
+        for _ in 0..bits {
+            value = (value << 1) | u32::from(self.get_with_probability(half)?);
+        }
+        Ok(value)
+    }
+
+    /// reads a bit with a probability of 1/2 without a context
+    #[inline(always)]
+    pub fn get_bypass(&mut self) -> Result<bool> {
+        let mut tmp_value = self.value;
+        let mut tmp_range = self.range;
+        let mut tmp_count = self.count;
+
+        if tmp_count < 0 {
+            Self::vpx_reader_fill(&mut tmp_value, &mut tmp_count, &mut self.upstream_reader)?;
+        }
+
+        let split = 1 + (tmp_range >> 1);
+        let big_split = (split as u64) << BITS_IN_LONG_MINUS_LAST_BYTE;
+        let bit = tmp_value >= big_split;
+
+        let shift;
+        if bit {
+            tmp_range -= split;
+            tmp_value -= big_split;
+
+            // so optimizer understands that 0 should never happen and uses a cold jump
+            // if we don't have LZCNT on x86 CPUs (older BSR instruction requires check for zero).
+            // This is better since the branch prediction figures quickly this never happens and can run
+            // the code sequentially.
+            #[cfg(all(
+                not(target_feature = "lzcnt"),
+                any(target_arch = "x86", target_arch = "x86_64")
+            ))]
+            assert!(tmp_range > 0);
+
+            shift = tmp_range.leading_zeros() as i32 - 24;
+        } else {
+            tmp_range = split;
+
+            // optimizer understands that split > 0
+            shift = split.leading_zeros() as i32 - 24;
+        }
+
+        self.value = tmp_value << shift;
+        self.range = tmp_range << shift;
+        self.count = tmp_count - shift;
+        return Ok(bit);
+    }
 }
 
 /// encoder from VP8/WebM
@@ -340,23 +650,102 @@ pub struct VP8Writer<W> {
     writer: W,
     num_buffered_bytes: u32,
     buffered_byte: u8,
+    raw: bool,
 }
 
 impl<W: Write> VP8Writer<W> {
     pub fn new(writer: W) -> Result<Self> {
         let mut retval = VP8Writer {
+            raw: false,
+            ..Self::new_raw(writer)
+        };
+
+        let mut dummy_branch = VP8Context::default();
+        retval.put(false, &mut dummy_branch)?;
+
+        Ok(retval)
+    }
+
+    /// creates a writer whose output is exactly what the RFC 6386 bool_encoder produces, so it
+    /// can be used for the partitions of a VP8 frame or a WebP image. No marker bit is written,
+    /// and `finish` does the padding of flush_bool_encoder instead of writing bypass bits.
+    pub fn new_raw(writer: W) -> Self {
+        VP8Writer {
             low_value: 0,
             range: 255,
             bits_left: -24,
-            writer: writer,
+            writer,
             num_buffered_bytes: 0,
             buffered_byte: 0,
-        };
+            raw: true,
+        }
+    }
 
-        let mut dummy_branch = VP8Context::default();
-        retval.put(false, &mut dummy_branch)?;
+    /// flush_bool_encoder from RFC 6386: resolves the pending carry and writes out the
+    /// 4 bytes of the bottom register, which includes the remaining bits of the partition.
+    fn flush_raw(&mut self) -> Result<()> {
+        // the number of bits that still have to be shifted in before the next byte is complete
+        let bit_count = -self.bits_left;
 
-        Ok(retval)
+        let carry = (self.low_value >> (32 - bit_count)) & 1;
+        self.flush_buffered_bytes(carry as u8)?;
+
+        let v = self.low_value << bit_count;
+        self.writer.write_all(&v.to_be_bytes())
+    }
+
+    /// writes the lower `bits` bits of value as an unsigned literal, most significant bit
+    /// first, each with a probability of 128 (`L(n)` in RFC 6386 and the VP9 specification).
+    /// Note that this is not the same split as `put_bypass`.
+    pub fn put_literal(&mut self, value: u32, bits: u32) -> Result<()> {
+        let half = NonZeroU8::new(128).unwrap();
+
+        for i in (0..bits).rev() {
+            self.put_with_probability((value >> i) & 1 != 0, half)?;
+        }
+        Ok(())
+    }
+
+    /// writes a bit with a fixed probability of it being zero of probability / 256, rather
+    /// than with an adaptive context
+    #[inline(always)]
+    pub fn put_with_probability(&mut self, value: bool, probability: NonZeroU8) -> Result<()> {
+        let probability = probability.get() as u32;
+
+        let mut tmp_range = self.range;
+        let split = 1 + (((tmp_range - 1) * probability) >> 8);
+
+        let mut tmp_low_value = self.low_value;
+
+        let mut shift;
+        if value {
+            tmp_low_value += split;
+            tmp_range -= split;
+
+            shift = (tmp_range as u8).leading_zeros() as i32;
+        } else {
+            tmp_range = split;
+
+            // optimizer understands that split > 0, so it can optimize this
+            shift = (split as u8).leading_zeros() as i32;
+        }
+
+        tmp_range <<= shift;
+
+        let mut tmp_count = self.bits_left;
+        tmp_count += shift;
+
+        if tmp_count >= 0 {
+            self.send_to_output(&mut shift, &mut tmp_count, &mut tmp_low_value)?;
+        }
+
+        tmp_low_value <<= shift;
+
+        self.bits_left = tmp_count;
+        self.low_value = tmp_low_value;
+        self.range = tmp_range;
+
+        Ok(())
     }
 
     #[inline]
@@ -406,20 +795,16 @@ impl<W: Write> VP8Writer<W> {
         }
         Ok(())
     }
-}
 
-impl<W: Write> CabacWriter<VP8Context> for VP8Writer<W> {
+    /// writes a bit with a probability of 1/2 without a context
     #[inline(always)]
-    fn put(&mut self, value: bool, branch: &mut VP8Context) -> Result<()> {
-        let probability = branch.get_probability().get() as u32;
-
+    pub fn put_bypass(&mut self, value: bool) -> Result<()> {
         let mut tmp_range = self.range;
-        let split = 1 + (((tmp_range - 1) * probability) >> 8);
+        let split = 1 + (tmp_range >> 1);
 
         let mut tmp_low_value = self.low_value;
 
         let mut shift;
-        let b = branch.record_and_update_bit(value);
         if value {
             tmp_low_value += split;
             tmp_range -= split;
@@ -443,7 +828,6 @@ impl<W: Write> CabacWriter<VP8Context> for VP8Writer<W> {
 
         tmp_low_value <<= shift;
 
-        *branch = b;
         self.bits_left = tmp_count;
         self.low_value = tmp_low_value;
         self.range = tmp_range;
@@ -451,54 +835,58 @@ impl<W: Write> CabacWriter<VP8Context> for VP8Writer<W> {
         Ok(())
     }
 
-    #[inline(always)]
-    fn put_bypass(&mut self, value: bool) -> Result<()> {
-        let mut tmp_range = self.range;
-        let split = 1 + (tmp_range >> 1);
-
-        let mut tmp_low_value = self.low_value;
-
-        let mut shift;
-        if value {
-            tmp_low_value += split;
-            tmp_range -= split;
-
-            shift = (tmp_range as u8).leading_zeros() as i32;
-        } else {
-            tmp_range = split;
-
-            // optimizer understands that split > 0, so it can optimize this
-            shift = (split as u8).leading_zeros() as i32;
+    /// flushes the remaining bits, see `new` and `new_raw` for how the stream is ended
+    pub fn finish(&mut self) -> Result<()> {
+        if self.raw {
+            return self.flush_raw();
         }
 
-        tmp_range <<= shift;
+        // pad the rest of the stream so we don't have to
+        // worry about carrying the last byte
+        while self.low_value > 0 {
+            self.put_bypass(false)?;
+        }
 
-        let mut tmp_count = self.bits_left;
-        tmp_count += shift;
+        self.flush_buffered_bytes(0)?;
 
-        if tmp_count >= 0 {
-            self.send_to_output(&mut shift, &mut tmp_count, &mut tmp_low_value)?;
-        }
+        Ok(())
+    }
+}
 
-        tmp_low_value <<= shift;
+impl<W: Write> CabacWriter<VP8Context> for VP8Writer<W> {
+    #[inline(always)]
+    fn put(&mut self, value: bool, branch: &mut VP8Context) -> Result<()> {
+        let b = branch.record_and_update_bit(value);
 
-        self.bits_left = tmp_count;
-        self.low_value = tmp_low_value;
-        self.range = tmp_range;
+        self.put_with_probability(value, branch.get_probability())?;
 
+        *branch = b;
         Ok(())
     }
 
+    #[inline(always)]
+    fn put_bypass(&mut self, value: bool) -> Result<()> {
+        self.put_bypass(value)
+    }
+
     fn finish(&mut self) -> Result<()> {
-        // pad the rest of the stream so we don't have to
-        // worry about carrying the last byte
-        while self.low_value > 0 {
-            self.put_bypass(false)?;
-        }
+        self.finish()
+    }
+}
 
-        self.flush_buffered_bytes(0)?;
+impl<W: Write> ProbabilityWriter for VP8Writer<W> {
+    #[inline(always)]
+    fn put_with_probability(&mut self, bit: bool, probability: NonZeroU8) -> Result<()> {
+        self.put_with_probability(bit, probability)
+    }
 
-        Ok(())
+    #[inline(always)]
+    fn put_bypass(&mut self, bit: bool) -> Result<()> {
+        self.put_bypass(bit)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish()
     }
 }
 
@@ -534,6 +922,226 @@ fn test_all_contexts() {
     }
 }
 
+/// Literal implementation of bool_encoder and bool_decoder from RFC 6386 (section 7.3), used to
+/// verify that the raw mode produces and consumes exactly the same partitions.
+#[cfg(test)]
+struct Rfc6386BoolEncoder {
+    output: Vec<u8>,
+    range: u32,
+    bottom: u32,
+    bit_count: i32,
+}
+
+#[cfg(test)]
+impl Rfc6386BoolEncoder {
+    fn new() -> Self {
+        Rfc6386BoolEncoder {
+            output: Vec::new(),
+            range: 255,
+            bottom: 0,
+            bit_count: 24,
+        }
+    }
+
+    fn add_one_to_output(&mut self) {
+        let mut i = self.output.len();
+        loop {
+            i -= 1;
+            if self.output[i] != 255 {
+                break;
+            }
+            self.output[i] = 0;
+        }
+        self.output[i] += 1;
+    }
+
+    fn write_bool(&mut self, prob: u32, value: bool) {
+        let split = 1 + (((self.range - 1) * prob) >> 8);
+
+        if value {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        while self.range < 128 {
+            self.range <<= 1;
+
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one_to_output();
+            }
+
+            self.bottom <<= 1;
+
+            self.bit_count -= 1;
+            if self.bit_count == 0 {
+                self.output.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    fn flush(mut self) -> Vec<u8> {
+        let mut c = self.bit_count;
+        let mut v = self.bottom;
+
+        if v & (1 << (32 - c)) != 0 {
+            self.add_one_to_output();
+        }
+
+        v <<= c & 7;
+        c >>= 3;
+        while c > 0 {
+            v <<= 8;
+            c -= 1;
+        }
+
+        for _ in 0..4 {
+            self.output.push((v >> 24) as u8);
+            v <<= 8;
+        }
+
+        self.output
+    }
+}
+
+#[cfg(test)]
+fn rfc6386_read_bools(input: &[u8], probs: &[u32]) -> Vec<bool> {
+    // the reference decoder reads 2 bytes up front, reading past the end returns zeros here
+    let mut input = input.iter().copied().chain(std::iter::repeat(0));
+    let mut value = (u32::from(input.next().unwrap()) << 8) | u32::from(input.next().unwrap());
+    let mut range = 255u32;
+    let mut bit_count = 0;
+
+    let mut bits = Vec::new();
+    for &prob in probs {
+        let split = 1 + (((range - 1) * prob) >> 8);
+        let big_split = split << 8;
+
+        let bit = value >= big_split;
+        if bit {
+            range -= split;
+            value -= big_split;
+        } else {
+            range = split;
+        }
+
+        while range < 128 {
+            value <<= 1;
+            range <<= 1;
+            bit_count += 1;
+            if bit_count == 8 {
+                bit_count = 0;
+                value |= u32::from(input.next().unwrap());
+            }
+        }
+
+        bits.push(bit);
+    }
+    bits
+}
+
+#[test]
+fn raw_mode_matches_rfc6386() {
+    use rand::Rng;
+    use std::io::Cursor;
+
+    let mut rng = rand::thread_rng();
+
+    // an empty partition is just the 4 bytes of padding
+    let mut output = Vec::new();
+    VP8Writer::new_raw(&mut output).finish().unwrap();
+    assert_eq!(output, [0, 0, 0, 0]);
+
+    for _ in 0..500 {
+        let len = rng.gen_range(0..2000);
+        let skew = rng.gen_range(1..=255);
+
+        let mut counts = Vec::new();
+        let mut bits = Vec::new();
+        for _ in 0..len {
+            counts.push(rng.gen_range(1..=0xffffu16));
+            bits.push(rng.gen_range(0..=255) >= skew);
+        }
+
+        let mut reference = Rfc6386BoolEncoder::new();
+        let mut output = Vec::new();
+        let mut writer = VP8Writer::new_raw(&mut output);
+        let mut probs = Vec::new();
+
+        for (&counts, &bit) in counts.iter().zip(&bits) {
+            let mut context = VP8Context { counts };
+            let prob = u32::from(context.get_probability().get());
+            probs.push(prob);
+
+            reference.write_bool(prob, bit);
+            writer.put(bit, &mut context).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(output, reference.flush());
+        assert_eq!(rfc6386_read_bools(&output, &probs), bits);
+
+        let mut reader = VP8Reader::new_raw(Cursor::new(&output[..])).unwrap();
+        for (&counts, &bit) in counts.iter().zip(&bits) {
+            assert_eq!(reader.get(&mut VP8Context { counts }).unwrap(), bit);
+        }
+    }
+}
+
+/// partitions written by the bool_encoder of RFC 6386 for the bools of
+/// `raw_mode_known_answers`, printed by `tests/data/rfc6386_bool_encoder.c`
+#[cfg(test)]
+const RFC6386_VARIED: [u8; 16] = [
+    0x06, 0xdb, 0xd9, 0x16, 0xb5, 0xf5, 0x38, 0x6c, 0x3b, 0x94, 0x33, 0x81, 0x76, 0xb1, 0x50, 0x18,
+];
+
+/// the carry turns 0x41 0xff 0xff 0xff into 0x42 0x00 0x00 0x00
+#[cfg(test)]
+const RFC6386_CARRY: [u8; 19] = [
+    0x04, 0x24, 0x1d, 0xe4, 0x87, 0xf8, 0x2b, 0xb7, 0x42, 0x00, 0x00, 0x00, 0x8f, 0xed, 0x52, 0x06,
+    0xaf, 0x02, 0x00,
+];
+
+#[test]
+fn raw_mode_known_answers() {
+    use std::io::Cursor;
+
+    let varied: Vec<(u8, bool)> = (0..200u32)
+        .map(|i| ((1 + i * 73 % 255) as u8, (i * i + 3 * i) % 7 < 3))
+        .collect();
+
+    let mut seed = 5912107u32;
+    let carry: Vec<(u8, bool)> = (0..100)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            ((1 + (seed >> 8) % 255) as u8, (seed >> 24) >= (seed & 0xff))
+        })
+        .collect();
+
+    for (bools, expected) in [(varied, &RFC6386_VARIED[..]), (carry, &RFC6386_CARRY[..])] {
+        let mut output = Vec::new();
+        let mut writer = VP8Writer::new_raw(&mut output);
+        for &(prob, bit) in &bools {
+            writer
+                .put_with_probability(bit, NonZeroU8::new(prob).unwrap())
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(output, expected);
+
+        let mut reader = VP8Reader::new_raw(Cursor::new(expected)).unwrap();
+        for &(prob, bit) in &bools {
+            let read = reader.get_with_probability(NonZeroU8::new(prob).unwrap());
+            assert_eq!(read.unwrap(), bit);
+        }
+    }
+}
+
 /// run through all the possible combinations of counts and ensure that the probability is the same
 #[test]
 fn test_all_probabilities() {
@@ -624,6 +1232,83 @@ fn test_all_probabilities() {
     }
 }
 
+/// checks the tree walk against the token bit strings listed in RFC 6386 section 13.2
+#[test]
+fn coefficient_token_tree_matches_rfc6386() {
+    use std::io::Cursor;
+
+    // (token, branches taken, pair of each branch)
+    let paths: [(u8, &str, &[usize]); 12] = [
+        (DCT_EOB_TOKEN, "0", &[0]),
+        (ZERO_TOKEN, "10", &[0, 1]),
+        (ONE_TOKEN, "110", &[0, 1, 2]),
+        (TWO_TOKEN, "11100", &[0, 1, 2, 3, 4]),
+        (THREE_TOKEN, "111010", &[0, 1, 2, 3, 4, 5]),
+        (FOUR_TOKEN, "111011", &[0, 1, 2, 3, 4, 5]),
+        (DCT_VAL_CATEGORY1, "111100", &[0, 1, 2, 3, 6, 7]),
+        (DCT_VAL_CATEGORY2, "111101", &[0, 1, 2, 3, 6, 7]),
+        (DCT_VAL_CATEGORY3, "1111100", &[0, 1, 2, 3, 6, 8, 9]),
+        (DCT_VAL_CATEGORY4, "1111101", &[0, 1, 2, 3, 6, 8, 9]),
+        (DCT_VAL_CATEGORY5, "1111110", &[0, 1, 2, 3, 6, 8, 10]),
+        (DCT_VAL_CATEGORY6, "1111111", &[0, 1, 2, 3, 6, 8, 10]),
+    ];
+
+    // default coefficient probabilities for the first band and context of the Y after Y2 plane
+    let probs: [u8; 11] = [253, 136, 254, 255, 228, 219, 128, 128, 128, 128, 128];
+    let mut contexts = probs.map(|p| StaticContext::new(NonZeroU8::new(p).unwrap()));
+
+    let mut buffer = Vec::new();
+    let mut writer = StaticCoder::new(VP8Writer::new_raw(&mut buffer));
+    let mut reference = Rfc6386BoolEncoder::new();
+    for &(token, bits, pairs) in &paths {
+        writer
+            .put_tree(token, &COEFFICIENT_TOKEN_TREE, &mut contexts)
+            .unwrap();
+        for (bit, &pair) in bits.chars().zip(pairs) {
+            reference.write_bool(u32::from(probs[pair]), bit == '1');
+        }
+    }
+
+    // after a zero token the end of block branch is skipped
+    for &(token, bits, pairs) in &paths[1..] {
+        writer
+            .put_tree_from(token, &COEFFICIENT_TOKEN_TREE, 2, &mut contexts)
+            .unwrap();
+        for (bit, &pair) in bits.chars().zip(pairs).skip(1) {
+            reference.write_bool(u32::from(probs[pair]), bit == '1');
+        }
+    }
+    writer.finish().unwrap();
+    assert_eq!(buffer, reference.flush());
+
+    let mut reader = StaticCoder::new(VP8Reader::new_raw(Cursor::new(&buffer)).unwrap());
+    for &(token, _, _) in &paths {
+        assert_eq!(
+            token,
+            reader
+                .get_tree(&COEFFICIENT_TOKEN_TREE, &mut contexts)
+                .unwrap()
+        );
+    }
+    for &(token, _, _) in &paths[1..] {
+        assert_eq!(
+            token,
+            reader
+                .get_tree_from(&COEFFICIENT_TOKEN_TREE, 2, &mut contexts)
+                .unwrap()
+        );
+    }
+
+    // values that aren't leaves, or can't be reached from the start pair, are rejected
+    let mut writer = StaticCoder::new(VP8Writer::new_raw(Vec::new()));
+    assert!(writer
+        .put_tree(12, &COEFFICIENT_TOKEN_TREE, &mut contexts)
+        .is_err());
+    assert!(writer
+        .put_tree_from(DCT_EOB_TOKEN, &COEFFICIENT_TOKEN_TREE, 2, &mut contexts)
+        .is_err());
+}
+
 /// ensure that all the permutations of the counts are handled correctly in the SIMD version
 #[cfg(feature = "simd")]
 #[test]
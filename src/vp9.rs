@@ -0,0 +1,474 @@
+//! VP9 probability updates on top of the VP8 boolean coder.
+//!
+//! VP9 codes everything with fixed 8 bit probabilities that are only changed between frames,
+//! either forward by sending `diff_update_prob` deltas in the compressed header, or backward by
+//! adapting them at the end of each frame from the counts of the symbols that were decoded.
+//!
+//! Based on vp9_dsubexp.c, vp9_subexp.c and vpx_dsp/prob.h from libvpx.
+/*
+ *  Copyright (c) 2013 The WebM project authors. All Rights Reserved.
+ *
+ *  Use of this source code is governed by a BSD-style license
+ *  that can be found in the LICENSE file in the root of the source
+ *  tree. An additional intellectual property rights grant can be found
+ *  in the file PATENTS.  All contributing project authors may
+ *  be found in the AUTHORS file in the root of the source tree.
+ */
+use std::{
+    io::{Read, Result, Write},
+    num::NonZeroU8,
+};
+
+use crate::vp8::{VP8Reader, VP8Writer};
+
+/// probability of the flag that says whether a probability is updated
+pub const DIFF_UPDATE_PROB: NonZeroU8 = NonZeroU8::new(252).unwrap();
+
+/// count saturation and update factor used for the backward adaptation of mode and motion
+/// vector probabilities
+pub const MODE_MV_COUNT_SAT: u32 = 20;
+pub const MODE_MV_MAX_UPDATE_FACTOR: u32 = 128;
+
+/// count saturation and update factors used for the backward adaptation of coefficient
+/// probabilities (the factor depends on whether the previous frame was a key frame)
+pub const COEF_COUNT_SAT: u32 = 24;
+pub const COEF_MAX_UPDATE_FACTOR: u32 = 112;
+pub const COEF_MAX_UPDATE_FACTOR_KEY: u32 = 112;
+pub const COEF_MAX_UPDATE_FACTOR_AFTER_KEY: u32 = 128;
+
+const MAX_PROB: i32 = 255;
+
+/// maps the coded delta to the recentered distance from the old probability. The first 20
+/// entries are the coarse steps 7, 20, 33.. that are cheapest to code, followed by every other
+/// distance in order. The last entry is padding since a delta of 254 can be coded.
+const INV_MAP_TABLE: [u8; 255] = inv_map_table();
+
+const fn inv_map_table() -> [u8; 255] {
+    let mut table = [0u8; 255];
+    let mut i = 0;
+    while i < 20 {
+        table[i] = 7 + 13 * i as u8;
+        i += 1;
+    }
+
+    let mut v = 1;
+    while v < 255 {
+        if v % 13 != 7 {
+            table[i] = v as u8;
+            i += 1;
+        }
+        v += 1;
+    }
+
+    table[i] = 253;
+    table
+}
+
+/// inverse of `INV_MAP_TABLE`, indexed by the recentered distance - 1
+const MAP_TABLE: [u8; 254] = map_table();
+
+const fn map_table() -> [u8; 254] {
+    let mut table = [0u8; 254];
+    let mut i = 0;
+    while i < 254 {
+        table[INV_MAP_TABLE[i] as usize - 1] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+fn inv_recenter_nonneg(v: i32, m: i32) -> i32 {
+    if v > 2 * m {
+        v
+    } else if v & 1 != 0 {
+        m - ((v + 1) >> 1)
+    } else {
+        m + (v >> 1)
+    }
+}
+
+fn recenter_nonneg(v: i32, m: i32) -> i32 {
+    if v > (m << 1) {
+        v
+    } else if v >= m {
+        (v - m) << 1
+    } else {
+        ((m - v) << 1) - 1
+    }
+}
+
+/// returns the new probability for the coded delta relative to the old probability
+fn inv_remap_prob(delta: u32, old: NonZeroU8) -> NonZeroU8 {
+    let v = i32::from(INV_MAP_TABLE[delta as usize]);
+    let m = i32::from(old.get()) - 1;
+
+    let p = if (m << 1) <= MAX_PROB {
+        1 + inv_recenter_nonneg(v, m)
+    } else {
+        MAX_PROB - inv_recenter_nonneg(v, MAX_PROB - 1 - m)
+    };
+
+    NonZeroU8::new(p as u8).unwrap()
+}
+
+/// returns the delta to code for changing the old probability to the new one, which must differ
+fn remap_prob(new: NonZeroU8, old: NonZeroU8) -> u32 {
+    let v = i32::from(new.get()) - 1;
+    let m = i32::from(old.get()) - 1;
+
+    let i = if (m << 1) <= MAX_PROB {
+        recenter_nonneg(v, m) - 1
+    } else {
+        recenter_nonneg(MAX_PROB - 1 - v, MAX_PROB - 1 - m) - 1
+    };
+
+    u32::from(MAP_TABLE[i as usize])
+}
+
+/// number of bits used by the sub-exponential code for a delta
+fn delta_bits(delta: u32) -> u32 {
+    match delta {
+        0..16 => 5,
+        16..32 => 6,
+        32..64 => 8,
+        64..129 => 10,
+        _ => 11,
+    }
+}
+
+/// cost of coding a bit with the given probability of it being zero, in 1/256 bits
+fn bit_cost(bit: bool, probability: NonZeroU8) -> u64 {
+    let p = f64::from(probability.get()) / 256.0;
+    let p = if bit { 1.0 - p } else { p };
+    (-p.log2() * 256.0).round() as u64
+}
+
+/// cost of coding counts[0] zeros and counts[1] ones with the given probability, in 1/256 bits
+fn branch_cost(counts: [u32; 2], probability: NonZeroU8) -> u64 {
+    u64::from(counts[0]) * bit_cost(false, probability)
+        + u64::from(counts[1]) * bit_cost(true, probability)
+}
+
+/// returns num / den as a probability of a zero, clipped to 1-255
+pub fn get_prob(num: u32, den: u32) -> NonZeroU8 {
+    assert!(den != 0);
+    let p = (u64::from(num) * 256 + u64::from(den >> 1)) / u64::from(den);
+    NonZeroU8::new(p.clamp(1, 255) as u8).unwrap()
+}
+
+/// returns the probability of a zero given the number of zeros and ones seen, or 128 if
+/// nothing was seen
+pub fn get_binary_prob(n0: u32, n1: u32) -> NonZeroU8 {
+    let den = n0 + n1;
+    if den == 0 {
+        return NonZeroU8::new(128).unwrap();
+    }
+    get_prob(n0, den)
+}
+
+fn weighted_prob(prob1: NonZeroU8, prob2: NonZeroU8, factor: u32) -> NonZeroU8 {
+    let p = (u32::from(prob1.get()) * (256 - factor) + u32::from(prob2.get()) * factor + 128) >> 8;
+    NonZeroU8::new(p as u8).unwrap()
+}
+
+/// backward adaptation of a probability at the end of a frame. The probability the frame
+/// started with is moved towards the probability of the counts, by more the more symbols
+/// were seen (up to max_update_factor / 256 once count_sat symbols were seen).
+pub fn merge_probs(
+    pre_prob: NonZeroU8,
+    counts: [u32; 2],
+    count_sat: u32,
+    max_update_factor: u32,
+) -> NonZeroU8 {
+    let prob = get_binary_prob(counts[0], counts[1]);
+    let count = (counts[0] + counts[1]).min(count_sat);
+    let factor = max_update_factor * count / count_sat;
+    weighted_prob(pre_prob, prob, factor)
+}
+
+/// backward adaptation of a mode or motion vector probability
+pub fn mode_mv_merge_probs(pre_prob: NonZeroU8, counts: [u32; 2]) -> NonZeroU8 {
+    merge_probs(
+        pre_prob,
+        counts,
+        MODE_MV_COUNT_SAT,
+        MODE_MV_MAX_UPDATE_FACTOR,
+    )
+}
+
+/// backward adaptation of the probabilities of a tree (in the layout used by `put_tree`)
+/// from the counts of each leaf value. The counts of each branch are the sum of the leaves
+/// below it.
+pub fn tree_merge_probs(
+    tree: &[i8],
+    pre_probs: &[NonZeroU8],
+    counts: &[u32],
+    probs: &mut [NonZeroU8],
+) {
+    let _ = tree_merge_probs_impl(0, tree, pre_probs, counts, probs);
+}
+
+fn tree_merge_probs_impl(
+    i: usize,
+    tree: &[i8],
+    pre_probs: &[NonZeroU8],
+    counts: &[u32],
+    probs: &mut [NonZeroU8],
+) -> u32 {
+    let mut branch_counts = [0; 2];
+    for (bit, count) in branch_counts.iter_mut().enumerate() {
+        let next = tree[i + bit];
+        *count = if next <= 0 {
+            counts[usize::from(next.unsigned_abs())]
+        } else {
+            tree_merge_probs_impl(next as usize, tree, pre_probs, counts, probs)
+        };
+    }
+
+    probs[i >> 1] = mode_mv_merge_probs(pre_probs[i >> 1], branch_counts);
+    branch_counts[0] + branch_counts[1]
+}
+
+impl<R: Read> VP8Reader<R> {
+    /// reads the `diff_update_prob` syntax element from the VP9 compressed header and applies
+    /// the update to the probability if there is one
+    pub fn get_diff_update_prob(&mut self, probability: &mut NonZeroU8) -> Result<()> {
+        if self.get_with_probability(DIFF_UPDATE_PROB)? {
+            let delta = self.get_term_subexp()?;
+            *probability = inv_remap_prob(delta, *probability);
+        }
+        Ok(())
+    }
+
+    /// reads a terminated sub-exponential code, which is shorter for smaller values
+    fn get_term_subexp(&mut self) -> Result<u32> {
+        if self.get_literal(1)? == 0 {
+            return self.get_literal(4);
+        }
+        if self.get_literal(1)? == 0 {
+            return Ok(self.get_literal(4)? + 16);
+        }
+        if self.get_literal(1)? == 0 {
+            return Ok(self.get_literal(5)? + 32);
+        }
+
+        // uniform code for the remaining 191 values, the first 65 use 7 bits and the rest 8
+        let v = self.get_literal(7)?;
+        if v < 65 {
+            Ok(v + 64)
+        } else {
+            Ok((v << 1) - 65 + self.get_literal(1)? + 64)
+        }
+    }
+}
+
+impl<W: Write> VP8Writer<W> {
+    /// writes the `diff_update_prob` syntax element for the VP9 compressed header, with an
+    /// update only if the new probability differs
+    pub fn put_diff_update_prob(
+        &mut self,
+        probability: &mut NonZeroU8,
+        new_probability: NonZeroU8,
+    ) -> Result<()> {
+        let update = new_probability != *probability;
+        self.put_with_probability(update, DIFF_UPDATE_PROB)?;
+
+        if update {
+            self.put_term_subexp(remap_prob(new_probability, *probability))?;
+            *probability = new_probability;
+        }
+        Ok(())
+    }
+
+    /// writes the `diff_update_prob` syntax element, picking the update from the counts of
+    /// zeros and ones that will be coded with the probability. An update is only sent if it
+    /// saves more bits than it costs to code.
+    pub fn put_cond_diff_update_prob(
+        &mut self,
+        probability: &mut NonZeroU8,
+        counts: [u32; 2],
+    ) -> Result<()> {
+        let new_probability = best_update(*probability, counts).unwrap_or(*probability);
+        self.put_diff_update_prob(probability, new_probability)
+    }
+
+    fn put_term_subexp(&mut self, delta: u32) -> Result<()> {
+        if delta < 16 {
+            self.put_literal(0, 1)?;
+            return self.put_literal(delta, 4);
+        }
+        self.put_literal(1, 1)?;
+        if delta < 32 {
+            self.put_literal(0, 1)?;
+            return self.put_literal(delta - 16, 4);
+        }
+        self.put_literal(1, 1)?;
+        if delta < 64 {
+            self.put_literal(0, 1)?;
+            return self.put_literal(delta - 32, 5);
+        }
+        self.put_literal(1, 1)?;
+
+        let v = delta - 64;
+        if v < 65 {
+            self.put_literal(v, 7)
+        } else {
+            self.put_literal(65 + ((v - 65) >> 1), 7)?;
+            self.put_literal((v - 65) & 1, 1)
+        }
+    }
+}
+
+/// searches for the probability between the one of the counts and the old one that saves
+/// the most bits including the cost of the update, returns None if no update is worth it
+fn best_update(old: NonZeroU8, counts: [u32; 2]) -> Option<NonZeroU8> {
+    let old_cost = branch_cost(counts, old);
+    let flag_cost = bit_cost(true, DIFF_UPDATE_PROB) - bit_cost(false, DIFF_UPDATE_PROB);
+
+    // not even the cheapest update could pay for itself
+    if old_cost <= flag_cost + 5 * 256 {
+        return None;
+    }
+
+    let target = get_binary_prob(counts[0], counts[1]);
+    if target == old {
+        return None;
+    }
+
+    let (low, high) = if target < old {
+        (target.get(), old.get() - 1)
+    } else {
+        (old.get() + 1, target.get())
+    };
+
+    let mut best = None;
+    let mut best_savings = 0;
+    for p in low..=high {
+        let p = NonZeroU8::new(p).unwrap();
+        let update_cost = u64::from(delta_bits(remap_prob(p, old))) * 256 + flag_cost;
+        let savings = old_cost as i64 - branch_cost(counts, p) as i64 - update_cost as i64;
+        if savings > best_savings {
+            best_savings = savings;
+            best = Some(p);
+        }
+    }
+    best
+}
+
+#[test]
+fn remap_roundtrip() {
+    for old in 1..=255u8 {
+        let old = NonZeroU8::new(old).unwrap();
+        for new in 1..=255u8 {
+            let new = NonZeroU8::new(new).unwrap();
+            if new != old {
+                assert_eq!(new, inv_remap_prob(remap_prob(new, old), old));
+            }
+        }
+    }
+}
+
+#[test]
+fn best_update_at_max_probability() {
+    // the counts already match the old probability, so there is nothing to search
+    let old = NonZeroU8::new(255).unwrap();
+    assert_eq!(best_update(old, [100_000, 0]), None);
+}
+
+#[test]
+fn diff_update_roundtrip() {
+    use rand::Rng;
+    use std::io::Cursor;
+
+    let mut rng = rand::thread_rng();
+    let updates: Vec<(NonZeroU8, NonZeroU8, [u32; 2])> = (0..2000)
+        .map(|_| {
+            (
+                NonZeroU8::new(rng.gen_range(1..=255)).unwrap(),
+                NonZeroU8::new(rng.gen_range(1..=255)).unwrap(),
+                [rng.gen_range(0..1000), rng.gen_range(0..1000)],
+            )
+        })
+        .collect();
+
+    let mut buffer = Vec::new();
+    let mut writer = VP8Writer::new_raw(&mut buffer);
+    let mut expected = Vec::new();
+    for &(old, new, counts) in &updates {
+        let mut p = old;
+        writer.put_diff_update_prob(&mut p, new).unwrap();
+        assert_eq!(p, new);
+
+        let mut p = old;
+        writer.put_cond_diff_update_prob(&mut p, counts).unwrap();
+        expected.push(p);
+    }
+    writer.finish().unwrap();
+
+    let mut reader = VP8Reader::new_raw(Cursor::new(&buffer)).unwrap();
+    for (&(old, new, _), &cond) in updates.iter().zip(&expected) {
+        let mut p = old;
+        reader.get_diff_update_prob(&mut p).unwrap();
+        assert_eq!(p, new);
+
+        let mut p = old;
+        reader.get_diff_update_prob(&mut p).unwrap();
+        assert_eq!(p, cond);
+    }
+}
+
+#[test]
+fn cond_update_follows_counts() {
+    let old = NonZeroU8::new(128).unwrap();
+
+    // too few symbols to pay for the update
+    assert_eq!(best_update(old, [3, 1]), None);
+
+    // lots of zeros move the probability up, close to the observed 0.9
+    let p = best_update(old, [9000, 1000]).unwrap();
+    assert!((225..=235).contains(&p.get()), "{p}");
+
+    // the observed probability matches, so nothing to update
+    assert_eq!(best_update(old, [5000, 5000]), None);
+}
+
+#[test]
+fn merge_probs_matches_libvpx() {
+    let p = |v| NonZeroU8::new(v).unwrap();
+
+    // no counts leaves the probability alone
+    assert_eq!(mode_mv_merge_probs(p(100), [0, 0]), p(100));
+
+    // saturated counts move halfway to the observed probability
+    assert_eq!(mode_mv_merge_probs(p(100), [200, 0]), p(178));
+    assert_eq!(mode_mv_merge_probs(p(100), [0, 200]), p(51));
+
+    // a single symbol moves it by 6/256 of the distance
+    assert_eq!(mode_mv_merge_probs(p(100), [1, 0]), p(104));
+
+    assert_eq!(
+        merge_probs(p(100), [0, 24], COEF_COUNT_SAT, COEF_MAX_UPDATE_FACTOR),
+        p(57)
+    );
+}
+
+#[test]
+fn tree_merge_probs_sums_leaves() {
+    use crate::vp8::{DC_PRED, H_PRED, TM_PRED, UV_MODE_TREE, V_PRED};
+
+    let pre_probs = [NonZeroU8::new(128).unwrap(); 3];
+    let mut probs = pre_probs;
+
+    let mut counts = [0; 4];
+    counts[usize::from(DC_PRED)] = 100;
+    counts[usize::from(V_PRED)] = 50;
+    counts[usize::from(H_PRED)] = 0;
+    counts[usize::from(TM_PRED)] = 50;
+    tree_merge_probs(&UV_MODE_TREE, &pre_probs, &counts, &mut probs);
+
+    // DC vs the rest is 100:100, V vs H/TM is 50:50, H vs TM is 0:50
+    assert_eq!(probs[0], mode_mv_merge_probs(pre_probs[0], [100, 100]));
+    assert_eq!(probs[1], mode_mv_merge_probs(pre_probs[1], [50, 50]));
+    assert_eq!(probs[2], mode_mv_merge_probs(pre_probs[2], [0, 50]));
+    assert_eq!(probs[2].get(), 65);
+}
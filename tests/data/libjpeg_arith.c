@@ -0,0 +1,89 @@
+/* compresses a 16x16 grayscale image with the arithmetic coder of libjpeg and prints the entropy
+ * coded segment and the quantized coefficients of its 4 blocks in zigzag order, used for the
+ * matches_libjpeg test in src/jpeg.rs.
+ *
+ * build: gcc -O2 -o libjpeg_arith libjpeg_arith.c -ljpeg
+ * run:   ./libjpeg_arith
+ *
+ * The test data was generated with libjpeg-turbo 2.1.5 (Debian libjpeg62-turbo 1:2.1.5-2). */
+#include <stdio.h>
+#include <jpeglib.h>
+
+static const int zigzag[64] = {
+    0,  1,  8,  16, 9,  2,  3,  10, 17, 24, 32, 25, 18, 11, 4,  5,  12, 19, 26, 33, 40, 48,
+    41, 34, 27, 20, 13, 6,  7,  14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23,
+    30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+};
+
+int main(void) {
+    enum { W = 16, H = 16 };
+    unsigned char image[W * H];
+    struct jpeg_error_mgr err;
+
+    /* a gradient with a diagonal edge and a soft bump */
+    for (int y = 0; y < H; y++) {
+        for (int x = 0; x < W; x++) {
+            int edge = x + 2 * y > 20 ? 70 : 0;
+            int bump = ((x - 5) * (x - 5) + (y - 11) * (y - 11)) / 4;
+            image[y * W + x] = (unsigned char)(40 + x * 5 + y * 3 + edge + bump);
+        }
+    }
+
+    struct jpeg_compress_struct c;
+    c.err = jpeg_std_error(&err);
+    jpeg_create_compress(&c);
+    unsigned char *jpeg = NULL;
+    unsigned long len = 0;
+    jpeg_mem_dest(&c, &jpeg, &len);
+
+    c.image_width = W;
+    c.image_height = H;
+    c.input_components = 1;
+    c.in_color_space = JCS_GRAYSCALE;
+    jpeg_set_defaults(&c);
+    jpeg_set_quality(&c, 75, TRUE);
+    c.arith_code = TRUE;
+
+    jpeg_start_compress(&c, TRUE);
+    while (c.next_scanline < H) {
+        JSAMPROW row = image + c.next_scanline * W;
+        jpeg_write_scanlines(&c, &row, 1);
+    }
+    jpeg_finish_compress(&c);
+
+    /* the entropy coded segment follows the SOS marker segment up to the end of the file */
+    unsigned long pos = 2;
+    for (;;) {
+        int marker = jpeg[pos + 1];
+        pos += 2 + ((jpeg[pos + 2] << 8) | jpeg[pos + 3]);
+        if (marker == 0xda) {
+            break;
+        }
+    }
+    printf("segment:");
+    for (; pos < len; pos++) {
+        printf(" 0x%02x,", jpeg[pos]);
+    }
+    printf("\n");
+
+    struct jpeg_decompress_struct d;
+    d.err = jpeg_std_error(&err);
+    jpeg_create_decompress(&d);
+    jpeg_mem_src(&d, jpeg, len);
+    jpeg_read_header(&d, TRUE);
+    jvirt_barray_ptr *coefficients = jpeg_read_coefficients(&d);
+
+    jpeg_component_info *comp = &d.comp_info[0];
+    for (JDIMENSION by = 0; by < comp->height_in_blocks; by++) {
+        JBLOCKARRAY blocks =
+            d.mem->access_virt_barray((j_common_ptr)&d, coefficients[0], by, 1, FALSE);
+        for (JDIMENSION bx = 0; bx < comp->width_in_blocks; bx++) {
+            printf("[");
+            for (int k = 0; k < 64; k++) {
+                printf("%d, ", blocks[0][bx][zigzag[k]]);
+            }
+            printf("],\n");
+        }
+    }
+    return 0;
+}
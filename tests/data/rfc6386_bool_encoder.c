@@ -0,0 +1,127 @@
+/* encodes fixed sequences of bools with the bool_encoder of RFC 6386 (section 7.3, copied
+ * below without changes) and prints the partitions, used for the raw_mode_known_answers test
+ * in src/vp8.rs.
+ *
+ * build: gcc -O2 -o rfc6386_bool_encoder rfc6386_bool_encoder.c
+ * run:   ./rfc6386_bool_encoder */
+#include <stdint.h>
+#include <stdio.h>
+
+typedef uint8_t uint8;
+typedef uint32_t uint32;
+typedef uint8 Prob;
+
+/* RFC 6386 section 7.3 */
+typedef struct {
+  uint8 *output;  /* ptr to next byte to be written */
+  uint32 range;   /* 128 <= range <= 255 */
+  uint32 bottom;  /* minimum value of remaining output */
+  int bit_count;  /* # of shifts before an output byte is available */
+} bool_encoder;
+
+static void init_bool_encoder(bool_encoder *e, uint8 *start_partition)
+{
+  e->output = start_partition;
+  e->range = 255;
+  e->bottom = 0;
+  e->bit_count = 24;
+}
+
+static void add_one_to_output(uint8 *q)
+{
+  while (*--q == 255)
+    *q = 0;
+  ++*q;
+}
+
+static void write_bool(bool_encoder *e, Prob prob, int bool_value)
+{
+  /* split is approximately (range * prob) / 256 and,
+     crucially, is strictly bigger than zero and strictly
+     smaller than range */
+
+  uint32 split = 1 + (((e->range - 1) * prob) >> 8);
+
+  if (bool_value) {
+    e->bottom += split; /* move up bottom of interval */
+    e->range -= split;  /* with corresponding decrease in range */
+  } else
+    e->range = split;   /* decrease range, leaving bottom alone */
+
+  while (e->range < 128)
+  {
+    e->range <<= 1;
+
+    if (e->bottom & (1 << 31))  /* detect carry */
+      add_one_to_output(e->output);
+
+    e->bottom <<= 1;        /* before shifting bottom */
+
+    if (!--e->bit_count) {  /* write out high byte of bottom ... */
+
+      *e->output++ = (uint8) (e->bottom >> 24);
+
+      e->bottom &= (1 << 24) - 1;  /* ... keeping low 3 bytes */
+
+      e->bit_count = 8;            /* 8 shifts until next output */
+    }
+  }
+}
+
+/* Call this function (exactly once) after encoding the last
+   bool value for the partition being written */
+
+static void flush_bool_encoder(bool_encoder *e)
+{
+  int c = e->bit_count;
+  uint32 v = e->bottom;
+
+  if (v & (1 << (32 - c)))   /* propagate (unlikely) carry */
+    add_one_to_output(e->output);
+  v <<= c & 7;               /* before shifting remaining output */
+  c >>= 3;                   /* to top of internal buffer */
+  while (--c >= 0)
+    v <<= 8;
+  c = 4;
+  while (--c >= 0) {    /* write remaining data, possibly padded */
+    *e->output++ = (uint8) (v >> 24);
+    v <<= 8;
+  }
+}
+/* end of RFC 6386 section 7.3 */
+
+static void print_partition(const char *name, uint8 *start, uint8 *end)
+{
+  printf("%s (%d bytes):", name, (int)(end - start));
+  for (uint8 *p = start; p < end; p++)
+    printf("%s0x%02x", p - start ? ", " : " ", *p);
+  printf("\n");
+}
+
+int main(void)
+{
+  static uint8 buffer[4096];
+  bool_encoder e;
+
+  /* probabilities and bools that vary with the position */
+  init_bool_encoder(&e, buffer);
+  for (int i = 0; i < 200; i++)
+    write_bool(&e, (Prob)(1 + i * 73 % 255), (i * i + 3 * i) % 7 < 3);
+  flush_bool_encoder(&e);
+  print_partition("varied", buffer, e.output);
+
+  /* bools and probabilities from a xorshift generator, with a seed for which a carry
+     propagates over a 0xff byte that was already written */
+  uint32 seed = 5912107;
+  init_bool_encoder(&e, buffer);
+  for (int i = 0; i < 100; i++) {
+    seed ^= seed << 13;
+    seed ^= seed >> 17;
+    seed ^= seed << 5;
+    write_bool(&e, (Prob)(1 + (seed >> 8) % 255), (seed >> 24) >= (seed & 0xff));
+  }
+  flush_bool_encoder(&e);
+  print_partition("carry", buffer, e.output);
+
+  return 0;
+}
@@ -0,0 +1,88 @@
+/* writes CABAC slice data with the entropy coder of x265 (x265::Entropy), used for the
+ * x265_reference_payload test in src/h265.rs.
+ *
+ * build: gcc -O2 -o x265_cabac x265_cabac.c -l:libx265.so.199
+ * run:   ./x265_cabac 3000 0x2545f491
+ *
+ * x265 does not install the Entropy headers, so the offsets of the fields used below are
+ * those of libx265 3.5 (Debian 3.5-2+b1, x86-64, 8-bit build). */
+#include <stdio.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+void entropy_ctor(void *) __asm__("_ZN4x2657EntropyC1Ev");
+void encode_bin(void *, uint32_t, uint8_t *) __asm__("_ZN4x2657Entropy9encodeBinEjRh");
+void encode_bin_ep(void *, uint32_t) __asm__("_ZN4x2657Entropy11encodeBinEPEj");
+void encode_bin_trm(void *, uint32_t) __asm__("_ZN4x2657Entropy12encodeBinTrmEj");
+void entropy_finish(void *) __asm__("_ZN4x2657Entropy6finishEv");
+void bitstream_ctor(void *) __asm__("_ZN4x2659BitstreamC1Ev");
+void write_byte_alignment(void *) __asm__("_ZN4x2659Bitstream18writeByteAlignmentEv");
+
+static uint32_t state;
+static uint32_t next(void) {
+    state ^= state << 13;
+    state ^= state >> 17;
+    state ^= state << 5;
+    return state;
+}
+
+/* Entropy::start() */
+static void start(uint8_t *e) {
+    *(uint32_t *)(e + 0xb0) = 0;    /* m_low */
+    *(uint32_t *)(e + 0xb4) = 510;  /* m_range */
+    *(uint32_t *)(e + 0xb8) = 0xff; /* m_bufferedByte */
+    *(int32_t *)(e + 0xbc) = 0;     /* m_numBufferedBytes */
+    *(int32_t *)(e + 0xc0) = -12;   /* m_bitsLeft */
+}
+
+static int clip3(int lo, int hi, int v) { return v < lo ? lo : v > hi ? hi : v; }
+
+int main(int argc, char **argv) {
+    static const uint8_t init_values[8] = {139, 141, 157, 154, 197, 185, 201, 110};
+    int qp = 32, len = atoi(argv[1]);
+    state = (uint32_t)strtoul(argv[2], 0, 0);
+
+    uint8_t *e = calloc(1, 1 << 20), *bs = calloc(1, 4096);
+    entropy_ctor(e);
+    bitstream_ctor(bs);
+    *(void **)e = bs; /* m_bitIf */
+    start(e);
+
+    uint8_t ctx[8];
+    for (int i = 0; i < 8; i++) {
+        int m = (init_values[i] >> 4) * 5 - 45, n = ((init_values[i] & 15) << 3) - 16;
+        int pre = clip3(1, 126, ((m * clip3(0, 51, qp)) >> 4) + n);
+        int mps = pre > 63;
+        ctx[i] = (uint8_t)(((mps ? pre - 64 : 63 - pre) << 1) | mps);
+    }
+
+    for (int i = 0; i < len; i++) {
+        uint32_t r = next();
+        uint32_t kind = r % 10, c = (r >> 8) & 7;
+        if (kind <= 5) {
+            encode_bin(e, ((r >> 16) & 63) < 2 + 8 * c, &ctx[c]);
+        } else if (kind <= 8) {
+            encode_bin_ep(e, r >> 31);
+        } else if (((r >> 16) & 7) == 0) {
+            /* end_of_subset_one_bit */
+            encode_bin_trm(e, 1);
+            entropy_finish(e);
+            write_byte_alignment(bs);
+            start(e);
+        } else {
+            encode_bin_trm(e, 0);
+        }
+    }
+
+    /* end_of_slice_segment_flag */
+    encode_bin_trm(e, 1);
+    entropy_finish(e);
+    write_byte_alignment(bs);
+
+    uint8_t *fifo = *(uint8_t **)(bs + 8);
+    uint32_t size = *(uint32_t *)(bs + 0x14);
+    for (uint32_t i = 0; i < size; i++)
+        printf("0x%02x,%c", fifo[i], i % 12 == 11 ? '\n' : ' ');
+    printf("\n");
+    return 0;
+}
@@ -1,14 +1,17 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read, Write};
 
 use cabac::fpaq0::{Fpaq0Decoder, Fpaq0Encoder};
 use cabac::fpaq0parallel::{
     BypassBitDecoder, BypassBitEncoder, Fpaq0DecoderParallel, Fpaq0EncoderParallel,
     ParallelEncoderOutput,
 };
-use cabac::h265::{H265Reader, H265Writer};
+use cabac::h265::{H265Context, H265Reader, H265Writer};
 use cabac::rans32::{RansReader32, RansWriter32};
-use cabac::vp8::{VP8Context, VP8Reader, VP8Writer};
+use cabac::rans32x::{RansReader32x, RansWriter32x};
+use cabac::rans64::{RansReader64, RansWriter64};
+use cabac::vp8::{StaticCoder, StaticContext, VP8Context, VP8Reader, VP8Writer};
 use cabac::{CabacReader, CabacWriter};
+use std::num::NonZeroU8;
 
 #[derive(Clone, Copy)]
 enum Seq {
@@ -32,7 +35,7 @@ fn do_write<C: Default, CW: CabacWriter<C>>(seq: &[Seq], mut writer: CW) {
     writer.finish().unwrap();
 }
 
-fn do_read<C: Default, CR: CabacReader<C>>(seq: &[Seq], mut reader: CR, scheme: &str) {
+fn do_read<C: Default, CR: CabacReader<C>>(seq: &[Seq], mut reader: CR, scheme: &str) -> CR {
     {
         let mut context = Vec::new();
         for _ in 0..16 {
@@ -58,30 +61,60 @@ fn do_read<C: Default, CR: CabacReader<C>>(seq: &[Seq], mut reader: CR, scheme:
             }
         }
     }
+
+    reader
 }
 
 fn test_seq_vp8(seq: &[Seq]) {
     let mut vec = Vec::new();
     do_write(seq, VP8Writer::new(&mut vec).unwrap());
-    do_read(seq, VP8Reader::new(Cursor::new(&vec)).unwrap(), "vp8");
+    let _ = do_read(seq, VP8Reader::new(Cursor::new(&vec)).unwrap(), "vp8");
 }
 
 fn test_seq_h265(seq: &[Seq]) {
     let mut vec = Vec::new();
     do_write(seq, H265Writer::new(&mut vec));
-    do_read(seq, H265Reader::new(Cursor::new(&vec)).unwrap(), "h265");
+    let _ = do_read(seq, H265Reader::new(Cursor::new(&vec)).unwrap(), "h265");
+}
+
+fn test_seq_h265_conformant(seq: &[Seq]) {
+    let mut vec = Vec::new();
+    do_write(seq, H265Writer::new_conformant(&mut vec));
+
+    let mut reader = do_read(
+        seq,
+        H265Reader::new(Cursor::new(&vec)).unwrap(),
+        "h265_conformant",
+    );
+    assert!(reader.get_terminate().unwrap());
 }
 
 fn test_seq_rans(seq: &[Seq]) {
     let mut vec = Vec::new();
     do_write(seq, RansWriter32::new(&mut vec));
-    do_read(seq, RansReader32::new(Cursor::new(&vec)).unwrap(), "rans");
+    let _ = do_read(seq, RansReader32::new(Cursor::new(&vec)).unwrap(), "rans");
+}
+
+fn test_seq_rans32x(seq: &[Seq]) {
+    let mut vec = Vec::new();
+    do_write(seq, RansWriter32x::<_, 8>::new(&mut vec));
+    let _ = do_read(
+        seq,
+        RansReader32x::<_, 8>::new(Cursor::new(&vec)).unwrap(),
+        "rans32x",
+    );
+}
+
+fn test_seq_rans64(seq: &[Seq]) {
+    let mut vec = Vec::new();
+    do_write(seq, RansWriter64::new(&mut vec));
+    let _ = do_read(seq, RansReader64::new(Cursor::new(&vec)).unwrap(), "rans64");
 }
 
 fn test_seq_fpaq(seq: &[Seq]) {
     let mut vec = Vec::new();
     do_write(seq, Fpaq0Encoder::new(&mut vec));
-    do_read(seq, Fpaq0Decoder::new(Cursor::new(&vec)).unwrap(), "fpaq");
+    let _ = do_read(seq, Fpaq0Decoder::new(Cursor::new(&vec)).unwrap(), "fpaq");
 }
 
 /// FPAQ parallel encoder/decoder
@@ -151,7 +184,10 @@ fn test_seq_fpaq_parallel(seq: &[Seq]) {
 fn test_all(seq: &[Seq]) {
     test_seq_vp8(seq);
     test_seq_h265(seq);
+    test_seq_h265_conformant(seq);
     test_seq_rans(seq);
+    test_seq_rans32x(seq);
+    test_seq_rans64(seq);
     test_seq_fpaq(seq);
     test_seq_fpaq_parallel(seq);
 }
@@ -256,3 +292,367 @@ fn test_alt_bypass() {
 
     test_all(&seq);
 }
+
+/// bits coded with forward-signalled probabilities instead of adaptive contexts
+#[test]
+fn explicit_probability() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let bins: Vec<(bool, NonZeroU8)> = (0..10000)
+        .map(|_| {
+            let p = NonZeroU8::new(rng.gen_range(1..=255)).unwrap();
+            (rng.gen_range(0..=255) >= p.get(), p)
+        })
+        .collect();
+
+    let mut vec = Vec::new();
+    let mut writer = VP8Writer::new(&mut vec).unwrap();
+    for &(b, p) in &bins {
+        writer.put_with_probability(b, p).unwrap();
+    }
+    writer.finish().unwrap();
+    let mut reader = VP8Reader::new(Cursor::new(&vec)).unwrap();
+    for &(b, p) in &bins {
+        assert_eq!(b, reader.get_with_probability(p).unwrap(), "vp8");
+    }
+
+    let mut vec = Vec::new();
+    let mut writer = Fpaq0Encoder::new(&mut vec);
+    for &(b, p) in &bins {
+        writer.put_with_probability(b, p).unwrap();
+    }
+    writer.finish().unwrap();
+    let mut reader = Fpaq0Decoder::new(Cursor::new(&vec)).unwrap();
+    for &(b, p) in &bins {
+        assert_eq!(b, reader.get_with_probability(p).unwrap(), "fpaq");
+    }
+
+    let mut vec = Vec::new();
+    let mut writer = RansWriter32::new(&mut vec);
+    for &(b, p) in &bins {
+        writer.put_with_probability(b, p).unwrap();
+    }
+    writer.finish().unwrap();
+    let mut reader = RansReader32::new(Cursor::new(&vec)).unwrap();
+    for &(b, p) in &bins {
+        assert_eq!(b, reader.get_with_probability(p).unwrap(), "rans");
+    }
+
+    let mut vec = Vec::new();
+    let mut writer = RansWriter64::new(&mut vec);
+    for &(b, p) in &bins {
+        writer.put_with_probability(b, p).unwrap();
+    }
+    writer.finish().unwrap();
+    let mut reader = RansReader64::new(Cursor::new(&vec)).unwrap();
+    for &(b, p) in &bins {
+        assert_eq!(b, reader.get_with_probability(p).unwrap(), "rans64");
+    }
+
+    let mut vec = Vec::new();
+    {
+        let mut encoder_output = ParallelEncoderOutput::new(&mut vec);
+        let mut writer = Fpaq0EncoderParallel::new(&mut encoder_output);
+        for &(b, p) in &bins {
+            writer
+                .put_with_probability(b, p, &mut encoder_output)
+                .unwrap();
+        }
+        writer.finish(&mut encoder_output).unwrap();
+    }
+    let mut bytestreamreader = Cursor::new(&vec);
+    let mut reader = Fpaq0DecoderParallel::new(&mut bytestreamreader).unwrap();
+    for &(b, p) in &bins {
+        assert_eq!(
+            b,
+            reader
+                .get_with_probability(p, &mut bytestreamreader)
+                .unwrap(),
+            "fpaq_parallel"
+        );
+    }
+}
+
+/// a StaticContext never adapts, so any coder wrapped in a StaticCoder can use it with the
+/// generic traits
+#[test]
+fn static_context() {
+    let mut seq = Vec::new();
+    for i in 0..10000 {
+        seq.push(if i % 7 == 0 {
+            Seq::Bypass(i % 3 == 0)
+        } else {
+            Seq::Normal(i % 5 == 0, i % 16)
+        });
+    }
+
+    let mut vec = Vec::new();
+    do_write::<StaticContext, _>(&seq, StaticCoder::new(VP8Writer::new(&mut vec).unwrap()));
+    let reader = StaticCoder::new(VP8Reader::new(Cursor::new(&vec)).unwrap());
+    let _ = do_read::<StaticContext, _>(&seq, reader, "vp8");
+
+    let mut vec = Vec::new();
+    do_write::<StaticContext, _>(&seq, StaticCoder::new(Fpaq0Encoder::new(&mut vec)));
+    let reader = StaticCoder::new(Fpaq0Decoder::new(Cursor::new(&vec)).unwrap());
+    let _ = do_read::<StaticContext, _>(&seq, reader, "fpaq");
+
+    let mut vec = Vec::new();
+    do_write::<StaticContext, _>(&seq, StaticCoder::new(RansWriter32::new(&mut vec)));
+    let reader = StaticCoder::new(RansReader32::new(Cursor::new(&vec)).unwrap());
+    let _ = do_read::<StaticContext, _>(&seq, reader, "rans");
+
+    let mut vec = Vec::new();
+    do_write::<StaticContext, _>(&seq, StaticCoder::new(RansWriter64::new(&mut vec)));
+    let reader = StaticCoder::new(RansReader64::new(Cursor::new(&vec)).unwrap());
+    let _ = do_read::<StaticContext, _>(&seq, reader, "rans64");
+
+    // the probability stays where it was set no matter what is coded
+    let mut context = StaticContext::new(NonZeroU8::new(200).unwrap());
+    let mut vec = Vec::new();
+    let mut writer = StaticCoder::new(VP8Writer::new(&mut vec).unwrap());
+    for _ in 0..100 {
+        writer.put(true, &mut context).unwrap();
+    }
+    assert_eq!(context.get_probability().get(), 200);
+}
+
+/// terminate bins split the stream into separate codewords, with raw bytes (like PCM samples)
+/// in between that have to be byte aligned
+#[test]
+fn terminate_h265() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..100 {
+        let mut seq = Vec::new();
+        for _ in 0..rng.gen_range(0..2000) {
+            let ctx = rng.gen_range(0..16);
+            seq.push(match rng.gen_range(0..4) {
+                0 | 1 => Seq::Normal(rng.gen_bool(0.1 + ctx as f64 / 20.0), ctx),
+                _ => Seq::Bypass(rng.gen()),
+            });
+        }
+        let pcm: Vec<u8> = (0..rng.gen_range(0..10)).map(|_| rng.gen()).collect();
+
+        let mut vec = Vec::new();
+        {
+            let mut writer = H265Writer::new(&mut vec);
+            let mut context = [H265Context::default(); 16];
+
+            for (i, &s) in seq.iter().enumerate() {
+                match s {
+                    Seq::Normal(b, c) => writer.put(b, &mut context[c]).unwrap(),
+                    Seq::Bypass(b) => writer.put_bypass(b).unwrap(),
+                }
+                if i % 100 == 99 {
+                    writer.put_terminate(false).unwrap();
+                }
+            }
+            writer.put_terminate(true).unwrap();
+            writer.get_mut().write_all(&pcm).unwrap();
+
+            writer.put(true, &mut context[0]).unwrap();
+            writer.put_terminate(true).unwrap();
+        }
+
+        let mut reader = H265Reader::new(Cursor::new(&vec)).unwrap();
+        let mut context = [H265Context::default(); 16];
+
+        for (i, s) in seq.iter().enumerate() {
+            match *s {
+                Seq::Normal(b, c) => assert_eq!(b, reader.get(&mut context[c]).unwrap()),
+                Seq::Bypass(b) => assert_eq!(b, reader.get_bypass().unwrap()),
+            }
+            if i % 100 == 99 {
+                assert!(!reader.get_terminate().unwrap());
+            }
+        }
+        assert!(reader.get_terminate().unwrap());
+
+        let mut read_pcm = vec![0; pcm.len()];
+        reader.read_bytes(&mut read_pcm).unwrap();
+        assert_eq!(pcm, read_pcm);
+
+        reader.reset().unwrap();
+        assert!(reader.get(&mut context[0]).unwrap());
+        assert!(reader.get_terminate().unwrap());
+
+        // everything up to the final stop bit and alignment was consumed
+        let mut rest = Vec::new();
+        let _ = reader.into_inner().read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
+
+/// encodes a slice with wavefront substreams and decodes each row on its own thread
+#[test]
+fn wpp_h265() {
+    use cabac::h265::wpp::{
+        entry_point_offsets_minus1, split_substreams, WppReader, WppSync, WppWriter,
+    };
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    for &(width, first_ctb_x, num_ctus) in &[(1, 0, 5), (2, 1, 7), (5, 0, 23), (8, 3, 40)] {
+        // each CTU consists of a few bins that depend on the position in the picture
+        let ctus: Vec<Vec<Seq>> = (0..num_ctus)
+            .map(|_| {
+                (0..rng.gen_range(1..200))
+                    .map(|_| {
+                        let ctx = rng.gen_range(0..16);
+                        if rng.gen_bool(0.8) {
+                            Seq::Normal(rng.gen_bool(0.05 + ctx as f64 / 20.0), ctx)
+                        } else {
+                            Seq::Bypass(rng.gen())
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut writer = WppWriter::new(width, first_ctb_x, [H265Context::default(); 16]);
+        for (i, ctu) in ctus.iter().enumerate() {
+            let (w, contexts) = writer.start_ctu();
+            for &s in ctu {
+                match s {
+                    Seq::Normal(b, c) => w.put(b, &mut contexts[c]).unwrap(),
+                    Seq::Bypass(b) => w.put_bypass(b).unwrap(),
+                }
+            }
+            writer.end_ctu(i == ctus.len() - 1).unwrap();
+        }
+
+        let substreams = writer.into_substreams();
+        assert_eq!(substreams.len(), (first_ctb_x + num_ctus - 1) / width + 1);
+
+        let sizes: Vec<usize> = substreams.iter().map(|s| s.len()).collect();
+        let entry_points = entry_point_offsets_minus1(&sizes);
+        let slice_data = substreams.concat();
+
+        // split the CTUs up by row
+        let mut rows: Vec<&[Vec<Seq>]> = Vec::new();
+        let mut rest = &ctus[..];
+        let mut row_len = width - first_ctb_x;
+        while !rest.is_empty() {
+            let (row, r) = rest.split_at(row_len.min(rest.len()));
+            rows.push(row);
+            rest = r;
+            row_len = width;
+        }
+
+        let sync = WppSync::new(width, first_ctb_x, [H265Context::default(); 16]);
+        let substreams = split_substreams(&slice_data, &entry_points);
+        assert_eq!(substreams.len(), rows.len());
+
+        std::thread::scope(|s| {
+            // start the threads from the bottom row so they actually have to wait
+            for (row, (&substream, &ctus)) in substreams.iter().zip(rows.iter()).enumerate().rev() {
+                let sync = &sync;
+                let _ = s.spawn(move || {
+                    let mut reader = WppReader::new(sync, row, substream).unwrap();
+                    for (i, ctu) in ctus.iter().enumerate() {
+                        let (r, contexts) = reader.start_ctu();
+                        for &s in ctu {
+                            match s {
+                                Seq::Normal(b, c) => {
+                                    assert_eq!(b, r.get(&mut contexts[c]).unwrap())
+                                }
+                                Seq::Bypass(b) => assert_eq!(b, r.get_bypass().unwrap()),
+                            }
+                        }
+                        assert_eq!(reader.end_ctu().unwrap(), i != ctus.len() - 1);
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// CABAC output sent through emulation prevention has no start code emulations and decodes
+/// after the escapes have been removed
+#[test]
+fn emulation_prevention_h265() {
+    use cabac::nal::{EmulationPreventionReader, EmulationPreventionWriter};
+
+    // long runs of zeros produce lots of zero bytes
+    let mut seq = vec![Seq::Normal(false, 0); 20000];
+    seq.extend((0..2000).map(|i| Seq::Bypass(i % 7 == 0)));
+    seq.extend((0..2000).map(|_| Seq::Bypass(false)));
+
+    let mut escaped = Vec::new();
+    let inserted;
+    let raw_len;
+    {
+        let mut raw = Vec::new();
+        do_write(&seq, H265Writer::new_conformant(&mut raw));
+        raw_len = raw.len();
+
+        let mut writer = EmulationPreventionWriter::new(&mut escaped);
+        writer.write_all(&raw).unwrap();
+        writer.finish().unwrap();
+        inserted = writer.inserted_bytes();
+    }
+
+    assert!(inserted > 0);
+    assert_eq!(escaped.len(), raw_len + inserted as usize);
+    assert!(escaped
+        .windows(3)
+        .all(|w| !(w[0] == 0 && w[1] == 0 && w[2] <= 2)));
+
+    let mut reader = EmulationPreventionReader::new(Cursor::new(&escaped));
+    let _ = do_read(&seq, H265Reader::new(&mut reader).unwrap(), "h265_escaped");
+}
+
+/// a raw header followed by arithmetic coded data in the same stream
+#[test]
+fn bitstream_header_handoff() {
+    use cabac::bitstream::{BitReader, BitWriter};
+
+    let mut seq = Vec::new();
+    for i in 0..1000 {
+        seq.push(Seq::Normal(i % 3 == 0, i % 4));
+        seq.push(Seq::Bypass(i % 5 == 0));
+    }
+
+    let mut output = Vec::new();
+    {
+        let mut header = BitWriter::new(&mut output);
+        header.put_bits(0x1a, 6).unwrap();
+        header.put_ue(12345).unwrap();
+        header.put_se(-77).unwrap();
+        header.put_flag(true).unwrap();
+        header.put_rbsp_trailing_bits().unwrap();
+
+        do_write(&seq, H265Writer::new_conformant(header.into_inner()));
+    }
+    {
+        let mut header = BitWriter::new(&mut output);
+        header.put_ue(3).unwrap();
+        header.byte_align().unwrap();
+
+        do_write(&seq, VP8Writer::new(header.into_inner()).unwrap());
+    }
+
+    let mut cursor = Cursor::new(&output);
+
+    let mut header = BitReader::new(&mut cursor);
+    assert_eq!(header.get_bits(6).unwrap(), 0x1a);
+    assert_eq!(header.get_ue().unwrap(), 12345);
+    assert_eq!(header.get_se().unwrap(), -77);
+    assert!(header.get_flag().unwrap());
+    assert!(header.get_flag().unwrap());
+    header.byte_align();
+
+    let mut reader = do_read(&seq, H265Reader::new(header.into_inner()).unwrap(), "h265");
+    assert!(reader.get_terminate().unwrap());
+
+    // the terminated codeword ends right before the next header
+    let mut header = BitReader::new(reader.into_inner());
+    assert_eq!(header.get_ue().unwrap(), 3);
+    header.byte_align();
+
+    let _ = do_read(&seq, VP8Reader::new(header.into_inner()).unwrap(), "vp8");
+}